@@ -0,0 +1,296 @@
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use glam::{vec2, vec3, Mat4};
+use rand::{distributions::Uniform, prelude::Distribution, SeedableRng};
+use rt_summer::{
+    bvh::BvhOptions,
+    color::{
+        color_space::ColorSpace,
+        quantity::ColorMode,
+        spectrum::{rgb_spectrum, SampledWavelengths},
+    },
+    geometry::Ray,
+    integrator::{scratch::RenderScratch, Integrator},
+    pbrt_loader::{
+        scene_description::{
+            Material, SceneDescription, ScreenWideOptions, Shape, ShapeWithParams, TriMesh,
+        },
+        SceneLoader,
+    },
+    render_threads::{RenderContext, RenderThreads},
+    sampler::Sampler,
+    scene::{primitive::PrimitiveLayout, Scene},
+};
+
+const SCENE_PATH: &str = "resources/scenes/cornell-box/scene-v4.pbrt";
+const NUM_RAYS: usize = 100_000;
+
+fn load_scene_desc() -> SceneDescription {
+    SceneLoader::load_from_path(SCENE_PATH).unwrap()
+}
+
+fn bench_bvh_build(c: &mut Criterion) {
+    let mut scene = Scene::init(load_scene_desc()).unwrap();
+
+    c.bench_function("bvh build", |b| {
+        b.iter(|| scene.rebuild_bvh(BvhOptions::default()))
+    });
+}
+
+/// Camera rays through random points of the image, so that the traversal sees a representative
+/// mix of hits.
+fn gen_camera_rays(render_context: &RenderContext) -> Vec<Ray> {
+    let mut rng = Sampler::seed_from_u64(0);
+    let dist = Uniform::from(0f32..1f32);
+
+    (0..NUM_RAYS)
+        .map(|_| {
+            let mut ray = render_context
+                .cam
+                .gen_ray(vec2(dist.sample(&mut rng), dist.sample(&mut rng)));
+            ray.transform(render_context.world_from_camera);
+            ray
+        })
+        .collect()
+}
+
+fn bench_bvh_traversal(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bvh traversal");
+    group.throughput(Throughput::Elements(NUM_RAYS as u64));
+
+    for cache_triangles in [false, true] {
+        let mut scene_desc = load_scene_desc();
+        scene_desc.options.general_options.bvh.cache_triangles = cache_triangles;
+
+        let integrator = Integrator::new("simple-path").unwrap();
+        let render_context = RenderContext::new(scene_desc, integrator).unwrap();
+        let rays = gen_camera_rays(&render_context);
+        let scene = &render_context.scene;
+
+        let name = if cache_triangles {
+            "camera rays cached triangles"
+        } else {
+            "camera rays"
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                rays.iter()
+                    .filter(|ray| {
+                        scene
+                            .bvh()
+                            .intersect(ray, f32::INFINITY, scene.primitives())
+                            .is_some()
+                    })
+                    .count()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// A bumpy grid of 2 * LARGE_MESH_RES^2 triangles in the XZ plane, spanning [0, 1]^2
+fn large_mesh_scene_desc(bvh: BvhOptions) -> SceneDescription {
+    const LARGE_MESH_RES: usize = 512;
+
+    let vertex_row = LARGE_MESH_RES + 1;
+    let pos = (0..vertex_row * vertex_row)
+        .map(|i| {
+            let (x, z) = (i % vertex_row, i / vertex_row);
+            let (x, z) = (
+                x as f32 / LARGE_MESH_RES as f32,
+                z as f32 / LARGE_MESH_RES as f32,
+            );
+            vec3(x, 0.02 * (40. * x).sin() * (40. * z).cos(), z)
+        })
+        .collect();
+
+    let mut indices = Vec::with_capacity(LARGE_MESH_RES * LARGE_MESH_RES * 6);
+    for z in 0..LARGE_MESH_RES {
+        for x in 0..LARGE_MESH_RES {
+            let i = (z * vertex_row + x) as i32;
+            let row = vertex_row as i32;
+            indices.extend_from_slice(&[i, i + 1, i + row, i + 1, i + row + 1, i + row]);
+        }
+    }
+
+    let mut options = ScreenWideOptions::default();
+    options.general_options.bvh = bvh;
+
+    SceneDescription {
+        options,
+        shapes: vec![ShapeWithParams::new(
+            Shape::TriMesh(TriMesh::new(indices, pos, None, None, None)),
+            Material::new_empty(),
+            None,
+            Mat4::IDENTITY,
+            false,
+            None,
+        )],
+        infinite_light: None,
+        projection_lights: Vec::new(),
+    }
+}
+
+/// Intersection throughput on a mesh that's large compared to the cornell box, where fetching
+/// the vertices dominates.
+fn bench_large_mesh_traversal(c: &mut Criterion) {
+    let mut rng = Sampler::seed_from_u64(0);
+    let dist = Uniform::from(0f32..1f32);
+    let rays: Vec<Ray> = (0..NUM_RAYS)
+        .map(|_| {
+            let orig = vec3(dist.sample(&mut rng), 1., dist.sample(&mut rng));
+            let target = vec3(dist.sample(&mut rng), 0., dist.sample(&mut rng));
+            Ray::new(orig, target - orig)
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("large mesh traversal");
+    group.throughput(Throughput::Elements(NUM_RAYS as u64));
+
+    for (name, cache_triangles, primitive_layout) in [
+        ("512k triangles", false, PrimitiveLayout::Boxed),
+        ("512k triangles cached", true, PrimitiveLayout::Boxed),
+        ("512k triangles soa", false, PrimitiveLayout::Soa),
+        ("512k triangles soa cached", true, PrimitiveLayout::Soa),
+    ] {
+        let scene = Scene::init(large_mesh_scene_desc(BvhOptions {
+            cache_triangles,
+            primitive_layout,
+            ..BvhOptions::default()
+        }))
+        .unwrap();
+
+        // Traced through the scene, the BVH alone can't tell the layouts apart
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                rays.iter()
+                    .filter(|ray| scene.trace_ray(ray).is_some())
+                    .count()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Compares the per-ray setup of fetching the RGB2Spec table from the OnceLock and constructing
+/// new wavelengths, with the reference stored in the RenderContext and the reused wavelengths.
+/// The same for constructing the uniform distribution for every draw versus reusing the one
+/// in the per-thread scratch.
+fn bench_per_ray_overhead(c: &mut Criterion) {
+    let integrator = Integrator::new("simple-path").unwrap();
+    let render_context = RenderContext::new(load_scene_desc(), integrator).unwrap();
+    let mut rng = Sampler::seed_from_u64(0);
+
+    let mut group = c.benchmark_group("per-ray overhead");
+    group.throughput(Throughput::Elements(NUM_RAYS as u64));
+
+    group.bench_function("oncelock lookup and new wavelengths", |b| {
+        b.iter(|| {
+            for _ in 0..NUM_RAYS {
+                let rgbtospec = rgb_spectrum::rgbtospec(ColorSpace::Srgb).unwrap();
+                let sampled_lambdas: SampledWavelengths =
+                    SampledWavelengths::new_sample_uniform(&mut rng);
+                black_box((rgbtospec, &sampled_lambdas));
+            }
+        })
+    });
+
+    group.bench_function("context reference and reused wavelengths", |b| {
+        let mut sampled_lambdas: SampledWavelengths =
+            SampledWavelengths::new_sample_uniform(&mut rng);
+        b.iter(|| {
+            for _ in 0..NUM_RAYS {
+                let rgbtospec = render_context.rgbtospec;
+                sampled_lambdas.resample_uniform(&mut rng);
+                black_box((rgbtospec, &sampled_lambdas));
+            }
+        })
+    });
+
+    // A path of a few bounces draws around a dozen uniform samples
+    const DRAWS_PER_RAY: usize = 12;
+
+    group.bench_function("new uniform per draw", |b| {
+        b.iter(|| {
+            for _ in 0..(NUM_RAYS * DRAWS_PER_RAY) {
+                black_box(Uniform::from(0f32..1f32).sample(&mut rng));
+            }
+        })
+    });
+
+    group.bench_function("scratch uniform", |b| {
+        let scratch = RenderScratch::new(render_context.rgbtospec)
+            .with_force_diffuse(render_context.force_diffuse);
+        b.iter(|| {
+            for _ in 0..(NUM_RAYS * DRAWS_PER_RAY) {
+                black_box(scratch.uniform.sample(&mut rng));
+            }
+        })
+    });
+
+    group.finish();
+}
+
+fn bench_render_sample(c: &mut Criterion) {
+    let mut group = c.benchmark_group("integration");
+    group.sample_size(10);
+
+    for integrator_name in ["random-walk", "simple-path"] {
+        for (color_mode, mode_name) in [(ColorMode::Spectral, ""), (ColorMode::Rgb, " rgb")] {
+            let integrator = Integrator::new(integrator_name).unwrap();
+            let render_context = RenderContext::new(load_scene_desc(), integrator)
+                .unwrap()
+                .with_color_mode(color_mode)
+                .unwrap();
+            let render_context = Arc::new(render_context);
+            let mut threads =
+                RenderThreads::new(num_cpus::get(), Some(0), Arc::clone(&render_context)).unwrap();
+
+            group.bench_function(
+                format!("cornell box 1spp {integrator_name}{mode_name}"),
+                |b| b.iter(|| threads.render_once()),
+            );
+        }
+    }
+
+    group.finish();
+}
+
+/// Whole passes with and without the per-thread tile buffers
+fn bench_thread_scaling(c: &mut Criterion) {
+    let mut group = c.benchmark_group("thread scaling");
+    group.sample_size(10);
+
+    for num_threads in [1, 4, 8] {
+        for (tile_buffers, buffers_name) in [(false, "direct"), (true, "tile buffers")] {
+            let integrator = Integrator::new("simple-path").unwrap();
+            let render_context = RenderContext::new(load_scene_desc(), integrator)
+                .unwrap()
+                .with_tile_buffers(tile_buffers);
+            let render_context = Arc::new(render_context);
+            let pixels = render_context.film.width() * render_context.film.height();
+            let mut threads =
+                RenderThreads::new(num_threads, Some(0), Arc::clone(&render_context)).unwrap();
+
+            group.throughput(Throughput::Elements(pixels as u64));
+            group.bench_function(
+                format!("cornell box 1spp {num_threads} threads {buffers_name}"),
+                |b| b.iter(|| threads.render_once()),
+            );
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_bvh_build,
+    bench_bvh_traversal,
+    bench_large_mesh_traversal,
+    bench_per_ray_overhead,
+    bench_render_sample,
+    bench_thread_scaling
+);
+criterion_main!(benches);
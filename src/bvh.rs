@@ -1,11 +1,92 @@
-use glam::Vec3;
+use std::{
+    array,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use eyre::Result;
+use glam::{BVec4A, Vec3, Vec4};
 
 use crate::{
     geometry::{Axis, Ray, AABB},
-    scene::{primitive::Primitive, HitInfo},
+    scene::{
+        primitive::{Primitive, PrimitiveLayout},
+        HitInfo,
+    },
+    stats::{self, TestKind},
     util::TaggedPtr,
 };
 
+/// How the primitives of a node are divided between its children
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitMethod {
+    /// Surface-area heuristic
+    Sah,
+    /// Splits the centroid bounds in the middle of the longest axis
+    Middle,
+    /// Splits the primitives into two halves with the same count
+    EqualCounts,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BvhOptions {
+    pub split_method: SplitMethod,
+    /// Used by the SAH, larger nodes are always split
+    pub max_prims_in_node: usize,
+    /// Precompute triangle edges and normals, uses more memory
+    pub cache_triangles: bool,
+    pub primitive_layout: PrimitiveLayout,
+}
+
+impl Default for BvhOptions {
+    fn default() -> Self {
+        Self {
+            split_method: SplitMethod::Sah,
+            max_prims_in_node: 4,
+            cache_triangles: false,
+            primitive_layout: PrimitiveLayout::default(),
+        }
+    }
+}
+
+/// Storage of the primitives that the BVH is built over. The primitives are addressed by their
+/// index, building the BVH reorders them so that every leaf refers to a range of indices.
+pub trait PrimitiveStorage {
+    fn primitive_count(&self) -> usize;
+
+    fn primitive_aabb(&self, index: usize) -> AABB;
+
+    fn intersect_primitive(&self, index: usize, ray: &Ray) -> Option<HitInfo>;
+
+    /// Fills in the parts of the closest hit that aren't needed for finding it
+    fn complete_hit(&self, index: usize, hitinfo: &mut HitInfo);
+
+    fn swap_primitives(&mut self, a: usize, b: usize);
+}
+
+impl PrimitiveStorage for [TaggedPtr<Primitive>] {
+    fn primitive_count(&self) -> usize {
+        self.len()
+    }
+
+    fn primitive_aabb(&self, index: usize) -> AABB {
+        self[index].aabb()
+    }
+
+    fn intersect_primitive(&self, index: usize, ray: &Ray) -> Option<HitInfo> {
+        self[index].intersect(ray)
+    }
+
+    fn complete_hit(&self, index: usize, hitinfo: &mut HitInfo) {
+        self[index].complete_hit(hitinfo)
+    }
+
+    fn swap_primitives(&mut self, a: usize, b: usize) {
+        self.swap(a, b);
+    }
+}
+
 // This BVH is basically taken straight out of PBRTv4 with small modifications
 #[derive(Debug)]
 pub struct Bvh {
@@ -13,11 +94,12 @@ pub struct Bvh {
 }
 
 impl Bvh {
-    pub fn build(primitives: &mut [TaggedPtr<Primitive>]) -> Self {
-        let mut bvh_primitives: Vec<BvhPrimitive> = primitives
-            .iter()
-            .enumerate()
-            .map(|(i, prim)| BvhPrimitive::new(i, prim.aabb()))
+    pub fn build<P>(primitives: &mut P, options: BvhOptions) -> Self
+    where
+        P: PrimitiveStorage + ?Sized,
+    {
+        let mut bvh_primitives: Vec<BvhPrimitive> = (0..primitives.primitive_count())
+            .map(|i| BvhPrimitive::new(i, primitives.primitive_aabb(i)))
             .collect();
 
         // Indices into primitives
@@ -28,6 +110,7 @@ impl Bvh {
             &mut bvh_primitives,
             &mut ordered_primitives,
             &mut total_nodes,
+            &options,
         );
 
         drop(bvh_primitives);
@@ -41,8 +124,11 @@ impl Bvh {
         flattened
     }
 
-    fn sort_by_indices<T>(data: &mut [T], mut indices: Vec<usize>) {
-        for idx in 0..data.len() {
+    fn sort_by_indices<P>(data: &mut P, mut indices: Vec<usize>)
+    where
+        P: PrimitiveStorage + ?Sized,
+    {
+        for idx in 0..data.primitive_count() {
             if indices[idx] != idx {
                 let mut current_idx = idx;
                 loop {
@@ -51,19 +137,17 @@ impl Bvh {
                     if indices[target_idx] == target_idx {
                         break;
                     }
-                    data.swap(current_idx, target_idx);
+                    data.swap_primitives(current_idx, target_idx);
                     current_idx = target_idx;
                 }
             }
         }
     }
 
-    pub fn intersect(
-        &self,
-        ray: &Ray,
-        mut tmax: f32,
-        primitives: &[TaggedPtr<Primitive>],
-    ) -> Option<HitInfo> {
+    pub fn intersect<P>(&self, ray: &Ray, mut tmax: f32, primitives: &P) -> Option<HitInfo>
+    where
+        P: PrimitiveStorage + ?Sized,
+    {
         let inv_dir = Vec3::ONE / ray.dir;
         let dir_is_neg = inv_dir.cmplt(Vec3::ZERO);
 
@@ -72,18 +156,23 @@ impl Bvh {
         let mut nodes_to_visit = [0usize; 64];
 
         let mut closest_hitinfo = None;
+        let mut closest_index = 0;
 
         loop {
             let node = &self.nodes[current_node_index];
+            stats::count_tests(TestKind::Aabb, 1);
             if node.aabb.intersects(ray, tmax, inv_dir, dir_is_neg) {
                 if node.primitive_count > 0 {
                     // Leaf node
                     let offset = node.primitive_offset_or_second_child_offset;
                     for prim_offset in offset..(offset + node.primitive_count as u32) {
-                        let primitive = &primitives[prim_offset as usize];
-                        if let Some(hitinfo) = primitive.intersect(ray) {
-                            tmax = hitinfo.t;
-                            closest_hitinfo = Some(hitinfo);
+                        let hit = primitives.intersect_primitive(prim_offset as usize, ray);
+                        if let Some(hitinfo) = hit {
+                            if hitinfo.t < tmax {
+                                tmax = hitinfo.t;
+                                closest_hitinfo = Some(hitinfo);
+                                closest_index = prim_offset as usize;
+                            }
                         }
                     }
 
@@ -123,9 +212,165 @@ impl Bvh {
             }
         }
 
+        if let Some(hitinfo) = &mut closest_hitinfo {
+            primitives.complete_hit(closest_index, hitinfo);
+        }
+
         closest_hitinfo
     }
 
+    /// Traverses the BVH with a packet of coherent rays (camera rays of neighbouring pixels).
+    /// Every node is tested against all rays at once, leaves are only tested against the rays
+    /// that hit them. The results are the same as intersecting each ray separately.
+    pub fn intersect_packet<P>(
+        &self,
+        rays: &[Ray; PACKET_SIZE],
+        primitives: &P,
+    ) -> [Option<HitInfo>; PACKET_SIZE]
+    where
+        P: PrimitiveStorage + ?Sized,
+    {
+        let packet = RayPacket::new(rays);
+        let mut tmax = Vec4::INFINITY;
+        // Children are ordered by the first ray, the rays of a packet mostly agree
+        let dir_is_neg = rays[0].dir.cmplt(Vec3::ZERO);
+
+        let mut current_node_index = 0;
+        let mut to_visit_offset = 0;
+        let mut nodes_to_visit = [0usize; 64];
+
+        let mut closest_hitinfos: [Option<HitInfo>; PACKET_SIZE] = Default::default();
+        let mut closest_indices = [0; PACKET_SIZE];
+
+        loop {
+            let node = &self.nodes[current_node_index];
+            stats::count_tests(TestKind::Aabb, PACKET_SIZE as u64);
+            let active = packet.intersects(&node.aabb, tmax);
+            if active.any() {
+                if node.primitive_count > 0 {
+                    // Leaf node
+                    let active = active.bitmask();
+                    let offset = node.primitive_offset_or_second_child_offset;
+                    for (i, ray) in rays.iter().enumerate() {
+                        if active & (1 << i) == 0 {
+                            continue;
+                        }
+
+                        for prim_offset in offset..(offset + node.primitive_count as u32) {
+                            let hit = primitives.intersect_primitive(prim_offset as usize, ray);
+                            if let Some(hitinfo) = hit {
+                                if hitinfo.t < tmax[i] {
+                                    tmax[i] = hitinfo.t;
+                                    closest_hitinfos[i] = Some(hitinfo);
+                                    closest_indices[i] = prim_offset as usize;
+                                }
+                            }
+                        }
+                    }
+
+                    if to_visit_offset == 0 {
+                        break;
+                    } else {
+                        to_visit_offset -= 1;
+                        current_node_index = nodes_to_visit[to_visit_offset];
+                    }
+                } else {
+                    // Interior node
+                    let is_neg = match node.split_axis {
+                        Axis::X => dir_is_neg.x,
+                        Axis::Y => dir_is_neg.y,
+                        Axis::Z => dir_is_neg.z,
+                    };
+
+                    if is_neg {
+                        nodes_to_visit[to_visit_offset] = current_node_index + 1;
+                        to_visit_offset += 1;
+                        current_node_index = node.primitive_offset_or_second_child_offset as usize;
+                    } else {
+                        nodes_to_visit[to_visit_offset] =
+                            node.primitive_offset_or_second_child_offset as usize;
+                        to_visit_offset += 1;
+                        current_node_index += 1;
+                    }
+                }
+            } else {
+                if to_visit_offset == 0 {
+                    break;
+                } else {
+                    to_visit_offset -= 1;
+                    current_node_index = nodes_to_visit[to_visit_offset];
+                }
+            }
+        }
+
+        for (hitinfo, index) in closest_hitinfos.iter_mut().zip(closest_indices) {
+            if let Some(hitinfo) = hitinfo {
+                primitives.complete_hit(index, hitinfo);
+            }
+        }
+
+        closest_hitinfos
+    }
+
+    /// Writes the AABB of every node as a wireframe box for inspecting the hierarchy in a
+    /// mesh viewer. The boxes are grouped by the depth of their node.
+    pub fn export_obj<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        self.write_obj(writer)?;
+        Ok(())
+    }
+
+    fn write_obj<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        // Pairs of corners that differ in a single axis
+        const BOX_EDGES: [(usize, usize); 12] = [
+            (0, 1),
+            (2, 3),
+            (4, 5),
+            (6, 7),
+            (0, 2),
+            (1, 3),
+            (4, 6),
+            (5, 7),
+            (0, 4),
+            (1, 5),
+            (2, 6),
+            (3, 7),
+        ];
+
+        writeln!(writer, "# BVH with {} nodes", self.nodes.len())?;
+
+        let mut nodes_to_visit = Vec::new();
+        if !self.nodes.is_empty() {
+            nodes_to_visit.push((0, 0));
+        }
+
+        let mut boxes_written = 0;
+        while let Some((node_index, depth)) = nodes_to_visit.pop() {
+            let node = &self.nodes[node_index];
+
+            writeln!(writer, "g depth_{depth}")?;
+            for corner in 0..8 {
+                let c = node.aabb.corner(corner);
+                writeln!(writer, "v {} {} {}", c.x, c.y, c.z)?;
+            }
+
+            // OBJ indices start at 1
+            let first_vertex = boxes_written * 8 + 1;
+            for (a, b) in BOX_EDGES {
+                writeln!(writer, "l {} {}", first_vertex + a, first_vertex + b)?;
+            }
+            boxes_written += 1;
+
+            if node.primitive_count == 0 {
+                let second_child = node.primitive_offset_or_second_child_offset as usize;
+                nodes_to_visit.push((second_child, depth + 1));
+                nodes_to_visit.push((node_index + 1, depth + 1));
+            }
+        }
+
+        writer.flush()
+    }
+
     fn flatten(root: &BuildBvhNode, total_nodes: usize) -> Self {
         let mut nodes = Vec::with_capacity(total_nodes);
 
@@ -171,6 +416,7 @@ impl Bvh {
         bvh_primitives: &mut [BvhPrimitive],
         ordered_primitives: &mut Vec<usize>,
         total_nodes: &mut usize,
+        options: &BvhOptions,
     ) -> BuildBvhNode {
         *total_nodes += 1;
         let aabb = bvh_primitives
@@ -185,100 +431,169 @@ impl Bvh {
             BuildBvhNode::new_leaf(aabb, first_prim_offset, bvh_primitives.len())
         };
 
-        if aabb.area() == 0. || bvh_primitives.len() == 1 {
+        if bvh_primitives.len() == 1 {
             return create_leaf_node();
         } else {
             // Interior node
-            let mid;
             let centroids_aabb = bvh_primitives.iter().fold(AABB::EMPTY, |bounds, prim| {
                 bounds.union_point(prim.aabb.center())
             });
 
+            // The primitives can't be separated when all of the centroids coincide
             let split_axis = centroids_aabb.max_axis();
             if centroids_aabb.is_empty() {
                 return create_leaf_node();
-            } else {
-                if bvh_primitives.len() <= 2 {
-                    mid = bvh_primitives.len() / 2;
-                    // Equal-counts split method, applying the SAH here doesn't make sense
-                    bvh_primitives.select_nth_unstable_by(mid, |p0, p1| {
-                        p0.aabb.center()[split_axis as usize]
-                            .partial_cmp(&p1.aabb.center()[split_axis as usize])
-                            .unwrap_or(std::cmp::Ordering::Equal)
-                    });
-                } else {
-                    // Surface-area heuristic split method
-                    let mut buckets = [BvhSahBucket::new_emnpty(); SAH_BUCKETS];
-                    for prim in &*bvh_primitives {
-                        let mut bucket = (SAH_BUCKETS as f32
-                            * centroids_aabb.offset_of(prim.aabb.center())[split_axis as usize])
-                            as usize;
-
-                        if bucket == SAH_BUCKETS {
-                            bucket -= 1;
-                        }
+            }
 
-                        buckets[bucket].count += 1;
-                        buckets[bucket].aabb = buckets[bucket].aabb.union_aabb(prim.aabb);
+            let mid = match options.split_method {
+                SplitMethod::Middle => {
+                    let mid = Self::split_middle(bvh_primitives, &centroids_aabb, split_axis);
+                    // All centroids can be on one side when the primitives overlap a lot
+                    if mid == 0 || mid == bvh_primitives.len() {
+                        Self::split_equal_counts(bvh_primitives, split_axis)
+                    } else {
+                        mid
+                    }
+                }
+                SplitMethod::EqualCounts => Self::split_equal_counts(bvh_primitives, split_axis),
+                SplitMethod::Sah => {
+                    if bvh_primitives.len() <= 2 || aabb.half_area() == 0. {
+                        // Equal-counts split method, applying the SAH here doesn't make sense.
+                        // Bounds without any area (all primitives on a line) can't be
+                        // compared using the SAH either.
+                        Self::split_equal_counts(bvh_primitives, split_axis)
+                    } else {
+                        match Self::sah_split_bucket(
+                            bvh_primitives,
+                            &aabb,
+                            &centroids_aabb,
+                            split_axis,
+                            options.max_prims_in_node,
+                        ) {
+                            Some(split_bucket) => {
+                                bvh_primitives.iter_mut().partition_in_place(|prim| {
+                                    Self::sah_bucket(prim, &centroids_aabb, split_axis)
+                                        <= split_bucket
+                                })
+                            }
+                            None => return create_leaf_node(),
+                        }
                     }
+                }
+            };
 
-                    const SPLIT_COUNT: usize = SAH_BUCKETS - 1;
-                    let mut costs = [0.; SPLIT_COUNT];
+            let child_l = Self::build_recursive(
+                &mut bvh_primitives[..mid],
+                ordered_primitives,
+                total_nodes,
+                options,
+            );
+            let child_r = Self::build_recursive(
+                &mut bvh_primitives[mid..],
+                ordered_primitives,
+                total_nodes,
+                options,
+            );
 
-                    let mut count_below = 0;
-                    let mut aabb_below = AABB::EMPTY;
-                    for i in 0..SPLIT_COUNT {
-                        aabb_below = aabb_below.union_aabb(buckets[i].aabb);
-                        count_below += buckets[i].count;
-                        costs[i] += count_below as f32 * aabb_below.area();
-                    }
+            BuildBvhNode::new_interior(split_axis, child_l, child_r)
+        }
+    }
 
-                    let mut count_above = 0;
-                    let mut aabb_above = AABB::EMPTY;
-                    for i in (1..=SPLIT_COUNT).rev() {
-                        aabb_above = aabb_above.union_aabb(buckets[i].aabb);
-                        count_above += buckets[i].count;
-                        costs[i - 1] += count_above as f32 * aabb_above.area();
-                    }
+    /// Returns the index of the first primitive that belongs to the right child
+    fn split_middle(
+        bvh_primitives: &mut [BvhPrimitive],
+        centroids_aabb: &AABB,
+        split_axis: Axis,
+    ) -> usize {
+        let axis_mid = centroids_aabb.center()[split_axis as usize];
+
+        bvh_primitives
+            .iter_mut()
+            .partition_in_place(|prim| prim.aabb.center()[split_axis as usize] < axis_mid)
+    }
 
-                    let (min_cost_split_bucket, min_cost) = costs
-                        .iter()
-                        .enumerate()
-                        .min_by(|(_, c0), (_, c1)| c0.total_cmp(c1))
-                        .unwrap();
+    /// Returns the index of the first primitive that belongs to the right child
+    fn split_equal_counts(bvh_primitives: &mut [BvhPrimitive], split_axis: Axis) -> usize {
+        let mid = bvh_primitives.len() / 2;
+        bvh_primitives.select_nth_unstable_by(mid, |p0, p1| {
+            p0.aabb.center()[split_axis as usize]
+                .partial_cmp(&p1.aabb.center()[split_axis as usize])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
 
-                    let min_cost = 0.5 + min_cost / aabb.area();
-                    let leaf_cost = bvh_primitives.len();
+        mid
+    }
 
-                    if (bvh_primitives.len() > MAX_PRIMS_IN_NODE) || (min_cost < leaf_cost as f32) {
-                        mid = bvh_primitives.iter_mut().partition_in_place(|prim| {
-                            let mut bucket = (SAH_BUCKETS as f32
-                                * centroids_aabb.offset_of(prim.aabb.center())[split_axis as usize])
-                                as usize;
+    fn sah_bucket(prim: &BvhPrimitive, centroids_aabb: &AABB, split_axis: Axis) -> usize {
+        let bucket = (SAH_BUCKETS as f32
+            * centroids_aabb.offset_of(prim.aabb.center())[split_axis as usize])
+            as usize;
 
-                            if bucket == SAH_BUCKETS {
-                                bucket -= 1;
-                            }
+        bucket.min(SAH_BUCKETS - 1)
+    }
 
-                            bucket <= min_cost_split_bucket
-                        });
+    /// Surface-area heuristic split method.
+    /// Returns the last bucket of the left child, or None if creating a leaf is cheaper.
+    fn sah_split_bucket(
+        bvh_primitives: &[BvhPrimitive],
+        aabb: &AABB,
+        centroids_aabb: &AABB,
+        split_axis: Axis,
+        max_prims_in_node: usize,
+    ) -> Option<usize> {
+        let mut buckets = [BvhSahBucket::new_emnpty(); SAH_BUCKETS];
+        for prim in bvh_primitives {
+            let bucket = Self::sah_bucket(prim, centroids_aabb, split_axis);
+
+            buckets[bucket].count += 1;
+            buckets[bucket].aabb = buckets[bucket].aabb.union_aabb(prim.aabb);
+        }
 
-                        if mid == bvh_primitives.len() {
-                            dbg!("shit");
-                        }
-                    } else {
-                        return create_leaf_node();
-                    }
-                }
-            }
+        let costs = Self::sah_split_costs(&buckets, aabb);
 
-            let child_l =
-                Self::build_recursive(&mut bvh_primitives[..mid], ordered_primitives, total_nodes);
-            let child_r =
-                Self::build_recursive(&mut bvh_primitives[mid..], ordered_primitives, total_nodes);
+        let (min_cost_split_bucket, min_cost) = costs
+            .iter()
+            .enumerate()
+            .min_by(|(_, c0), (_, c1)| c0.total_cmp(c1))
+            .unwrap();
 
-            BuildBvhNode::new_interior(split_axis, child_l, child_r)
+        let leaf_cost = bvh_primitives.len();
+
+        if (bvh_primitives.len() > max_prims_in_node) || (*min_cost < leaf_cost as f32) {
+            Some(min_cost_split_bucket)
+        } else {
+            None
+        }
+    }
+
+    /// Cost of splitting after each bucket relative to the cost of intersecting a primitive.
+    /// Traversal costs 1/2 and the probability of hitting a child is the ratio of its
+    /// (half) surface area to the surface area of the parent.
+    fn sah_split_costs(
+        buckets: &[BvhSahBucket; SAH_BUCKETS],
+        aabb: &AABB,
+    ) -> [f32; SAH_BUCKETS - 1] {
+        const SPLIT_COUNT: usize = SAH_BUCKETS - 1;
+        let mut costs = [0.; SPLIT_COUNT];
+
+        let mut count_below = 0;
+        let mut aabb_below = AABB::EMPTY;
+        for i in 0..SPLIT_COUNT {
+            aabb_below = aabb_below.union_aabb(buckets[i].aabb);
+            count_below += buckets[i].count;
+            costs[i] += count_below as f32 * aabb_below.half_area();
+        }
+
+        let mut count_above = 0;
+        let mut aabb_above = AABB::EMPTY;
+        for i in (1..=SPLIT_COUNT).rev() {
+            aabb_above = aabb_above.union_aabb(buckets[i].aabb);
+            count_above += buckets[i].count;
+            costs[i - 1] += count_above as f32 * aabb_above.half_area();
         }
+
+        let parent_area = aabb.half_area();
+        costs.map(|cost| 0.5 + cost / parent_area)
     }
 
     /// Checks whether the flattened BVH is the same as the pointer-based BVH. Doesn't
@@ -448,6 +763,44 @@ impl std::fmt::Debug for BuildBvhNode {
     }
 }
 
+/// Number of rays traced together by `Bvh::intersect_packet()`
+pub const PACKET_SIZE: usize = 4;
+
+/// Rays in the structure-of-arrays layout, so that the slab test runs on all of them at once
+struct RayPacket {
+    orig: [Vec4; 3],
+    inv_dir: [Vec4; 3],
+}
+
+impl RayPacket {
+    fn new(rays: &[Ray; PACKET_SIZE]) -> Self {
+        let orig = array::from_fn(|axis| Vec4::from_array(array::from_fn(|i| rays[i].orig[axis])));
+        let inv_dir =
+            array::from_fn(|axis| Vec4::from_array(array::from_fn(|i| 1. / rays[i].dir[axis])));
+
+        Self { orig, inv_dir }
+    }
+
+    /// Same test as `AABB::intersects()`, but for every ray of the packet
+    fn intersects(&self, aabb: &AABB, ray_tmax: Vec4) -> BVec4A {
+        let mut tmin = Vec4::NEG_INFINITY;
+        let mut tmax = Vec4::INFINITY;
+
+        for axis in 0..3 {
+            let t0 = (Vec4::splat(aabb.min[axis]) - self.orig[axis]) * self.inv_dir[axis];
+            let t1 = (Vec4::splat(aabb.max[axis]) - self.orig[axis]) * self.inv_dir[axis];
+
+            // A NaN (origin on the slab plane of a parallel ray) never culls the box,
+            // so the packet can't miss anything the single-ray test would hit
+            let nan = t0.is_nan_mask() | t1.is_nan_mask();
+            tmin = Vec4::select(nan, tmin, tmin.max(t0.min(t1)));
+            tmax = Vec4::select(nan, tmax, tmax.min(t0.max(t1)));
+        }
+
+        tmin.cmple(tmax) & tmin.cmplt(ray_tmax) & tmax.cmpgt(Vec4::ZERO)
+    }
+}
+
 struct BvhPrimitive {
     /// Index to the primitive array
     id: usize,
@@ -461,7 +814,6 @@ impl BvhPrimitive {
 }
 
 const SAH_BUCKETS: usize = 12;
-const MAX_PRIMS_IN_NODE: usize = 4;
 
 #[derive(Clone, Copy)]
 struct BvhSahBucket {
@@ -494,7 +846,7 @@ mod test_super {
 
     use super::*;
 
-    fn build_test_bvh() -> (Bvh, Vec<TaggedPtr<Primitive>>) {
+    fn build_test_bvh(options: BvhOptions) -> (Bvh, Vec<TaggedPtr<Primitive>>) {
         let sphere_0 = Sphere::new_mock(vec3(2., 0., 1.), 0.2);
         let sphere_1 = Sphere::new_mock(vec3(2., 0., -1.), 0.5);
 
@@ -509,16 +861,18 @@ mod test_super {
                 TaggedPtr::new(Primitive::Simple(Box::new(SimplePrimtive::new(
                     TaggedPtr::new(Shape::Sphere(Box::new(shape))),
                     material.clone(),
+                    None,
+                    None,
                 ))))
             })
             .collect();
 
-        (Bvh::build(&mut primitives), primitives)
+        (Bvh::build(primitives.as_mut_slice(), options), primitives)
     }
 
     #[test]
     fn test_bvh_build() {
-        let (bvh, primitives) = build_test_bvh();
+        let (bvh, primitives) = build_test_bvh(BvhOptions::default());
 
         // Interior nodes
         assert_eq!(
@@ -577,9 +931,166 @@ mod test_super {
     }
 
     #[test]
-    /// Tests that all intersections with the BVH match manual intersections.
     fn test_bvh_intersect() {
-        let (bvh, primitives) = build_test_bvh();
+        let (bvh, primitives) = build_test_bvh(BvhOptions::default());
+        test_bvh_intersect_primitives(&bvh, primitives.as_slice());
+    }
+
+    #[test]
+    fn test_bvh_intersect_middle() {
+        let options = BvhOptions {
+            split_method: SplitMethod::Middle,
+            ..BvhOptions::default()
+        };
+
+        let (bvh, primitives) = build_test_bvh(options);
+        bvh.check_primitive_bounds(&primitives);
+        test_bvh_intersect_primitives(&bvh, primitives.as_slice());
+    }
+
+    #[test]
+    fn test_bvh_intersect_equal_counts() {
+        let options = BvhOptions {
+            split_method: SplitMethod::EqualCounts,
+            ..BvhOptions::default()
+        };
+
+        let (bvh, primitives) = build_test_bvh(options);
+        bvh.check_primitive_bounds(&primitives);
+        test_bvh_intersect_primitives(&bvh, primitives.as_slice());
+    }
+
+    /// Collects the depth and primitive count of every leaf
+    fn collect_leaves(bvh: &Bvh, node_index: usize, depth: u32, leaves: &mut Vec<(u32, u16)>) {
+        let node = &bvh.nodes[node_index];
+        if node.primitive_count > 0 {
+            leaves.push((depth, node.primitive_count));
+        } else {
+            let second_child = node.primitive_offset_or_second_child_offset as usize;
+            collect_leaves(bvh, node_index + 1, depth + 1, leaves);
+            collect_leaves(bvh, second_child, depth + 1, leaves);
+        }
+    }
+
+    #[test]
+    fn test_bvh_equal_counts_balanced() {
+        let material = Arc::new(crate::pbrt_loader::scene_description::Material::new_empty());
+
+        // Clustered unevenly, which the middle split wouldn't divide evenly
+        let mut primitives: Vec<TaggedPtr<Primitive>> = (0..13)
+            .map(|i| {
+                let x = if i < 10 {
+                    i as f32 * 0.1
+                } else {
+                    i as f32 * 10.
+                };
+                let sphere = Sphere::new_mock(vec3(x, 0., 0.), 0.01);
+                TaggedPtr::new(Primitive::Simple(Box::new(SimplePrimtive::new(
+                    TaggedPtr::new(Shape::Sphere(Box::new(sphere))),
+                    material.clone(),
+                    None,
+                    None,
+                ))))
+            })
+            .collect();
+
+        let options = BvhOptions {
+            split_method: SplitMethod::EqualCounts,
+            ..BvhOptions::default()
+        };
+        let bvh = Bvh::build(primitives.as_mut_slice(), options);
+
+        let mut leaves = Vec::new();
+        collect_leaves(&bvh, 0, 0, &mut leaves);
+
+        assert!(leaves.iter().all(|(_, count)| *count == 1));
+        assert_eq!(leaves.len(), primitives.len());
+
+        let min_depth = leaves.iter().map(|(d, _)| *d).min().unwrap();
+        let max_depth = leaves.iter().map(|(d, _)| *d).max().unwrap();
+        assert!(max_depth - min_depth <= 1, "{min_depth} {max_depth}");
+    }
+
+    #[test]
+    fn test_sah_split_costs() {
+        // Two unit cubes 2 units apart, one in the first and one in the last bucket
+        let box_l = AABB::new(Vec3::ZERO, Vec3::ONE);
+        let box_r = AABB::new(vec3(3., 0., 0.), vec3(4., 1., 1.));
+        let parent = box_l.union_aabb(box_r);
+
+        let mut buckets = [BvhSahBucket::new_emnpty(); SAH_BUCKETS];
+        buckets[0] = BvhSahBucket {
+            count: 1,
+            aabb: box_l,
+        };
+        buckets[SAH_BUCKETS - 1] = BvhSahBucket {
+            count: 1,
+            aabb: box_r,
+        };
+
+        // Half areas: children 1*1 + 1*1 + 1*1 = 3, parent 4*1 + 4*1 + 1*1 = 9
+        // Cost: 0.5 + (1 * 3 + 1 * 3) / 9
+        let expected = 0.5 + 6. / 9.;
+        let costs = Bvh::sah_split_costs(&buckets, &parent);
+        for cost in costs {
+            assert!((cost - expected).abs() < 1e-6, "{cost} {expected}");
+        }
+    }
+
+    /// Tests that packet traversal finds the same closest hits as tracing the rays one by one.
+    #[test]
+    fn test_bvh_intersect_packet() {
+        let (bvh, primitives) = build_test_bvh(BvhOptions::default());
+
+        // A tile of coherent rays looking at the spheres from above, some of them miss
+        let orig = vec3(0., 3., 0.);
+        let (width, height) = (32, 16);
+        let rays: Vec<Ray> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let target = vec3(
+                    -2.6 + 5.2 * x as f32 / (width - 1) as f32,
+                    0.,
+                    -1.7 + 3.4 * y as f32 / (height - 1) as f32,
+                );
+                Ray::new(orig, target - orig)
+            })
+            .collect();
+
+        for packet in rays.chunks_exact(PACKET_SIZE) {
+            let packet: &[Ray; PACKET_SIZE] = packet.try_into().unwrap();
+            let packet_hits = bvh.intersect_packet(packet, primitives.as_slice());
+
+            for (ray, packet_hit) in packet.iter().zip(packet_hits) {
+                let hit = bvh.intersect(ray, f32::INFINITY, primitives.as_slice());
+                assert_eq!(packet_hit.map(|h| (h.pos, h.t)), hit.map(|h| (h.pos, h.t)));
+            }
+        }
+        assert!(rays.iter().any(|ray| bvh
+            .intersect(ray, f32::INFINITY, primitives.as_slice())
+            .is_some()));
+
+        // Diverging rays of one packet still get their own closest hits
+        let packet = [
+            Ray::new(orig, vec3(2., -3., 1.)),
+            Ray::new(orig, vec3(-2., -3., -1.)),
+            Ray::new(orig, vec3(0., 1., 0.)),
+            Ray::new(vec3(2., 0., 3.), -Vec3::Z),
+        ];
+        let packet_hits = bvh.intersect_packet(&packet, primitives.as_slice());
+        for (ray, packet_hit) in packet.iter().zip(packet_hits) {
+            let hit = bvh.intersect(ray, f32::INFINITY, primitives.as_slice());
+            assert_eq!(packet_hit.map(|h| (h.pos, h.t)), hit.map(|h| (h.pos, h.t)));
+        }
+        // Ray along the z axis hits the closer sphere first
+        let hit = bvh
+            .intersect(&packet[3], f32::INFINITY, primitives.as_slice())
+            .unwrap();
+        assert!((hit.pos.z - 1.2).abs() < 1e-4, "{}", hit.pos);
+    }
+
+    /// Tests that all intersections with the BVH match manual intersections.
+    fn test_bvh_intersect_primitives(bvh: &Bvh, primitives: &[TaggedPtr<Primitive>]) {
         let mut rng = SmallRng::from_entropy();
 
         let rays = 100_000;
@@ -603,11 +1114,11 @@ mod test_super {
             let ray_dir = target_point - ray_orig;
             let ray = Ray::new(ray_orig, ray_dir);
 
-            let bvh_closest_hit = bvh.intersect(&ray, f32::INFINITY, &primitives);
+            let bvh_closest_hit = bvh.intersect(&ray, f32::INFINITY, primitives);
 
             let mut mint = f32::MAX;
             let mut manual_closest_hit = None;
-            for prim in &primitives {
+            for prim in primitives {
                 if let Some(hit) = prim.intersect(&ray) {
                     if hit.t < mint {
                         mint = hit.t;
@@ -713,4 +1224,34 @@ mod test_super {
         );
         assert_eq!(wrong, 0);
     }
+
+    #[test]
+    fn test_bvh_export_obj() {
+        let (bvh, _primitives) = build_test_bvh(BvhOptions::default());
+
+        let mut obj = Vec::new();
+        bvh.write_obj(&mut obj).unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+
+        let vertices = obj.lines().filter(|l| l.starts_with("v ")).count();
+        let lines: Vec<&str> = obj.lines().filter(|l| l.starts_with("l ")).collect();
+        let groups = obj.lines().filter(|l| l.starts_with("g ")).count();
+
+        // One box per node
+        assert_eq!(groups, bvh.nodes.len());
+        assert_eq!(vertices, 8 * bvh.nodes.len());
+        assert_eq!(lines.len(), 12 * bvh.nodes.len());
+
+        for line in lines {
+            for index in line.split_whitespace().skip(1) {
+                let index: usize = index.parse().unwrap();
+                assert!((1..=vertices).contains(&index));
+            }
+        }
+
+        // The test hierarchy has three levels
+        assert!(obj.contains("g depth_0"));
+        assert!(obj.contains("g depth_1"));
+        assert!(obj.contains("g depth_2"));
+    }
 }
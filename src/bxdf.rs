@@ -1,83 +1,234 @@
 use std::f32::consts::PI;
 
-use glam::Vec3;
-use rand::rngs::SmallRng;
+use glam::{Vec2, Vec3};
+use rand::{distributions::Uniform, prelude::Distribution};
+use rgb2spec::RGB2Spec;
+
+pub mod measured;
+pub mod multiple_scattering;
+pub mod thin_film;
 
 use crate::{
-    color::spectrum::{SampledWavelengths, SpectralQuantity},
-    integrator::shading_geometry::ShadingGeometry,
+    color::{quantity::Quantity, spectrum::rgb_spectrum::RgbSpectrumKind},
+    integrator::{scratch::RenderScratch, shading_geometry::ShadingGeometry},
+    math::lerp,
     pbrt_loader::scene_description::{ConductorMaterial, Material},
+    sampler::Sampler,
     sampling, vecmath,
 };
 
 pub struct Bxdf<'m> {
     mat: &'m Material,
-    rng: &'m mut SmallRng,
+    rgbtospec: &'m RGB2Spec,
+    rng: &'m mut Sampler,
+    vertex_color: Option<Vec3>,
+    uv: Option<Vec2>,
+    /// Conductors and measured materials are replaced by a diffuse lobe with their albedo
+    force_diffuse: bool,
 }
 
 impl<'m> Bxdf<'m> {
-    pub fn new(mat: &'m Material, rng: &'m mut SmallRng) -> Self {
-        Self { mat, rng }
+    pub fn new(mat: &'m Material, scratch: &'m RenderScratch, rng: &'m mut Sampler) -> Self {
+        // Interfaces only mark media boundaries and hidden geometry, they aren't overridden
+        let mat = match scratch.override_material {
+            Some(override_material) if !mat.is_interface() => override_material,
+            _ => mat,
+        };
+
+        Self {
+            mat,
+            rgbtospec: scratch.rgbtospec,
+            rng,
+            vertex_color: None,
+            uv: None,
+            force_diffuse: scratch.force_diffuse,
+        }
     }
 
-    pub fn sample(&mut self, normal: Vec3, view_dir: Vec3) -> Vec3 {
-        match self.mat {
-            Material::Diffuse(_) => {
+    /// Vertex color of the hit, used by diffuse materials that don't have a reflectance
+    pub fn with_vertex_color(mut self, color: Option<Vec3>) -> Self {
+        self.vertex_color = color;
+        self
+    }
+
+    /// UV coordinates of the hit, used by textured mix amounts
+    pub fn with_uv(mut self, uv: Option<Vec2>) -> Self {
+        self.uv = uv;
+        self
+    }
+
+    /// Returns None if the material doesn't scatter light and the path should be terminated.
+    pub fn sample(&mut self, normal: Vec3, view_dir: Vec3) -> Option<Vec3> {
+        self.sample_material(self.mat, normal, view_dir)
+    }
+
+    fn sample_material(&mut self, mat: &Material, normal: Vec3, view_dir: Vec3) -> Option<Vec3> {
+        let sample_dir = match mat {
+            // TODO: importance sample the measured data
+            Material::Diffuse(_) | Material::Measured(_) => {
+                let sample_dir = sampling::sample_cosine_hemisphere(self.rng);
+                vecmath::orient_dir(sample_dir, normal)
+            }
+            Material::Conductor(_) if self.force_diffuse => {
                 let sample_dir = sampling::sample_cosine_hemisphere(self.rng);
                 vecmath::orient_dir(sample_dir, normal)
             }
             Material::Conductor(material) => {
-                // TODO: better sampling algorithm
-                let halfway = sampling::sample_trowbridge_reitz(
-                    self.rng,
-                    normal,
-                    material.roughness.vroughness,
-                );
-                (2. * view_dir.dot(halfway) * halfway - view_dir).normalize()
+                let roughness = material.roughness.vroughness;
+                let lobe_prob =
+                    multiple_scattering::lobe_probability(normal.dot(view_dir), roughness);
+
+                // The multiple-scattering lobe is close to diffuse
+                if Uniform::from(0f32..1f32).sample(self.rng) < lobe_prob {
+                    let sample_dir = sampling::sample_cosine_hemisphere(self.rng);
+                    vecmath::orient_dir(sample_dir, normal)
+                } else {
+                    // TODO: better sampling algorithm
+                    let halfway = sampling::sample_trowbridge_reitz(self.rng, normal, roughness);
+                    (2. * view_dir.dot(halfway) * halfway - view_dir).normalize()
+                }
+            }
+            // One of the materials is chosen, pdf() and eval() account for both
+            Material::Mix(mix) => {
+                let amount = mix.amount.eval(self.uv);
+                let chosen = if Uniform::from(0f32..1f32).sample(self.rng) < amount {
+                    &mix.materials[1]
+                } else {
+                    &mix.materials[0]
+                };
+
+                return self.sample_material(chosen, normal, view_dir);
+            }
+            Material::Black => return None,
+            // Straight through, the integrators skip interfaces before sampling
+            Material::Interface => -view_dir,
+        };
+
+        Some(sample_dir)
+    }
+
+    /// Samples the hemisphere around the normal uniformly instead of following the BxDF, the pdf
+    /// is `sampling::pdf_uniform_hemisphere()`. Doesn't work for delta BxDFs like interfaces.
+    /// Returns None if the material doesn't scatter light.
+    pub fn sample_uniform(&mut self, normal: Vec3) -> Option<Vec3> {
+        match self.mat {
+            Material::Black => None,
+            _ => {
+                let sample_dir = sampling::sample_uniform_hemisphere(self.rng);
+                Some(vecmath::orient_dir(sample_dir, normal))
             }
         }
     }
 
     pub fn pdf(&mut self, sgeom: &ShadingGeometry) -> f32 {
-        let pdf = match self.mat {
-            Material::Diffuse(_) => sgeom.cos_theta / PI,
+        let pdf = self.pdf_material(self.mat, sgeom);
+
+        debug_assert!(pdf > 0. || matches!(self.mat, Material::Black | Material::Mix(_)));
+        pdf
+    }
+
+    fn pdf_material(&self, mat: &Material, sgeom: &ShadingGeometry) -> f32 {
+        match mat {
+            Material::Diffuse(_) | Material::Measured(_) => {
+                sampling::pdf_cosine_hemisphere(sgeom.cos_theta)
+            }
+            Material::Conductor(_) if self.force_diffuse => {
+                sampling::pdf_cosine_hemisphere(sgeom.cos_theta)
+            }
             Material::Conductor(material) => {
-                let d = distribution_trowbridge_reitz(sgeom.noh, material.roughness.vroughness);
+                let roughness = material.roughness.vroughness;
+                let d = distribution_trowbridge_reitz(sgeom.noh, roughness);
                 let mut res = d * sgeom.noh / (4. * sgeom.hov);
                 if res <= 0. {
                     res = -res;
                 }
 
-                res
+                lerp(
+                    multiple_scattering::lobe_probability(sgeom.nov, roughness),
+                    res,
+                    sampling::pdf_cosine_hemisphere(sgeom.cos_theta),
+                )
             }
-        };
-
-        debug_assert!(pdf > 0.);
-        pdf
+            Material::Mix(mix) => lerp(
+                mix.amount.eval(self.uv),
+                self.pdf_material(&mix.materials[0], sgeom),
+                self.pdf_material(&mix.materials[1], sgeom),
+            ),
+            Material::Black => 0.,
+            // Delta distribution
+            Material::Interface => 1.,
+        }
     }
 
-    pub fn eval(
+    pub fn eval<C: Quantity>(
         &mut self,
         sgeom: &ShadingGeometry,
-        sampled_lambdas: &SampledWavelengths,
-    ) -> SpectralQuantity {
-        let brdf = match self.mat {
+        sampled_lambdas: &C::Lambdas,
+    ) -> C {
+        let brdf: C = self.eval_material(self.mat, sgeom, sampled_lambdas);
+        debug_assert!(brdf.min_value() >= 0.);
+        brdf
+    }
+
+    fn eval_material<C: Quantity>(
+        &self,
+        mat: &Material,
+        sgeom: &ShadingGeometry,
+        sampled_lambdas: &C::Lambdas,
+    ) -> C {
+        match mat {
+            Material::Conductor(_) | Material::Measured(_) if self.force_diffuse => {
+                self.albedo::<C>(mat, sampled_lambdas) * (1. / PI)
+            }
             Material::Diffuse(diffuse_mat) => {
-                let brdf = sampled_lambdas
-                    .lambdas
-                    .map(|lambda| diffuse_mat.reflectance.eval_single(lambda) / PI);
-                SpectralQuantity::new(brdf)
+                let reflectance = match self.vertex_color {
+                    Some(rgb) if diffuse_mat.use_vertex_colors => C::from_rgb(
+                        rgb,
+                        RgbSpectrumKind::Reflectance,
+                        self.rgbtospec,
+                        sampled_lambdas,
+                    ),
+                    _ => C::from_spectrum(&diffuse_mat.reflectance, sampled_lambdas),
+                };
+
+                reflectance * (1. / PI)
             }
             Material::Conductor(conductor_mat) => {
-                let brdf = sampled_lambdas
-                    .lambdas
-                    .map(|lambda| eval_conductor_brdf(lambda, conductor_mat, sgeom));
-                SpectralQuantity::new(brdf)
+                let fresnel: C = eval_conductor_fresnel(conductor_mat, sgeom.hov, sampled_lambdas);
+                fresnel * eval_conductor_brdf(conductor_mat, sgeom)
+                    + eval_conductor_multiple_scattering(conductor_mat, sgeom, sampled_lambdas)
             }
-        };
+            Material::Measured(brdf) => C::from_rgb(
+                brdf.eval(sgeom),
+                RgbSpectrumKind::Unbounded,
+                self.rgbtospec,
+                sampled_lambdas,
+            ),
+            Material::Mix(mix) => lerp(
+                mix.amount.eval(self.uv),
+                self.eval_material::<C>(&mix.materials[0], sgeom, sampled_lambdas),
+                self.eval_material(&mix.materials[1], sgeom, sampled_lambdas),
+            ),
+            Material::Black => C::ZERO,
+            Material::Interface => C::ONE,
+        }
+    }
 
-        debug_assert!(brdf.vals.iter().all(|brdf| *brdf >= 0.));
-        brdf
+    /// Reflectance of the diffuse lobe that replaces the material when diffuse is forced.
+    /// Conductors use the Fresnel reflectance at normal incidence.
+    fn albedo<C: Quantity>(&self, mat: &Material, sampled_lambdas: &C::Lambdas) -> C {
+        match mat {
+            Material::Conductor(conductor_mat) => {
+                eval_conductor_fresnel(conductor_mat, 1., sampled_lambdas)
+            }
+            Material::Measured(brdf) => C::from_rgb(
+                brdf.albedo(),
+                RgbSpectrumKind::Reflectance,
+                self.rgbtospec,
+                sampled_lambdas,
+            ),
+            _ => unreachable!("only glossy materials are replaced"),
+        }
     }
 }
 
@@ -92,6 +243,46 @@ fn fresnel_schlick(f0: Vec3, voh: f32) -> Vec3 {
     f0 + (1. - f0) * f32::powi(f32::clamp(1. - voh, 0.0, 1.0), 5)
 }
 
+/// Fresnel reflectance of the conductor for every wavelength, including the thin film
+fn eval_conductor_fresnel<C: Quantity>(
+    mat: &ConductorMaterial,
+    cos_theta: f32,
+    sampled_lambdas: &C::Lambdas,
+) -> C {
+    let ior: C = mat.ior.eval(sampled_lambdas);
+    let k = mat.absorbtion_k.eval(sampled_lambdas);
+
+    match &mat.thin_film {
+        Some(film) => C::wavelengths(sampled_lambdas).zip_map3(ior, k, |lambda, ior, k| {
+            thin_film::fresnel_thin_film_conductor(cos_theta, lambda, film, ior, k)
+        }),
+        None => ior.zip_map(k, |ior, k| fresnel_conductor(cos_theta, ior, k)),
+    }
+}
+
+/// Unpolarized Fresnel reflectance of a conductor with the complex IOR `ior + i k`, for a single
+/// wavelength. Taken from Sébastien Lagarde - Memo on Fresnel equations.
+fn fresnel_conductor(cos_theta: f32, ior: f32, k: f32) -> f32 {
+    let cos_theta = cos_theta.abs().min(1.);
+    let cos_sq = cos_theta * cos_theta;
+    let sin_sq = 1. - cos_sq;
+    let ior_sq = ior * ior;
+    let k_sq = k * k;
+
+    let t0 = ior_sq - k_sq - sin_sq;
+    let a_sq_plus_b_sq = f32::sqrt(t0 * t0 + 4. * ior_sq * k_sq);
+    let t1 = a_sq_plus_b_sq + cos_sq;
+    let a = f32::sqrt(0.5 * (a_sq_plus_b_sq + t0).max(0.));
+    let t2 = 2. * cos_theta * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos_sq * a_sq_plus_b_sq + sin_sq * sin_sq;
+    let t4 = t2 * sin_sq;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    0.5 * (rp + rs)
+}
+
 fn visibility_smith_height_correlated_ggx(nov: f32, nol: f32, roughness: f32) -> f32 {
     let asq = roughness * roughness;
     let nov_sq = nov * nov;
@@ -104,7 +295,8 @@ fn visibility_smith_height_correlated_ggx(nov: f32, nol: f32, roughness: f32) ->
     0.5 / (denoml + denomv + 0.00001)
 }
 
-fn eval_conductor_brdf(lambda: f32, mat: &ConductorMaterial, sgeom: &ShadingGeometry) -> f32 {
+/// Without the Fresnel term, which depends on the wavelength
+fn eval_conductor_brdf(mat: &ConductorMaterial, sgeom: &ShadingGeometry) -> f32 {
     // TODO: support anisotropic version
     assert_eq!(mat.roughness.vroughness, mat.roughness.uroughness);
 
@@ -112,8 +304,209 @@ fn eval_conductor_brdf(lambda: f32, mat: &ConductorMaterial, sgeom: &ShadingGeom
 
     let visibility = visibility_smith_height_correlated_ggx(sgeom.nov, sgeom.cos_theta, roughness);
     let dist = distribution_trowbridge_reitz(sgeom.noh, roughness);
-    //let fresnel = fresnel_schlick(f0, sgeom.hov);
-    let fresnel = 1.;
 
-    visibility * dist * fresnel
+    visibility * dist
+}
+
+/// Energy compensation of the microfacet interreflections, see `multiple_scattering`.
+/// The average Fresnel reflectance is approximated from the reflectance at normal incidence.
+fn eval_conductor_multiple_scattering<C: Quantity>(
+    mat: &ConductorMaterial,
+    sgeom: &ShadingGeometry,
+    sampled_lambdas: &C::Lambdas,
+) -> C {
+    let roughness = mat.roughness.uroughness;
+    let lobe = multiple_scattering::brdf(sgeom.nov, sgeom.cos_theta, roughness);
+    if lobe == 0. {
+        return C::ZERO;
+    }
+
+    let f0: C = eval_conductor_fresnel(mat, 1., sampled_lambdas);
+    f0.map(|f0| multiple_scattering::fresnel(multiple_scattering::fresnel_average(f0), roughness))
+        * lobe
+}
+
+#[cfg(test)]
+mod test_super {
+    use glam::vec3;
+    use rand::SeedableRng;
+
+    use crate::{
+        color::spectrum::rgb_spectrum::flat_rgbtospec,
+        math::sqr,
+        pbrt_loader::scene_description::{
+            ConductorMaterial, DiffuseMaterial, MaterialOverride, MaterialRoughness, MixAmount,
+            MixMaterial,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_force_diffuse() {
+        let rgbtospec = flat_rgbtospec();
+        let conductor = Material::Conductor(ConductorMaterial::new(
+            &rgbtospec,
+            Vec3::splat(0.2),
+            Vec3::splat(3.),
+            MaterialRoughness::new(0.05, 0.05),
+        ));
+
+        let view_dir = vec3(0.5, 0., 1.).normalize();
+        let mirror_dir = vec3(-0.5, 0., 1.).normalize();
+        let sgeom = ShadingGeometry::new(&Vec3::Z, &mirror_dir, &-view_dir);
+        let mut rng = Sampler::seed_from_u64(0);
+
+        let scratch = RenderScratch::new(&rgbtospec);
+        let specular: Vec3 = Bxdf::new(&conductor, &scratch, &mut rng).eval(&sgeom, &());
+        assert!(specular.x > 1.);
+
+        let scratch = RenderScratch::new(&rgbtospec).with_force_diffuse(true);
+        let mut bxdf = Bxdf::new(&conductor, &scratch, &mut rng);
+        let diffuse: Vec3 = bxdf.eval(&sgeom, &());
+        // The conductor's reflectance at normal incidence
+        let albedo = fresnel_conductor(1., 0.2, 3.);
+        assert!((albedo - 9.64 / 10.44).abs() < 1e-5);
+        assert!((diffuse - Vec3::splat(albedo / PI)).abs().max_element() < 1e-5);
+        assert!((bxdf.pdf(&sgeom) - sgeom.cos_theta / PI).abs() < 1e-5);
+
+        // Cosine-weighted samples, the mean cosine is 2/3 instead of the peak around the mirror
+        // direction
+        const SAMPLES: usize = 10000;
+        let mean_cos = (0..SAMPLES)
+            .map(|_| bxdf.sample(Vec3::Z, view_dir).unwrap().z)
+            .sum::<f32>()
+            / SAMPLES as f32;
+        assert!((mean_cos - 2. / 3.).abs() < 0.01, "{mean_cos}");
+
+        // Diffuse materials keep their reflectance, interfaces are kept
+        let gray = Material::Diffuse(DiffuseMaterial::new(&rgbtospec, Vec3::splat(0.3)));
+        let gray_eval: Vec3 = Bxdf::new(&gray, &scratch, &mut rng).eval(&sgeom, &());
+        assert!((gray_eval - Vec3::splat(0.3 / PI)).abs().max_element() < 1e-5);
+
+        let interface = Material::Interface;
+        let mut bxdf = Bxdf::new(&interface, &scratch, &mut rng);
+        assert_eq!(bxdf.sample(Vec3::Z, view_dir), Some(-view_dir));
+    }
+
+    #[test]
+    fn test_material_override() {
+        let rgbtospec = flat_rgbtospec();
+        let scene_material = Material::Conductor(ConductorMaterial::new(
+            &rgbtospec,
+            Vec3::splat(1.5),
+            Vec3::splat(0.5),
+            MaterialRoughness::new(0.5, 0.5),
+        ));
+
+        let view_dir = vec3(0.5, 0., 1.).normalize();
+        let light_dir = vec3(-0.2, 0.3, 1.).normalize();
+        let sgeom = ShadingGeometry::new(&Vec3::Z, &light_dir, &-view_dir);
+        let mut rng = Sampler::seed_from_u64(0);
+
+        // The chosen diffuse color is used instead of the scene's conductor
+        let clay = MaterialOverride::Diffuse(vec3(0.8, 0.6, 0.4)).material(&rgbtospec);
+        let scratch = RenderScratch::new(&rgbtospec).with_override_material(Some(&clay));
+        let mut bxdf = Bxdf::new(&scene_material, &scratch, &mut rng);
+        let value: Vec3 = bxdf.eval(&sgeom, &());
+        assert!((value - vec3(0.8, 0.6, 0.4) / PI).abs().max_element() < 1e-5);
+        assert!((bxdf.pdf(&sgeom) - sgeom.cos_theta / PI).abs() < 1e-5);
+
+        // The default conductor evaluates the same as copper in the scene
+        let copper = MaterialOverride::new("conductor")
+            .unwrap()
+            .material(&rgbtospec);
+        let scratch = RenderScratch::new(&rgbtospec).with_override_material(Some(&copper));
+        let overridden: Vec3 = Bxdf::new(&scene_material, &scratch, &mut rng).eval(&sgeom, &());
+        let plain_scratch = RenderScratch::new(&rgbtospec);
+        let expected: Vec3 = Bxdf::new(&copper, &plain_scratch, &mut rng).eval(&sgeom, &());
+        let scene: Vec3 = Bxdf::new(&scene_material, &plain_scratch, &mut rng).eval(&sgeom, &());
+        assert_eq!(overridden, expected);
+        assert_ne!(overridden, scene);
+        // Copper is reddish
+        assert!(overridden.x > overridden.z);
+
+        // Interfaces keep letting the rays through
+        let interface = Material::Interface;
+        let mut bxdf = Bxdf::new(&interface, &scratch, &mut rng);
+        assert_eq!(bxdf.sample(Vec3::Z, view_dir), Some(-view_dir));
+    }
+
+    #[test]
+    fn test_mix_material() {
+        let rgbtospec = flat_rgbtospec();
+        let diffuse = Material::Diffuse(DiffuseMaterial::new(&rgbtospec, Vec3::splat(0.5)));
+        let conductor = Material::Conductor(ConductorMaterial::new(
+            &rgbtospec,
+            Vec3::splat(0.2),
+            Vec3::splat(3.),
+            MaterialRoughness::new(0.2, 0.2),
+        ));
+        let mix = Material::Mix(MixMaterial::new(
+            diffuse.clone(),
+            conductor.clone(),
+            MixAmount::Constant(0.5),
+        ));
+
+        let scratch = RenderScratch::new(&rgbtospec);
+        let mut rng = Sampler::seed_from_u64(0);
+        let view_dir = vec3(0.5, 0., 1.).normalize();
+        let mirror_dir = vec3(-0.5, 0., 1.).normalize();
+
+        for sample_dir in [mirror_dir, Vec3::Z, vec3(0.3, 0.4, 1.).normalize()] {
+            let sgeom = ShadingGeometry::new(&Vec3::Z, &sample_dir, &-view_dir);
+            let mut eval_pdf = |mat| {
+                let mut bxdf = Bxdf::new(mat, &scratch, &mut rng);
+                let eval: Vec3 = bxdf.eval(&sgeom, &());
+                (eval, bxdf.pdf(&sgeom))
+            };
+
+            let (diffuse_eval, diffuse_pdf) = eval_pdf(&diffuse);
+            let (conductor_eval, conductor_pdf) = eval_pdf(&conductor);
+            let (mix_eval, mix_pdf) = eval_pdf(&mix);
+
+            assert!(mix_eval.cmpge(diffuse_eval.min(conductor_eval)).all());
+            assert!(mix_eval.cmple(diffuse_eval.max(conductor_eval)).all());
+            let average = (diffuse_eval + conductor_eval) / 2.;
+            assert!((mix_eval - average).abs().max_element() < 1e-5);
+            assert!((mix_pdf - (diffuse_pdf + conductor_pdf) / 2.).abs() < 1e-4);
+        }
+
+        // About half of the samples follow each material
+        const SAMPLES: usize = 10000;
+        let mut mean_mirror_cos = |mat| {
+            let mut bxdf = Bxdf::new(mat, &scratch, &mut rng);
+            (0..SAMPLES)
+                .map(|_| bxdf.sample(Vec3::Z, view_dir).unwrap().dot(mirror_dir))
+                .sum::<f32>()
+                / SAMPLES as f32
+        };
+        let diffuse_cos = mean_mirror_cos(&diffuse);
+        let conductor_cos = mean_mirror_cos(&conductor);
+        let mix_cos = mean_mirror_cos(&mix);
+        assert!(
+            (mix_cos - (diffuse_cos + conductor_cos) / 2.).abs() < 0.02,
+            "{diffuse_cos} {conductor_cos} {mix_cos}"
+        );
+    }
+
+    #[test]
+    fn test_fresnel_conductor() {
+        for (ior, k) in [(1.5, 0.), (0.2, 3.), (1.1, 1.)] {
+            // Normal incidence has a closed form
+            let normal = (sqr(ior - 1.) + k * k) / (sqr(ior + 1.) + k * k);
+            assert!((fresnel_conductor(1., ior, k) - normal).abs() < 1e-5);
+
+            // Everything is reflected at grazing angles
+            assert!((fresnel_conductor(0., ior, k) - 1.).abs() < 1e-5);
+
+            for cos_theta in [0.8, 0.5, 0.2, -0.5] {
+                let fresnel = fresnel_conductor(cos_theta, ior, k);
+                assert!((0. ..=1.).contains(&fresnel), "{ior} {k} {cos_theta}");
+            }
+        }
+
+        // Without absorption, it's the dielectric Fresnel reflectance
+        assert!((fresnel_conductor(1., 1.5, 0.) - 0.04).abs() < 1e-5);
+    }
 }
@@ -0,0 +1,280 @@
+use std::{
+    f32::consts::{FRAC_PI_2, PI},
+    fmt,
+    path::Path,
+};
+
+use eyre::{eyre, Result};
+use glam::Vec3;
+
+use crate::{integrator::shading_geometry::ShadingGeometry, math::lerp, vecmath};
+
+/// Scales of the color channels of the MERL data
+const MERL_SCALE: Vec3 = Vec3::new(1. / 1500., 1.15 / 1500., 1.66 / 1500.);
+
+/// Isotropic BRDF tabulated in the half-difference parametrization of the MERL database.
+/// Rusinkiewicz - A New Change of Variables for Efficient BRDF Representation.
+/// Only the φd values in [0, π) are stored, reciprocity mirrors the rest.
+pub struct MeasuredBrdf {
+    /// Number of θh, θd and φd samples
+    resolution: [usize; 3],
+    /// φd changes fastest, then θd and θh
+    values: Vec<Vec3>,
+    /// Reflectance for the view direction along the normal, used when diffuse is forced
+    albedo: Vec3,
+}
+
+impl MeasuredBrdf {
+    /// MERL files are recognized by their `.binary` extension, the format has no header magic
+    pub fn is_merl_path(path: &Path) -> bool {
+        path.extension().is_some_and(|ext| ext == "binary")
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| eyre!("Couldn't read measured BRDF '{}': {}", path.display(), e))?;
+        Self::from_merl_bytes(&bytes)
+    }
+
+    /// The MERL binary format: the three resolutions as i32s, followed by the red, green and
+    /// blue tables as f64s, all little-endian. Invalid (negative) samples are clamped to 0.
+    pub fn from_merl_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 12 {
+            return Err(eyre!("Measured BRDF file is too short"));
+        }
+
+        let mut resolution = [0; 3];
+        for (res, chunk) in resolution.iter_mut().zip(bytes[..12].chunks_exact(4)) {
+            let r = i32::from_le_bytes(chunk.try_into().unwrap());
+            if r <= 0 {
+                return Err(eyre!("Invalid measured BRDF resolution: '{}'", r));
+            }
+            *res = r as usize;
+        }
+
+        let count: usize = resolution.iter().product();
+        let data = &bytes[12..];
+        if data.len() != count * 3 * 8 {
+            return Err(eyre!(
+                "Measured BRDF of resolution {:?} should have {} bytes of data, found {}",
+                resolution,
+                count * 3 * 8,
+                data.len()
+            ));
+        }
+
+        let sample = |i: usize| {
+            let bytes = &data[i * 8..(i + 1) * 8];
+            f64::from_le_bytes(bytes.try_into().unwrap()) as f32
+        };
+
+        let values = (0..count)
+            .map(|i| {
+                let rgb = Vec3::new(sample(i), sample(i + count), sample(i + 2 * count));
+                (rgb * MERL_SCALE).max(Vec3::ZERO)
+            })
+            .collect();
+
+        let mut brdf = Self {
+            resolution,
+            values,
+            albedo: Vec3::ZERO,
+        };
+        brdf.albedo = brdf.normal_albedo();
+        Ok(brdf)
+    }
+
+    pub fn albedo(&self) -> Vec3 {
+        self.albedo
+    }
+
+    /// Integrates the BRDF over the hemisphere with the view direction along the normal.
+    /// The halfway vector is then halfway between the normal and the light, so θh = θd and φd
+    /// is 0 for every light direction.
+    fn normal_albedo(&self) -> Vec3 {
+        const STEPS: usize = 90;
+        let d_theta = FRAC_PI_2 / STEPS as f32;
+
+        let integral: Vec3 = (0..STEPS)
+            .map(|i| {
+                let theta = (i as f32 + 0.5) * d_theta;
+                let (sin, cos) = theta.sin_cos();
+                self.lookup(theta / 2., theta / 2., 0.) * cos * sin
+            })
+            .sum();
+
+        (integral * 2. * PI * d_theta).min(Vec3::ONE)
+    }
+
+    /// The RGB value of the BRDF for the light and view directions of the shading geometry
+    pub fn eval(&self, sgeom: &ShadingGeometry) -> Vec3 {
+        let (theta_h, theta_d, phi_d) = half_diff_coords(sgeom.n, sgeom.h, sgeom.l);
+        self.lookup(theta_h, theta_d, phi_d)
+    }
+
+    /// Trilinear interpolation of the samples. θh is stored with a square-root mapping, so that
+    /// there are more samples around the specular peak.
+    pub fn lookup(&self, theta_h: f32, theta_d: f32, phi_d: f32) -> Vec3 {
+        let [res_h, res_d, res_phi] = self.resolution;
+
+        let x_h = (theta_h / FRAC_PI_2).max(0.).sqrt() * res_h as f32;
+        let x_d = theta_d / FRAC_PI_2 * res_d as f32;
+        // Reciprocity, φd and φd + π are the same
+        let x_phi = phi_d.rem_euclid(PI) / PI * res_phi as f32;
+
+        let (h0, h1, th) = clamped_cell(x_h, res_h);
+        let (d0, d1, td) = clamped_cell(x_d, res_d);
+
+        let p0 = (x_phi.floor() as usize).min(res_phi - 1);
+        let p1 = (p0 + 1) % res_phi;
+        let tp = x_phi - p0 as f32;
+
+        let at = |h: usize, d: usize, p: usize| self.values[(h * res_d + d) * res_phi + p];
+        let along_phi = |h: usize, d: usize| lerp(tp, at(h, d, p0), at(h, d, p1));
+        let along_d = |h: usize| lerp(td, along_phi(h, d0), along_phi(h, d1));
+
+        lerp(th, along_d(h0), along_d(h1))
+    }
+}
+
+/// The two neighbouring samples and the interpolation factor, clamped to the last sample
+fn clamped_cell(x: f32, res: usize) -> (usize, usize, f32) {
+    let x = x.clamp(0., (res - 1) as f32);
+    let i0 = x.floor() as usize;
+    let i1 = (i0 + 1).min(res - 1);
+    (i0, i1, x - i0 as f32)
+}
+
+/// θh, θd and φd of the (normalized) normal, halfway vector and light direction.
+/// φd is measured in a frame around the halfway vector, whose X axis points away from the normal.
+fn half_diff_coords(n: Vec3, h: Vec3, l: Vec3) -> (f32, f32, f32) {
+    let noh = n.dot(h).clamp(-1., 1.);
+    let theta_h = noh.acos();
+    let theta_d = h.dot(l).clamp(-1., 1.).acos();
+
+    // Any perpendicular axis works when the halfway vector is the normal
+    let x = (h * noh - n)
+        .try_normalize()
+        .unwrap_or_else(|| vecmath::coordinate_system(h).1);
+    let y = h.cross(x);
+    let phi_d = l.dot(y).atan2(l.dot(x));
+
+    (theta_h, theta_d, phi_d)
+}
+
+impl fmt::Debug for MeasuredBrdf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MeasuredBrdf")
+            .field("resolution", &self.resolution)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use glam::vec3;
+    use rand::SeedableRng;
+
+    use crate::{
+        bxdf::Bxdf, color::spectrum::rgb_spectrum::flat_rgbtospec,
+        integrator::scratch::RenderScratch, pbrt_loader::scene_description::Material,
+        sampler::Sampler, util::test_dir,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_measured_grid_points() {
+        const RES: [usize; 3] = [4, 3, 6];
+        let count = RES.iter().product::<usize>();
+        // A different value for every sample and channel
+        let raw = |i: usize, channel: usize| (i * 3 + channel + 1) as f64;
+
+        let mut bytes = Vec::new();
+        for res in RES {
+            bytes.extend((res as i32).to_le_bytes());
+        }
+        for channel in 0..3 {
+            for i in 0..count {
+                bytes.extend(raw(i, channel).to_le_bytes());
+            }
+        }
+
+        let path = test_dir("measured").join("brdf.binary");
+        std::fs::write(&path, &bytes).unwrap();
+        let brdf = MeasuredBrdf::load(&path).unwrap();
+        assert!(MeasuredBrdf::from_merl_bytes(&bytes[..bytes.len() - 8]).is_err());
+
+        let material = Material::Measured(std::sync::Arc::new(brdf));
+        let rgbtospec = flat_rgbtospec();
+        let mut rng = Sampler::seed_from_u64(0);
+        let scratch = RenderScratch::new(&rgbtospec);
+        let mut bxdf = Bxdf::new(&material, &scratch, &mut rng);
+
+        let mut checked = 0;
+        for ih in 1..RES[0] {
+            // φd is undefined when θd is 0
+            for id in 1..RES[1] {
+                for ip in 0..RES[2] {
+                    let theta_h = (ih as f32 / RES[0] as f32).powi(2) * FRAC_PI_2;
+                    let theta_d = id as f32 / RES[1] as f32 * FRAC_PI_2;
+                    let phi_d = ip as f32 / RES[2] as f32 * PI;
+
+                    // Rotate the difference vector from the frame around the halfway vector
+                    let (sin_h, cos_h) = theta_h.sin_cos();
+                    let (sin_d, cos_d) = theta_d.sin_cos();
+                    let diff = vec3(sin_d * phi_d.cos(), sin_d * phi_d.sin(), cos_d);
+                    let l = vec3(
+                        cos_h * diff.x + sin_h * diff.z,
+                        diff.y,
+                        cos_h * diff.z - sin_h * diff.x,
+                    );
+                    let h = vec3(sin_h, 0., cos_h);
+                    let v = 2. * h.dot(l) * h - l;
+                    if l.z <= 0. || v.z <= 0. {
+                        continue;
+                    }
+
+                    let sgeom = ShadingGeometry::new(&Vec3::Z, &l, &-v);
+                    let value: Vec3 = bxdf.eval(&sgeom, &());
+
+                    let i = (ih * RES[1] + id) * RES[2] + ip;
+                    let expected =
+                        Vec3::from_array([0, 1, 2].map(|c| raw(i, c) as f32)) * MERL_SCALE;
+                    assert!(
+                        ((value - expected) / expected).abs().max_element() < 1e-3,
+                        "{ih} {id} {ip}: {value} != {expected}"
+                    );
+                    checked += 1;
+                }
+            }
+        }
+        assert!(checked > 20, "{checked}");
+    }
+
+    #[test]
+    fn test_measured_albedo() {
+        // A constant BRDF is Lambertian, the albedo is π times the value
+        const RES: [usize; 3] = [8, 8, 16];
+        const ALBEDO: Vec3 = Vec3::new(0.2, 0.4, 0.6);
+        let count = RES.iter().product::<usize>();
+
+        let mut bytes = Vec::new();
+        for res in RES {
+            bytes.extend((res as i32).to_le_bytes());
+        }
+        for channel in 0..3 {
+            let raw = (ALBEDO[channel] / PI / MERL_SCALE[channel]) as f64;
+            for _ in 0..count {
+                bytes.extend(raw.to_le_bytes());
+            }
+        }
+
+        let brdf = MeasuredBrdf::from_merl_bytes(&bytes).unwrap();
+        assert!(
+            (brdf.albedo() - ALBEDO).abs().max_element() < 1e-3,
+            "{}",
+            brdf.albedo()
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use std::{f32::consts::PI, sync::OnceLock};
+
+use glam::vec3;
+
+use super::visibility_smith_height_correlated_ggx;
+
+/// Number of the tabulated roughnesses and cosines of the view direction, both go from 0 to 1
+const TABLE_RESOLUTION: usize = 32;
+
+/// Halfway vectors per entry of the albedo table
+const INTEGRATION_STEPS: usize = 64;
+
+/// Directional albedo of the GGX microfacet BRDF without the Fresnel term, used by the energy
+/// compensation of Kulla and Conty - Revisiting Physically Based Shading at Imageworks.
+/// The single-scattering BRDF loses the light that bounces between the microfacets more than
+/// once, the missing energy is added back by a diffuse-like lobe.
+struct AlbedoTable {
+    /// E(μ) for every roughness, μ changes fastest
+    albedo: Vec<f32>,
+    /// Cosine-weighted average of E(μ) for every roughness
+    average: Vec<f32>,
+}
+
+static ALBEDO_TABLE: OnceLock<AlbedoTable> = OnceLock::new();
+
+impl AlbedoTable {
+    fn get() -> &'static Self {
+        ALBEDO_TABLE.get_or_init(Self::compute)
+    }
+
+    fn compute() -> Self {
+        let mut albedo = Vec::with_capacity(TABLE_RESOLUTION * TABLE_RESOLUTION);
+        let mut average = Vec::with_capacity(TABLE_RESOLUTION);
+
+        for ir in 0..TABLE_RESOLUTION {
+            let roughness = table_value(ir);
+            let row: Vec<f32> = (0..TABLE_RESOLUTION)
+                .map(|imu| directional_albedo(table_value(imu), roughness))
+                .collect();
+
+            // Trapezoidal rule for 2 ∫ E(μ) μ dμ
+            let step = 1. / (TABLE_RESOLUTION - 1) as f32;
+            let integral: f32 = row
+                .windows(2)
+                .enumerate()
+                .map(|(i, e)| {
+                    let (mu0, mu1) = (table_value(i), table_value(i + 1));
+                    0.5 * (e[0] * mu0 + e[1] * mu1) * step
+                })
+                .sum();
+
+            albedo.extend(row);
+            average.push((2. * integral).min(1.));
+        }
+
+        Self { albedo, average }
+    }
+
+    /// Bilinear interpolation between the entries
+    fn albedo(&self, mu: f32, roughness: f32) -> f32 {
+        let (r0, r1, tr) = table_cell(roughness);
+        let (m0, m1, tm) = table_cell(mu);
+
+        let row = |r: usize| {
+            let row = &self.albedo[r * TABLE_RESOLUTION..(r + 1) * TABLE_RESOLUTION];
+            row[m0] + (row[m1] - row[m0]) * tm
+        };
+
+        row(r0) + (row(r1) - row(r0)) * tr
+    }
+
+    fn average(&self, roughness: f32) -> f32 {
+        let (r0, r1, tr) = table_cell(roughness);
+        self.average[r0] + (self.average[r1] - self.average[r0]) * tr
+    }
+}
+
+fn table_value(index: usize) -> f32 {
+    index as f32 / (TABLE_RESOLUTION - 1) as f32
+}
+
+/// The two neighbouring entries and the interpolation factor between them
+fn table_cell(value: f32) -> (usize, usize, f32) {
+    let x = value.clamp(0., 1.) * (TABLE_RESOLUTION - 1) as f32;
+    let i0 = (x as usize).min(TABLE_RESOLUTION - 2);
+    (i0, i0 + 1, x - i0 as f32)
+}
+
+/// Integrates the BRDF times the cosine over the hemisphere, with the halfway vectors
+/// distributed by the GGX distribution on a stratified grid
+fn directional_albedo(mu: f32, roughness: f32) -> f32 {
+    // Grazing view directions and perfect mirrors are singular
+    let mu = mu.max(1e-3);
+    let roughness = roughness.max(1e-3);
+    let view = vec3(f32::sqrt(1. - mu * mu), 0., mu);
+
+    let mut sum = 0.;
+    for i in 0..INTEGRATION_STEPS {
+        for j in 0..INTEGRATION_STEPS {
+            let u = (i as f32 + 0.5) / INTEGRATION_STEPS as f32;
+            let v = (j as f32 + 0.5) / INTEGRATION_STEPS as f32;
+
+            let phi = 2. * PI * u;
+            let cos_h = f32::sqrt((1. - v) / (1. + (roughness * roughness - 1.) * v));
+            let sin_h = f32::sqrt(1. - cos_h * cos_h);
+            let h = vec3(phi.cos() * sin_h, phi.sin() * sin_h, cos_h);
+
+            let hov = h.dot(view);
+            let l = 2. * hov * h - view;
+            if l.z <= 0. || hov <= 0. {
+                continue;
+            }
+
+            // The distribution cancels out with the pdf of the halfway vector
+            let visibility = visibility_smith_height_correlated_ggx(mu, l.z, roughness);
+            sum += 4. * visibility * l.z * hov / h.z;
+        }
+    }
+
+    (sum / (INTEGRATION_STEPS * INTEGRATION_STEPS) as f32).min(1.)
+}
+
+/// The compensation lobe without the Fresnel term, for perfectly reflecting microfacets it
+/// makes the BRDF energy-conserving
+pub fn brdf(nov: f32, nol: f32, roughness: f32) -> f32 {
+    let table = AlbedoTable::get();
+    let average = table.average(roughness);
+    if average >= 1. - 1e-4 {
+        return 0.;
+    }
+
+    let albedo_v = table.albedo(nov, roughness);
+    let albedo_l = table.albedo(nol, roughness);
+    (1. - albedo_v) * (1. - albedo_l) / (PI * (1. - average))
+}
+
+/// How often the lobe is sampled instead of the microfacets, the energy the single-scattering
+/// BRDF misses for the view direction
+pub fn lobe_probability(nov: f32, roughness: f32) -> f32 {
+    1. - AlbedoTable::get().albedo(nov, roughness)
+}
+
+/// Energy that survives the multiple bounces of colored microfacets, relative to the lobe.
+/// `fresnel_avg` is the cosine-weighted average Fresnel reflectance.
+pub fn fresnel(fresnel_avg: f32, roughness: f32) -> f32 {
+    let average = AlbedoTable::get().average(roughness);
+    fresnel_avg * fresnel_avg * average / (1. - fresnel_avg * (1. - average))
+}
+
+/// Cosine-weighted average of the Schlick approximation with the reflectance at normal incidence
+pub fn fresnel_average(f0: f32) -> f32 {
+    f0 + (1. - f0) / 21.
+}
+
+#[cfg(test)]
+mod test_super {
+    use glam::Vec3;
+
+    use super::*;
+
+    #[test]
+    fn test_albedo_table() {
+        let table = AlbedoTable::get();
+
+        // A smooth surface reflects everything, rougher ones lose more and more energy
+        assert!(table.albedo(0.8, 0.) > 0.99);
+        let mut last = Vec3::ONE;
+        for roughness in [0.25, 0.5, 0.75, 1.] {
+            let albedo = vec3(
+                table.albedo(0.3, roughness),
+                table.albedo(0.8, roughness),
+                table.average(roughness),
+            );
+            assert!(albedo.cmplt(last).all(), "{roughness}: {albedo}");
+            last = albedo;
+        }
+
+        // The lobe adds back the missing energy of the directional albedo
+        for roughness in [0.3, 0.6, 1.] {
+            for mu in [0.2, 0.5, 0.9] {
+                const STEPS: usize = 256;
+                let lobe: f32 = (0..STEPS)
+                    .map(|i| {
+                        let nol = (i as f32 + 0.5) / STEPS as f32;
+                        brdf(mu, nol, roughness) * nol * 2. * PI / STEPS as f32
+                    })
+                    .sum();
+                let total = table.albedo(mu, roughness) + lobe;
+                assert!((total - 1.).abs() < 1e-2, "{roughness} {mu}: {total}");
+            }
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    f32::consts::PI,
+    ops::{Add, Div, Mul, Sub},
+};
+
+use crate::pbrt_loader::scene_description::ThinFilm;
+
+/// Reflectance of a conductor with the complex IOR `ior + i k` that is coated by a thin film,
+/// for a single wavelength in nanometers. The light reflected from the top of the film interferes
+/// with the light reflected from the conductor, the sum of all the internal reflections is
+/// given by the Airy formula. Outside of the film is air.
+pub fn fresnel_thin_film_conductor(
+    cos_theta: f32,
+    lambda: f32,
+    film: &ThinFilm,
+    ior: f32,
+    k: f32,
+) -> f32 {
+    let cos_1 = cos_theta.abs().min(1.);
+    let sin_sq_1 = 1. - cos_1 * cos_1;
+
+    let n_1 = Complex::real(1.);
+    let n_2 = Complex::real(film.ior);
+    let n_3 = Complex::new(ior, k);
+
+    // Snell's law, the angles of the conductor are complex
+    let cos_2 = (Complex::real(1.) - Complex::real(sin_sq_1) / (n_2 * n_2)).sqrt();
+    let cos_3 = (Complex::real(1.) - Complex::real(sin_sq_1) / (n_3 * n_3)).sqrt();
+    let cos_1 = Complex::real(cos_1);
+
+    // Phase difference of the light that went through the film and back
+    let phase = n_2 * cos_2 * Complex::real(4. * PI * film.thickness / lambda);
+    let phase_shift = (Complex::new(0., 1.) * phase).exp();
+
+    let airy = |r_12: Complex, r_23: Complex| {
+        let r = (r_12 + r_23 * phase_shift) / (Complex::real(1.) + r_12 * r_23 * phase_shift);
+        r.norm_sqr()
+    };
+
+    let rs = airy(
+        fresnel_s(n_1, cos_1, n_2, cos_2),
+        fresnel_s(n_2, cos_2, n_3, cos_3),
+    );
+    let rp = airy(
+        fresnel_p(n_1, cos_1, n_2, cos_2),
+        fresnel_p(n_2, cos_2, n_3, cos_3),
+    );
+
+    0.5 * (rs + rp)
+}
+
+/// Amplitude reflection coefficient of s-polarized light
+fn fresnel_s(n_i: Complex, cos_i: Complex, n_t: Complex, cos_t: Complex) -> Complex {
+    (n_i * cos_i - n_t * cos_t) / (n_i * cos_i + n_t * cos_t)
+}
+
+/// Amplitude reflection coefficient of p-polarized light
+fn fresnel_p(n_i: Complex, cos_i: Complex, n_t: Complex, cos_t: Complex) -> Complex {
+    (n_t * cos_i - n_i * cos_t) / (n_t * cos_i + n_i * cos_t)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+impl Complex {
+    fn new(re: f32, im: f32) -> Self {
+        Self { re, im }
+    }
+
+    fn real(re: f32) -> Self {
+        Self::new(re, 0.)
+    }
+
+    fn norm_sqr(self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+
+    /// Principal square root
+    fn sqrt(self) -> Self {
+        let norm = self.norm_sqr().sqrt();
+        let re = (0.5 * (norm + self.re)).max(0.).sqrt();
+        let im = (0.5 * (norm - self.re)).max(0.).sqrt();
+        Self::new(re, im.copysign(self.im))
+    }
+
+    fn exp(self) -> Self {
+        let (sin, cos) = self.im.sin_cos();
+        let scale = self.re.exp();
+        Self::new(scale * cos, scale * sin)
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self::new(self.re + rhs.re, self.im + rhs.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self::new(self.re - rhs.re, self.im - rhs.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        Self::new(
+            self.re * rhs.re - self.im * rhs.im,
+            self.re * rhs.im + self.im * rhs.re,
+        )
+    }
+}
+
+impl Div for Complex {
+    type Output = Self;
+
+    fn div(self, rhs: Self) -> Self {
+        let denom = rhs.norm_sqr();
+        Self::new(
+            (self.re * rhs.re + self.im * rhs.im) / denom,
+            (self.im * rhs.re - self.re * rhs.im) / denom,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use crate::bxdf::fresnel_conductor;
+
+    use super::*;
+
+    #[test]
+    fn test_thin_film_no_thickness() {
+        // A film without thickness changes nothing, regardless of its IOR
+        let film = ThinFilm::new(0., 1.5);
+        for cos_theta in [1., 0.7, 0.2] {
+            for (ior, k) in [(0.2, 3.), (1.5, 0.5)] {
+                let coated = fresnel_thin_film_conductor(cos_theta, 550., &film, ior, k);
+                let plain = fresnel_conductor(cos_theta, ior, k);
+                assert!((coated - plain).abs() < 1e-4, "{coated} != {plain}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_thin_film_interference() {
+        const THICKNESS: f32 = 500.;
+        const FILM_IOR: f32 = 1.33;
+        let film = ThinFilm::new(THICKNESS, FILM_IOR);
+
+        // Titanium-like substrate, constant over the wavelengths
+        let reflectance = |lambda| fresnel_thin_film_conductor(1., lambda, &film, 2.7, 3.8);
+        let lambdas: Vec<f32> = (0..4000).map(|i| 380. + i as f32 * 0.1).collect();
+        let values: Vec<f32> = lambdas.iter().map(|&l| reflectance(l)).collect();
+
+        let maxima: Vec<f32> = (1..values.len() - 1)
+            .filter(|&i| values[i] > values[i - 1] && values[i] >= values[i + 1])
+            .map(|i| lambdas[i])
+            .collect();
+
+        // The phase 4π n d / λ changes by 2π between neighbouring maxima
+        assert!(maxima.len() >= 2, "{maxima:?}");
+        for pair in maxima.windows(2) {
+            let order_diff = 2. * FILM_IOR * THICKNESS * (1. / pair[0] - 1. / pair[1]);
+            assert!((order_diff - 1.).abs() < 0.01, "{maxima:?}");
+        }
+
+        // The colors actually change, the minima are much darker than the maxima
+        let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = values.iter().copied().fold(0., f32::max);
+        assert!(max - min > 0.1, "{min} {max}");
+        assert!(max <= 1.);
+
+        // Thicker films oscillate faster
+        let thicker = ThinFilm::new(2. * THICKNESS, FILM_IOR);
+        let thicker_maxima = (1..lambdas.len() - 1)
+            .filter(|&i| {
+                let r = |i: usize| fresnel_thin_film_conductor(1., lambdas[i], &thicker, 2.7, 3.8);
+                r(i) > r(i - 1) && r(i) >= r(i + 1)
+            })
+            .count();
+        assert!(thicker_maxima > maxima.len());
+    }
+}
@@ -1,7 +1,31 @@
+use eyre::{eyre, Result};
 use glam::{vec3, Vec2, Vec3};
 
 use crate::geometry::Ray;
 
+/// Which side of the image the camera FOV spans
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FovAxis {
+    /// The narrower of the image's width and height, like in PBRT
+    #[default]
+    Narrower,
+    Horizontal,
+    Vertical,
+}
+
+impl FovAxis {
+    pub fn new(axis: &str) -> Result<Self> {
+        Ok(match axis {
+            "narrower" => Self::Narrower,
+            "horizontal" => Self::Horizontal,
+            "vertical" => Self::Vertical,
+            _ => return Err(eyre!("Unknown FOV axis: '{}'", axis)),
+        })
+    }
+}
+
+/// Pinhole camera at the origin of the camera space.
+/// The camera space is left-handed like in PBRT: +X is right, +Y is up and the camera looks along +Z.
 pub struct Camera {
     origin: Vec3,
     bottom_left: Vec3,
@@ -11,6 +35,11 @@ pub struct Camera {
 
 impl Camera {
     pub fn new(width: usize, height: usize, fov: f32) -> Self {
+        Self::new_with_fov_axis(width, height, fov, FovAxis::Narrower)
+    }
+
+    /// Some tools always apply the FOV to the same axis, this matches their images
+    pub fn new_with_fov_axis(width: usize, height: usize, fov: f32, fov_axis: FovAxis) -> Self {
         let aspect_ratio = width as f32 / height as f32;
 
         let viewport_height = 2.;
@@ -18,13 +47,18 @@ impl Camera {
 
         // From PBRT docs about FOV:
         // This is the spread angle of the viewing frustum along the narrower of the image's width and height.
-        let narrower = viewport_width.min(viewport_height);
-        let focal_length = -(narrower / 2.) / f32::tan(fov.to_radians() / 2.);
+        // The viewport is in front of the camera, so a larger FOV means a shorter focal length.
+        let fov_side = match fov_axis {
+            FovAxis::Narrower => viewport_width.min(viewport_height),
+            FovAxis::Horizontal => viewport_width,
+            FovAxis::Vertical => viewport_height,
+        };
+        let focal_length = (fov_side / 2.) / f32::tan(fov.to_radians() / 2.);
 
         let origin = Vec3::ZERO;
         let horizontal = vec3(viewport_width, 0., 0.);
         let vertical = vec3(0., viewport_height, 0.);
-        let bottom_left = origin - horizontal / 2. - vertical / 2. - vec3(0., 0., focal_length);
+        let bottom_left = origin - horizontal / 2. - vertical / 2. + vec3(0., 0., focal_length);
 
         Self {
             origin,
@@ -34,6 +68,7 @@ impl Camera {
         }
     }
 
+    /// UV (0, 0) is the bottom-left corner of the image
     pub fn gen_ray(&self, uv: Vec2) -> Ray {
         let offset = vec3(uv.x, uv.y, 0.) * vec3(self.viewport_width, self.viewport_height, 0.);
 
@@ -45,6 +80,8 @@ impl Camera {
 
 #[cfg(test)]
 mod test_camera {
+    use glam::vec2;
+
     use super::*;
 
     #[test]
@@ -55,4 +92,61 @@ mod test_camera {
             Ray::new(Vec3::ZERO, vec3(0., 0., 1.))
         );
     }
+
+    /// Angle between the ray and the view direction in degrees
+    fn ray_angle(cam: &Camera, uv: Vec2) -> f32 {
+        cam.gen_ray(uv).dir.angle_between(Vec3::Z).to_degrees()
+    }
+
+    #[test]
+    fn test_cam_fov() {
+        for fov in [45., 90.] {
+            let cam = Camera::new(100, 100, fov);
+
+            // The edges of a square image are at half of the FOV
+            assert!((ray_angle(&cam, vec2(0., 0.5)) - fov / 2.).abs() < 1e-3);
+            assert!((ray_angle(&cam, vec2(0.5, 1.)) - fov / 2.).abs() < 1e-3);
+
+            let corner = f32::atan(2f32.sqrt() * (fov / 2.).to_radians().tan()).to_degrees();
+            for uv in [Vec2::ZERO, vec2(1., 0.), vec2(0., 1.), Vec2::ONE] {
+                assert!((ray_angle(&cam, uv) - corner).abs() < 1e-3, "{fov} {uv}");
+            }
+        }
+
+        let narrow = Camera::new(100, 100, 45.);
+        let wide = Camera::new(100, 100, 90.);
+        assert!(ray_angle(&wide, Vec2::ONE) > ray_angle(&narrow, Vec2::ONE));
+
+        // Image orientation
+        let bottom_left = wide.gen_ray(Vec2::ZERO).dir;
+        assert!(bottom_left.x < 0. && bottom_left.y < 0. && bottom_left.z > 0.);
+
+        // The FOV applies to the narrower height of a wide image
+        let cam = Camera::new(200, 100, 60.);
+        assert!((ray_angle(&cam, vec2(0.5, 0.)) - 30.).abs() < 1e-3);
+        assert!(ray_angle(&cam, vec2(0., 0.5)) > 30.);
+    }
+
+    #[test]
+    fn test_cam_fov_axis() {
+        assert_eq!(FovAxis::new("vertical").unwrap(), FovAxis::Vertical);
+        assert!(FovAxis::new("diagonal").is_err());
+
+        // A wide image: the vertical axis is the narrower one, the horizontal one isn't
+        let (width, height) = (200, 100);
+        let narrower = Camera::new(width, height, 60.);
+        let vertical = Camera::new_with_fov_axis(width, height, 60., FovAxis::Vertical);
+        let horizontal = Camera::new_with_fov_axis(width, height, 60., FovAxis::Horizontal);
+        assert_eq!(narrower.bottom_left.z, vertical.bottom_left.z);
+        assert!(horizontal.bottom_left.z > narrower.bottom_left.z);
+        assert!((ray_angle(&horizontal, vec2(0., 0.5)) - 30.).abs() < 1e-3);
+
+        // On a tall image, forcing the vertical FOV changes the focal length
+        let (width, height) = (100, 200);
+        let narrower = Camera::new(width, height, 60.);
+        let vertical = Camera::new_with_fov_axis(width, height, 60., FovAxis::Vertical);
+        assert!(vertical.bottom_left.z > narrower.bottom_left.z);
+        assert!((ray_angle(&vertical, vec2(0.5, 0.)) - 30.).abs() < 1e-3);
+        assert!((ray_angle(&narrower, vec2(0., 0.5)) - 30.).abs() < 1e-3);
+    }
 }
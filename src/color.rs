@@ -1,2 +1,3 @@
 pub mod color_space;
+pub mod quantity;
 pub mod spectrum;
@@ -1,21 +1,79 @@
-use glam::{Mat3, Vec3};
+use eyre::{eyre, Result};
+use glam::{vec2, Mat3, Vec2, Vec3};
 
-#[derive(Clone, Copy)]
+/// RGB color space of the scene's colors and of the rendered image.
+/// PBRT also has ACES2065-1, which has a D60 white point. The illuminant spectrum for it
+/// isn't available, so it's left out until it's implemented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum ColorSpace {
-    Aces2065_1,
     Rec2020,
     DciP3,
+    #[default]
     Srgb,
 }
 
 impl ColorSpace {
+    /// The names of the PBRT ColorSpace directive
+    pub fn new(name: &str) -> Result<Self> {
+        Ok(match name {
+            "srgb" => Self::Srgb,
+            "rec2020" => Self::Rec2020,
+            "dci-p3" => Self::DciP3,
+            "aces2065-1" => return Err(eyre!("The ACES2065-1 color space isn't supported yet")),
+            _ => return Err(eyre!("Unknown color space: '{}'", name)),
+        })
+    }
+
+    /// CIE xy chromaticities of the red, green and blue primaries and of the white point
+    pub fn chromaticities(&self) -> [Vec2; 4] {
+        const D65: Vec2 = vec2(0.3127, 0.3290);
+        match self {
+            ColorSpace::Rec2020 => [
+                vec2(0.708, 0.292),
+                vec2(0.170, 0.797),
+                vec2(0.131, 0.046),
+                D65,
+            ],
+            ColorSpace::DciP3 => [
+                vec2(0.680, 0.320),
+                vec2(0.265, 0.690),
+                vec2(0.150, 0.060),
+                D65,
+            ],
+            ColorSpace::Srgb => [vec2(0.64, 0.33), vec2(0.30, 0.60), vec2(0.15, 0.06), D65],
+        }
+    }
+
     /// Converts a color from XYZ to "self" color space.
     pub fn from_xyz(&self, xyz: Vec3) -> Vec3 {
+        Self::apply_from_xyz(&self.from_xyz_matrix(), xyz)
+    }
+
+    /// The matrix used by from_xyz(), can be resolved once for converting many colors
+    pub fn from_xyz_matrix(&self) -> Mat3 {
         match self {
-            ColorSpace::Aces2065_1 => todo!(),
-            ColorSpace::Rec2020 => todo!(),
-            ColorSpace::DciP3 => todo!(),
-            ColorSpace::Srgb => (S_RGB_FROM_XYZ * xyz).clamp(Vec3::ZERO, Vec3::splat(f32::MAX)),
+            ColorSpace::Rec2020 => REC2020_FROM_XYZ,
+            ColorSpace::DciP3 => DCI_P3_FROM_XYZ,
+            ColorSpace::Srgb => S_RGB_FROM_XYZ,
+        }
+    }
+
+    /// Converts with a matrix from from_xyz_matrix(), colors outside of the gamut are clamped
+    pub fn apply_from_xyz(matrix: &Mat3, xyz: Vec3) -> Vec3 {
+        (*matrix * xyz).clamp(Vec3::ZERO, Vec3::splat(f32::MAX))
+    }
+
+    /// Converts a color from "self" color space to XYZ.
+    pub fn to_xyz(&self, rgb: Vec3) -> Vec3 {
+        self.to_xyz_matrix() * rgb
+    }
+
+    /// The matrix used by to_xyz()
+    pub fn to_xyz_matrix(&self) -> Mat3 {
+        match self {
+            ColorSpace::Rec2020 => XYZ_FROM_REC2020,
+            ColorSpace::DciP3 => XYZ_FROM_DCI_P3,
+            ColorSpace::Srgb => XYZ_FROM_S_RGB,
         }
     }
 }
@@ -28,3 +86,85 @@ const S_RGB_FROM_XYZ: Mat3 = Mat3::from_cols_array(&[
     -1.5373084456298136, 1.8759663029085742,   -0.20400746093241362,
     -0.4985865229069666, 0.04155503085668564,  1.0571295702861434,
 ]);
+
+/// Inverse of S_RGB_FROM_XYZ, from the same source.
+#[rustfmt::skip]
+const XYZ_FROM_S_RGB: Mat3 = Mat3::from_cols_array(&[
+    0.41239079926595934, 0.21263900587151027,  0.01933081871559182,
+    0.357584339383878,   0.715168678767756,    0.11919477979462598,
+    0.1804807884018343,  0.07219231536073371,  0.9505321522496607,
+]);
+
+/// Computed from the chromaticities of ITU-R BT.2020, the white point is D65
+#[rustfmt::skip]
+const REC2020_FROM_XYZ: Mat3 = Mat3::from_cols_array(&[
+    1.7166511879712676,  -0.666684351832489,  0.017639857445310915,
+    -0.3556707837763924, 1.616481236634939,   -0.042770613257808655,
+    -0.2533662813736598, 0.01576854581391113, 0.942103121235474,
+]);
+
+/// Inverse of REC2020_FROM_XYZ
+#[rustfmt::skip]
+const XYZ_FROM_REC2020: Mat3 = Mat3::from_cols_array(&[
+    0.6369580483012913,  0.26270021201126703,  0.0,
+    0.14461690358620838, 0.677998071518871,    0.028072693049087508,
+    0.16888097516417205, 0.059301716469861945, 1.0609850577107909,
+]);
+
+/// Computed from the DCI-P3 primaries with the D65 white point, the same as PBRT's DCI-P3
+#[rustfmt::skip]
+const DCI_P3_FROM_XYZ: Mat3 = Mat3::from_cols_array(&[
+    2.4934969119414245,   -0.829488969561575,  0.035845830243784335,
+    -0.9313836179191236,  1.7626640603183468,  -0.07617238926804171,
+    -0.40271078445071684, 0.02362468584194359, 0.9568845240076873,
+]);
+
+/// Inverse of DCI_P3_FROM_XYZ
+#[rustfmt::skip]
+const XYZ_FROM_DCI_P3: Mat3 = Mat3::from_cols_array(&[
+    0.48657094864821626, 0.22897456406974884, 0.0,
+    0.26566769316909294, 0.6917385218365062,  0.045113381858902575,
+    0.1982172852343625,  0.079286914093745,   1.0439443689009757,
+]);
+
+#[cfg(test)]
+mod test_super {
+    use glam::vec3;
+
+    use super::*;
+
+    #[test]
+    fn test_color_space_primaries() {
+        for color_space in [ColorSpace::Srgb, ColorSpace::Rec2020, ColorSpace::DciP3] {
+            let [red, green, blue, white] = color_space.chromaticities();
+            let chromaticity = |xyz: Vec3| vec2(xyz.x, xyz.y) / (xyz.x + xyz.y + xyz.z);
+
+            // The primaries and the white are where the chromaticities say
+            for (rgb, expected) in [(Vec3::X, red), (Vec3::Y, green), (Vec3::Z, blue)] {
+                let xyz = color_space.to_xyz(rgb);
+                assert!(chromaticity(xyz).abs_diff_eq(expected, 1e-4), "{xyz}");
+            }
+            let xyz = color_space.to_xyz(Vec3::ONE);
+            assert!(chromaticity(xyz).abs_diff_eq(white, 1e-4), "{xyz}");
+            assert!((xyz.y - 1.).abs() < 1e-4);
+
+            let rgb = color_space.from_xyz(color_space.to_xyz(vec3(0.2, 0.5, 0.9)));
+            assert!(rgb.abs_diff_eq(vec3(0.2, 0.5, 0.9), 1e-3), "{rgb}");
+        }
+
+        // sRGB colors are inside of the Rec.2020 gamut, but not the other way around
+        let srgb_red = ColorSpace::Srgb.to_xyz(Vec3::X);
+        let in_rec2020 = ColorSpace::Rec2020.from_xyz(srgb_red);
+        assert!(
+            in_rec2020.abs_diff_eq(vec3(0.6274, 0.0691, 0.0164), 1e-3),
+            "{in_rec2020}"
+        );
+        let rec2020_red = ColorSpace::Rec2020.to_xyz(Vec3::X);
+        let in_srgb = ColorSpace::Srgb.from_xyz(rec2020_red);
+        assert_eq!((in_srgb.y, in_srgb.z), (0., 0.));
+
+        assert_eq!(ColorSpace::new("rec2020").unwrap(), ColorSpace::Rec2020);
+        assert!(ColorSpace::new("aces2065-1").is_err());
+        assert!(ColorSpace::new("adobe-rgb").is_err());
+    }
+}
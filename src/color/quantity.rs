@@ -0,0 +1,240 @@
+use std::ops::{Add, AddAssign, Mul, MulAssign};
+
+use eyre::{eyre, Result};
+use glam::{DVec3, Vec3};
+use rgb2spec::RGB2Spec;
+
+use crate::sampler::Sampler;
+
+use super::{
+    color_space::ColorSpace,
+    spectrum::{
+        piecewise_spectrum::{PiecewiseLinearSpectrum, RGB_WAVELENGTHS},
+        rgb_spectrum::{RgbSpectrum, RgbSpectrumKind},
+        ColorMatchingFunctions, SampledWavelengths, SpectralQuantity,
+    },
+};
+
+/// How colors are represented while rendering
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMode {
+    /// Sampled wavelengths, accurate but noisier and slower
+    #[default]
+    Spectral,
+    /// Plain RGB triples, used for fast previews
+    Rgb,
+}
+
+impl ColorMode {
+    pub fn new(kind: &str) -> Result<Self> {
+        Ok(match kind {
+            "spectral" => Self::Spectral,
+            "rgb" => Self::Rgb,
+            _ => return Err(eyre!("Unknown color mode: '{}'", kind)),
+        })
+    }
+}
+
+/// Radiance, throughput, BxDF values etc... The integrators are generic over this, so that
+/// the same code can render either spectrally or in RGB.
+pub trait Quantity:
+    Copy
+    + Add<Output = Self>
+    + AddAssign
+    + Mul<Output = Self>
+    + MulAssign
+    + Mul<f32, Output = Self>
+    + MulAssign<f32>
+{
+    /// What's needed for evaluating spectra, the sampled wavelengths in spectral mode
+    type Lambdas;
+
+    const ZERO: Self;
+    const ONE: Self;
+
+    fn sample_lambdas(rng: &mut Sampler) -> Self::Lambdas;
+
+    /// Samples new wavelengths in place, so that one instance can be reused for every ray
+    fn resample_lambdas(lambdas: &mut Self::Lambdas, rng: &mut Sampler);
+
+    fn from_spectrum(spectrum: &RgbSpectrum, lambdas: &Self::Lambdas) -> Self;
+
+    fn from_piecewise_spectrum(spectrum: &PiecewiseLinearSpectrum, lambdas: &Self::Lambdas)
+        -> Self;
+
+    /// Skips creating the spectrum in RGB mode
+    fn from_rgb(
+        rgb: Vec3,
+        kind: RgbSpectrumKind,
+        rgbtospec: &RGB2Spec,
+        lambdas: &Self::Lambdas,
+    ) -> Self;
+
+    fn max_value(&self) -> f32;
+
+    fn min_value(&self) -> f32;
+
+    fn average(&self) -> f32;
+
+    /// Applies the function to every element
+    fn map(self, f: impl Fn(f32) -> f32) -> Self;
+
+    /// Combines the values of the two quantities element by element
+    fn zip_map(self, other: Self, f: impl Fn(f32, f32) -> f32) -> Self;
+
+    /// Same as `zip_map()`, for three quantities
+    fn zip_map3(self, b: Self, c: Self, f: impl Fn(f32, f32, f32) -> f32) -> Self;
+
+    /// The wavelength of every element in nanometers, for wavelength-dependent effects
+    fn wavelengths(lambdas: &Self::Lambdas) -> Self;
+
+    /// RGB has no spectrum, the color-matching functions only matter in spectral mode
+    fn to_xyz(&self, lambdas: &Self::Lambdas, cmfs: &ColorMatchingFunctions) -> DVec3;
+}
+
+impl<const N: usize> Quantity for SpectralQuantity<N> {
+    type Lambdas = SampledWavelengths<N>;
+
+    const ZERO: Self = SpectralQuantity::ZERO;
+    const ONE: Self = SpectralQuantity::ONE;
+
+    fn sample_lambdas(rng: &mut Sampler) -> Self::Lambdas {
+        SampledWavelengths::new_sample_uniform(rng)
+    }
+
+    fn resample_lambdas(lambdas: &mut Self::Lambdas, rng: &mut Sampler) {
+        lambdas.resample_uniform(rng);
+    }
+
+    fn from_spectrum(spectrum: &RgbSpectrum, lambdas: &Self::Lambdas) -> Self {
+        spectrum.eval(lambdas)
+    }
+
+    fn from_piecewise_spectrum(
+        spectrum: &PiecewiseLinearSpectrum,
+        lambdas: &Self::Lambdas,
+    ) -> Self {
+        spectrum.eval(lambdas)
+    }
+
+    fn from_rgb(
+        rgb: Vec3,
+        kind: RgbSpectrumKind,
+        rgbtospec: &RGB2Spec,
+        lambdas: &Self::Lambdas,
+    ) -> Self {
+        RgbSpectrum::new(rgbtospec, rgb, kind).eval(lambdas)
+    }
+
+    fn max_value(&self) -> f32 {
+        SpectralQuantity::max_value(self)
+    }
+
+    fn min_value(&self) -> f32 {
+        self.vals.iter().copied().fold(f32::INFINITY, f32::min)
+    }
+
+    fn average(&self) -> f32 {
+        SpectralQuantity::average(self)
+    }
+
+    fn map(mut self, f: impl Fn(f32) -> f32) -> Self {
+        self.vals.iter_mut().for_each(|v| *v = f(*v));
+        self
+    }
+
+    fn zip_map(mut self, other: Self, f: impl Fn(f32, f32) -> f32) -> Self {
+        self.vals
+            .iter_mut()
+            .zip(other.vals)
+            .for_each(|(s, o)| *s = f(*s, o));
+        self
+    }
+
+    fn zip_map3(mut self, b: Self, c: Self, f: impl Fn(f32, f32, f32) -> f32) -> Self {
+        self.vals
+            .iter_mut()
+            .zip(b.vals.iter().zip(c.vals))
+            .for_each(|(s, (b, c))| *s = f(*s, *b, c));
+        self
+    }
+
+    fn wavelengths(lambdas: &Self::Lambdas) -> Self {
+        SpectralQuantity::new(lambdas.lambdas)
+    }
+
+    fn to_xyz(&self, lambdas: &Self::Lambdas, cmfs: &ColorMatchingFunctions) -> DVec3 {
+        lambdas.to_xyz(self, cmfs)
+    }
+}
+
+/// Linear sRGB
+impl Quantity for Vec3 {
+    type Lambdas = ();
+
+    const ZERO: Self = Vec3::ZERO;
+    const ONE: Self = Vec3::ONE;
+
+    fn sample_lambdas(_rng: &mut Sampler) -> Self::Lambdas {}
+
+    fn resample_lambdas(_lambdas: &mut Self::Lambdas, _rng: &mut Sampler) {}
+
+    fn from_spectrum(spectrum: &RgbSpectrum, _lambdas: &Self::Lambdas) -> Self {
+        spectrum.rgb()
+    }
+
+    fn from_piecewise_spectrum(
+        spectrum: &PiecewiseLinearSpectrum,
+        _lambdas: &Self::Lambdas,
+    ) -> Self {
+        spectrum.rgb()
+    }
+
+    fn from_rgb(
+        rgb: Vec3,
+        kind: RgbSpectrumKind,
+        _rgbtospec: &RGB2Spec,
+        _lambdas: &Self::Lambdas,
+    ) -> Self {
+        match kind {
+            RgbSpectrumKind::Reflectance => rgb.clamp(Vec3::ZERO, Vec3::ONE),
+            RgbSpectrumKind::Unbounded | RgbSpectrumKind::Illuminant(_) => rgb,
+        }
+    }
+
+    fn max_value(&self) -> f32 {
+        self.max_element()
+    }
+
+    fn min_value(&self) -> f32 {
+        self.min_element()
+    }
+
+    fn average(&self) -> f32 {
+        (self.x + self.y + self.z) / 3.
+    }
+
+    fn map(self, f: impl Fn(f32) -> f32) -> Self {
+        Vec3::new(f(self.x), f(self.y), f(self.z))
+    }
+
+    fn zip_map(self, other: Self, f: impl Fn(f32, f32) -> f32) -> Self {
+        Vec3::new(f(self.x, other.x), f(self.y, other.y), f(self.z, other.z))
+    }
+
+    fn zip_map3(self, b: Self, c: Self, f: impl Fn(f32, f32, f32) -> f32) -> Self {
+        Vec3::new(
+            f(self.x, b.x, c.x),
+            f(self.y, b.y, c.y),
+            f(self.z, b.z, c.z),
+        )
+    }
+
+    fn wavelengths(_lambdas: &Self::Lambdas) -> Self {
+        RGB_WAVELENGTHS
+    }
+
+    fn to_xyz(&self, _lambdas: &Self::Lambdas, _cmfs: &ColorMatchingFunctions) -> DVec3 {
+        ColorSpace::Srgb.to_xyz(*self).as_dvec3()
+    }
+}
@@ -1,10 +1,17 @@
-use std::ops::{Add, AddAssign, Mul, MulAssign};
+use std::{
+    ops::{Add, AddAssign, Mul, MulAssign},
+    sync::OnceLock,
+};
 
+use eyre::{eyre, Result};
 use glam::DVec3;
-use rand::{distributions::Uniform, prelude::Distribution, rngs::SmallRng};
+use rand::{distributions::Uniform, prelude::Distribution};
 
-use crate::math::lerp;
+use crate::{color::quantity::Quantity, math::lerp, sampler::Sampler};
 
+use self::{piecewise_spectrum::PiecewiseLinearSpectrum, rgb_spectrum::RgbSpectrum};
+
+pub mod piecewise_spectrum;
 pub mod rgb_spectrum;
 
 pub const LAMBDA_MIN: usize = 360;
@@ -13,68 +20,157 @@ pub const LAMBDA_RANGE: usize = LAMBDA_MAX - LAMBDA_MIN + 1;
 
 const CIE_Y_INTEGRAL: f32 = 106.856895;
 
+/// Number of wavelengths traced with every camera ray, the only place the renderer's count is set.
+/// Fewer wavelengths are faster but give noisier color, with 1 every ray carries a single color.
 pub const SPECTRUM_SAMPLES: usize = 4;
 
-pub struct SampledWavelengths {
-    pub lambdas: [f32; SPECTRUM_SAMPLES],
-    pub pdfs: [f32; SPECTRUM_SAMPLES],
+/// `N` wavelengths stratified over the visible range
+pub struct SampledWavelengths<const N: usize = SPECTRUM_SAMPLES> {
+    pub lambdas: [f32; N],
+    pub pdfs: [f32; N],
 }
 
-impl SampledWavelengths {
+impl<const N: usize> SampledWavelengths<N> {
+    pub fn new_sample_uniform(rng: &mut Sampler) -> Self {
+        let mut sampled_lambdas = Self {
+            lambdas: [0f32; N],
+            pdfs: [0f32; N],
+        };
+
+        sampled_lambdas.resample_uniform(rng);
+        sampled_lambdas
+    }
+
+    /// Samples new wavelengths in place, so that one instance can be reused for every ray.
     /// Cod taken from PBRTv4
-    pub fn new_sample_uniform(rng: &mut SmallRng) -> Self {
+    pub fn resample_uniform(&mut self, rng: &mut Sampler) {
+        const LAMBDA_MIN_F: f32 = LAMBDA_MIN as f32;
+        const LAMBDA_MAX_F: f32 = LAMBDA_MAX as f32;
+        const PDF: f32 = 1. / (LAMBDA_MAX_F - LAMBDA_MIN_F);
+        let delta = (LAMBDA_MAX_F - LAMBDA_MIN_F) / N as f32;
+
         let dist = Uniform::from(0f32..1f32);
         let u = dist.sample(rng);
 
-        let lambda_max = LAMBDA_MAX as f32;
-        let lambda_min = LAMBDA_MIN as f32;
-
         // Sample first wavelength
-        let mut lambdas = [0f32; SPECTRUM_SAMPLES];
-        lambdas[0] = lerp(u, lambda_min, lambda_max);
+        self.lambdas[0] = lerp(u, LAMBDA_MIN_F, LAMBDA_MAX_F);
 
         // Initialize remaining wavelenghts
-        let delta = (lambda_max - lambda_min) / SPECTRUM_SAMPLES as f32;
-
-        for i in 1..SPECTRUM_SAMPLES {
-            lambdas[i] = lambdas[i - 1] + delta;
-            if lambdas[i] > lambda_max {
-                lambdas[i] = lambda_min + (lambdas[i] - lambda_max);
+        for i in 1..N {
+            self.lambdas[i] = self.lambdas[i - 1] + delta;
+            if self.lambdas[i] > LAMBDA_MAX_F {
+                self.lambdas[i] = LAMBDA_MIN_F + (self.lambdas[i] - LAMBDA_MAX_F);
             }
         }
 
-        let pdf = 1. / (lambda_max - lambda_min);
-
-        Self {
-            lambdas,
-            pdfs: [pdf; SPECTRUM_SAMPLES],
-        }
+        self.pdfs = [PDF; N];
     }
 
-    pub fn to_xyz(&self, radiances: &SpectralQuantity) -> DVec3 {
-        let mut x = CIE_X.eval(&self) * *radiances;
-        let mut y = CIE_Y.eval(&self) * *radiances;
-        let mut z = CIE_Z.eval(&self) * *radiances;
+    pub fn to_xyz(&self, radiances: &SpectralQuantity<N>, cmfs: &ColorMatchingFunctions) -> DVec3 {
+        let mut x = cmfs.x.eval(self) * *radiances;
+        let mut y = cmfs.y.eval(self) * *radiances;
+        let mut z = cmfs.z.eval(self) * *radiances;
 
         x.div_pdf(&self.pdfs);
         y.div_pdf(&self.pdfs);
         z.div_pdf(&self.pdfs);
 
-        let x = x.average() / CIE_Y_INTEGRAL;
-        let y = y.average() / CIE_Y_INTEGRAL;
-        let z = z.average() / CIE_Y_INTEGRAL;
+        let x = x.average() / cmfs.y_integral;
+        let y = y.average() / cmfs.y_integral;
+        let z = z.average() / cmfs.y_integral;
         DVec3::new(x as f64, y as f64, z as f64)
     }
 }
 
+/// The standard observer whose color-matching functions convert the spectra to XYZ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Observer {
+    /// CIE 1931 2° standard observer
+    #[default]
+    Cie1931,
+    /// CIE 1964 10° standard observer, meant for fields of view larger than 4°
+    Cie1964,
+}
+
+impl Observer {
+    pub fn new(kind: &str) -> Result<Self> {
+        Ok(match kind {
+            "cie1931" => Self::Cie1931,
+            "cie1964" => Self::Cie1964,
+            _ => return Err(eyre!("Unknown observer: '{}'", kind)),
+        })
+    }
+
+    pub fn cmfs(self) -> &'static ColorMatchingFunctions {
+        match self {
+            Self::Cie1931 => &CIE_1931,
+            Self::Cie1964 => CIE_1964.get_or_init(ColorMatchingFunctions::new_cie_1964),
+        }
+    }
+}
+
+/// Color-matching functions of an observer, sampled at every nanometer
+pub struct ColorMatchingFunctions {
+    pub x: DenselySampledSpectrum,
+    pub y: DenselySampledSpectrum,
+    pub z: DenselySampledSpectrum,
+    /// Integral of the Y function, a constant spectrum of 1 has Y = 1 after dividing by it
+    pub y_integral: f32,
+}
+
+static CIE_1931: ColorMatchingFunctions = ColorMatchingFunctions {
+    x: CIE_X,
+    y: CIE_Y,
+    z: CIE_Z,
+    y_integral: CIE_Y_INTEGRAL,
+};
+
+static CIE_1964: OnceLock<ColorMatchingFunctions> = OnceLock::new();
+
+impl ColorMatchingFunctions {
+    /// The tables aren't bundled, the functions are the analytic fit from Wyman et al. - Simple
+    /// Analytic Approximations to the CIE XYZ Color Matching Functions. X and Z are scaled to
+    /// the integral of Y, so that the equal-energy spectrum stays white like with the tables.
+    fn new_cie_1964() -> Self {
+        let x = |l: f32| {
+            0.398 * (-1250. * ((l + 570.1) / 1014.).ln().powi(2)).exp()
+                + 1.132 * (-234. * ((1338. - l) / 743.5).ln().powi(2)).exp()
+        };
+        let y = |l: f32| 1.011 * (-0.5 * ((l - 556.1) / 46.14).powi(2)).exp();
+        let z = |l: f32| 2.06 * (-32. * ((l - 265.8) / 180.4).ln().powi(2)).exp();
+
+        let tabulate = |f: &dyn Fn(f32) -> f32| {
+            let mut table = Box::new([0f32; LAMBDA_RANGE]);
+            for (i, v) in table.iter_mut().enumerate() {
+                *v = f((LAMBDA_MIN + i) as f32);
+            }
+            table
+        };
+
+        let (mut x, y, mut z) = (tabulate(&x), tabulate(&y), tabulate(&z));
+        let y_integral: f32 = y.iter().sum();
+        for table in [&mut x, &mut z] {
+            let scale = y_integral / table.iter().sum::<f32>();
+            table.iter_mut().for_each(|v| *v *= scale);
+        }
+
+        Self {
+            x: DenselySampledSpectrum::Heap(x),
+            y: DenselySampledSpectrum::Heap(y),
+            z: DenselySampledSpectrum::Heap(z),
+            y_integral,
+        }
+    }
+}
+
 /// A generic spectral quantity - BRDFs, throughput for each wavelength etc...
 #[derive(Clone, Copy)]
-pub struct SpectralQuantity {
-    pub vals: [f32; SPECTRUM_SAMPLES],
+pub struct SpectralQuantity<const N: usize = SPECTRUM_SAMPLES> {
+    pub vals: [f32; N],
 }
 
-impl SpectralQuantity {
-    pub fn new(vals: [f32; SPECTRUM_SAMPLES]) -> Self {
+impl<const N: usize> SpectralQuantity<N> {
+    pub fn new(vals: [f32; N]) -> Self {
         Self { vals }
     }
 
@@ -90,8 +186,8 @@ impl SpectralQuantity {
     }
 
     /// PDF can be set to zero when paths are terminated so care has to be taken when performing division.
-    pub fn div_pdf(&mut self, pdfs: &[f32; SPECTRUM_SAMPLES]) {
-        for i in 0..SPECTRUM_SAMPLES {
+    pub fn div_pdf(&mut self, pdfs: &[f32; N]) {
+        for i in 0..N {
             if pdfs[i] != 0. {
                 self.vals[i] /= pdfs[i];
             }
@@ -99,20 +195,16 @@ impl SpectralQuantity {
     }
 
     pub fn average(&self) -> f32 {
-        self.vals.iter().sum::<f32>() / SPECTRUM_SAMPLES as f32
+        self.vals.iter().sum::<f32>() / N as f32
     }
 
-    pub const ZERO: Self = SpectralQuantity {
-        vals: [0f32; SPECTRUM_SAMPLES],
-    };
+    pub const ZERO: Self = SpectralQuantity { vals: [0f32; N] };
 
-    pub const ONE: Self = SpectralQuantity {
-        vals: [1f32; SPECTRUM_SAMPLES],
-    };
+    pub const ONE: Self = SpectralQuantity { vals: [1f32; N] };
 }
 
-impl Mul<f32> for SpectralQuantity {
-    type Output = SpectralQuantity;
+impl<const N: usize> Mul<f32> for SpectralQuantity<N> {
+    type Output = SpectralQuantity<N>;
 
     fn mul(self, rhs: f32) -> Self::Output {
         let mut vals = self.vals;
@@ -121,13 +213,13 @@ impl Mul<f32> for SpectralQuantity {
     }
 }
 
-impl MulAssign<f32> for SpectralQuantity {
+impl<const N: usize> MulAssign<f32> for SpectralQuantity<N> {
     fn mul_assign(&mut self, rhs: f32) {
         self.vals.iter_mut().for_each(|v| *v *= rhs);
     }
 }
 
-impl Mul<Self> for SpectralQuantity {
+impl<const N: usize> Mul<Self> for SpectralQuantity<N> {
     type Output = Self;
 
     fn mul(self, rhs: Self) -> Self::Output {
@@ -139,7 +231,7 @@ impl Mul<Self> for SpectralQuantity {
     }
 }
 
-impl MulAssign<Self> for SpectralQuantity {
+impl<const N: usize> MulAssign<Self> for SpectralQuantity<N> {
     fn mul_assign(&mut self, rhs: Self) {
         self.vals
             .iter_mut()
@@ -148,7 +240,7 @@ impl MulAssign<Self> for SpectralQuantity {
     }
 }
 
-impl Add<Self> for SpectralQuantity {
+impl<const N: usize> Add<Self> for SpectralQuantity<N> {
     type Output = Self;
 
     fn add(mut self, rhs: Self) -> Self::Output {
@@ -160,7 +252,7 @@ impl Add<Self> for SpectralQuantity {
     }
 }
 
-impl AddAssign<Self> for SpectralQuantity {
+impl<const N: usize> AddAssign<Self> for SpectralQuantity<N> {
     fn add_assign(&mut self, rhs: Self) {
         self.vals
             .iter_mut()
@@ -169,6 +261,30 @@ impl AddAssign<Self> for SpectralQuantity {
     }
 }
 
+/// Spectra that can be given either as an RGB or as wavelength-value samples, like the IOR of
+/// materials
+#[derive(Clone, Debug)]
+pub enum Spectrum {
+    Rgb(RgbSpectrum),
+    PiecewiseLinear(PiecewiseLinearSpectrum),
+}
+
+impl Spectrum {
+    pub fn eval_single(&self, lambda: f32) -> f32 {
+        match self {
+            Spectrum::Rgb(spectrum) => spectrum.eval_single(lambda),
+            Spectrum::PiecewiseLinear(spectrum) => spectrum.eval_single(lambda),
+        }
+    }
+
+    pub fn eval<C: Quantity>(&self, lambdas: &C::Lambdas) -> C {
+        match self {
+            Spectrum::Rgb(spectrum) => C::from_spectrum(spectrum, lambdas),
+            Spectrum::PiecewiseLinear(spectrum) => C::from_piecewise_spectrum(spectrum, lambdas),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum DenselySampledSpectrum {
     Heap(Box<[f32; LAMBDA_RANGE]>),
@@ -182,9 +298,9 @@ impl DenselySampledSpectrum {
         arr[index]
     }
 
-    pub fn eval(&self, lambdas: &SampledWavelengths) -> SpectralQuantity {
-        let mut res = [0f32; SPECTRUM_SAMPLES];
-        for i in 0..SPECTRUM_SAMPLES {
+    pub fn eval<const N: usize>(&self, lambdas: &SampledWavelengths<N>) -> SpectralQuantity<N> {
+        let mut res = [0f32; N];
+        for i in 0..N {
             res[i] = self.eval_single(lambdas.lambdas[i]);
         }
 
@@ -1684,3 +1800,100 @@ pub const CIE_D65_RAW: [f32; LAMBDA_RANGE] = [
     56.8924, 57.4406, 57.7278, 58.015, 58.3022, 58.5894, 58.8765, 59.1637, 59.4509, 59.7381,
     60.0253, 60.3125,
 ];
+
+#[cfg(test)]
+mod test_super {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn test_wavelength_count_unbiased() {
+        // A ramp from blue to red, so that every color matching function matters
+        let spectrum = PiecewiseLinearSpectrum::new(&[(400., 0.2), (700., 1.)]).unwrap();
+
+        let mut reference = DVec3::ZERO;
+        for lambda in LAMBDA_MIN..=LAMBDA_MAX {
+            let lambda = lambda as f32;
+            let cie = DVec3::new(
+                CIE_X.eval_single(lambda) as f64,
+                CIE_Y.eval_single(lambda) as f64,
+                CIE_Z.eval_single(lambda) as f64,
+            );
+            reference += cie * spectrum.eval_single(lambda) as f64;
+        }
+        reference /= CIE_Y_INTEGRAL as f64;
+
+        fn mean_xyz<const N: usize>(spectrum: &PiecewiseLinearSpectrum) -> DVec3 {
+            const SAMPLES: usize = 1 << 16;
+            let mut rng = Sampler::seed_from_u64(0);
+            let mut lambdas = SampledWavelengths::<N>::new_sample_uniform(&mut rng);
+
+            let mut xyz = DVec3::ZERO;
+            for _ in 0..SAMPLES {
+                lambdas.resample_uniform(&mut rng);
+                xyz += lambdas.to_xyz(&spectrum.eval(&lambdas), Observer::Cie1931.cmfs());
+            }
+            xyz / SAMPLES as f64
+        }
+
+        for xyz in [
+            mean_xyz::<1>(&spectrum),
+            mean_xyz::<SPECTRUM_SAMPLES>(&spectrum),
+            mean_xyz::<8>(&spectrum),
+        ] {
+            let error = (xyz - reference).abs() / reference;
+            assert!(error.max_element() < 0.01, "{xyz} {reference}");
+        }
+    }
+
+    #[test]
+    fn test_observers() {
+        assert_eq!(Observer::new("cie1964").unwrap(), Observer::Cie1964);
+        assert!(Observer::new("cie2015").is_err());
+
+        // The default observer gives the same values as the CIE 1931 tables
+        let mut rng = Sampler::seed_from_u64(0);
+        let lambdas = SampledWavelengths::<SPECTRUM_SAMPLES>::new_sample_uniform(&mut rng);
+        let radiances = SpectralQuantity::new([0.5, 1., 2., 4.]);
+        let manual = [CIE_X, CIE_Y, CIE_Z].map(|cmf| {
+            let mut q = cmf.eval(&lambdas) * radiances;
+            q.div_pdf(&lambdas.pdfs);
+            (q.average() / CIE_Y_INTEGRAL) as f64
+        });
+        assert_eq!(
+            lambdas.to_xyz(&radiances, Observer::default().cmfs()),
+            DVec3::from_array(manual)
+        );
+
+        // Chromaticities of D65 for both observers
+        let chromaticity = |cmfs: &ColorMatchingFunctions, spectrum: &dyn Fn(f32) -> f32| {
+            let mut xyz = DVec3::ZERO;
+            for lambda in LAMBDA_MIN..=LAMBDA_MAX {
+                let lambda = lambda as f32;
+                let cmf = [&cmfs.x, &cmfs.y, &cmfs.z].map(|f| f.eval_single(lambda) as f64);
+                xyz += DVec3::from_array(cmf) * spectrum(lambda) as f64;
+            }
+            let sum = xyz.x + xyz.y + xyz.z;
+            (xyz.x / sum, xyz.y / sum, xyz.y / cmfs.y_integral as f64)
+        };
+
+        let d65 = |lambda| CIE_D65.eval_single(lambda);
+        let (x, y, _) = chromaticity(Observer::Cie1931.cmfs(), &d65);
+        assert!(
+            (x - 0.3127).abs() < 1e-3 && (y - 0.329).abs() < 1e-3,
+            "{x} {y}"
+        );
+        // The 10° observer moves the white point, the tabulated value is (0.3138, 0.331)
+        let (x_10, y_10, _) = chromaticity(Observer::Cie1964.cmfs(), &d65);
+        assert!((x_10 - 0.3138).abs() < 1e-3 && (y_10 - 0.331).abs() < 1e-3);
+        assert!(x_10 > x && y_10 > y, "{x_10} {y_10}");
+
+        // The equal-energy spectrum stays white with Y = 1
+        for observer in [Observer::Cie1931, Observer::Cie1964] {
+            let (x, y, luminance) = chromaticity(observer.cmfs(), &|_| 1.);
+            assert!((x - 1. / 3.).abs() < 1e-3 && (y - 1. / 3.).abs() < 1e-3);
+            assert!((luminance - 1.).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use eyre::{eyre, Result};
+use glam::Vec3;
+
+use crate::math::lerp;
+
+use super::{SampledWavelengths, SpectralQuantity};
+
+/// Dominant wavelengths of the sRGB primaries, stand in for the sampled wavelengths in RGB mode
+pub const RGB_WAVELENGTHS: Vec3 = Vec3::new(611., 549., 464.);
+
+/// Linear interpolation between (wavelength, value) samples.
+/// Outside of the sampled range the spectrum is extended by the first and the last value.
+#[derive(Clone, Debug)]
+pub struct PiecewiseLinearSpectrum {
+    lambdas: Vec<f32>,
+    values: Vec<f32>,
+}
+
+impl PiecewiseLinearSpectrum {
+    /// The samples have to be sorted by wavelength
+    pub fn new(samples: &[(f32, f32)]) -> Result<Self> {
+        if samples.is_empty() {
+            return Err(eyre!(
+                "A piecewise-linear spectrum needs at least one sample"
+            ));
+        }
+
+        if samples.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return Err(eyre!(
+                "Spectrum wavelengths have to be increasing: '{:?}'",
+                samples
+            ));
+        }
+
+        let (lambdas, values) = samples.iter().copied().unzip();
+        Ok(Self { lambdas, values })
+    }
+
+    pub fn new_constant(value: f32) -> Self {
+        Self {
+            lambdas: vec![0.],
+            values: vec![value],
+        }
+    }
+
+    pub fn eval_single(&self, lambda: f32) -> f32 {
+        let last = self.lambdas.len() - 1;
+        if lambda <= self.lambdas[0] {
+            return self.values[0];
+        } else if lambda >= self.lambdas[last] {
+            return self.values[last];
+        }
+
+        // Index of the first sample past lambda, can't be 0 or past the end
+        let i = self.lambdas.partition_point(|&l| l <= lambda);
+        let t = (lambda - self.lambdas[i - 1]) / (self.lambdas[i] - self.lambdas[i - 1]);
+        lerp(t, self.values[i - 1], self.values[i])
+    }
+
+    pub fn eval<const N: usize>(&self, lambdas: &SampledWavelengths<N>) -> SpectralQuantity<N> {
+        let mut vals = lambdas.lambdas;
+        vals.iter_mut().for_each(|l| *l = self.eval_single(*l));
+
+        SpectralQuantity::new(vals)
+    }
+
+    /// Used when rendering in RGB, the values at the dominant wavelengths of the sRGB primaries
+    pub fn rgb(&self) -> Vec3 {
+        Vec3::from_array(RGB_WAVELENGTHS.to_array().map(|l| self.eval_single(l)))
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_piecewise_linear_eval() {
+        let spectrum = PiecewiseLinearSpectrum::new(&[(400., 1.), (500., 3.), (700., 2.)]).unwrap();
+
+        assert_eq!(spectrum.eval_single(400.), 1.);
+        assert_eq!(spectrum.eval_single(450.), 2.);
+        assert_eq!(spectrum.eval_single(500.), 3.);
+        assert_eq!(spectrum.eval_single(650.), 2.25);
+
+        // Constant outside of the samples
+        assert_eq!(spectrum.eval_single(360.), 1.);
+        assert_eq!(spectrum.eval_single(830.), 2.);
+
+        let constant = PiecewiseLinearSpectrum::new_constant(1.5);
+        assert_eq!(constant.rgb(), Vec3::splat(1.5));
+        assert_eq!(constant.eval_single(360.), 1.5);
+    }
+
+    #[test]
+    fn test_piecewise_linear_invalid() {
+        assert!(PiecewiseLinearSpectrum::new(&[]).is_err());
+        assert!(PiecewiseLinearSpectrum::new(&[(500., 1.), (400., 2.)]).is_err());
+        assert!(PiecewiseLinearSpectrum::new(&[(500., 1.), (500., 2.)]).is_err());
+    }
+}
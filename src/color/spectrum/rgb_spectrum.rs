@@ -1,8 +1,15 @@
-use std::sync::OnceLock;
+use std::{
+    io::ErrorKind,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
 
-use eyre::Result;
+use eyre::{eyre, Result};
 use glam::Vec3;
-use rgb2spec::RGB2Spec;
+use rgb2spec::{
+    optimize::{gamut::Gamut, optimize},
+    RGB2Spec,
+};
 
 use crate::color::color_space::ColorSpace;
 
@@ -10,15 +17,168 @@ use super::{
     DenselySampledSpectrum, SampledWavelengths, SpectralQuantity, CIE_D65, CIE_Y_INTEGRAL,
 };
 
-pub static RGBTOSPEC: OnceLock<RGB2Spec> = OnceLock::new();
+/// Resolution of the tables in resources, the same as PBRT's
+const TABLE_RESOLUTION: usize = 64;
+
+struct RgbToSpecTable {
+    table: OnceLock<RGB2Spec>,
+    /// Held while the table is loaded or computed, so that it's only done once
+    init: Mutex<()>,
+    path: &'static str,
+}
 
-pub fn init_rgbtospec() -> Result<()> {
-    let rgbtospec = RGB2Spec::load("resources/srgb-to-spec-64")?;
-    #[cfg(test)]
-    let _ = RGBTOSPEC.set(rgbtospec);
-    #[cfg(not(test))]
-    RGBTOSPEC.set(rgbtospec).unwrap();
-    Ok(())
+impl RgbToSpecTable {
+    const fn new(path: &'static str) -> Self {
+        Self {
+            table: OnceLock::new(),
+            init: Mutex::new(()),
+            path,
+        }
+    }
+}
+
+static SRGB_TO_SPEC: RgbToSpecTable = RgbToSpecTable::new("resources/srgb-to-spec-64");
+static REC2020_TO_SPEC: RgbToSpecTable = RgbToSpecTable::new("resources/rec2020-to-spec-64");
+static DCI_P3_TO_SPEC: RgbToSpecTable = RgbToSpecTable::new("resources/dci-p3-to-spec-64");
+
+/// The table of the color space and the file it's loaded from, the same tables as PBRT's
+fn table(color_space: ColorSpace) -> &'static RgbToSpecTable {
+    match color_space {
+        ColorSpace::Srgb => &SRGB_TO_SPEC,
+        ColorSpace::Rec2020 => &REC2020_TO_SPEC,
+        ColorSpace::DciP3 => &DCI_P3_TO_SPEC,
+    }
+}
+
+/// Loads the table of the color space the first time it's needed, later calls just return it.
+/// A table that's missing in resources is computed and saved there for the next runs, which
+/// takes about half a minute.
+pub fn init_rgbtospec(color_space: ColorSpace) -> Result<&'static RGB2Spec> {
+    let table = table(color_space);
+    if let Some(rgbtospec) = table.table.get() {
+        return Ok(rgbtospec);
+    }
+
+    // A panic while computing the table leaves nothing behind, the next caller can try again
+    let _init = table.init.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(rgbtospec) = table.table.get() {
+        return Ok(rgbtospec);
+    }
+
+    let rgbtospec = match RGB2Spec::load(table.path) {
+        Ok(rgbtospec) => rgbtospec,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            eprintln!(
+                "The RGB to spectrum table '{}' is missing, computing it",
+                table.path
+            );
+            let rgbtospec = compute_rgbtospec(color_space)?;
+            if let Err(e) = save_rgbtospec(&rgbtospec, table.path) {
+                eprintln!(
+                    "Couldn't save the RGB to spectrum table '{}': {}",
+                    table.path, e
+                );
+            }
+            rgbtospec
+        }
+        Err(e) => {
+            return Err(eyre!(
+                "Couldn't load the RGB to spectrum table '{}': {}",
+                table.path,
+                e
+            ))
+        }
+    };
+
+    Ok(table.table.get_or_init(|| rgbtospec))
+}
+
+/// The table of the color space, None if it wasn't loaded yet
+pub fn rgbtospec(color_space: ColorSpace) -> Option<&'static RGB2Spec> {
+    table(color_space).table.get()
+}
+
+/// Optimizes the table the same way as rgb2spec_opt, which the tables in resources come from
+fn compute_rgbtospec(color_space: ColorSpace) -> Result<RGB2Spec> {
+    let gamut = match color_space {
+        ColorSpace::Srgb => Gamut::SRGB,
+        ColorSpace::Rec2020 => Gamut::REC2020,
+        ColorSpace::DciP3 => {
+            let rec2020 = init_rgbtospec(ColorSpace::Rec2020)?;
+            return Ok(resample_rgbtospec(
+                rec2020,
+                ColorSpace::Rec2020,
+                ColorSpace::DciP3,
+                TABLE_RESOLUTION,
+            ));
+        }
+    };
+
+    optimize(gamut, TABLE_RESOLUTION)
+        .map_err(|e| eyre!("Couldn't compute the RGB to spectrum table: {}", e))
+}
+
+/// Writes a temporary file first, so that other processes never load a partial table
+fn save_rgbtospec(rgbtospec: &RGB2Spec, path: &str) -> std::io::Result<()> {
+    let path = Path::new(path);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let tmp_path = path.with_extension(format!("tmp-{}", std::process::id()));
+    rgbtospec.save(&tmp_path)?;
+    std::fs::rename(tmp_path, path)
+}
+
+/// Builds the table of color_space from the table of a wider color space with the same white
+/// point. rgb2spec can't optimize DCI-P3, but every DCI-P3 color is inside of Rec.2020, so the
+/// spectra of the Rec.2020 table reproduce it. The grid is the same as the one rgb2spec_opt uses.
+pub(crate) fn resample_rgbtospec(
+    source: &RGB2Spec,
+    source_color_space: ColorSpace,
+    color_space: ColorSpace,
+    resolution: usize,
+) -> RGB2Spec {
+    let to_source = source_color_space.from_xyz_matrix() * color_space.to_xyz_matrix();
+
+    let smoothstep = |x: f32| x * x * (3. - 2. * x);
+    let scale: Vec<f32> = (0..resolution)
+        .map(|k| smoothstep(smoothstep(k as f32 / (resolution - 1) as f32)))
+        .collect();
+
+    let mut data = Vec::with_capacity(3 * resolution.pow(3) * 3);
+    for l in 0..3 {
+        for &z in &scale {
+            for j in 0..resolution {
+                for i in 0..resolution {
+                    let mut rgb = Vec3::ZERO;
+                    rgb[l] = z;
+                    rgb[(l + 1) % 3] = i as f32 / (resolution - 1) as f32 * z;
+                    rgb[(l + 2) % 3] = j as f32 / (resolution - 1) as f32 * z;
+
+                    data.extend(source.fetch((to_source * rgb).to_array()));
+                }
+            }
+        }
+    }
+
+    rgbtospec_from_parts(resolution, &scale, &data)
+}
+
+/// RGB2Spec can only be created by loading it, so the parts are written in its binary format
+fn rgbtospec_from_parts(resolution: usize, scale: &[f32], data: &[f32]) -> RGB2Spec {
+    let mut bytes = b"SPEC".to_vec();
+    bytes.extend((resolution as u32).to_le_bytes());
+    bytes.extend(scale.iter().chain(data).flat_map(|f| f.to_le_bytes()));
+    RGB2Spec::from_reader(&mut bytes.as_slice()).expect("The table has the right size")
+}
+
+/// A table with all coefficients set to zero, so every RGB is mapped to a flat spectrum.
+/// Used by tests that need spectra without the precomputed table in resources.
+#[cfg(test)]
+pub(crate) fn flat_rgbtospec() -> RGB2Spec {
+    const RES: usize = 2;
+    rgbtospec_from_parts(RES, &[0., 1.], &[0.; RES * RES * RES * 9])
 }
 
 #[derive(Clone, Debug)]
@@ -26,11 +186,21 @@ pub struct RgbSpectrum {
     sigmoid_coeff: [f32; 3],
     kind: RgbSpectrumKind,
     scale: f32,
+    /// The original color, used when rendering in RGB
+    rgb: Vec3,
 }
 
 impl RgbSpectrum {
+    /// Reflectance RGBs are clamped to [0, 1], because a surface can't reflect more light than
+    /// it receives. Over-bright reflectances (common in textures) would otherwise be looked up
+    /// outside of the table's gamut. Use `Unbounded` for values that can exceed 1.
     pub fn new(rgbtospec: &RGB2Spec, rgb: Vec3, kind: RgbSpectrumKind) -> Self {
-        let (scale, rgb) = match kind {
+        let rgb = match kind {
+            RgbSpectrumKind::Reflectance => rgb.clamp(Vec3::ZERO, Vec3::ONE),
+            RgbSpectrumKind::Unbounded | RgbSpectrumKind::Illuminant(_) => rgb,
+        };
+
+        let (scale, normalized_rgb) = match kind {
             RgbSpectrumKind::Reflectance => (1., rgb),
             RgbSpectrumKind::Unbounded | RgbSpectrumKind::Illuminant(_) => {
                 let max = rgb.max_element();
@@ -40,11 +210,12 @@ impl RgbSpectrum {
             }
         };
 
-        let coeff = rgbtospec.fetch(rgb.to_array());
+        let coeff = rgbtospec.fetch(normalized_rgb.to_array());
         Self {
             sigmoid_coeff: coeff,
             kind,
             scale,
+            rgb,
         }
     }
 
@@ -53,9 +224,24 @@ impl RgbSpectrum {
             sigmoid_coeff: [0., 0., 0.],
             kind: RgbSpectrumKind::Reflectance,
             scale: 0.,
+            rgb: Vec3::ZERO,
+        }
+    }
+
+    /// Returns the spectrum multiplied by a constant factor
+    pub fn scaled(&self, factor: f32) -> Self {
+        Self {
+            scale: self.scale * factor,
+            rgb: self.rgb * factor,
+            ..self.clone()
         }
     }
 
+    /// The color the spectrum was created from
+    pub fn rgb(&self) -> Vec3 {
+        self.rgb
+    }
+
     pub fn eval_single(&self, lambda: f32) -> f32 {
         let mut res = self.scale * rgb2spec::eval_precise(self.sigmoid_coeff, lambda);
         if let RgbSpectrumKind::Illuminant(illuminant) = &self.kind {
@@ -66,7 +252,7 @@ impl RgbSpectrum {
         res
     }
 
-    pub fn eval(&self, lambdas: &SampledWavelengths) -> SpectralQuantity {
+    pub fn eval<const N: usize>(&self, lambdas: &SampledWavelengths<N>) -> SpectralQuantity<N> {
         let mut vals = lambdas.lambdas;
         vals.iter_mut().for_each(|l| *l = self.eval_single(*l));
 
@@ -94,8 +280,7 @@ impl std::fmt::Debug for RgbSpectrumKind {
 impl RgbSpectrumKind {
     pub fn new_illuminant(color_space: ColorSpace) -> Self {
         match color_space {
-            ColorSpace::Srgb => Self::Illuminant(CIE_D65),
-            _ => todo!(),
+            ColorSpace::Srgb | ColorSpace::Rec2020 | ColorSpace::DciP3 => Self::Illuminant(CIE_D65),
         }
     }
 }
@@ -201,6 +386,20 @@ mod test_super {
         );
     }
 
+    #[test]
+    fn test_reflectance_clamped() {
+        // The flat table has the same coefficients everywhere, check the looked up color instead
+        let rgbtospec = flat_rgbtospec();
+        let rgb = Vec3::new(1.2, -0.1, 0.5);
+
+        let overbright = RgbSpectrum::new(&rgbtospec, rgb, RgbSpectrumKind::Reflectance);
+        assert_eq!(overbright.rgb(), Vec3::new(1., 0., 0.5));
+        assert_eq!(overbright.scale, 1.);
+
+        let unbounded = RgbSpectrum::new(&rgbtospec, rgb, RgbSpectrumKind::Unbounded);
+        assert_eq!(unbounded.rgb(), rgb);
+    }
+
     fn plot_spectrum(rgbspectrum: &RgbSpectrum, name: &str, color: [f32; 3], yrange: Range<f32>) {
         use plotters::prelude::*;
 
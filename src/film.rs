@@ -1,52 +1,183 @@
-use std::cell::UnsafeCell;
+use std::{
+    cell::UnsafeCell,
+    ops::Range,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
 
-use glam::{DVec3, Vec3};
+use eyre::{eyre, Result};
+use glam::{DVec3, Mat3, Vec3};
 
 use crate::color::color_space::ColorSpace;
 
+/// Running estimate of one pixel, updated with every sample
+#[derive(Debug, Clone, Copy, Default)]
+struct FilmPixel {
+    /// Mean of the XYZ samples
+    mean: DVec3,
+    /// Variance of the luminance (Y), also counts the samples
+    luminance: RunningVariance,
+}
+
 pub struct Film {
-    /// Stores XYZ values. Y = 0 is at the top.
-    pub buffer: Box<[UnsafeCell<DVec3>]>,
+    /// Y = 0 is at the top.
+    buffer: Box<[UnsafeCell<FilmPixel>]>,
+    /// Sums of the splatted XYZ contributions, separate from the camera samples
+    splats: Box<[[AtomicF64; 3]]>,
+    /// Light paths traced per pixel, the splat sums are divided by it
+    splat_samples: AtomicU32,
     height: usize,
     width: usize,
     color_space: ColorSpace,
+    /// Resolved once, the readout converts every pixel
+    from_xyz: Mat3,
 }
 
 impl Film {
     pub fn new(width: usize, height: usize, color_space: ColorSpace) -> Self {
         let mut buffer = Vec::with_capacity(width * height);
         for _ in 0..(width * height) {
-            buffer.push(UnsafeCell::new(DVec3::ZERO));
+            buffer.push(UnsafeCell::new(FilmPixel::default()));
         }
 
+        let splats = (0..width * height)
+            .map(|_| {
+                [
+                    AtomicF64::default(),
+                    AtomicF64::default(),
+                    AtomicF64::default(),
+                ]
+            })
+            .collect();
+
         Self {
             buffer: buffer.into_boxed_slice(),
+            splats,
+            splat_samples: AtomicU32::new(0),
             height,
             width,
             color_space,
+            from_xyz: color_space.from_xyz_matrix(),
         }
     }
 
+    /// The space of the RGB values returned by get_rgb()
+    pub fn color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+
+    /// The pixel estimate, the mean of its samples
     pub fn get_rgb(&self, x: usize, y: usize) -> Vec3 {
         let xyz = self.get_xyz(x, y);
-        self.color_space.from_xyz(xyz.as_vec3())
+        ColorSpace::apply_from_xyz(&self.from_xyz, xyz.as_vec3())
     }
 
+    /// Where a light path splats doesn't depend on the pixel, so the splats are divided by the
+    /// light paths per pixel of the whole film, not by the camera samples of the pixel
     fn get_xyz(&self, x: usize, y: usize) -> DVec3 {
-        unsafe { *(self.buffer[self.width * y + x].get() as *const DVec3) }
+        let pixel = self.get_pixel(x, y);
+        let splat = self.get_splat(x, y);
+        let splat_samples = self.splat_samples.load(Ordering::Relaxed).max(1);
+        pixel.mean + splat / splat_samples as f64
+    }
+
+    fn get_splat(&self, x: usize, y: usize) -> DVec3 {
+        let [x, y, z] = &self.splats[self.width * y + x];
+        DVec3::new(x.load(), y.load(), z.load())
+    }
+
+    fn get_pixel(&self, x: usize, y: usize) -> FilmPixel {
+        unsafe { *(self.buffer[self.width * y + x].get() as *const FilmPixel) }
+    }
+
+    /// Number of samples of the pixel, pixels can have different counts
+    pub fn samples(&self, x: usize, y: usize) -> u32 {
+        self.get_pixel(x, y).luminance.samples()
+    }
+
+    /// Statistics of the pixel's luminance, used by the variance pass and adaptive sampling
+    pub fn variance(&self, x: usize, y: usize) -> RunningVariance {
+        self.get_pixel(x, y).luminance
     }
 
-    /// This is unsafe because multiple threads writing to the same index is UB
+    /// Replaces the pixel with a single sample.
+    ///
+    /// # Safety
+    /// Only one thread may write a pixel at a time. The render threads own disjoint tiles or
+    /// pixels of a pass.
     pub unsafe fn set(&self, x: usize, y: usize, val: DVec3) {
         let index = self.width * y + x;
         let ptr = self.buffer[index].get();
-        ptr.write(val);
+
+        let mut luminance = RunningVariance::default();
+        luminance.add(val.y);
+        ptr.write(FilmPixel {
+            mean: val,
+            luminance,
+        });
     }
 
-    /// This is unsafe because multiple threads writing to the same index is UB
+    /// Adds a sample to the running mean of the pixel.
+    ///
+    /// # Safety
+    /// Same as set(), only one thread may write a pixel at a time.
     pub unsafe fn accumulate(&self, x: usize, y: usize, val: DVec3) {
-        let current = self.get_xyz(x, y);
-        self.set(x, y, current + val);
+        let index = self.width * y + x;
+        let pixel = &mut *self.buffer[index].get();
+
+        pixel.luminance.add(val.y);
+        pixel.mean += (val - pixel.mean) / pixel.luminance.samples() as f64;
+    }
+
+    /// Adds a contribution to an arbitrary pixel, not the one whose camera sample is being
+    /// rendered. Used for light tracing and bidirectional methods. Safe to call from multiple
+    /// threads for the same pixel, the splats are summed atomically.
+    pub fn splat(&self, x: usize, y: usize, val: DVec3) {
+        let index = self.width * y + x;
+        for (sum, v) in self.splats[index].iter().zip(val.to_array()) {
+            sum.fetch_add(v);
+        }
+    }
+
+    /// Counts the light paths traced per pixel, like PBRT's 1/spp splat scale. A light tracer
+    /// adds its paths per pixel after every pass.
+    pub fn add_splat_samples(&self, samples: u32) {
+        self.splat_samples.fetch_add(samples, Ordering::Relaxed);
+    }
+
+    /// Adds the samples of the tile to the running means of its pixels.
+    ///
+    /// # Safety
+    /// Same as accumulate() for every pixel of the tile, tiles merged at the same time must not
+    /// overlap.
+    pub unsafe fn merge_tile(&self, tile: &FilmTile) {
+        let width = tile.xs.len();
+        for (i, xyz) in tile.samples.iter().enumerate() {
+            self.accumulate(tile.xs.start + i % width, tile.ys.start + i / width, *xyz);
+        }
+    }
+
+    /// Luminance (XYZ Y) of the pixel estimates
+    /// The luminance is multiplied by the scale first, so that the log-average matches the
+    /// scaled image
+    pub fn luminance_stats(&self, scale: f32) -> LuminanceStats {
+        let mut sum = 0.;
+        let mut log_sum = 0.;
+        let mut max = 0f64;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let luminance = self.get_xyz(x, y).y * scale as f64;
+                sum += luminance;
+                log_sum += (LuminanceStats::LOG_DELTA + luminance).ln();
+                max = max.max(luminance);
+            }
+        }
+
+        let pixels = self.buffer.len().max(1) as f64;
+        LuminanceStats {
+            mean: (sum / pixels) as f32,
+            log_average: (log_sum / pixels).exp() as f32,
+            max: max as f32,
+        }
     }
 
     pub fn height(&self) -> usize {
@@ -60,6 +191,247 @@ impl Film {
 
 unsafe impl Sync for Film {}
 
+/// f64 stored as its bits, std doesn't have atomic floats
+#[derive(Default)]
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn fetch_add(&self, val: f64) {
+        let mut current = self.0.load(Ordering::Relaxed);
+        loop {
+            let new = (f64::from_bits(current) + val).to_bits();
+            match self
+                .0
+                .compare_exchange_weak(current, new, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+/// One sample of every pixel of a tile. A render thread fills it and merges it into the film
+/// when the tile is finished, so that threads rendering neighbouring tiles don't keep stealing
+/// the cache lines of the shared film from each other.
+#[derive(Debug, Default)]
+pub struct FilmTile {
+    xs: Range<usize>,
+    ys: Range<usize>,
+    /// XYZ samples in row-major order
+    samples: Vec<DVec3>,
+}
+
+impl FilmTile {
+    /// Starts the next tile, the buffer is reused between tiles
+    pub fn reset(&mut self, xs: Range<usize>, ys: Range<usize>) {
+        self.samples.clear();
+        self.samples.resize(xs.len() * ys.len(), DVec3::ZERO);
+        self.xs = xs;
+        self.ys = ys;
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, xyz: DVec3) {
+        debug_assert!(self.xs.contains(&x) && self.ys.contains(&y));
+        let index = (y - self.ys.start) * self.xs.len() + (x - self.xs.start);
+        self.samples[index] = xyz;
+    }
+}
+
+/// Used for metering the exposure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LuminanceStats {
+    pub mean: f32,
+    /// exp(mean(ln(delta + Y))), not dominated by a few bright pixels like the mean
+    pub log_average: f32,
+    pub max: f32,
+}
+
+impl LuminanceStats {
+    /// Keeps the logarithm of black pixels finite
+    pub const LOG_DELTA: f64 = 1e-4;
+}
+
+/// Copy of the pixel estimates of the film
+pub struct FilmSnapshot {
+    /// Linear RGB values in row-major order. Y = 0 is at the top.
+    pub pixels: Vec<Vec3>,
+    pub width: usize,
+    pub height: usize,
+    pub samples: u32,
+}
+
+impl FilmSnapshot {
+    pub fn new(film: &Film, samples: u32) -> Self {
+        let mut pixels = Vec::with_capacity(film.width * film.height);
+        for y in 0..film.height {
+            for x in 0..film.width {
+                pixels.push(film.get_rgb(x, y));
+            }
+        }
+
+        Self {
+            pixels,
+            width: film.width,
+            height: film.height,
+            samples,
+        }
+    }
+
+    pub fn get_rgb(&self, x: usize, y: usize) -> Vec3 {
+        self.pixels[self.width * y + x]
+    }
+}
+
+/// How the primary-ray hit distances of a pixel's samples are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DepthMode {
+    /// Distance of the first sample
+    #[default]
+    First,
+    /// Closest distance of all samples
+    Min,
+    /// Average distance of the samples that hit something
+    Average,
+}
+
+impl DepthMode {
+    pub fn new(kind: &str) -> Result<Self> {
+        Ok(match kind {
+            "first" => Self::First,
+            "min" => Self::Min,
+            "average" => Self::Average,
+            _ => return Err(eyre!("Unknown depth mode: '{}'", kind)),
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct DepthSample {
+    depth: f32,
+    hits: u32,
+    samples: u32,
+}
+
+/// Primary-ray hit distance of every pixel, stored the same way as the Film.
+/// Pixels where nothing was hit have an infinite depth.
+pub struct DepthFilm {
+    buffer: Box<[UnsafeCell<DepthSample>]>,
+    height: usize,
+    width: usize,
+    mode: DepthMode,
+}
+
+impl DepthFilm {
+    pub fn new(width: usize, height: usize, mode: DepthMode) -> Self {
+        let initial = match mode {
+            DepthMode::Average => 0.,
+            DepthMode::First | DepthMode::Min => f32::INFINITY,
+        };
+
+        let mut buffer = Vec::with_capacity(width * height);
+        for _ in 0..(width * height) {
+            buffer.push(UnsafeCell::new(DepthSample {
+                depth: initial,
+                hits: 0,
+                samples: 0,
+            }));
+        }
+
+        Self {
+            buffer: buffer.into_boxed_slice(),
+            height,
+            width,
+            mode,
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        let sample = unsafe { *self.buffer[self.width * y + x].get() };
+        match self.mode {
+            DepthMode::Average if sample.hits == 0 => f32::INFINITY,
+            DepthMode::Average => sample.depth / sample.hits as f32,
+            DepthMode::First | DepthMode::Min => sample.depth,
+        }
+    }
+
+    /// Adds the hit distance of one sample, None if the ray didn't hit anything.
+    ///
+    /// # Safety
+    /// Multiple threads writing to the same index is UB
+    pub unsafe fn add_sample(&self, x: usize, y: usize, depth: Option<f32>) {
+        let sample = &mut *self.buffer[self.width * y + x].get();
+
+        match (self.mode, depth) {
+            (DepthMode::First, Some(depth)) if sample.samples == 0 => sample.depth = depth,
+            (DepthMode::Min, Some(depth)) => sample.depth = sample.depth.min(depth),
+            (DepthMode::Average, Some(depth)) => sample.depth += depth,
+            _ => (),
+        }
+
+        sample.hits += depth.is_some() as u32;
+        sample.samples += 1;
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+}
+
+unsafe impl Sync for DepthFilm {}
+
+/// Welford's online algorithm, numerically stable and doesn't need the samples to be stored
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunningVariance {
+    mean: f64,
+    /// Sum of squared differences from the mean
+    m2: f64,
+    samples: u32,
+}
+
+impl RunningVariance {
+    pub fn add(&mut self, value: f64) {
+        self.samples += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.samples as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// Unbiased sample variance, 0 until there are at least 2 samples
+    pub fn sample_variance(&self) -> f64 {
+        if self.samples < 2 {
+            0.
+        } else {
+            self.m2 / (self.samples - 1) as f64
+        }
+    }
+
+    /// Variance of the mean, this is the noise left in the pixel estimate
+    pub fn variance_of_mean(&self) -> f64 {
+        if self.samples == 0 {
+            0.
+        } else {
+            self.sample_variance() / self.samples as f64
+        }
+    }
+}
+
 #[cfg(test)]
 mod test_film {
     use super::*;
@@ -102,4 +474,240 @@ mod test_film {
 
         assert_eq!(film.get_xyz(0, 0), DVec3::ONE);
     }
+
+    #[test]
+    fn test_film_accumulate_miri() {
+        let film = Film::new(16, 16, ColorSpace::Srgb);
+
+        let a = &film;
+        let b = &film;
+
+        // Every thread owns its pixel
+        std::thread::scope(|s| unsafe {
+            s.spawn(|| {
+                for i in 0..100 {
+                    a.accumulate(0, 0, DVec3::splat(i as f64));
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..50 {
+                    b.accumulate(1, 0, DVec3::new(1., 2., 3.));
+                }
+            });
+        });
+
+        assert_eq!(film.get_xyz(0, 0), DVec3::splat(49.5));
+        assert_eq!(film.samples(0, 0), 100);
+        assert_eq!(film.get_xyz(1, 0), DVec3::new(1., 2., 3.));
+        assert_eq!(film.samples(1, 0), 50);
+        assert_eq!(film.samples(2, 0), 0);
+        assert_eq!(film.get_xyz(2, 0), DVec3::ZERO);
+    }
+
+    #[test]
+    fn test_film_merge_tile_miri() {
+        let film = Film::new(5, 4, ColorSpace::Srgb);
+
+        let a = &film;
+        let b = &film;
+
+        // Every thread owns its tile, the right one is cut off by the film edge
+        std::thread::scope(|s| unsafe {
+            s.spawn(|| {
+                let mut tile = FilmTile::default();
+                for i in 0..4 {
+                    tile.reset(0..3, 0..4);
+                    for (x, y) in (0..4).flat_map(|y| (0..3).map(move |x| (x, y))) {
+                        tile.set(x, y, DVec3::splat((x + 10 * y + i) as f64));
+                    }
+                    a.merge_tile(&tile);
+                }
+            });
+            s.spawn(|| {
+                let mut tile = FilmTile::default();
+                tile.reset(3..5, 2..4);
+                tile.set(3, 2, DVec3::ONE);
+                tile.set(4, 3, DVec3::new(1., 2., 3.));
+                b.merge_tile(&tile);
+            });
+        });
+
+        assert_eq!(film.get_xyz(2, 1), DVec3::splat(12. + 1.5));
+        assert_eq!(film.samples(2, 1), 4);
+        assert_eq!(film.get_xyz(0, 3), DVec3::splat(30. + 1.5));
+        assert_eq!(film.get_xyz(3, 2), DVec3::ONE);
+        assert_eq!(film.get_xyz(4, 3), DVec3::new(1., 2., 3.));
+        assert_eq!(film.samples(4, 2), 1);
+        assert_eq!(film.get_xyz(4, 2), DVec3::ZERO);
+        assert_eq!(film.samples(3, 0), 0);
+    }
+
+    #[test]
+    fn test_film_splat_miri() {
+        const THREADS: usize = 8;
+        const SPLATS: usize = if cfg!(miri) { 20 } else { 1000 };
+        let film = Film::new(4, 4, ColorSpace::Srgb);
+
+        // Every thread splats to the same pixels
+        std::thread::scope(|s| {
+            for t in 0..THREADS {
+                let film = &film;
+                s.spawn(move || {
+                    for i in 0..SPLATS {
+                        film.splat(1, 2, DVec3::new(1., 2., t as f64));
+                        film.splat(i % 4, 3, DVec3::ONE);
+                    }
+                });
+            }
+        });
+
+        let total = (THREADS * SPLATS) as f64;
+        let sum_t = (SPLATS * THREADS * (THREADS - 1) / 2) as f64;
+        assert_eq!(film.get_xyz(1, 2), DVec3::new(total, 2. * total, sum_t));
+        assert_eq!(film.get_xyz(2, 3), DVec3::splat(total / 4.));
+        assert_eq!(film.get_xyz(0, 0), DVec3::ZERO);
+        // Splats aren't camera samples
+        assert_eq!(film.samples(1, 2), 0);
+
+        // Combined with the camera samples of the pixel at readout
+        for _ in 0..4 {
+            unsafe { film.accumulate(3, 0, DVec3::ONE) };
+        }
+        film.splat(3, 0, DVec3::new(8., 0., 4.));
+        film.add_splat_samples(4);
+        assert_eq!(film.get_xyz(3, 0), DVec3::new(3., 1., 2.));
+        assert_eq!(film.samples(3, 0), 4);
+    }
+
+    #[test]
+    fn test_film_splat_normalization() {
+        let film = Film::new(3, 1, ColorSpace::Srgb);
+
+        // The pixels have different numbers of camera samples, like in a time-limited render
+        unsafe {
+            film.accumulate(0, 0, DVec3::ONE);
+            for _ in 0..3 {
+                film.accumulate(1, 0, DVec3::ONE);
+            }
+        }
+        for _ in 0..2 {
+            for x in 0..3 {
+                film.splat(x, 0, DVec3::splat(4.));
+            }
+            film.add_splat_samples(2);
+        }
+
+        // Divided by the light paths per pixel of the film, even without camera samples
+        assert_eq!(film.get_xyz(0, 0), DVec3::splat(1. + 2.));
+        assert_eq!(film.get_xyz(1, 0), DVec3::splat(1. + 2.));
+        assert_eq!(film.get_xyz(2, 0), DVec3::splat(2.));
+        assert_eq!(film.samples(2, 0), 0);
+    }
+
+    #[test]
+    fn test_film_cached_color_space() {
+        let film = Film::new(4, 1, ColorSpace::Srgb);
+        // The last one is out of the sRGB gamut and gets clamped
+        let colors = [
+            DVec3::new(0.95, 1., 1.09),
+            DVec3::new(0.2, 0.1, 0.7),
+            DVec3::new(3., 2., 0.5),
+            DVec3::new(0.1, 0.9, 0.1),
+        ];
+        for (x, xyz) in colors.into_iter().enumerate() {
+            unsafe { film.set(x, 0, xyz) };
+            let expected = ColorSpace::Srgb.from_xyz(xyz.as_vec3());
+            assert_eq!(film.get_rgb(x, 0), expected);
+        }
+        assert_eq!(film.get_rgb(3, 0).x, 0.);
+    }
+
+    #[test]
+    fn test_luminance_stats() {
+        let film = Film::new(2, 2, ColorSpace::Srgb);
+        unsafe {
+            film.set(0, 0, DVec3::new(5., 4., 3.));
+            film.set(1, 0, DVec3::new(0., 2., 0.));
+            film.set(0, 1, DVec3::new(1., 2., 1.));
+        }
+
+        // The X and Z components don't matter
+        let stats = film.luminance_stats(1.);
+        assert_eq!(stats.mean, 2.);
+        assert_eq!(stats.max, 4.);
+
+        // The black pixel pulls the log-average down much more than the mean
+        let delta = LuminanceStats::LOG_DELTA;
+        let log_average = ((4. + delta) * (2. + delta) * (2. + delta) * delta).powf(0.25) as f32;
+        assert!((stats.log_average - log_average).abs() < 1e-5, "{stats:?}");
+        assert!(stats.log_average < stats.mean / 4.);
+
+        let scaled = film.luminance_stats(2.);
+        assert_eq!(scaled.mean, 4.);
+        assert_eq!(scaled.max, 8.);
+        assert!(scaled.log_average > stats.log_average);
+
+        let empty = Film::new(2, 2, ColorSpace::Srgb).luminance_stats(1.);
+        assert_eq!(empty.mean, 0.);
+        assert_eq!(empty.max, 0.);
+        assert!((empty.log_average - delta as f32).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_depth_modes() {
+        let depths = [None, Some(4.), Some(2.), Some(3.)];
+
+        let depth_of = |mode| {
+            let film = DepthFilm::new(2, 1, mode);
+            for depth in depths {
+                unsafe {
+                    film.add_sample(0, 0, depth);
+                    film.add_sample(1, 0, None);
+                }
+            }
+            (film.get(0, 0), film.get(1, 0))
+        };
+
+        // The first sample was a miss
+        assert_eq!(depth_of(DepthMode::First), (f32::INFINITY, f32::INFINITY));
+        assert_eq!(depth_of(DepthMode::Min), (2., f32::INFINITY));
+        assert_eq!(depth_of(DepthMode::Average), (3., f32::INFINITY));
+    }
+
+    #[test]
+    fn test_running_variance() {
+        let values = [2., 4., 4., 4., 5., 5., 7., 9.];
+
+        let mut acc = RunningVariance::default();
+        for value in values {
+            acc.add(value);
+        }
+
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / 7.;
+        assert_eq!(acc.mean(), 5.);
+        assert!((acc.sample_variance() - variance).abs() < 1e-12);
+        assert!((acc.variance_of_mean() - variance / 8.).abs() < 1e-12);
+
+        assert_eq!(RunningVariance::default().variance_of_mean(), 0.);
+    }
+
+    #[test]
+    fn test_film_variance_flat_and_noisy() {
+        let film = Film::new(2, 1, ColorSpace::Srgb);
+
+        // The flat pixel always gets the same value, the noisy one alternates between 0 and 1
+        for i in 0..64 {
+            unsafe {
+                film.accumulate(0, 0, DVec3::splat(0.7));
+                film.accumulate(1, 0, DVec3::new(0., (i % 2) as f64, 0.));
+            }
+        }
+
+        assert_eq!(film.get_xyz(1, 0), DVec3::new(0., 0.5, 0.));
+        let flat = film.variance(0, 0).variance_of_mean();
+        let noisy = film.variance(1, 0).variance_of_mean();
+        assert!(flat < 1e-12, "{flat}");
+        assert!((noisy - 0.25 / 63.).abs() < 1e-9, "{noisy}");
+    }
 }
@@ -3,16 +3,21 @@ use std::ops::Index;
 use enum_ptr::EnumPtr;
 use glam::{BVec3, Vec2, Vec3};
 
+pub mod cuboid;
+pub mod infinite_plane;
+pub mod quad;
 pub mod ray;
 pub mod sphere;
 pub mod trianglemesh;
 
-use rand::rngs::SmallRng;
 pub use ray::Ray;
 
-use crate::{scene::ShapeSample, util::TaggedPtr};
+use crate::{sampler::Sampler, scene::ShapeSample, util::TaggedPtr};
 
-use self::{sphere::Sphere, trianglemesh::Triangle};
+use self::{
+    cuboid::Cuboid, infinite_plane::InfinitePlane, quad::Quad, sphere::Sphere,
+    trianglemesh::Triangle,
+};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
@@ -27,11 +32,41 @@ pub struct ShapeHitInfo {
     pub normal: Vec3,
     pub t: f32,
     pub uv: Option<Vec2>,
+    /// Shading tangent, only provided by shapes that can compute a meaningful one
+    pub tangent: Option<Vec3>,
+    /// dp/du and dp/dv, provided by the shapes with UVs
+    pub uv_derivatives: Option<[Vec3; 2]>,
+    /// Barycentric coordinates of triangle hits, the tangent and the vertex color of triangles
+    /// are computed from them only for the closest hit
+    pub barycentrics: Option<[f32; 3]>,
 }
 
 impl ShapeHitInfo {
     pub fn new(pos: Vec3, normal: Vec3, t: f32, uv: Option<Vec2>) -> Self {
-        Self { pos, normal, t, uv }
+        Self {
+            pos,
+            normal,
+            t,
+            uv,
+            tangent: None,
+            uv_derivatives: None,
+            barycentrics: None,
+        }
+    }
+
+    pub fn with_tangent(mut self, tangent: Vec3) -> Self {
+        self.tangent = Some(tangent);
+        self
+    }
+
+    pub fn with_uv_derivatives(mut self, dpdu: Vec3, dpdv: Vec3) -> Self {
+        self.uv_derivatives = Some([dpdu, dpdv]);
+        self
+    }
+
+    pub fn with_barycentrics(mut self, barycentrics: [f32; 3]) -> Self {
+        self.barycentrics = Some(barycentrics);
+        self
     }
 }
 
@@ -40,6 +75,9 @@ impl ShapeHitInfo {
 pub enum Shape {
     Sphere(Box<Sphere>),
     Triangle(Box<Triangle>),
+    InfinitePlane(Box<InfinitePlane>),
+    Quad(Box<Quad>),
+    Cuboid(Box<Cuboid>),
 }
 
 impl TaggedPtr<Shape> {
@@ -47,22 +85,64 @@ impl TaggedPtr<Shape> {
         self.0.map_ref(|s| match s {
             Shape::Sphere(sphere) => sphere.hit(ray),
             Shape::Triangle(triangle) => triangle.intersect(ray),
+            Shape::InfinitePlane(plane) => plane.hit(ray),
+            Shape::Quad(quad) => quad.hit(ray),
+            Shape::Cuboid(cuboid) => cuboid.hit(ray),
         })
     }
 
     /// Must not be called on non-light Hittables
-    pub fn sample_point(&self, rng: &mut SmallRng) -> ShapeSample {
+    pub fn sample_point(&self, rng: &mut Sampler) -> ShapeSample {
         self.0.map_ref(|s| match s {
             Shape::Sphere(sphere) => sphere.sample_point(rng),
             Shape::Triangle(_) => unreachable!(),
+            Shape::InfinitePlane(_) => unreachable!(),
+            Shape::Quad(quad) => quad.sample_point(rng),
+            Shape::Cuboid(_) => unreachable!(),
+        })
+    }
+
+    /// Samples a point as seen from p_ref, returns the sample and its solid-angle PDF.
+    /// Quads and spheres are sampled by their solid angle, other shapes uniformly by area.
+    /// The cone of a sphere is undefined when p_ref is inside of it, the whole sphere is visible
+    /// then and it's sampled by area as well.
+    /// Must not be called on non-light Hittables
+    pub fn sample_point_from(&self, p_ref: Vec3, rng: &mut Sampler) -> (ShapeSample, f32) {
+        self.0.map_ref(|s| match s {
+            Shape::Quad(quad) => quad.sample_point_solid_angle(p_ref, rng),
+            Shape::Sphere(sphere) => match sphere.sample_point_solid_angle(p_ref, rng) {
+                Some(sample) => sample,
+                None => self.sample_point_by_area(p_ref, rng),
+            },
+            _ => self.sample_point_by_area(p_ref, rng),
         })
     }
 
+    /// Solid-angle PDF of sample_point_from() returning the sample
+    pub fn pdf_from(&self, p_ref: Vec3, sample: &ShapeSample) -> f32 {
+        let pdf_by_area = || sample.area_to_solid_angle_pdf(p_ref, 1. / self.area());
+
+        self.0.map_ref(|s| match s {
+            Shape::Quad(quad) => quad.pdf_solid_angle(p_ref),
+            Shape::Sphere(sphere) => sphere.pdf_solid_angle(p_ref).unwrap_or_else(pdf_by_area),
+            _ => pdf_by_area(),
+        })
+    }
+
+    fn sample_point_by_area(&self, p_ref: Vec3, rng: &mut Sampler) -> (ShapeSample, f32) {
+        let sample = self.sample_point(rng);
+        let pdf = sample.area_to_solid_angle_pdf(p_ref, 1. / self.area());
+        (sample, pdf)
+    }
+
     /// Must not be called on non-light Hittables
     pub fn area(&self) -> f32 {
         self.0.map_ref(|s| match s {
             Shape::Sphere(sphere) => sphere.area(),
             Shape::Triangle(_) => unreachable!(),
+            Shape::InfinitePlane(_) => f32::INFINITY,
+            Shape::Quad(quad) => quad.area(),
+            Shape::Cuboid(_) => unreachable!(),
         })
     }
 
@@ -70,6 +150,9 @@ impl TaggedPtr<Shape> {
         self.0.map_ref(|s| match s {
             Shape::Sphere(sphere) => sphere.aabb(),
             Shape::Triangle(_) => unreachable!(),
+            Shape::InfinitePlane(plane) => plane.aabb(),
+            Shape::Quad(quad) => quad.aabb(),
+            Shape::Cuboid(cuboid) => cuboid.aabb(),
         })
     }
 }
@@ -97,6 +180,20 @@ impl AABB {
         inv_ray_dir: Vec3,
         dir_is_neg: BVec3,
     ) -> bool {
+        match self.intersect_slabs(ray, inv_ray_dir, dir_is_neg) {
+            Some((tmin, tmax)) => (tmin < ray_tmax) && (tmax > 0.),
+            None => false,
+        }
+    }
+
+    /// Returns the ray parameters where the ray enters and exits the slabs of the box.
+    /// The values can be negative if the box is (partially) behind the ray origin.
+    pub fn intersect_slabs(
+        &self,
+        ray: &Ray,
+        inv_ray_dir: Vec3,
+        dir_is_neg: BVec3,
+    ) -> Option<(f32, f32)> {
         let mut tmin = (self[dir_is_neg.x].x - ray.orig.x) * inv_ray_dir.x;
         let mut tmax = (self[!dir_is_neg.x].x - ray.orig.x) * inv_ray_dir.x;
         let tymin = (self[dir_is_neg.y].y - ray.orig.y) * inv_ray_dir.y;
@@ -104,7 +201,7 @@ impl AABB {
         // TODO: robust floating-point computation
 
         if tmin > tymax || tymin > tmax {
-            return false;
+            return None;
         }
         if tymin > tmin {
             tmin = tymin;
@@ -117,7 +214,7 @@ impl AABB {
         let tzmax = (self[!dir_is_neg.z].z - ray.orig.z) * inv_ray_dir.z;
 
         if tmin > tzmax || tzmin > tmax {
-            return false;
+            return None;
         }
         if tzmin > tmin {
             tmin = tzmin;
@@ -126,7 +223,7 @@ impl AABB {
             tmax = tzmax;
         }
 
-        return (tmin < ray_tmax) && (tmax > 0.);
+        Some((tmin, tmax))
     }
 
     pub fn union_point(self, b: Vec3) -> Self {
@@ -163,15 +260,30 @@ impl AABB {
         off
     }
 
-    pub fn area(&self) -> f32 {
+    /// Full surface area of the box
+    pub fn surface_area(&self) -> f32 {
+        2. * self.half_area()
+    }
+
+    /// Half of the surface area, which is what the SAH uses as it only cares about ratios
+    pub fn half_area(&self) -> f32 {
         let d = self.diagonal();
-        2. * (d.x * d.y + d.x * d.z + d.z * d.y)
+        d.x * d.y + d.x * d.z + d.z * d.y
     }
 
     pub fn center(&self) -> Vec3 {
         (self.min + self.max) / 2.
     }
 
+    /// The bits of the index choose between min (0) and max (1) for X, Y and Z
+    pub fn corner(&self, index: usize) -> Vec3 {
+        Vec3::select(
+            BVec3::new(index & 1 != 0, index & 2 != 0, index & 4 != 0),
+            self.max,
+            self.min,
+        )
+    }
+
     pub fn max_axis(&self) -> Axis {
         let diag = self.diagonal();
         if diag.x > diag.y && diag.x > diag.z {
@@ -187,10 +299,19 @@ impl AABB {
         self.min == self.max
     }
 
+    pub fn is_bounded(&self) -> bool {
+        self.min.is_finite() && self.max.is_finite()
+    }
+
     pub const EMPTY: AABB = AABB {
         min: Vec3::splat(f32::MAX),
         max: Vec3::splat(f32::MIN),
     };
+
+    pub const UNBOUNDED: AABB = AABB {
+        min: Vec3::NEG_INFINITY,
+        max: Vec3::INFINITY,
+    };
 }
 
 impl Index<bool> for AABB {
@@ -223,7 +344,12 @@ mod test_geometry {
         assert_eq!(aabb_2.center(), Vec3::ONE);
 
         let aabb_3 = AABB::new(vec3(-1.8, -0.3, 0.9), vec3(1.2, 1.7, 1.9));
-        assert_eq!(aabb_3.area(), 22.);
+        assert_eq!(aabb_3.surface_area(), 22.);
+        assert_eq!(aabb_3.half_area(), 11.);
+
+        // Planar bounds still have an area
+        let aabb_planar = AABB::new(Vec3::ZERO, vec3(2., 3., 0.));
+        assert_eq!(aabb_planar.half_area(), 6.);
     }
 
     #[test]
@@ -0,0 +1,145 @@
+use glam::{vec3, Mat3, Mat4, Vec3};
+
+use crate::{
+    geometry::Ray,
+    pbrt_loader::scene_description::{self, ShapeWithParams},
+};
+
+use super::{ShapeHitInfo, AABB};
+
+/// A box that is axis-aligned in object space.
+/// Rays are transformed into object space and intersected with the box's slabs.
+pub struct Cuboid {
+    bounds: AABB,
+    object_to_world: Mat4,
+    world_to_object: Mat4,
+    /// Inverse transpose of object_to_world, for transforming normals
+    normal_to_world: Mat3,
+    reverse_normals: bool,
+}
+
+impl Cuboid {
+    pub fn new(shape: &ShapeWithParams, cuboid: &scene_description::Cuboid) -> Self {
+        let mut c = Self::new_mock(cuboid.p0, cuboid.p1, shape.object_to_world);
+        c.reverse_normals = shape.reverse_normals;
+        c
+    }
+
+    pub fn new_mock(p0: Vec3, p1: Vec3, object_to_world: Mat4) -> Self {
+        let world_to_object = object_to_world.inverse();
+
+        Self {
+            bounds: AABB::new(p0, p1),
+            object_to_world,
+            world_to_object,
+            normal_to_world: Mat3::from_mat4(world_to_object).transpose(),
+            reverse_normals: false,
+        }
+    }
+
+    pub fn hit(&self, ray: &Ray) -> Option<ShapeHitInfo> {
+        let eps = 0.0000001;
+
+        // The direction isn't normalized, so that t stays the same in both spaces
+        let obj_ray = Ray {
+            orig: self.world_to_object.transform_point3(ray.orig),
+            dir: self.world_to_object.transform_vector3(ray.dir),
+        };
+
+        let inv_dir = Vec3::ONE / obj_ray.dir;
+        let dir_is_neg = inv_dir.cmplt(Vec3::ZERO);
+        let (tmin, tmax) = self.bounds.intersect_slabs(&obj_ray, inv_dir, dir_is_neg)?;
+
+        // The ray origin can be inside of the box
+        let t = if tmin > eps {
+            tmin
+        } else if tmax > eps {
+            tmax
+        } else {
+            return None;
+        };
+
+        let obj_pos = obj_ray.orig + obj_ray.dir * t;
+        let mut normal = self
+            .normal_to_world
+            .mul_vec3(self.face_normal(obj_pos))
+            .normalize();
+        if self.reverse_normals {
+            normal = -normal;
+        }
+
+        let pos = ray.orig + ray.dir * t;
+        Some(ShapeHitInfo::new(pos, normal, t, None))
+    }
+
+    /// Object-space normal of the face closest to the point
+    fn face_normal(&self, obj_pos: Vec3) -> Vec3 {
+        let half_extent = self.bounds.diagonal() / 2.;
+        let local = (obj_pos - self.bounds.center()) / half_extent;
+        let dist = local.abs();
+
+        if dist.x > dist.y && dist.x > dist.z {
+            vec3(local.x.signum(), 0., 0.)
+        } else if dist.y > dist.z {
+            vec3(0., local.y.signum(), 0.)
+        } else {
+            vec3(0., 0., local.z.signum())
+        }
+    }
+
+    pub fn aabb(&self) -> AABB {
+        let (min, max) = (self.bounds.min, self.bounds.max);
+
+        (0..8).fold(AABB::EMPTY, |aabb, i| {
+            let corner = vec3(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            );
+            aabb.union_point(self.object_to_world.transform_point3(corner))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_cuboid_face_normals() {
+        let transform = Mat4::from_translation(vec3(0., 0., 5.));
+        let cuboid = Cuboid::new_mock(vec3(-1., -2., -3.), vec3(1., 2., 3.), transform);
+
+        assert_eq!(
+            cuboid.aabb(),
+            AABB::new(vec3(-1., -2., 2.), vec3(1., 2., 8.))
+        );
+
+        let center = vec3(0., 0., 5.);
+        let cases = [
+            (Vec3::X, 1.),
+            (Vec3::NEG_X, 1.),
+            (Vec3::Y, 2.),
+            (Vec3::NEG_Y, 2.),
+            (Vec3::Z, 3.),
+            (Vec3::NEG_Z, 3.),
+        ];
+
+        for (face_normal, half_extent) in cases {
+            // From outside of the box towards the face
+            let ray = Ray::new(center + face_normal * 10., -face_normal);
+            let hitinfo = cuboid.hit(&ray).unwrap();
+            assert_eq!(hitinfo.normal, face_normal);
+            assert!((hitinfo.t - (10. - half_extent)).abs() < 0.0001);
+
+            // From the center of the box towards the face
+            let ray = Ray::new(center, face_normal);
+            let hitinfo = cuboid.hit(&ray).unwrap();
+            assert_eq!(hitinfo.normal, face_normal);
+            assert!((hitinfo.t - half_extent).abs() < 0.0001);
+        }
+
+        let ray_miss = Ray::new(vec3(3., 0., 0.), Vec3::Z);
+        assert!(cuboid.hit(&ray_miss).is_none());
+    }
+}
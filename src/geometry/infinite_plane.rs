@@ -0,0 +1,90 @@
+use glam::{Mat3, Vec3};
+
+use crate::{
+    geometry::Ray,
+    pbrt_loader::scene_description::{self, ShapeWithParams},
+};
+
+use super::{ShapeHitInfo, AABB};
+
+/// A plane with an infinite extent.
+/// It has an unbounded AABB, so it can't be stored in the BVH and can't be an area light.
+// The tag of TaggedPtr<Shape> needs 3 free low bits in the pointer
+#[repr(align(8))]
+pub struct InfinitePlane {
+    point: Vec3,
+    normal: Vec3,
+}
+
+impl InfinitePlane {
+    pub fn new(shape: &ShapeWithParams, plane: &scene_description::InfinitePlane) -> Self {
+        let point = shape.object_to_world.transform_point3(plane.point);
+        // Normals have to be transformed by the inverse transpose
+        let normal_transform = Mat3::from_mat4(shape.object_to_world).inverse().transpose();
+        let mut normal = normal_transform.mul_vec3(plane.normal).normalize();
+        if shape.reverse_normals {
+            normal = -normal;
+        }
+
+        Self { point, normal }
+    }
+
+    pub fn new_mock(point: Vec3, normal: Vec3) -> Self {
+        Self {
+            point,
+            normal: normal.normalize(),
+        }
+    }
+
+    pub fn hit(&self, ray: &Ray) -> Option<ShapeHitInfo> {
+        let eps = 0.0000001;
+
+        let denom = self.normal.dot(ray.dir);
+        if denom.abs() < eps {
+            return None;
+        }
+
+        let t = (self.point - ray.orig).dot(self.normal) / denom;
+        if t > eps {
+            let pos = ray.orig + ray.dir * t;
+            Some(ShapeHitInfo::new(pos, self.normal, t, None))
+        } else {
+            None
+        }
+    }
+
+    pub fn aabb(&self) -> AABB {
+        AABB::UNBOUNDED
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::vecmath::vec3_cmp_assert;
+    use glam::vec3;
+
+    #[test]
+    fn test_infinite_plane_intersection() {
+        let plane = InfinitePlane::new_mock(vec3(0., -1., 0.), vec3(0., 1., 0.));
+
+        let ray_hit = Ray::new(vec3(0., 1., 0.), vec3(0., -1., 0.));
+        let hitinfo = plane.hit(&ray_hit).unwrap();
+        assert_eq!(hitinfo.t, 2.);
+        assert_eq!(hitinfo.pos, vec3(0., -1., 0.));
+        assert_eq!(hitinfo.normal, vec3(0., 1., 0.));
+
+        // Far away from the origin
+        let ray_far = Ray::new(vec3(1000., 1., -5000.), vec3(1., -1., 0.));
+        let hitinfo = plane.hit(&ray_far).unwrap();
+        vec3_cmp_assert(hitinfo.pos, vec3(1002., -1., -5000.));
+
+        let ray_parallel = Ray::new(vec3(0., 1., 0.), vec3(1., 0., 0.));
+        assert!(plane.hit(&ray_parallel).is_none());
+
+        let ray_away = Ray::new(vec3(0., 1., 0.), vec3(0., 1., 0.));
+        assert!(plane.hit(&ray_away).is_none());
+
+        assert!(!plane.aabb().is_bounded());
+    }
+}
@@ -0,0 +1,253 @@
+use glam::{vec2, Vec3};
+use rand::{distributions::Uniform, prelude::Distribution};
+
+use crate::{
+    geometry::Ray,
+    pbrt_loader::scene_description::{self, ShapeWithParams},
+    sampler::Sampler,
+    sampling,
+    scene::ShapeSample,
+};
+
+use super::{ShapeHitInfo, AABB};
+
+/// A rectangle spanned by two perpendicular edges starting at the corner.
+// The tag of TaggedPtr<Shape> needs 3 free low bits in the pointer
+#[repr(align(8))]
+pub struct Quad {
+    corner: Vec3,
+    edge_u: Vec3,
+    edge_v: Vec3,
+    normal: Vec3,
+    /// Normalized edge_u, the U direction of the UVs
+    tangent: Vec3,
+    /// Cached for computing the planar coordinates of hits
+    w: Vec3,
+    area: f32,
+}
+
+impl Quad {
+    pub fn new(shape: &ShapeWithParams, quad: &scene_description::Quad) -> Self {
+        let corner = shape.object_to_world.transform_point3(quad.corner);
+        let edge_u = shape.object_to_world.transform_vector3(quad.edge_u);
+        let edge_v = shape.object_to_world.transform_vector3(quad.edge_v);
+
+        let mut quad = Self::new_mock(corner, edge_u, edge_v);
+        // The normal is the cross product of the transformed edges
+        if shape.reverse_normals ^ shape.transform_swaps_handedness() {
+            quad.normal = -quad.normal;
+        }
+
+        quad
+    }
+
+    pub fn new_mock(corner: Vec3, edge_u: Vec3, edge_v: Vec3) -> Self {
+        let n = edge_u.cross(edge_v);
+
+        Self {
+            corner,
+            edge_u,
+            edge_v,
+            normal: n.normalize(),
+            tangent: edge_u.normalize(),
+            w: n / n.dot(n),
+            area: n.length(),
+        }
+    }
+
+    pub fn hit(&self, ray: &Ray) -> Option<ShapeHitInfo> {
+        let eps = 0.0000001;
+
+        let denom = self.normal.dot(ray.dir);
+        if denom.abs() < eps {
+            return None;
+        }
+
+        let t = (self.corner - ray.orig).dot(self.normal) / denom;
+        if t <= eps {
+            return None;
+        }
+
+        let pos = ray.orig + ray.dir * t;
+
+        // Planar coordinates of the hit
+        let q = pos - self.corner;
+        let a = self.w.dot(q.cross(self.edge_v));
+        let b = self.w.dot(self.edge_u.cross(q));
+
+        if !(0. ..=1.).contains(&a) || !(0. ..=1.).contains(&b) {
+            return None;
+        }
+
+        Some(
+            ShapeHitInfo::new(pos, self.normal, t, Some(vec2(a, b)))
+                .with_tangent(self.tangent)
+                .with_uv_derivatives(self.edge_u, self.edge_v),
+        )
+    }
+
+    /// Samples the quad uniformly with respect to area.
+    pub fn sample_point(&self, rng: &mut Sampler) -> ShapeSample {
+        let dist = Uniform::from(0f32..1f32);
+        let u = dist.sample(rng);
+        let v = dist.sample(rng);
+
+        let pos = self.corner + u * self.edge_u + v * self.edge_v;
+        ShapeSample::new(pos, self.normal)
+    }
+
+    /// Samples the quad uniformly with respect to the solid angle subtended at p_ref.
+    /// Returns the sample and its solid-angle PDF.
+    pub fn sample_point_solid_angle(&self, p_ref: Vec3, rng: &mut Sampler) -> (ShapeSample, f32) {
+        let (pos, pdf) =
+            sampling::sample_spherical_rectangle(rng, p_ref, self.corner, self.edge_u, self.edge_v);
+
+        (ShapeSample::new(pos, self.normal), pdf)
+    }
+
+    /// Solid-angle PDF of sample_point_solid_angle() for any point on the quad.
+    pub fn pdf_solid_angle(&self, p_ref: Vec3) -> f32 {
+        sampling::spherical_rectangle_pdf(p_ref, self.corner, self.edge_u, self.edge_v)
+    }
+
+    pub fn aabb(&self) -> AABB {
+        AABB::new(self.corner, self.corner + self.edge_u)
+            .union_point(self.corner + self.edge_v)
+            .union_point(self.corner + self.edge_u + self.edge_v)
+    }
+
+    pub fn area(&self) -> f32 {
+        self.area
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use glam::{vec3, Mat4};
+    use rand::SeedableRng;
+
+    use crate::pbrt_loader::scene_description::Material;
+
+    fn mock_quad() -> Quad {
+        Quad::new_mock(vec3(-1., -1., 2.), vec3(2., 0., 0.), vec3(0., 3., 0.))
+    }
+
+    #[test]
+    fn test_quad_intersection() {
+        let quad = mock_quad();
+        assert_eq!(quad.area(), 6.);
+        assert_eq!(quad.aabb(), AABB::new(vec3(-1., -1., 2.), vec3(1., 2., 2.)));
+
+        let ray_hit = Ray::new(vec3(0., 0., 0.), vec3(0., 0., 1.));
+        let hitinfo = quad.hit(&ray_hit).unwrap();
+        assert_eq!(hitinfo.t, 2.);
+        assert_eq!(hitinfo.normal, vec3(0., 0., 1.));
+        assert_eq!(hitinfo.uv, Some(vec2(0.5, 1. / 3.)));
+
+        let ray_outside = Ray::new(vec3(1.5, 0., 0.), vec3(0., 0., 1.));
+        assert!(quad.hit(&ray_outside).is_none());
+
+        let ray_behind = Ray::new(vec3(0., 0., 3.), vec3(0., 0., 1.));
+        assert!(quad.hit(&ray_behind).is_none());
+    }
+
+    #[test]
+    fn test_quad_mirrored_orientation() {
+        let quad_desc = || scene_description::Quad::new(Vec3::ZERO, Vec3::X, Vec3::Y);
+        let normal = |object_to_world, reverse_normals| {
+            let shape = ShapeWithParams::new(
+                scene_description::Shape::Quad(quad_desc()),
+                Material::new_empty(),
+                None,
+                object_to_world,
+                reverse_normals,
+                None,
+            );
+            Quad::new(&shape, &quad_desc()).normal
+        };
+
+        let mirror = Mat4::from_scale(vec3(-1., 1., 1.));
+        assert_eq!(normal(Mat4::IDENTITY, false), Vec3::Z);
+        assert_eq!(normal(Mat4::IDENTITY, true), -Vec3::Z);
+        // Mirroring along X doesn't change the orientation of the plane's normal
+        assert_eq!(normal(mirror, false), Vec3::Z);
+        assert_eq!(normal(mirror, true), -Vec3::Z);
+    }
+
+    #[test]
+    /// Compares the solid angle implied by the spherical-rectangle PDF with an estimate
+    /// computed from uniform area sampling.
+    fn test_quad_light_sampling() {
+        let quad = mock_quad();
+        let mut rng = Sampler::seed_from_u64(0);
+        let p_ref = vec3(0.3, 0.2, 0.);
+
+        let samples = 100_000;
+        let mut solid_angle_estimate = 0.;
+        for _ in 0..samples {
+            let sample = quad.sample_point(&mut rng);
+            assert_eq!(sample.pos.z, 2.);
+            assert!(quad.aabb().union_point(sample.pos) == quad.aabb());
+
+            let to_light = sample.pos - p_ref;
+            let cos_light = sample.normal.dot(-to_light.normalize()).abs();
+            solid_angle_estimate += cos_light * quad.area() / to_light.length_squared();
+        }
+        solid_angle_estimate /= samples as f32;
+
+        for _ in 0..1000 {
+            let (sample, pdf) = quad.sample_point_solid_angle(p_ref, &mut rng);
+            assert!((sample.pos.z - 2.).abs() < 0.0001);
+            assert!(sample.pos.x >= -1.0001 && sample.pos.x <= 1.0001);
+            assert!(sample.pos.y >= -1.0001 && sample.pos.y <= 2.0001);
+
+            let solid_angle = 1. / pdf;
+            assert!((solid_angle - solid_angle_estimate).abs() < 0.01 * solid_angle_estimate);
+        }
+    }
+
+    #[test]
+    /// Irradiance from a unit-radiance quad very close to the receiver. Both estimators
+    /// have to converge to the same value, the solid-angle one with a much lower variance.
+    fn test_quad_solid_angle_convergence() {
+        let quad = mock_quad();
+        let mut rng = Sampler::seed_from_u64(0);
+        let p_ref = vec3(0.3, 0.2, 2.1);
+        let receiver_normal = -Vec3::Z;
+
+        let samples = 100_000;
+        let mean_and_variance = |estimates: Vec<f32>| {
+            let mean = estimates.iter().sum::<f32>() / samples as f32;
+            let variance =
+                estimates.iter().map(|e| (e - mean).powi(2)).sum::<f32>() / samples as f32;
+            (mean, variance)
+        };
+
+        let area_estimates = (0..samples)
+            .map(|_| {
+                let sample = quad.sample_point(&mut rng);
+                let pdf = sample.area_to_solid_angle_pdf(p_ref, 1. / quad.area());
+                let cos = receiver_normal.dot((sample.pos - p_ref).normalize());
+                cos / pdf
+            })
+            .collect();
+
+        let solid_angle_estimates = (0..samples)
+            .map(|_| {
+                let (sample, pdf) = quad.sample_point_solid_angle(p_ref, &mut rng);
+                assert_eq!(pdf, quad.pdf_solid_angle(p_ref));
+                let cos = receiver_normal.dot((sample.pos - p_ref).normalize());
+                cos / pdf
+            })
+            .collect();
+
+        let (area_mean, area_variance) = mean_and_variance(area_estimates);
+        let (sa_mean, sa_variance) = mean_and_variance(solid_angle_estimates);
+
+        // Within 4 standard errors of the noisier estimator
+        let std_error = (area_variance / samples as f32).sqrt();
+        assert!((area_mean - sa_mean).abs() < 4. * std_error);
+        assert!(sa_variance * 100. < area_variance);
+    }
+}
@@ -14,9 +14,11 @@ impl Ray {
         }
     }
 
-    pub fn transform(&mut self, world_to_cam: Mat4) {
-        self.dir = world_to_cam.inverse().transform_vector3(self.dir);
-        self.orig = world_to_cam.inverse().transform_point3(self.orig);
+    /// The direction isn't normalized afterwards.
+    /// Camera rays are transformed by the inverse of the camera_from_world transform.
+    pub fn transform(&mut self, trans: Mat4) {
+        self.dir = trans.transform_vector3(self.dir);
+        self.orig = trans.transform_point3(self.orig);
     }
 }
 
@@ -1,92 +1,242 @@
 use std::f32::consts::PI;
 
-use glam::Vec3;
-use rand::rngs::SmallRng;
+use glam::{Mat3, Mat4, Vec3};
+use rand::Rng;
 
 use crate::{
     geometry::Ray,
-    math::sqr,
+    math::{self, sqr},
     pbrt_loader::scene_description::{self, ShapeWithParams},
+    sampler::Sampler,
     sampling,
     scene::ShapeSample,
+    stats::{self, TestKind},
+    vecmath,
 };
 
 use super::{ShapeHitInfo, AABB};
 
+/// A sphere centered at the origin in object space.
+/// Rays are transformed into object space, so non-uniform scale turns it into an ellipsoid.
 pub struct Sphere {
-    center: Vec3,
     radius: f32,
+    object_to_world: Mat4,
+    world_to_object: Mat4,
+    /// Inverse transpose of object_to_world, for transforming normals
+    normal_to_world: Mat3,
     area: f32,
+    /// Largest area stretch of the transform, for rejection sampling of ellipsoids
+    max_stretch: f32,
+    /// World-space radius, None for ellipsoids
+    world_radius: Option<f32>,
+    /// Normals point inwards. Like in PBRT, transforms that swap the handedness flip them too.
+    reverse_normals: bool,
 
     bh_index: usize,
 }
 
 impl Sphere {
     pub fn new(shape: &ShapeWithParams, sphere: &scene_description::Sphere) -> Self {
-        // TOOD: maybe I should really create a Transforms class...
-        let center = shape.object_to_world.col(3).truncate();
-        let radius = sphere.radius;
-        let area = Self::area_calc(radius);
-
-        Self {
-            center,
-            radius,
-            area,
-            bh_index: 0,
-        }
+        let mut s = Self::from_transform(shape.object_to_world, sphere.radius);
+        s.reverse_normals = shape.reverse_normals ^ shape.transform_swaps_handedness();
+        s
     }
 
     pub fn new_mock(origin: Vec3, radius: f32) -> Self {
-        Sphere {
-            center: origin,
+        Self::from_transform(Mat4::from_translation(origin), radius)
+    }
+
+    pub fn from_transform(object_to_world: Mat4, radius: f32) -> Self {
+        let world_to_object = object_to_world.inverse();
+
+        let mut s = Self {
             radius,
-            area: Self::area_calc(radius),
+            object_to_world,
+            world_to_object,
+            normal_to_world: Mat3::from_mat4(world_to_object).transpose(),
+            area: 0.,
+            max_stretch: 0.,
+            world_radius: Self::world_radius(object_to_world, radius),
+            reverse_normals: false,
             bh_index: 0,
-        }
+        };
+
+        (s.area, s.max_stretch) = s.area_calc();
+        s
     }
 
     pub fn hit(&self, ray: &Ray) -> Option<ShapeHitInfo> {
-        let oo = ray.orig - self.center;
+        stats::count_tests(TestKind::Sphere, 1);
+        let eps = 0.0000001;
+
+        // The direction isn't normalized, so that t stays the same in both spaces
+        let orig = self.world_to_object.transform_point3(ray.orig);
+        let dir = self.world_to_object.transform_vector3(ray.dir);
+
         // PBRT always uses f64 for precision here
-        let a = ray.dir.length_squared() as f64;
+        let a = dir.length_squared() as f64;
         // b = 2h -> quadratic formula can be simplified
-        let half_b = ray.dir.dot(oo) as f64;
-        let c = (oo.length_squared() - sqr(self.radius)) as f64;
+        let half_b = dir.dot(orig) as f64;
+        let c = (orig.length_squared() - sqr(self.radius)) as f64;
 
         let discriminant = sqr(half_b) - a * c;
-        let t = if discriminant < 0. {
+        if discriminant < 0. {
             return None;
+        }
+
+        // The ray origin can be inside of the sphere
+        let t0 = (-half_b - discriminant.sqrt()) / a;
+        let t1 = (-half_b + discriminant.sqrt()) / a;
+        let t = if t0 > eps {
+            t0 as f32
+        } else if t1 > eps {
+            t1 as f32
         } else {
-            let t0 = (-half_b + discriminant.sqrt()) / a;
-            let t1 = (-half_b - discriminant.sqrt()) / a;
-            t0.min(t1)
+            return None;
         };
 
-        let pos = ray.orig + ray.dir * t as f32;
-        let normal = (pos - self.center).normalize();
+        let obj_pos = orig + dir * t;
+        let normal = self.orient_normal(self.normal_to_world.mul_vec3(obj_pos).normalize());
+        let pos = ray.orig + ray.dir * t;
         // TODO: sphere UVs
 
-        Some(ShapeHitInfo::new(pos, normal, t as f32, None))
+        Some(ShapeHitInfo::new(pos, normal, t, None))
+    }
+
+    /// Samples a point uniformly by area.
+    /// On ellipsoids, points are rejected based on how much the transform stretches the surface.
+    pub fn sample_point(&self, rng: &mut Sampler) -> ShapeSample {
+        let obj_normal = loop {
+            let dir = sampling::sample_uniform_sphere(rng);
+            if rng.gen::<f32>() * self.max_stretch <= self.area_stretch(dir) {
+                break dir;
+            }
+        };
+
+        let pos = self
+            .object_to_world
+            .transform_point3(self.radius * obj_normal);
+        let normal = self.normal_to_world.mul_vec3(obj_normal).normalize();
+        ShapeSample::new(pos, self.orient_normal(normal))
+    }
+
+    /// Samples the cone of directions in which the sphere is visible from p_ref, taken from
+    /// PBRTv4 - Sphere::Sample. Returns the sample and its solid-angle PDF.
+    /// Returns None for ellipsoids and when p_ref is inside, area sampling has to be used then.
+    pub fn sample_point_solid_angle(
+        &self,
+        p_ref: Vec3,
+        rng: &mut Sampler,
+    ) -> Option<(ShapeSample, f32)> {
+        let (center, radius, cos_theta_max) = self.visible_cone(p_ref)?;
+
+        let to_center = center - p_ref;
+        let (axis, b1, b2) = vecmath::coordinate_system(to_center.normalize());
+        let local = sampling::sample_uniform_cone(cos_theta_max, rng);
+        let dir = b1 * local.x + b2 * local.y + axis * local.z;
+
+        // Closest intersection of the sampled direction and the sphere
+        let t_closest = dir.dot(to_center);
+        let half_chord =
+            math::safe_sqrt((sqr(radius) - (to_center - dir * t_closest).length_squared()).max(0.));
+        let pos = p_ref + dir * (t_closest - half_chord);
+
+        let normal = self.orient_normal((pos - center).normalize());
+        let pdf = sampling::pdf_uniform_cone(cos_theta_max);
+        Some((ShapeSample::new(pos, normal), pdf))
+    }
+
+    /// Solid-angle PDF of sample_point_solid_angle() for any visible point
+    pub fn pdf_solid_angle(&self, p_ref: Vec3) -> Option<f32> {
+        let (_, _, cos_theta_max) = self.visible_cone(p_ref)?;
+        Some(sampling::pdf_uniform_cone(cos_theta_max))
+    }
+
+    /// World-space center, radius and the cosine of the half-angle of the cone around p_ref
+    /// containing the sphere
+    fn visible_cone(&self, p_ref: Vec3) -> Option<(Vec3, f32, f32)> {
+        let radius = self.world_radius?;
+        let center = self.object_to_world.col(3).truncate();
+
+        let dist_sq = center.distance_squared(p_ref);
+        if dist_sq <= sqr(radius) {
+            return None;
+        }
+
+        let sin2_theta_max = sqr(radius) / dist_sq;
+        let cos_theta_max = math::safe_sqrt(1. - sin2_theta_max);
+        Some((center, radius, cos_theta_max))
+    }
+
+    /// Transforms with the same scale along every axis keep the sphere a sphere
+    fn world_radius(object_to_world: Mat4, radius: f32) -> Option<f32> {
+        let linear = Mat3::from_mat4(object_to_world);
+        let scale = linear.x_axis.length();
+        let eps = 0.0001 * scale;
+
+        let uniform = (linear.y_axis.length() - scale).abs() < eps
+            && (linear.z_axis.length() - scale).abs() < eps
+            && linear.x_axis.dot(linear.y_axis).abs() < eps * scale
+            && linear.x_axis.dot(linear.z_axis).abs() < eps * scale
+            && linear.y_axis.dot(linear.z_axis).abs() < eps * scale;
+
+        uniform.then_some(radius * scale)
     }
 
-    pub fn sample_point(&self, rng: &mut SmallRng) -> ShapeSample {
-        let sample_dir = sampling::sample_uniform_sphere(rng);
-        let pos = self.center + self.radius * sample_dir;
-        ShapeSample::new(pos, sample_dir)
+    fn orient_normal(&self, outward_normal: Vec3) -> Vec3 {
+        if self.reverse_normals {
+            -outward_normal
+        } else {
+            outward_normal
+        }
     }
 
     pub fn aabb(&self) -> AABB {
-        let a = self.center - self.radius;
-        let b = self.center + self.radius;
-        AABB::new(a, b)
+        let center = self.object_to_world.col(3).truncate();
+        // The extent of an ellipsoid along a world axis is the length of the matrix row
+        let linear = Mat3::from_mat4(self.object_to_world);
+        let extent = self.radius
+            * Vec3::new(
+                linear.row(0).length(),
+                linear.row(1).length(),
+                linear.row(2).length(),
+            );
+
+        AABB::new(center - extent, center + extent)
     }
 
     pub fn area(&self) -> f32 {
         self.area
     }
 
-    fn area_calc(radius: f32) -> f32 {
-        4. * PI * sqr(radius)
+    /// Ratio of the world-space and object-space surface area at the object-space normal
+    fn area_stretch(&self, obj_normal: Vec3) -> f32 {
+        let det = Mat3::from_mat4(self.object_to_world).determinant().abs();
+        det * self.normal_to_world.mul_vec3(obj_normal).length()
+    }
+
+    /// Returns the area and the maximum area stretch.
+    /// Ellipsoids don't have a closed-form area, so the stretch is integrated over an equal-area
+    /// grid of the unit sphere. The result is exact for spheres, where the stretch is constant.
+    fn area_calc(&self) -> (f32, f32) {
+        const Z_STEPS: usize = 64;
+        const PHI_STEPS: usize = 128;
+
+        let mut stretch_sum = 0.;
+        let mut max_stretch = 0f32;
+        for zi in 0..Z_STEPS {
+            let z = 1. - 2. * (zi as f32 + 0.5) / Z_STEPS as f32;
+            let r = (1. - sqr(z)).sqrt();
+            for phii in 0..PHI_STEPS {
+                let phi = 2. * PI * (phii as f32 + 0.5) / PHI_STEPS as f32;
+                let stretch = self.area_stretch(Vec3::new(r * phi.cos(), r * phi.sin(), z));
+                stretch_sum += stretch;
+                max_stretch = max_stretch.max(stretch);
+            }
+        }
+
+        let mean_stretch = stretch_sum / (Z_STEPS * PHI_STEPS) as f32;
+        (4. * PI * sqr(self.radius) * mean_stretch, max_stretch)
     }
 
     pub fn set_bh_node_index(&mut self, i: usize) {
@@ -101,7 +251,9 @@ impl Sphere {
 #[cfg(test)]
 mod test_super {
     use super::*;
-    use glam::vec3;
+    use glam::{vec3, Mat4};
+
+    use crate::{geometry::Shape, pbrt_loader::scene_description::Material, util::TaggedPtr};
 
     #[test]
     fn test_sphere_intersection() {
@@ -115,4 +267,168 @@ mod test_super {
         let hitinfo = sphere.hit(&ray_nohit);
         assert!(hitinfo.is_none());
     }
+
+    #[test]
+    fn test_sphere_transform() {
+        let translation = Mat4::from_translation(vec3(0., 0., 5.));
+
+        let scaled = Sphere::from_transform(translation * Mat4::from_scale(Vec3::splat(2.)), 1.);
+        let ray = Ray::new(Vec3::ZERO, Vec3::Z);
+        let hitinfo = scaled.hit(&ray).unwrap();
+        assert!((hitinfo.t - 3.).abs() < 0.0001);
+        assert!((hitinfo.normal - Vec3::NEG_Z).length() < 0.0001);
+        assert!((scaled.area() - 4. * PI * 4.).abs() < 0.01);
+        assert_eq!(
+            scaled.aabb(),
+            AABB::new(vec3(-2., -2., 3.), vec3(2., 2., 7.))
+        );
+
+        // The origin is inside of the sphere
+        let ray_inside = Ray::new(vec3(0., 0., 5.), Vec3::X);
+        assert!((scaled.hit(&ray_inside).unwrap().t - 2.).abs() < 0.0001);
+
+        // Ellipsoid with a rotated stretched axis
+        let rotation = Mat4::from_rotation_y(std::f32::consts::FRAC_PI_2);
+        let ellipsoid = Sphere::from_transform(
+            translation * rotation * Mat4::from_scale(vec3(3., 1., 1.)),
+            1.,
+        );
+        let hitinfo = ellipsoid.hit(&ray).unwrap();
+        assert!((hitinfo.t - 2.).abs() < 0.0001);
+        assert!((hitinfo.normal - Vec3::NEG_Z).length() < 0.0001);
+
+        let ray_side = Ray::new(vec3(-5., 0., 5.), Vec3::X);
+        assert!((ellipsoid.hit(&ray_side).unwrap().t - 4.).abs() < 0.0001);
+
+        let aabb = ellipsoid.aabb();
+        assert!((aabb.min - vec3(-1., -1., 2.)).length() < 0.0001);
+        assert!((aabb.max - vec3(1., 1., 8.)).length() < 0.0001);
+
+        // Known closed-form area of a prolate spheroid
+        let e = (1f32 - 1. / 9.).sqrt();
+        let spheroid_area = 2. * PI * (1. + 3. * e.asin() / e);
+        assert!((ellipsoid.area() - spheroid_area).abs() / spheroid_area < 0.001);
+
+        let mut rng = rand::SeedableRng::seed_from_u64(0);
+        for _ in 0..16 {
+            let sample = ellipsoid.sample_point(&mut rng);
+            let local = ellipsoid.world_to_object.transform_point3(sample.pos);
+            assert!((local.length() - 1.).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_sphere_reverse_orientation() {
+        let sphere_desc = || scene_description::Sphere::new(1.);
+        let translation = Mat4::from_translation(vec3(0., 0., 1.));
+        let new_sphere = |object_to_world, reverse_normals| {
+            let shape = ShapeWithParams::new(
+                scene_description::Shape::Sphere(sphere_desc()),
+                Material::new_empty(),
+                None,
+                object_to_world,
+                reverse_normals,
+                None,
+            );
+            Sphere::new(&shape, &sphere_desc())
+        };
+
+        let ray = Ray::new(vec3(0., 0., -1.), vec3(0., 0., 1.));
+
+        let sphere = new_sphere(translation, false);
+        assert_eq!(sphere.hit(&ray).unwrap().normal, vec3(0., 0., -1.));
+
+        // Mirroring flips the normals, the same as ReverseOrientation
+        let mirror = translation * Mat4::from_scale(vec3(-1., 1., 1.));
+        assert_eq!(new_sphere(mirror, false).hit(&ray).unwrap().normal, Vec3::Z);
+        assert_eq!(new_sphere(mirror, true).hit(&ray).unwrap().normal, -Vec3::Z);
+
+        let reversed = new_sphere(translation, true);
+        let hitinfo = reversed.hit(&ray).unwrap();
+        assert_eq!(hitinfo.t, 1.);
+        assert_eq!(hitinfo.normal, vec3(0., 0., 1.));
+
+        let mut rng = rand::SeedableRng::seed_from_u64(0);
+        let sample = reversed.sample_point(&mut rng);
+        assert!(sample.normal.dot(sample.pos - vec3(0., 0., 1.)) < 0.);
+    }
+
+    #[test]
+    fn test_sphere_solid_angle_sampling() {
+        let center = vec3(0., 0., 3.);
+        let sphere = Sphere::from_transform(
+            Mat4::from_translation(center)
+                * Mat4::from_rotation_x(0.3)
+                * Mat4::from_scale(Vec3::splat(2.)),
+            0.5,
+        );
+        let p_ref = Vec3::ZERO;
+        let mut rng = rand::SeedableRng::seed_from_u64(0);
+
+        // Irradiance at p_ref from a unit-radiance sphere, a disk with the same solid angle
+        let sin2_theta_max = 1. / 9.;
+        let expected = PI * sin2_theta_max;
+
+        let samples = 10_000;
+        let mut irradiance = 0.;
+        for _ in 0..samples {
+            let (sample, pdf) = sphere.sample_point_solid_angle(p_ref, &mut rng).unwrap();
+            assert!(((sample.pos - center).length() - 1.).abs() < 0.0001);
+            let dir = (sample.pos - p_ref).normalize();
+            // Only the visible side is sampled
+            assert!(sample.normal.dot(-dir) > -0.0001);
+            assert_eq!(pdf, sphere.pdf_solid_angle(p_ref).unwrap());
+
+            irradiance += dir.z / pdf;
+        }
+        irradiance /= samples as f32;
+        assert!((irradiance - expected).abs() < 0.01 * expected);
+
+        // Inside of the sphere and ellipsoids fall back to area sampling
+        assert!(sphere.sample_point_solid_angle(center, &mut rng).is_none());
+        let ellipsoid = Sphere::from_transform(Mat4::from_scale(vec3(3., 1., 1.)), 1.);
+        assert!(ellipsoid.pdf_solid_angle(vec3(0., 0., 5.)).is_none());
+    }
+
+    #[test]
+    fn test_sphere_sampling_from_inside() {
+        // A dome light around the scene, the normals point inwards to emit towards the inside
+        let shape = ShapeWithParams::new(
+            scene_description::Shape::Sphere(scene_description::Sphere::new(10.)),
+            Material::new_empty(),
+            None,
+            Mat4::from_translation(vec3(1., 2., 3.)),
+            true,
+            None,
+        );
+        let dome = TaggedPtr::new(Shape::Sphere(Box::new(Sphere::new(
+            &shape,
+            &scene_description::Sphere::new(10.),
+        ))));
+
+        let p_ref = vec3(4., -1., 5.);
+        let mut rng = rand::SeedableRng::seed_from_u64(0);
+        let samples = 20_000;
+        let dirs: Vec<(Vec3, f32)> = (0..samples)
+            .map(|_| {
+                let (sample, pdf) = dome.sample_point_from(p_ref, &mut rng);
+                assert!(pdf > 0. && pdf.is_finite(), "{pdf}");
+                assert!((pdf - dome.pdf_from(p_ref, &sample)).abs() < 1e-4 * pdf);
+                ((sample.pos - p_ref).normalize(), pdf)
+            })
+            .collect();
+
+        // Irradiance from a unit-radiance dome is π for every orientation of the receiver
+        for normal in [Vec3::X, -Vec3::X, Vec3::Y, -Vec3::Y, Vec3::Z, -Vec3::Z] {
+            let irradiance = dirs
+                .iter()
+                .map(|(dir, pdf)| normal.dot(*dir).max(0.) / pdf)
+                .sum::<f32>()
+                / samples as f32;
+            assert!(
+                (irradiance - PI).abs() < 0.03 * PI,
+                "{normal}: {irradiance}"
+            );
+        }
+    }
 }
@@ -2,13 +2,16 @@ use crate::{
     geometry::Ray,
     math::barycentric_interp,
     pbrt_loader::scene_description::{Material, TriMesh},
+    sampler::Sampler,
     sampling::sample_uniform_triangle,
     scene::ShapeSample,
+    stats::{self, TestKind},
+    texture::{AlphaMask, BumpMap},
+    vecmath::coordinate_system,
 };
 
-use glam::{Vec2, Vec3};
-use rand::rngs::SmallRng;
-use std::sync::Arc;
+use glam::{Mat3, Mat4, Vec2, Vec3};
+use std::{ops::Deref, sync::Arc};
 
 use super::{ShapeHitInfo, AABB};
 
@@ -21,38 +24,132 @@ pub struct TriHitInfo {
 pub struct TriangleMesh {
     material: Arc<Material>,
     reverse_normals: bool,
+    alpha: Option<Arc<AlphaMask>>,
+    bump_map: Option<Arc<BumpMap>>,
 
     indices: Box<[i32]>,
     pos: Box<[Vec3]>,
     normals: Option<Box<[Vec3]>>,
     uvs: Option<Box<[Vec2]>>,
     tangents: Option<Box<[Vec3]>>,
+    colors: Option<Box<[Vec3]>>,
+    /// Only present after precompute_triangles()
+    cache: Option<TriangleCache>,
+}
+
+/// Per-triangle data for intersection, so that rays don't have to gather the vertices.
+/// Stored as separate arrays indexed by the triangle id.
+struct TriangleCache {
+    p0: Box<[Vec3]>,
+    e1: Box<[Vec3]>,
+    e2: Box<[Vec3]>,
+    /// Not normalized
+    geometric_normals: Box<[Vec3]>,
 }
 
 impl TriangleMesh {
-    pub fn new(mesh: TriMesh, material: Arc<Material>, reverse_normals: bool) -> Self {
+    /// The vertices are transformed into world space, so triangles don't store the transform.
+    pub fn new(
+        mesh: TriMesh,
+        material: Arc<Material>,
+        object_to_world: Mat4,
+        reverse_normals: bool,
+        alpha: Option<Arc<AlphaMask>>,
+    ) -> Self {
         let TriMesh {
             indices,
             pos,
             normals,
             tangents,
             uvs,
+            colors,
         } = mesh;
 
-        // TODO: this should be in the loading code, don't know if it is
+        // Validated when loading, but attributes with a wrong length are dropped here as well, so
+        // that interpolating them can't index out of bounds
         debug_assert!(indices.len() % 3 == 0);
+        let vertex_count = pos.len();
+        let normals = normals.filter(|n| n.len() == vertex_count);
+        let tangents = tangents.filter(|t| t.len() == vertex_count);
+        let uvs = uvs.filter(|uv| uv.len() == vertex_count);
+        let colors = colors.filter(|c| c.len() == vertex_count);
+
+        let linear = Mat3::from_mat4(object_to_world);
+        // Inverse transpose for normals, tangents lie in the surface and use the transform itself
+        let normal_to_world = linear.inverse().transpose();
+        let pos = pos
+            .into_iter()
+            .map(|p| object_to_world.transform_point3(p))
+            .collect::<Vec<_>>();
+        let normals = normals.map(|normals| {
+            normals
+                .into_iter()
+                .map(|n| normal_to_world.mul_vec3(n).normalize())
+                .collect::<Vec<_>>()
+        });
+        let tangents = tangents.map(|tangents| {
+            tangents
+                .into_iter()
+                .map(|t| linear.mul_vec3(t).normalize())
+                .collect::<Vec<_>>()
+        });
+
+        // Geometric normals are cross products of the transformed edges, mirroring flips them.
+        // The vertex normals keep their orientation.
+        let swaps_handedness = linear.determinant() < 0.;
+        let reverse_normals = if normals.is_none() {
+            reverse_normals ^ swaps_handedness
+        } else {
+            reverse_normals
+        };
 
         Self {
             material,
             reverse_normals,
+            alpha,
+            bump_map: None,
             indices: indices.into_boxed_slice(),
             pos: pos.into_boxed_slice(),
             normals: normals.map(|n| n.into_boxed_slice()),
             uvs: uvs.map(|uv| uv.into_boxed_slice()),
             tangents: tangents.map(|t| t.into_boxed_slice()),
+            colors: colors.map(|c| c.into_boxed_slice()),
+            cache: None,
         }
     }
 
+    pub fn with_bump_map(mut self, bump_map: Option<Arc<BumpMap>>) -> Self {
+        self.bump_map = bump_map;
+        self
+    }
+
+    /// Stores the edges and geometric normals of all triangles.
+    /// Speeds up intersection at the cost of 48 bytes per triangle.
+    pub fn precompute_triangles(&mut self) {
+        let count = self.triangle_count();
+        let (mut p0, mut e1, mut e2) = (
+            Vec::with_capacity(count),
+            Vec::with_capacity(count),
+            Vec::with_capacity(count),
+        );
+
+        for indices in self.indices.chunks_exact(3) {
+            let [v0, v1, v2] = [0, 1, 2].map(|i| self.pos[indices[i] as usize]);
+            p0.push(v0);
+            e1.push(v1 - v0);
+            e2.push(v2 - v0);
+        }
+
+        let geometric_normals = e1.iter().zip(e2.iter()).map(|(e1, e2)| e1.cross(*e2));
+
+        self.cache = Some(TriangleCache {
+            geometric_normals: geometric_normals.collect(),
+            p0: p0.into_boxed_slice(),
+            e1: e1.into_boxed_slice(),
+            e2: e2.into_boxed_slice(),
+        });
+    }
+
     pub fn triangle_count(&self) -> usize {
         self.indices.len() / 3
     }
@@ -60,17 +157,26 @@ impl TriangleMesh {
     pub fn material(&self) -> Arc<Material> {
         self.material.clone()
     }
+
+    pub fn alpha(&self) -> Option<Arc<AlphaMask>> {
+        self.alpha.clone()
+    }
+
+    pub fn bump_map(&self) -> Option<Arc<BumpMap>> {
+        self.bump_map.clone()
+    }
 }
 
-pub struct Triangle {
+/// The mesh is either owned through an Arc, or borrowed by primitives that store only the indices
+pub struct Triangle<M = Arc<TriangleMesh>> {
     /// TODO: custom allocator for Arc https://github.com/rust-lang/rust/pull/89132
-    mesh: Arc<TriangleMesh>,
+    mesh: M,
     /// Triangle index in the TriMesh
     id: u64,
 }
 
-impl Triangle {
-    pub fn new(mesh: Arc<TriangleMesh>, id: u64) -> Self {
+impl<M: Deref<Target = TriangleMesh>> Triangle<M> {
+    pub fn new(mesh: M, id: u64) -> Self {
         Self { mesh, id }
     }
 
@@ -91,14 +197,35 @@ impl Triangle {
         (p0, p1, p2)
     }
 
+    /// Returns the first vertex and the edges from it to the other two
+    fn get_edges(&self) -> (Vec3, Vec3, Vec3) {
+        let id = self.id as usize;
+        match &self.mesh.cache {
+            Some(cache) => (cache.p0[id], cache.e1[id], cache.e2[id]),
+            None => {
+                let (p0, p1, p2) = self.get_positions();
+                (p0, p1 - p0, p2 - p0)
+            }
+        }
+    }
+
+    /// Not normalized
+    fn geometric_normal(&self) -> Vec3 {
+        match &self.mesh.cache {
+            Some(cache) => cache.geometric_normals[self.id as usize],
+            None => {
+                let (_, e1, e2) = self.get_edges();
+                e1.cross(e2)
+            }
+        }
+    }
+
     /// Möller-Trumbore algorithm
     pub fn intersect(&self, ray: &Ray) -> Option<ShapeHitInfo> {
+        stats::count_tests(TestKind::Triangle, 1);
         let eps = 0.0000001;
 
-        let (p0, p1, p2) = self.get_positions();
-
-        let e1 = p1 - p0;
-        let e2 = p2 - p0;
+        let (p0, e1, e2) = self.get_edges();
 
         let h = ray.dir.cross(e2);
         let a = e1.dot(h);
@@ -136,25 +263,22 @@ impl Triangle {
                 .as_ref()
                 .map(|uvs| barycentric_interp(&bar, &uvs[i0], &uvs[i1], &uvs[i2]));
 
-            let normal = self.get_normal(bar, (p0, p1, p2), (i0, i1, i2));
-            return Some(ShapeHitInfo::new(pos, normal, t, uv));
+            let normal = self.get_normal(bar, (i0, i1, i2));
+
+            return Some(ShapeHitInfo::new(pos, normal, t, uv).with_barycentrics(bar));
         }
 
         None
     }
 
-    pub fn get_normal(
-        &self,
-        bar: [f32; 3],
-        (p0, p1, p2): (Vec3, Vec3, Vec3),
-        (i0, i1, i2): (usize, usize, usize),
-    ) -> Vec3 {
+    /// Vertex normals that cancel out fall back to the geometric normal
+    pub fn get_normal(&self, bar: [f32; 3], (i0, i1, i2): (usize, usize, usize)) -> Vec3 {
         let normal = if let Some(n) = &self.mesh.normals {
             barycentric_interp(&bar, &n[i0], &n[i1], &n[i2])
+                .try_normalize()
+                .unwrap_or_else(|| self.geometric_normal())
         } else {
-            let v0 = p1 - p0;
-            let v1 = p2 - p0;
-            v0.cross(v1)
+            self.geometric_normal()
         };
 
         let normal = if self.mesh.reverse_normals {
@@ -166,26 +290,96 @@ impl Triangle {
         normal.normalize()
     }
 
-    pub fn sample_point(&self, rng: &mut SmallRng) -> ShapeSample {
+    /// The tangent isn't needed for finding the closest hit, so it's only computed for it
+    /// from the barycentric coordinates of the hit
+    pub fn tangent_at(&self, bar: [f32; 3], normal: Vec3) -> Vec3 {
+        self.get_tangent(bar, normal, self.get_positions(), self.get_indices())
+    }
+
+    /// Interpolated vertex color, computed only for the closest hit like the tangent
+    pub fn color_at(&self, bar: [f32; 3]) -> Option<Vec3> {
+        let (i0, i1, i2) = self.get_indices();
+        self.mesh
+            .colors
+            .as_ref()
+            .map(|c| barycentric_interp(&bar, &c[i0], &c[i1], &c[i2]))
+    }
+
+    /// Returns a normalized tangent orthogonal to the (normalized) shading normal.
+    /// Uses the vertex tangents if the mesh has them, otherwise the tangent is derived from
+    /// the UV gradient (dp/du). Without UVs an arbitrary tangent is chosen.
+    pub fn get_tangent(
+        &self,
+        bar: [f32; 3],
+        normal: Vec3,
+        (p0, p1, p2): (Vec3, Vec3, Vec3),
+        (i0, i1, i2): (usize, usize, usize),
+    ) -> Vec3 {
+        let tangent = if let Some(t) = &self.mesh.tangents {
+            Some(barycentric_interp(&bar, &t[i0], &t[i1], &t[i2]))
+        } else {
+            self.mesh
+                .uvs
+                .as_ref()
+                .and_then(|uvs| Self::dpduv((p0, p1, p2), (uvs[i0], uvs[i1], uvs[i2])))
+                .map(|[dpdu, _]| dpdu)
+        };
+
+        // Gram-Schmidt, the shading normal doesn't have to be perpendicular to dp/du
+        let tangent = tangent
+            .map(|t| (t - normal * normal.dot(t)).normalize())
+            .filter(|t| t.is_finite());
+
+        tangent.unwrap_or_else(|| coordinate_system(normal).1)
+    }
+
+    /// Derivatives of the position with respect to the UVs, constant over the triangle.
+    /// None if the mesh has no UVs or their mapping is degenerate.
+    pub fn uv_derivatives(&self) -> Option<[Vec3; 2]> {
+        let (i0, i1, i2) = self.get_indices();
+        let uvs = self.mesh.uvs.as_ref()?;
+        Self::dpduv(self.get_positions(), (uvs[i0], uvs[i1], uvs[i2]))
+    }
+
+    /// Solves the linear system of the triangle edges for dp/du and dp/dv.
+    /// Returns None if the UV mapping is degenerate.
+    fn dpduv(
+        (p0, p1, p2): (Vec3, Vec3, Vec3),
+        (uv0, uv1, uv2): (Vec2, Vec2, Vec2),
+    ) -> Option<[Vec3; 2]> {
+        let duv02 = uv0 - uv2;
+        let duv12 = uv1 - uv2;
+        let dp02 = p0 - p2;
+        let dp12 = p1 - p2;
+
+        let det = duv02.x * duv12.y - duv02.y * duv12.x;
+        if det.abs() < 1e-9 {
+            return None;
+        }
+
+        Some([
+            (duv12.y * dp02 - duv02.y * dp12) / det,
+            (duv02.x * dp12 - duv12.x * dp02) / det,
+        ])
+    }
+
+    pub fn sample_point(&self, rng: &mut Sampler) -> ShapeSample {
         let bar = sample_uniform_triangle(rng);
 
         let (p0, p1, p2) = self.get_positions();
         let (i0, i1, i2) = self.get_indices();
         let pos = barycentric_interp(&bar, &p0, &p1, &p2);
-        let normal = self.get_normal(bar, (p0, p1, p2), (i0, i1, i2));
+        let normal = self.get_normal(bar, (i0, i1, i2));
 
         ShapeSample::new(pos, normal)
     }
 
     pub fn area(&self) -> f32 {
-        let (p0, p1, p2) = self.get_positions();
-        let v0 = p1 - p0;
-        let v1 = p2 - p0;
-        v0.cross(v1).length() / 2.
+        self.geometric_normal().length() / 2.
     }
 
     pub fn mesh(&self) -> &TriangleMesh {
-        self.mesh.as_ref()
+        &self.mesh
     }
 
     pub fn aabb(&self) -> AABB {
@@ -196,3 +390,211 @@ impl Triangle {
         aabb
     }
 }
+
+#[cfg(test)]
+mod test_super {
+    use glam::vec2;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    fn textured_quad(uvs: Option<Vec<Vec2>>) -> Arc<TriangleMesh> {
+        let pos = vec![
+            Vec3::new(0., 0., 0.),
+            Vec3::new(1., 0., 0.),
+            Vec3::new(1., 1., 0.),
+            Vec3::new(0., 1., 0.),
+        ];
+        let mesh = TriMesh::new(vec![0, 1, 2, 0, 2, 3], pos, None, None, uvs);
+        Arc::new(TriangleMesh::new(
+            mesh,
+            Arc::new(Material::new_empty()),
+            Mat4::IDENTITY,
+            false,
+            None,
+        ))
+    }
+
+    fn hit_tangent(mesh: &Arc<TriangleMesh>, id: u64, target: Vec3) -> Vec3 {
+        let triangle = Triangle::new(mesh.clone(), id);
+        let ray = Ray::new(target + Vec3::Z, -Vec3::Z);
+        let hit = triangle.intersect(&ray).unwrap();
+        triangle.tangent_at(hit.barycentrics.unwrap(), hit.normal)
+    }
+
+    #[test]
+    fn test_uv_tangents() {
+        let uvs = vec![vec2(0., 0.), vec2(1., 0.), vec2(1., 1.), vec2(0., 1.)];
+        let mesh = textured_quad(Some(uvs));
+
+        for (id, target) in [(0, Vec3::new(0.7, 0.2, 0.)), (1, Vec3::new(0.2, 0.7, 0.))] {
+            let tangent = hit_tangent(&mesh, id, target);
+            assert!(tangent.abs_diff_eq(Vec3::X, 1e-5), "{tangent}");
+        }
+
+        // U runs along the Y axis, the tangent has to follow it
+        let uvs = vec![vec2(0., 0.), vec2(0., 1.), vec2(1., 1.), vec2(1., 0.)];
+        let mesh = textured_quad(Some(uvs));
+        let tangent = hit_tangent(&mesh, 0, Vec3::new(0.7, 0.2, 0.));
+        assert!(tangent.abs_diff_eq(Vec3::Y, 1e-5), "{tangent}");
+
+        // The derivatives keep the scale of the mapping
+        let uvs = vec![vec2(0., 0.), vec2(2., 0.), vec2(2., 4.), vec2(0., 4.)];
+        let mesh = textured_quad(Some(uvs));
+        for id in [0, 1] {
+            let [dpdu, dpdv] = Triangle::new(mesh.clone(), id).uv_derivatives().unwrap();
+            assert!(dpdu.abs_diff_eq(Vec3::X / 2., 1e-5), "{dpdu}");
+            assert!(dpdv.abs_diff_eq(Vec3::Y / 4., 1e-5), "{dpdv}");
+        }
+    }
+
+    #[test]
+    fn test_fallback_tangents() {
+        let mesh = textured_quad(None);
+        let tangent = hit_tangent(&mesh, 0, Vec3::new(0.7, 0.2, 0.));
+        assert!((tangent.length() - 1.).abs() < 1e-5);
+        assert!(tangent.dot(Vec3::Z).abs() < 1e-5);
+
+        // Degenerate UVs
+        let mesh = textured_quad(Some(vec![Vec2::ZERO; 4]));
+        let tangent = hit_tangent(&mesh, 0, Vec3::new(0.7, 0.2, 0.));
+        assert!(tangent.is_finite());
+        assert!(tangent.dot(Vec3::Z).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_opposite_vertex_normals() {
+        let pos = vec![
+            Vec3::new(0., 0., 0.),
+            Vec3::new(1., 0., 0.),
+            Vec3::new(1., 1., 0.),
+        ];
+        let normals = vec![Vec3::Z, -Vec3::Z, Vec3::Z];
+        let mesh = TriMesh::new(vec![0, 1, 2], pos, Some(normals), None, None);
+        let mesh = Arc::new(TriangleMesh::new(
+            mesh,
+            Arc::new(Material::new_empty()),
+            Mat4::IDENTITY,
+            false,
+            None,
+        ));
+
+        // The interpolated vertex normals are zero in the middle of the triangle
+        let triangle = Triangle::new(mesh.clone(), 0);
+        let normal = triangle.get_normal([0.25, 0.5, 0.25], (0, 1, 2));
+        assert!(normal.abs_diff_eq(Vec3::Z, 1e-5), "{normal}");
+
+        let ray = Ray::new(Vec3::new(0.75, 0.25, 1.), -Vec3::Z);
+        let hit = triangle.intersect(&ray).unwrap();
+        let tangent = triangle.tangent_at(hit.barycentrics.unwrap(), hit.normal);
+        assert!(hit.normal.is_finite() && tangent.is_finite());
+    }
+
+    #[test]
+    fn test_mesh_transform() {
+        let pos = vec![
+            Vec3::new(0., 0., 0.),
+            Vec3::new(1., 0., 0.),
+            Vec3::new(0., 1., 0.),
+        ];
+        let normals = vec![Vec3::new(0., 0., 1.); 3];
+        let tangents = vec![Vec3::new(1., 0., 0.); 3];
+        let transform =
+            Mat4::from_translation(Vec3::new(0., 0., 5.)) * Mat4::from_scale(Vec3::new(2., 1., 1.));
+
+        let new_mesh = |normals: Option<Vec<Vec3>>, transform: Mat4| {
+            let mesh = TriMesh::new(
+                vec![0, 1, 2],
+                pos.clone(),
+                normals,
+                Some(tangents.clone()),
+                None,
+            );
+            let material = Arc::new(Material::new_empty());
+            Arc::new(TriangleMesh::new(mesh, material, transform, false, None))
+        };
+
+        let mesh = new_mesh(Some(normals.clone()), transform);
+        let triangle = Triangle::new(mesh.clone(), 0);
+        assert_eq!(
+            triangle.get_positions(),
+            (
+                Vec3::new(0., 0., 5.),
+                Vec3::new(2., 0., 5.),
+                Vec3::new(0., 1., 5.)
+            )
+        );
+
+        let hitinfo = triangle
+            .intersect(&Ray::new(Vec3::new(1.5, 0.2, 0.), Vec3::Z))
+            .unwrap();
+        assert_eq!(hitinfo.t, 5.);
+        assert_eq!(hitinfo.normal, Vec3::Z);
+        let tangent = triangle.tangent_at(hitinfo.barycentrics.unwrap(), hitinfo.normal);
+        assert_eq!(tangent, Vec3::X);
+
+        // The untransformed position isn't hit
+        let miss = Ray::new(Vec3::new(0.2, 0.2, -1.), Vec3::X);
+        assert!(triangle.intersect(&miss).is_none());
+
+        // Mirroring keeps the geometric normal's orientation
+        let mirror = Mat4::from_scale(Vec3::new(-1., 1., 1.));
+        let mesh = new_mesh(None, mirror);
+        let ray = Ray::new(Vec3::new(-0.2, 0.2, 1.), -Vec3::Z);
+        let hitinfo = Triangle::new(mesh, 0).intersect(&ray).unwrap();
+        assert_eq!(hitinfo.normal, Vec3::Z);
+    }
+
+    #[test]
+    fn test_cached_triangles() {
+        let mut rng = Sampler::seed_from_u64(0);
+        let mut rand_vec = |scale: f32| {
+            Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 2. * scale - Vec3::splat(scale)
+        };
+        let pos: Vec<Vec3> = (0..30).map(|_| rand_vec(2.)).collect();
+        let uvs: Vec<Vec2> = pos.iter().map(|p| p.truncate()).collect();
+        let origins: Vec<Vec3> = (0..1000).map(|_| rand_vec(4.)).collect();
+        let transform = Mat4::from_rotation_x(0.3) * Mat4::from_scale(Vec3::new(2., 1., 0.5));
+
+        let new_mesh = || {
+            let mesh = TriMesh::new(
+                (0..30).collect(),
+                pos.clone(),
+                None,
+                None,
+                Some(uvs.clone()),
+            );
+            let material = Arc::new(Material::new_empty());
+            TriangleMesh::new(mesh, material, transform, false, None)
+        };
+
+        let uncached = Arc::new(new_mesh());
+        let mut cached = new_mesh();
+        cached.precompute_triangles();
+        let cached = Arc::new(cached);
+
+        for id in 0..10 {
+            let uncached = Triangle::new(uncached.clone(), id);
+            let cached = Triangle::new(cached.clone(), id);
+            assert_eq!(uncached.area(), cached.area());
+
+            for orig in &origins[id as usize * 100..(id as usize + 1) * 100] {
+                let orig = *orig;
+                let target = uncached.sample_point(&mut rng).pos;
+                let ray = Ray::new(orig, target - orig);
+
+                match (uncached.intersect(&ray), cached.intersect(&ray)) {
+                    (Some(a), Some(b)) => {
+                        assert_eq!(a.t, b.t);
+                        assert_eq!(a.pos, b.pos);
+                        assert_eq!(a.normal, b.normal);
+                        assert_eq!(a.uv, b.uv);
+                        assert_eq!(a.barycentrics, b.barycentrics);
+                    }
+                    (None, None) => (),
+                    _ => panic!("The cached triangle reports a different hit"),
+                }
+            }
+        }
+    }
+}
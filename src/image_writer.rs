@@ -1,48 +1,740 @@
-use crate::{film, pbrt_loader::scene_description};
-use eyre::Result;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    color::color_space::ColorSpace, film, pbrt_loader::scene_description, tonemap::Tonemapper,
+};
+use eyre::{eyre, Result};
+use glam::Vec3;
+
+/// Name of the EXR attribute that records how many samples per pixel were taken
+pub const SAMPLES_ATTRIBUTE: &str = "samples";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// Linear radiance
+    Exr,
+    /// Tonemapped 8-bit sRGB, looks the same as the preview window
+    Png,
+}
+
+impl ImageFormat {
+    /// Picks the format based on the file extension
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("exr") => Ok(Self::Exr),
+            Some("png") => Ok(Self::Png),
+            _ => Err(eyre!(
+                "Unknown output image format: '{}', expected .exr or .png",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// Layers of the EXR image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExrLayer {
+    /// The film
+    Rgb,
+    /// Primary-ray hit distances, needs the depth pass
+    Depth,
+    /// Variance of the pixels' luminance, needs the variance pass
+    Variance,
+}
+
+impl ExrLayer {
+    pub fn new(kind: &str) -> Result<Self> {
+        Ok(match kind {
+            "rgb" => Self::Rgb,
+            "depth" => Self::Depth,
+            "variance" => Self::Variance,
+            _ => return Err(eyre!("Unknown EXR layer: '{}'", kind)),
+        })
+    }
+
+    /// Comma-separated list, e.g. "rgb,depth"
+    pub fn new_list(list: &str) -> Result<Vec<Self>> {
+        let layers = list
+            .split(',')
+            .map(|layer| Self::new(layer.trim()))
+            .collect::<Result<Vec<_>>>()?;
+
+        for (i, layer) in layers.iter().enumerate() {
+            if layers[..i].contains(layer) {
+                return Err(eyre!("Duplicate EXR layer: '{:?}'", layer));
+            }
+        }
+
+        Ok(layers)
+    }
+}
+
+type ExrLayerData = exr::prelude::Layer<exr::prelude::AnyChannels<exr::prelude::FlatSamples>>;
 
 pub struct ImageWriter {
-    filepath: String,
+    filepath: PathBuf,
+    depth_filepath: PathBuf,
+    variance_filepath: PathBuf,
+    format: ImageFormat,
     width: u64,
     height: u64,
+    scale: f32,
+    max_component_value: f32,
+    /// Write half-precision EXR, full 32-bit floats are written otherwise
+    save_fp16: bool,
+    /// Write 16 bits per channel PNG, 8 bits are written otherwise
+    png_16bit: bool,
+    /// Layers of the EXR image, in this order
+    exr_layers: Vec<ExrLayer>,
+    tonemapper: Tonemapper,
 }
 
 impl ImageWriter {
     pub fn new(film: &scene_description::Film) -> Self {
         Self {
-            filepath: film.filename.clone(),
+            filepath: PathBuf::from(format!("{}.exr", film.filename)),
+            depth_filepath: PathBuf::from(format!("{}-depth.exr", film.filename)),
+            variance_filepath: PathBuf::from(format!("{}-variance.exr", film.filename)),
+            format: ImageFormat::Exr,
             width: film.xresolution as u64,
             height: film.yresolution as u64,
+            scale: film.scale,
+            max_component_value: film.max_component_value,
+            save_fp16: film.save_fp16,
+            png_16bit: false,
+            exr_layers: vec![ExrLayer::Rgb],
+            tonemapper: Tonemapper::default(),
         }
     }
 
+    /// Writes to the path instead of the film's filename, the format is chosen by the extension.
+    /// The depth and variance passes that aren't EXR layers are written next to it.
+    pub fn with_output(mut self, path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        self.format = ImageFormat::from_path(&path)?;
+
+        let stem = path
+            .file_stem()
+            .ok_or_else(|| eyre!("Output path '{}' has no file name", path.display()))?
+            .to_string_lossy();
+        self.depth_filepath = path.with_file_name(format!("{stem}-depth.exr"));
+        self.variance_filepath = path.with_file_name(format!("{stem}-variance.exr"));
+        self.filepath = path;
+
+        Ok(self)
+    }
+
+    /// PNG is written with 16 bits per channel, the extra precision is useful for further grading
+    pub fn with_png_16bit(mut self) -> Self {
+        self.png_16bit = true;
+        self
+    }
+
+    /// Selects the layers of the EXR image, enabled passes that aren't selected are written
+    /// into their own files
+    pub fn with_exr_layers(mut self, layers: Vec<ExrLayer>) -> Self {
+        self.exr_layers = layers;
+        self
+    }
+
+    /// Used for the tonemapped formats
+    pub fn with_tonemapper(mut self, tonemapper: Tonemapper) -> Self {
+        self.tonemapper = tonemapper;
+        self
+    }
+
+    pub fn filepath(&self) -> &Path {
+        &self.filepath
+    }
+
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// Fails if the image (or the depth and variance passes when enabled) already exists.
+    /// Meant to be checked before a render starts, the render itself rewrites its own outputs.
+    pub fn check_overwrite(&self, with_depth: bool, with_variance: bool) -> Result<()> {
+        let paths = [
+            Some(&self.filepath),
+            (with_depth && self.separate_pass(ExrLayer::Depth)).then_some(&self.depth_filepath),
+            (with_variance && self.separate_pass(ExrLayer::Variance))
+                .then_some(&self.variance_filepath),
+        ];
+        for path in paths.into_iter().flatten() {
+            if path.exists() {
+                return Err(eyre!(
+                    "Output file '{}' already exists, use --force to overwrite it",
+                    path.display()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// With auto exposure, meters the tonemapper on the film, the film scale is taken into
+    /// account. Has to be called before the film is displayed or written.
+    pub fn meter_exposure(&mut self, film: &film::Film) {
+        self.tonemapper.meter(&film.luminance_stats(self.scale));
+    }
+
+    /// Returns the pixel estimate with the film scale and the component clamp applied.
+    /// Y = 0 is at the top, same as in the Film.
+    pub fn pixel_rgb(&self, film: &film::Film, x: usize, y: usize) -> Vec3 {
+        let rgb = film.get_rgb(x, y) * self.scale;
+
+        // Scale the whole pixel down instead of clamping components separately to preserve the hue
+        let max = rgb.max_element();
+        if max > self.max_component_value {
+            rgb * (self.max_component_value / max)
+        } else {
+            rgb
+        }
+    }
+
+    /// Returns the tonemapped and gamma corrected pixel in [0, 1].
+    /// Displays and PNG viewers assume sRGB, so wide-gamut films are converted to it first and
+    /// the colors outside of sRGB are clamped.
+    pub fn display_rgb(&self, film: &film::Film, x: usize, y: usize) -> Vec3 {
+        let rgb = match film.color_space() {
+            ColorSpace::Srgb => self.pixel_rgb(film, x, y),
+            color_space => {
+                ColorSpace::Srgb.from_xyz(color_space.to_xyz(self.pixel_rgb(film, x, y)))
+            }
+        };
+        let c = self.tonemapper.tonemap(rgb);
+
+        const GAMMA: f32 = 2.2;
+        c.powf(1. / GAMMA)
+    }
+
+    /// Only writes the film, see `write_passes()`.
+    /// The number of samples per pixel is stored in the EXR attributes, PNG can't record it
     pub fn write_film(&self, film: &film::Film, samples: u32) -> Result<()> {
+        match self.format {
+            ImageFormat::Exr => self.write_film_exr(film, samples),
+            ImageFormat::Png => self.write_film_png(film),
+        }
+    }
+
+    fn write_film_exr(&self, film: &film::Film, samples: u32) -> Result<()> {
+        let layers = vec![self.rgb_layer(film, samples)];
+        self.write_exr(&self.filepath, film.color_space(), layers)
+    }
+
+    fn write_film_png(&self, film: &film::Film) -> Result<()> {
+        let display_rgb = |x: u32, y: u32| {
+            let y = self.height as usize - y as usize - 1;
+            self.display_rgb(film, x as usize, y).to_array()
+        };
+
+        let (width, height) = (self.width as u32, self.height as u32);
+        if self.png_16bit {
+            let image = image::ImageBuffer::from_fn(width, height, |x, y| {
+                image::Rgb(display_rgb(x, y).map(|f| (f * 65535.) as u16))
+            });
+            image.save_with_format(&self.filepath, image::ImageFormat::Png)?;
+        } else {
+            let image = image::RgbImage::from_fn(width, height, |x, y| {
+                image::Rgb(display_rgb(x, y).map(|f| (f * 255.) as u8))
+            });
+            image.save_with_format(&self.filepath, image::ImageFormat::Png)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the film and the enabled depth and variance passes. With EXR output, the passes
+    /// selected by `with_exr_layers()` are layers of the image, the rest is written next to it.
+    pub fn write_passes(
+        &self,
+        film: &film::Film,
+        depth: Option<&film::DepthFilm>,
+        variance: bool,
+        samples: u32,
+    ) -> Result<()> {
+        match self.format {
+            ImageFormat::Exr => {
+                let mut layers = Vec::new();
+                for layer in &self.exr_layers {
+                    layers.push(match layer {
+                        ExrLayer::Rgb => self.rgb_layer(film, samples),
+                        ExrLayer::Depth => self.depth_layer(
+                            depth.ok_or_else(|| eyre!("The depth layer needs the depth pass"))?,
+                        ),
+                        ExrLayer::Variance if variance => self.variance_layer(film),
+                        ExrLayer::Variance => {
+                            return Err(eyre!("The variance layer needs the variance pass"))
+                        }
+                    });
+                }
+                self.write_exr(&self.filepath, film.color_space(), layers)?;
+            }
+            ImageFormat::Png => self.write_film_png(film)?,
+        }
+
+        if let Some(depth) = depth.filter(|_| self.separate_pass(ExrLayer::Depth)) {
+            let layers = vec![self.depth_layer(depth)];
+            self.write_exr(&self.depth_filepath, film.color_space(), layers)?;
+        }
+        if variance && self.separate_pass(ExrLayer::Variance) {
+            let layers = vec![self.variance_layer(film)];
+            self.write_exr(&self.variance_filepath, film.color_space(), layers)?;
+        }
+
+        Ok(())
+    }
+
+    /// Passes that aren't layers of the image are written into their own files
+    fn separate_pass(&self, layer: ExrLayer) -> bool {
+        self.format != ImageFormat::Exr || !self.exr_layers.contains(&layer)
+    }
+
+    /// Collects a channel of all pixels, the EXR rows go from the top. Y = 0 is at the bottom
+    /// of the film.
+    fn channel<T>(&self, get_pixel: impl Fn(usize, usize) -> T) -> Vec<T> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, height - y - 1)))
+            .map(|(x, y)| get_pixel(x, y))
+            .collect()
+    }
+
+    fn rgb_layer(&self, film: &film::Film, samples: u32) -> ExrLayerData {
         use exr::prelude::*;
 
-        let get_pixel = |pos: exr::math::Vec2<usize>| {
-            let mut rgb = film.get_rgb(pos.x(), self.height as usize - pos.y() - 1);
-            rgb /= samples as f32;
-            (
-                f16::from_f32(rgb.x),
-                f16::from_f32(rgb.y),
-                f16::from_f32(rgb.z),
-            )
+        let pixels = self.channel(|x, y| self.pixel_rgb(film, x, y));
+        let channel = |name: &str, c: usize| {
+            let values = pixels.iter().map(|rgb| rgb[c]);
+            let samples = if self.save_fp16 {
+                FlatSamples::F16(values.map(f16::from_f32).collect())
+            } else {
+                FlatSamples::F32(values.collect())
+            };
+            AnyChannel::new(name, samples)
         };
+        let channels = AnyChannels::sort(smallvec::smallvec![
+            channel("R", 0),
+            channel("G", 1),
+            channel("B", 2),
+        ]);
 
-        let channels = SpecificChannels::rgb(get_pixel);
+        let mut attributes = LayerAttributes::named("main-layer");
+        attributes.other.insert(
+            Text::from(SAMPLES_ATTRIBUTE),
+            AttributeValue::I32(samples as i32),
+        );
+
+        self.layer(attributes, channels)
+    }
 
-        let image = Image::from_layer(Layer::new(
+    /// Primary-ray hit distances in the Z channel
+    fn depth_layer(&self, depth: &film::DepthFilm) -> ExrLayerData {
+        use exr::prelude::*;
+
+        let values = self.channel(|x, y| depth.get(x, y));
+        let channels = AnyChannels::sort(smallvec::smallvec![AnyChannel::new(
+            "Z",
+            FlatSamples::F32(values)
+        )]);
+
+        self.layer(LayerAttributes::named("depth"), channels)
+    }
+
+    /// Variance of the pixels' luminance in the Y channel.
+    /// The film scale is applied, so the values match the luminance of the written image.
+    fn variance_layer(&self, film: &film::Film) -> ExrLayerData {
+        use exr::prelude::*;
+
+        let scale_sq = (self.scale * self.scale) as f64;
+        let values =
+            self.channel(|x, y| (film.variance(x, y).variance_of_mean() * scale_sq) as f32);
+        let channels = AnyChannels::sort(smallvec::smallvec![AnyChannel::new(
+            "Y",
+            FlatSamples::F32(values)
+        )]);
+
+        self.layer(LayerAttributes::named("variance"), channels)
+    }
+
+    fn layer(
+        &self,
+        attributes: exr::prelude::LayerAttributes,
+        channels: exr::prelude::AnyChannels<exr::prelude::FlatSamples>,
+    ) -> ExrLayerData {
+        exr::prelude::Layer::new(
             (self.width as usize, self.height as usize),
-            LayerAttributes::named("main-layer"),
-            Encoding::FAST_LOSSLESS,
+            attributes,
+            exr::prelude::Encoding::FAST_LOSSLESS,
             channels,
-        ));
+        )
+    }
+
+    fn write_exr(
+        &self,
+        filepath: &Path,
+        color_space: ColorSpace,
+        layers: Vec<ExrLayerData>,
+    ) -> Result<()> {
+        use exr::{meta::attribute::Chromaticities, prelude::*};
 
-        let mut filepath = self.filepath.clone();
-        filepath.push_str(".exr");
+        let bounds = IntegerBounds::from_dimensions((self.width as usize, self.height as usize));
+        let mut image = Image::from_layers(ImageAttributes::new(bounds), layers);
 
-        image.write().to_file(&filepath)?;
+        // Readers assume sRGB (Rec. 709) primaries when the attribute is missing
+        let [red, green, blue, white] = color_space.chromaticities().map(|c| Vec2(c.x, c.y));
+        image.attributes.chromaticities = Some(Chromaticities {
+            red,
+            green,
+            blue,
+            white,
+        });
+
+        image.write().to_file(filepath)?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test_super {
+    use glam::DVec3;
+
+    use crate::util::test_dir;
+
+    use super::*;
+
+    #[test]
+    fn test_film_scale_and_clamp() {
+        let film = film::Film::new(2, 1, ColorSpace::Srgb);
+        unsafe {
+            film.set(0, 0, DVec3::new(0.2, 0.3, 0.1));
+            film.set(1, 0, DVec3::new(2., 8., 4.));
+        }
+
+        let unscaled = ImageWriter::new(&scene_description::Film::default());
+        let scaled = ImageWriter::new(&scene_description::Film {
+            scale: 2.,
+            ..Default::default()
+        });
+
+        let rgb = unscaled.pixel_rgb(&film, 0, 0);
+        let rgb_scaled = scaled.pixel_rgb(&film, 0, 0);
+        assert!((rgb_scaled - rgb * 2.).abs().max_element() < 1e-6);
+
+        let clamped = ImageWriter::new(&scene_description::Film {
+            scale: 2.,
+            max_component_value: 1.,
+            ..Default::default()
+        });
+
+        let rgb_scaled = scaled.pixel_rgb(&film, 1, 0);
+        let rgb_clamped = clamped.pixel_rgb(&film, 1, 0);
+        assert!(rgb_scaled.max_element() > 1.);
+        assert!((rgb_clamped.max_element() - 1.).abs() < 1e-6);
+        // The hue is preserved
+        let max = rgb_scaled.max_element();
+        assert!((rgb_clamped * max - rgb_scaled).abs().max_element() < 1e-5 * max);
+    }
+
+    fn read_exr(path: &str) -> Vec<Vec3> {
+        exr::prelude::read_first_rgba_layer_from_file(
+            path,
+            |resolution, _| vec![Vec3::ZERO; resolution.width() * resolution.height()],
+            |buffer, position, (r, g, b, _): (f32, f32, f32, f32)| {
+                buffer[position.y() * 2 + position.x()] = Vec3::new(r, g, b);
+            },
+        )
+        .unwrap()
+        .layer_data
+        .channel_data
+        .pixels
+    }
+
+    #[test]
+    fn test_exr_precision() {
+        let film = film::Film::new(2, 1, ColorSpace::Srgb);
+        unsafe {
+            film.set(0, 0, DVec3::new(0.123456, 0.345678, 0.234567));
+            film.set(1, 0, DVec3::new(12.3456, 34.5678, 23.4567));
+        }
+
+        let dir = test_dir("precision");
+        let write = |save_fp16: bool| {
+            let filename = dir.join(format!("precision-{save_fp16}"));
+            let filename = filename.to_str().unwrap().to_string();
+            let writer = ImageWriter::new(&scene_description::Film {
+                xresolution: 2,
+                yresolution: 1,
+                filename: filename.clone(),
+                save_fp16,
+                ..Default::default()
+            });
+            writer.write_film(&film, 1).unwrap();
+
+            let pixels = read_exr(&format!("{filename}.exr"));
+            let source = [writer.pixel_rgb(&film, 0, 0), writer.pixel_rgb(&film, 1, 0)];
+            pixels
+                .iter()
+                .zip(source.iter())
+                .map(|(p, s)| (*p - *s).abs().max_element())
+                .fold(0f32, f32::max)
+        };
+
+        let error_fp16 = write(true);
+        let error_fp32 = write(false);
+        assert!(error_fp16 > 0.);
+        assert!(error_fp32 < error_fp16);
+        assert_eq!(error_fp32, 0.);
+    }
+
+    #[test]
+    fn test_exr_samples_attribute() {
+        let film = film::Film::new(2, 1, ColorSpace::Srgb);
+
+        let dir = test_dir("samples");
+        for save_fp16 in [true, false] {
+            let output = dir.join(format!("samples-{save_fp16}.exr"));
+            let writer = ImageWriter::new(&scene_description::Film {
+                xresolution: 2,
+                yresolution: 1,
+                save_fp16,
+                ..Default::default()
+            })
+            .with_output(&output)
+            .unwrap();
+            writer.write_film(&film, 37).unwrap();
+
+            let meta = exr::meta::MetaData::read_from_file(&output, false).unwrap();
+            let samples = &meta.headers[0].own_attributes.other
+                [&exr::meta::attribute::Text::from(SAMPLES_ATTRIBUTE)];
+            assert_eq!(samples.to_i32().unwrap(), 37);
+        }
+    }
+
+    #[test]
+    fn test_exr_chromaticities() {
+        let dir = test_dir("chromaticities");
+
+        for color_space in [ColorSpace::Srgb, ColorSpace::Rec2020] {
+            let film = film::Film::new(2, 1, color_space);
+            let output = dir.join(format!("{color_space:?}.exr"));
+            let writer = ImageWriter::new(&scene_description::Film {
+                xresolution: 2,
+                yresolution: 1,
+                ..Default::default()
+            })
+            .with_output(&output)
+            .unwrap();
+            writer.write_film(&film, 1).unwrap();
+
+            let meta = exr::meta::MetaData::read_from_file(&output, false).unwrap();
+            let chromaticities = meta.headers[0].shared_attributes.chromaticities.unwrap();
+            let [red, _, _, white] = color_space.chromaticities();
+            assert_eq!(chromaticities.red, exr::math::Vec2(red.x, red.y));
+            assert_eq!(chromaticities.white, exr::math::Vec2(white.x, white.y));
+        }
+    }
+
+    #[test]
+    fn test_output_override() {
+        let film = film::Film::new(2, 1, ColorSpace::Srgb);
+        unsafe {
+            film.set(0, 0, DVec3::new(0.2, 0.3, 0.1));
+        }
+
+        let dir = test_dir("output");
+        let scene_filename = dir.join("scene-name");
+        let output = dir.join("foo.exr");
+
+        let writer = ImageWriter::new(&scene_description::Film {
+            xresolution: 2,
+            yresolution: 1,
+            filename: scene_filename.to_str().unwrap().to_string(),
+            save_fp16: false,
+            ..Default::default()
+        })
+        .with_output(&output)
+        .unwrap();
+
+        assert!(writer.check_overwrite(false, false).is_ok());
+        writer.write_film(&film, 1).unwrap();
+        assert!(!dir.join("scene-name.exr").exists());
+        assert_eq!(
+            read_exr(output.to_str().unwrap())[0],
+            writer.pixel_rgb(&film, 0, 0)
+        );
+
+        // The finished image is protected
+        assert!(writer.check_overwrite(false, false).is_err());
+
+        let png = writer.with_output(dir.join("foo.png")).unwrap();
+        assert_eq!(png.format(), ImageFormat::Png);
+        png.write_film(&film, 1).unwrap();
+        let image = image::open(png.filepath()).unwrap().to_rgb8();
+        let expected = png
+            .display_rgb(&film, 0, 0)
+            .to_array()
+            .map(|f| (f * 255.) as u8);
+        assert_eq!(image.get_pixel(0, 0).0, expected);
+
+        assert!(ImageFormat::from_path(Path::new("foo.jpg")).is_err());
+    }
+
+    #[test]
+    fn test_png_16bit() {
+        // A dark gradient, 8 bits can't tell most of the neighbouring pixels apart
+        const WIDTH: usize = 256;
+        let film = film::Film::new(WIDTH, 1, ColorSpace::Srgb);
+        for x in 0..WIDTH {
+            unsafe {
+                film.set(x, 0, DVec3::splat(0.01 * x as f64 / WIDTH as f64));
+            }
+        }
+
+        let dir = test_dir("png16");
+        let writer = |png_16bit| {
+            let writer = ImageWriter::new(&scene_description::Film {
+                xresolution: WIDTH as i32,
+                yresolution: 1,
+                ..Default::default()
+            })
+            .with_output(dir.join(format!("gradient-{png_16bit}.png")))
+            .unwrap();
+            if png_16bit {
+                writer.with_png_16bit()
+            } else {
+                writer
+            }
+        };
+
+        let levels = |mut values: Vec<u16>| {
+            values.dedup();
+            values.len()
+        };
+
+        let png8 = writer(false);
+        png8.write_film(&film, 1).unwrap();
+        let image8 = image::open(png8.filepath()).unwrap().to_rgb8();
+        let levels8 = levels(image8.pixels().map(|p| p.0[1] as u16).collect());
+
+        let png16 = writer(true);
+        png16.write_film(&film, 1).unwrap();
+        let image16 = image::open(png16.filepath()).unwrap();
+        assert_eq!(image16.color(), image::ColorType::Rgb16);
+        let image16 = image16.to_rgb16();
+        let levels16 = levels(image16.pixels().map(|p| p.0[1]).collect());
+
+        assert!(levels8 < WIDTH / 4, "{levels8}");
+        assert_eq!(levels16, WIDTH);
+
+        // Same tonemapping and gamma as the 8-bit image
+        for (p8, p16) in image8.pixels().zip(image16.pixels()) {
+            assert!(p8.0[1].abs_diff((p16.0[1] / 257) as u8) <= 1);
+        }
+    }
+
+    #[test]
+    fn test_png_wide_gamut() {
+        // An orange inside of sRGB and the Rec.2020 red outside of it
+        let orange = ColorSpace::Srgb
+            .to_xyz(Vec3::new(0.8, 0.3, 0.05))
+            .as_dvec3();
+        let red = ColorSpace::Rec2020.to_xyz(Vec3::X).as_dvec3();
+
+        let dir = test_dir("png-wide-gamut");
+        let write = |color_space| {
+            let film = film::Film::new(2, 1, color_space);
+            unsafe {
+                film.set(0, 0, orange);
+                film.set(1, 0, red);
+            }
+
+            let writer = ImageWriter::new(&scene_description::Film {
+                xresolution: 2,
+                yresolution: 1,
+                ..Default::default()
+            })
+            .with_output(dir.join(format!("{color_space:?}.png")))
+            .unwrap();
+            writer.write_film(&film, 1).unwrap();
+            image::open(writer.filepath()).unwrap().to_rgb8()
+        };
+
+        // PNG is sRGB, the same colors are written no matter the space of the film
+        let srgb = write(ColorSpace::Srgb);
+        let rec2020 = write(ColorSpace::Rec2020);
+        let [r, g, b] = rec2020.get_pixel(0, 0).0;
+        let [sr, sg, sb] = srgb.get_pixel(0, 0).0;
+        assert!(r.abs_diff(sr) <= 1 && g.abs_diff(sg) <= 1 && b.abs_diff(sb) <= 1);
+        assert_eq!(rec2020.get_pixel(1, 0), srgb.get_pixel(1, 0));
+        assert_eq!(rec2020.get_pixel(1, 0).0[1..], [0, 0]);
+    }
+
+    #[test]
+    fn test_exr_layers() {
+        let film = film::Film::new(2, 1, ColorSpace::Srgb);
+        let depth = film::DepthFilm::new(2, 1, film::DepthMode::First);
+        for i in 0..4 {
+            unsafe {
+                film.accumulate(0, 0, DVec3::splat(0.5));
+                film.accumulate(1, 0, DVec3::new(0., (i % 2) as f64, 0.));
+            }
+        }
+        unsafe {
+            depth.add_sample(1, 0, Some(5.));
+        }
+
+        let dir = test_dir("exr-layers");
+        let writer = |name: &str| {
+            ImageWriter::new(&scene_description::Film {
+                xresolution: 2,
+                yresolution: 1,
+                ..Default::default()
+            })
+            .with_output(dir.join(name))
+            .unwrap()
+        };
+
+        // Every pass in a single image
+        let layered = writer("layered.exr")
+            .with_exr_layers(ExrLayer::new_list("rgb, depth,variance").unwrap());
+        assert!(layered.check_overwrite(true, true).is_ok());
+        layered.write_passes(&film, Some(&depth), true, 4).unwrap();
+        assert!(!dir.join("layered-depth.exr").exists());
+        assert!(!dir.join("layered-variance.exr").exists());
+
+        let image = exr::prelude::read_all_flat_layers_from_file(layered.filepath()).unwrap();
+        let names: Vec<String> = image
+            .layer_data
+            .iter()
+            .map(|l| l.attributes.layer_name.as_ref().unwrap().to_string())
+            .collect();
+        assert_eq!(names, ["main-layer", "depth", "variance"]);
+
+        let values = |layer: usize| {
+            let channel = &image.layer_data[layer].channel_data.list[0];
+            channel.sample_data.values_as_f32().collect::<Vec<_>>()
+        };
+        assert_eq!(values(1)[1], 5.);
+        let variance = values(2);
+        assert_eq!(variance[0], 0.);
+        assert!(variance[1] > 0.);
+
+        // The passes that aren't layers are written next to the image
+        let separate = writer("separate.exr");
+        separate.write_passes(&film, Some(&depth), true, 4).unwrap();
+        assert!(dir.join("separate-depth.exr").exists());
+        assert!(dir.join("separate-variance.exr").exists());
+
+        // Layers of disabled passes
+        assert!(layered.write_passes(&film, None, true, 4).is_err());
+        assert!(ExrLayer::new_list("rgb,rgb").is_err());
+        assert!(ExrLayer::new_list("normals").is_err());
+    }
+}
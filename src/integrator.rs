@@ -1,115 +1,240 @@
 use eyre::{eyre, Result};
-use glam::Vec3;
-use rand::{distributions::Uniform, prelude::Distribution, rngs::SmallRng};
+use glam::{vec3, Vec3};
+use rand::{distributions::Uniform, prelude::Distribution};
 use rgb2spec::RGB2Spec;
 
 use crate::{
     bxdf::Bxdf,
-    color::spectrum::{rgb_spectrum::RGBTOSPEC, SampledWavelengths, SpectralQuantity},
+    color::{color_space::ColorSpace, quantity::Quantity, spectrum::rgb_spectrum::RgbSpectrumKind},
     geometry::Ray,
     math::sqr,
-    scene::{HitInfo, Scene},
+    sampler::Sampler,
+    sampling,
+    scene::{HitInfo, Scene, ShapeSample},
 };
 
+pub mod medium;
+pub mod scratch;
 pub mod shading_geometry;
 
+use medium::{MediumCoefficients, MediumEvent};
+use scratch::RenderScratch;
 use shading_geometry::ShadingGeometry;
 
+/// Paths are never terminated by russian roulette before this many bounces
+pub const DEFAULT_RR_START_DEPTH: u32 = 3;
+
 pub enum Integrator {
     RandomWalk(RandomWalkIntegrator),
     SimplePath(SimplePathIntegrator),
+    Debug(DebugIntegrator),
 }
 
 impl Integrator {
     pub fn new(kind: &str) -> Result<Self> {
         Ok(match kind {
-            "random-walk" => Self::RandomWalk(RandomWalkIntegrator),
-            "simple-path" => Self::SimplePath(SimplePathIntegrator),
+            "random-walk" => Self::RandomWalk(RandomWalkIntegrator::default()),
+            "simple-path" => Self::SimplePath(SimplePathIntegrator::new(MisHeuristic::Power)),
+            "debug-light-id" => Self::Debug(DebugIntegrator::new(DebugMode::LightId)),
             _ => return Err(eyre!("Unknown integrator kind: '{}'", kind)),
         })
     }
 
-    pub fn ray_l(
+    /// Only the simple path integrator uses MIS, the others ignore the heuristic.
+    pub fn with_mis_heuristic(mut self, heuristic: MisHeuristic) -> Self {
+        if let Self::SimplePath(simple) = &mut self {
+            simple.mis_heuristic = heuristic;
+        }
+        self
+    }
+
+    /// Projection lights are points, only the next event estimation of the simple path
+    /// integrator can reach them
+    pub fn supports_projection_lights(&self) -> bool {
+        matches!(self, Self::SimplePath(_))
+    }
+
+    /// Only the random walk integrator has a choice of directions, the others ignore this.
+    pub fn with_hemisphere_sampling(mut self, hemisphere_sampling: HemisphereSampling) -> Self {
+        if let Self::RandomWalk(random_walk) = &mut self {
+            random_walk.hemisphere_sampling = hemisphere_sampling;
+        }
+        self
+    }
+
+    /// Russian roulette only terminates paths after `depth` bounces.
+    /// The integrators have no maximum path depth, a start depth past the length of any path
+    /// (e.g. u32::MAX) disables the roulette, but then paths in closed scenes never end.
+    /// The debug integrator doesn't trace paths and ignores this.
+    pub fn with_rr_start_depth(mut self, depth: u32) -> Self {
+        match &mut self {
+            Self::RandomWalk(random_walk) => random_walk.rr_start_depth = depth,
+            Self::SimplePath(simple) => simple.rr_start_depth = depth,
+            Self::Debug(_) => {}
+        }
+        self
+    }
+
+    /// Generic over the color representation, see `ColorMode`
+    pub fn ray_l<C: Quantity>(
         &self,
         ray: &Ray,
-        sampled_lambdas: &mut SampledWavelengths,
+        sampled_lambdas: &mut C::Lambdas,
         scene: &Scene,
-        rng: &mut SmallRng,
-    ) -> SpectralQuantity {
-        let rgbtospec = RGBTOSPEC.get().unwrap();
+        scratch: &RenderScratch,
+        rng: &mut Sampler,
+    ) -> C {
+        let hit = scene.trace_ray(ray);
+        self.ray_l_from_hit(ray, hit, sampled_lambdas, scene, scratch, rng)
+    }
 
+    /// Same as `ray_l()`, but the primary hit was already traced, e.g. in a packet
+    pub fn ray_l_from_hit<C: Quantity>(
+        &self,
+        ray: &Ray,
+        hit: Option<HitInfo>,
+        sampled_lambdas: &mut C::Lambdas,
+        scene: &Scene,
+        scratch: &RenderScratch,
+        rng: &mut Sampler,
+    ) -> C {
         match self {
-            Integrator::RandomWalk(_) => RandomWalkIntegrator::ray_l(
-                ray,
-                sampled_lambdas,
-                scene,
-                rng,
-                rgbtospec,
-                0,
-                SpectralQuantity::ONE,
-            ),
-            Integrator::SimplePath(_) => SimplePathIntegrator::ray_l_iter(
-                ray.clone(),
-                sampled_lambdas,
-                scene,
-                rng,
-                rgbtospec,
-            ),
+            Integrator::RandomWalk(random_walk) => {
+                random_walk.ray_l(ray, hit, sampled_lambdas, scene, rng, scratch, 0, C::ONE)
+            }
+            Integrator::SimplePath(simple) => {
+                simple.ray_l_iter(ray.clone(), hit, sampled_lambdas, scene, rng, scratch)
+            }
+            Integrator::Debug(debug) => debug.ray_l(hit, sampled_lambdas, scene, rng, scratch),
         }
     }
 }
 
-pub struct RandomWalkIntegrator;
+/// How the random walk integrator chooses the next direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HemisphereSampling {
+    /// Importance sampling of the BxDF
+    #[default]
+    Bxdf,
+    /// Uniform directions around the normal. Noisier and wrong for near-specular materials,
+    /// but doesn't depend on the BxDF's sampling and pdf, so it can validate them.
+    Uniform,
+}
+
+impl HemisphereSampling {
+    pub fn new(kind: &str) -> Result<Self> {
+        Ok(match kind {
+            "bxdf" => Self::Bxdf,
+            "uniform" => Self::Uniform,
+            _ => return Err(eyre!("Unknown hemisphere sampling: '{}'", kind)),
+        })
+    }
+}
+
+pub struct RandomWalkIntegrator {
+    rr_start_depth: u32,
+    hemisphere_sampling: HemisphereSampling,
+}
+
+impl Default for RandomWalkIntegrator {
+    fn default() -> Self {
+        Self {
+            rr_start_depth: DEFAULT_RR_START_DEPTH,
+            hemisphere_sampling: HemisphereSampling::default(),
+        }
+    }
+}
 
 impl RandomWalkIntegrator {
-    fn ray_l(
+    #[allow(clippy::too_many_arguments)]
+    fn ray_l<C: Quantity>(
+        &self,
         hit_ray: &Ray,
-        sampled_lambdas: &mut SampledWavelengths,
+        hit: Option<HitInfo>,
+        sampled_lambdas: &mut C::Lambdas,
         scene: &Scene,
-        rng: &mut SmallRng,
-        rgbtospec: &RGB2Spec,
+        rng: &mut Sampler,
+        scratch: &RenderScratch,
         mut depth: u32,
-        mut throughput: SpectralQuantity,
-    ) -> SpectralQuantity {
+        mut throughput: C,
+    ) -> C {
         depth += 1;
 
-        if let Some(mut hitinfo) = scene.trace_ray(hit_ray) {
-            let mut emission = hitinfo
-                .light
-                .map(|light_id| scene.lights[light_id].emission.eval(sampled_lambdas))
-                .unwrap_or(SpectralQuantity::ZERO);
+        if let Some(mut hitinfo) = hit {
+            // Area lights emit from the side given by the shape's orientation, the bump-mapped
+            // shading normal doesn't change it
+            let front_face = -hit_ray.dir.dot(hitinfo.normal) >= 0.;
+            let emission = match hitinfo.light {
+                Some(light_id) if front_face => {
+                    C::from_spectrum(&scene.lights[light_id].emission, sampled_lambdas)
+                }
+                _ => C::ZERO,
+            };
 
+            hitinfo.apply_bump_map();
             hitinfo.normal = hitinfo.normal.normalize();
             if -hit_ray.dir.dot(hitinfo.normal) < 0. {
-                emission = SpectralQuantity::ZERO;
                 hitinfo.normal = -hitinfo.normal;
             }
 
-            let mut bxdf = Bxdf::new(&hitinfo.material, rng);
-            let sample_dir = bxdf.sample(hitinfo.normal, -hit_ray.dir);
+            // Interfaces don't scatter, the path continues as if they weren't there
+            if hitinfo.material.is_interface() {
+                let next_ray = spawn_ray_through(&hitinfo, hit_ray.dir);
+                return self.ray_l(
+                    &next_ray,
+                    scene.trace_ray(&next_ray),
+                    sampled_lambdas,
+                    scene,
+                    rng,
+                    scratch,
+                    depth - 1,
+                    throughput,
+                );
+            }
+
+            let mut bxdf = Bxdf::new(&hitinfo.material, scratch, rng)
+                .with_vertex_color(hitinfo.color)
+                .with_uv(hitinfo.uv);
+            let sample_dir = match self.hemisphere_sampling {
+                HemisphereSampling::Bxdf => bxdf.sample(hitinfo.normal, -hit_ray.dir),
+                HemisphereSampling::Uniform => bxdf.sample_uniform(hitinfo.normal),
+            };
+            let sample_dir = match sample_dir {
+                Some(sample_dir) => sample_dir,
+                None => return emission,
+            };
             let next_ray = spawn_ray(&hitinfo, sample_dir);
             let sgeom = ShadingGeometry::new(&hitinfo.normal, &sample_dir, &hit_ray.dir);
 
-            let pdf = bxdf.pdf(&sgeom);
-            let bxdf_eval = bxdf.eval(&sgeom, sampled_lambdas);
+            let pdf = match self.hemisphere_sampling {
+                HemisphereSampling::Bxdf => bxdf.pdf(&sgeom),
+                HemisphereSampling::Uniform => sampling::pdf_uniform_hemisphere(),
+            };
+            let bxdf_eval: C = bxdf.eval(&sgeom, sampled_lambdas);
 
             throughput *= bxdf_eval * sgeom.cos_theta * (1. / pdf);
 
-            let roulette_compensation =
-                if let Some(compensation) = russian_roulette(depth, rng, &throughput) {
-                    compensation
-                } else {
-                    return emission;
-                };
+            let roulette_compensation = if let Some(compensation) = russian_roulette(
+                depth,
+                self.rr_start_depth,
+                &scratch.uniform,
+                rng,
+                &throughput,
+            ) {
+                compensation
+            } else {
+                return emission;
+            };
 
             throughput *= 1. / roulette_compensation;
 
-            let li = Self::ray_l(
+            let li = self.ray_l(
                 &next_ray,
+                scene.trace_ray(&next_ray),
                 sampled_lambdas,
                 scene,
                 rng,
-                rgbtospec,
+                scratch,
                 depth,
                 throughput,
             );
@@ -118,97 +243,232 @@ impl RandomWalkIntegrator {
 
             return emission + estimate_brdf_sample * (1. / pdf);
         } else {
-            ray_nohit(hit_ray, scene, rgbtospec, &sampled_lambdas)
+            ray_nohit(hit_ray, scene, scratch.rgbtospec, sampled_lambdas)
+        }
+    }
+}
+
+/// Weighting of the BxDF and light samples in multiple importance sampling
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MisHeuristic {
+    #[default]
+    Power,
+    /// Has a higher variance, but is easier to reason about when validating MIS
+    Balance,
+}
+
+impl MisHeuristic {
+    pub fn new(kind: &str) -> Result<Self> {
+        Ok(match kind {
+            "power" => Self::Power,
+            "balance" => Self::Balance,
+            _ => return Err(eyre!("Unknown MIS heuristic: '{}'", kind)),
+        })
+    }
+
+    /// Weight of a sample from the distribution with fpdf
+    fn weight(self, fpdf: f32, gpdf: f32) -> f32 {
+        match self {
+            Self::Power => mis_power_heuristic(fpdf, gpdf),
+            Self::Balance => mis_balance_heuristic(fpdf, gpdf),
         }
     }
 }
 
-pub struct SimplePathIntegrator;
+/// Adapted from PBRT. Specific case where 1 sample is taken from each distribution.
+fn mis_power_heuristic(fpdf: f32, gpdf: f32) -> f32 {
+    sqr(fpdf) / (sqr(fpdf) + sqr(gpdf))
+}
+
+fn mis_balance_heuristic(fpdf: f32, gpdf: f32) -> f32 {
+    fpdf / (fpdf + gpdf)
+}
+
+pub struct SimplePathIntegrator {
+    mis_heuristic: MisHeuristic,
+    rr_start_depth: u32,
+}
 
 impl SimplePathIntegrator {
-    fn ray_l_iter(
+    pub fn new(mis_heuristic: MisHeuristic) -> Self {
+        Self {
+            mis_heuristic,
+            rr_start_depth: DEFAULT_RR_START_DEPTH,
+        }
+    }
+
+    fn ray_l_iter<C: Quantity>(
+        &self,
         hit_ray: Ray,
-        sampled_lambdas: &mut SampledWavelengths,
+        primary_hit: Option<HitInfo>,
+        sampled_lambdas: &mut C::Lambdas,
         scene: &Scene,
-        rng: &mut SmallRng,
-        rgbtospec: &RGB2Spec,
-    ) -> SpectralQuantity {
+        rng: &mut Sampler,
+        scratch: &RenderScratch,
+    ) -> C {
         let mut depth = 0;
-        let mut throughput = SpectralQuantity::ONE;
-        let mut radiance = SpectralQuantity::ZERO;
+        let mut throughput = C::ONE;
+        let mut radiance = C::ZERO;
         let mut last_pdf_bxdf = 1f32;
         let mut ray = hit_ray;
         let mut last_pos = Vec3::ZERO;
+        let mut hit = primary_hit;
+
+        let medium = scene
+            .camera_medium
+            .as_ref()
+            .map(|medium| MediumCoefficients::<C>::new(medium, sampled_lambdas));
+        let transmittance = |distance: f32| {
+            medium
+                .as_ref()
+                .map_or(C::ONE, |medium| medium.transmittance(distance))
+        };
 
         loop {
-            let hit = scene.trace_ray(&ray);
+            if let Some(medium) = &medium {
+                let distance = hit
+                    .as_ref()
+                    .map_or(f32::INFINITY, |hit| hit.pos.distance(ray.orig));
+
+                match medium.sample_event(distance, &scratch.uniform, rng) {
+                    MediumEvent::Pass { weight } => throughput *= weight,
+                    MediumEvent::Scatter { t, weight } => {
+                        throughput *= weight;
+                        let pos = ray.orig + ray.dir.normalize() * t;
+
+                        if let Some(light_s) = scene.sample_light(pos, rng) {
+                            let light_pos = light_s.shape_sample.pos;
+                            let p_to_l_norm = (light_pos - pos).normalize_or_zero();
+                            let cos_light = light_s.shape_sample.normal.dot(-p_to_l_norm);
+
+                            if cos_light > 0.
+                                && light_s.pdf > 0.
+                                && scene.is_unoccluded(pos, light_pos, rng)
+                            {
+                                let pdf_light = light_s.pdf;
+                                let weight_light = self
+                                    .mis_heuristic
+                                    .weight(pdf_light, sampling::pdf_uniform_sphere());
+                                let light_emission =
+                                    C::from_spectrum(light_s.emission, sampled_lambdas);
+
+                                // The isotropic phase function is the same as its pdf
+                                radiance += light_emission
+                                    * transmittance(pos.distance(light_pos))
+                                    * throughput
+                                    * weight_light
+                                    * (sampling::pdf_uniform_sphere() / pdf_light);
+                            }
+                        }
+
+                        match russian_roulette(
+                            depth,
+                            self.rr_start_depth,
+                            &scratch.uniform,
+                            rng,
+                            &throughput,
+                        ) {
+                            Some(compensation) => throughput *= 1. / compensation,
+                            None => break,
+                        };
+
+                        // The phase function and its pdf cancel out in the throughput
+                        depth += 1;
+                        last_pdf_bxdf = sampling::pdf_uniform_sphere();
+                        last_pos = pos;
+                        ray = Ray::new(pos, sampling::sample_uniform_sphere(rng));
+                        hit = scene.trace_ray(&ray);
+                        continue;
+                    }
+                }
+            }
+
             if hit.is_none() {
-                let li = ray_nohit(&ray, scene, rgbtospec, sampled_lambdas);
+                let li: C = ray_nohit(&ray, scene, scratch.rgbtospec, sampled_lambdas);
                 radiance += throughput * li;
                 break;
             }
 
-            let mut hitinfo = hit.unwrap();
+            let mut hitinfo = hit.take().unwrap();
+
+            // Area lights emit from the side given by the shape's orientation, the bump-mapped
+            // shading normal doesn't change it
+            let surface_normal = hitinfo.normal.normalize();
+            let front_face = -ray.dir.dot(surface_normal) >= 0.;
 
+            hitinfo.apply_bump_map();
             hitinfo.normal = hitinfo.normal.normalize();
-            let backside = -ray.dir.dot(hitinfo.normal) < 0.;
-            if backside {
+            if -ray.dir.dot(hitinfo.normal) < 0. {
                 hitinfo.normal = -hitinfo.normal;
             }
 
-            if let Some(light) = hitinfo.light {
-                let light = &scene.lights[light];
-                let emission = if backside {
-                    SpectralQuantity::ZERO
+            // Interfaces don't scatter, so the path continues as if they weren't there.
+            // The depth and the last scattering vertex used for MIS stay the same.
+            if hitinfo.material.is_interface() {
+                ray = spawn_ray_through(&hitinfo, ray.dir);
+                hit = scene.trace_ray(&ray);
+                continue;
+            }
+
+            if let Some(light_id) = hitinfo.light {
+                let light = &scene.lights[light_id];
+                let emission = if front_face {
+                    C::from_spectrum(&light.emission, sampled_lambdas)
                 } else {
-                    light.emission.eval(sampled_lambdas)
+                    C::ZERO
                 };
 
                 if depth == 0 {
                     radiance += throughput * emission;
                 } else {
-                    let p_to_l_norm = (hitinfo.pos - last_pos).normalize();
-                    let p_to_l_mag_sq = (hitinfo.pos - last_pos).length_squared();
-                    let cos_light = hitinfo.normal.dot(-p_to_l_norm);
-
-                    let pdf_light = p_to_l_mag_sq
-                        / (scene.light_area(&light) * cos_light * scene.lights.len() as f32);
-                    let bxdf_weight = Self::mis_power_heuristic(last_pdf_bxdf, pdf_light);
+                    let light_sample = ShapeSample::new(hitinfo.pos, surface_normal);
+                    let pdf_light = scene.light_pdf(light_id, last_pos, &light_sample);
+                    let bxdf_weight = self.mis_heuristic.weight(last_pdf_bxdf, pdf_light);
 
                     radiance += throughput * bxdf_weight * emission;
                 }
             }
 
-            let mut bxdf = Bxdf::new(&hitinfo.material, rng);
-            let sample_dir = bxdf.sample(hitinfo.normal, -ray.dir);
+            let mut bxdf = Bxdf::new(&hitinfo.material, scratch, rng)
+                .with_vertex_color(hitinfo.color)
+                .with_uv(hitinfo.uv);
+            // Nothing is reflected, so light sampling wouldn't contribute either
+            let sample_dir = match bxdf.sample(hitinfo.normal, -ray.dir) {
+                Some(sample_dir) => sample_dir,
+                None => break,
+            };
             let bxdf_ray = spawn_ray(&hitinfo, sample_dir);
             let sgeom_bxdf = ShadingGeometry::new(&hitinfo.normal, &sample_dir, &ray.dir);
 
             let pdf_bxdf = bxdf.pdf(&sgeom_bxdf);
-            let bxdf_eval = bxdf.eval(&sgeom_bxdf, sampled_lambdas);
+            let bxdf_eval: C = bxdf.eval(&sgeom_bxdf, sampled_lambdas);
 
-            if let Some(light_s) = scene.sample_light(rng) {
+            if let Some(light_s) = scene.sample_light(hitinfo.pos, rng) {
                 let light_pos = light_s.shape_sample.pos;
-                let p_to_l_norm = (light_pos - hitinfo.pos).normalize();
-                let p_to_l_mag_sq = (light_pos - hitinfo.pos).length_squared();
+                // The sample can land on the shading point itself when it lies on the light,
+                // the zero direction then fails the cosine tests below
+                let p_to_l_norm = (light_pos - hitinfo.pos).normalize_or_zero();
 
                 let cos_light = light_s.shape_sample.normal.dot(-p_to_l_norm);
                 let sgeom_light = ShadingGeometry::new(&hitinfo.normal, &p_to_l_norm, &ray.dir);
 
-                if sgeom_light.cos_theta > 0. && cos_light > 0. {
-                    let visibility = scene.is_unoccluded(bxdf_ray.orig, light_pos);
+                if sgeom_light.cos_theta > 0. && cos_light > 0. && light_s.pdf > 0. {
+                    let visibility = scene.is_unoccluded(bxdf_ray.orig, light_pos, rng);
 
                     if visibility {
-                        let pdf_light = light_s.pmf * p_to_l_mag_sq / (light_s.area * cos_light);
-                        let mut bxdf = Bxdf::new(&hitinfo.material, rng);
-                        let bxdf_light_eval = bxdf.eval(&sgeom_light, sampled_lambdas);
+                        let pdf_light = light_s.pdf;
+                        let mut bxdf = Bxdf::new(&hitinfo.material, scratch, rng)
+                            .with_vertex_color(hitinfo.color)
+                            .with_uv(hitinfo.uv);
+                        let bxdf_light_eval: C = bxdf.eval(&sgeom_light, sampled_lambdas);
 
                         let weight_light =
-                            Self::mis_power_heuristic(pdf_light, bxdf.pdf(&sgeom_light));
-                        let light_emission = light_s.emission.eval(sampled_lambdas);
+                            self.mis_heuristic.weight(pdf_light, bxdf.pdf(&sgeom_light));
+                        let light_emission = C::from_spectrum(light_s.emission, sampled_lambdas);
 
                         radiance += bxdf_light_eval
                             * light_emission
+                            * transmittance(hitinfo.pos.distance(light_pos))
                             * weight_light
                             * throughput
                             * sgeom_light.cos_theta
@@ -217,7 +477,45 @@ impl SimplePathIntegrator {
                 }
             }
 
-            match russian_roulette(depth, rng, &throughput) {
+            for projection_light in &scene.projection_lights {
+                let light_pos = projection_light.pos();
+                let p_to_l_norm = (light_pos - hitinfo.pos).normalize();
+                let p_to_l_mag_sq = (light_pos - hitinfo.pos).length_squared();
+
+                if hitinfo.normal.dot(p_to_l_norm) <= 0. {
+                    continue;
+                }
+
+                let emission =
+                    match projection_light.emission_towards(hitinfo.pos, scratch.rgbtospec) {
+                        Some(emission) => emission,
+                        None => continue,
+                    };
+
+                if scene.is_unoccluded(bxdf_ray.orig, light_pos, rng) {
+                    let sgeom_light = ShadingGeometry::new(&hitinfo.normal, &p_to_l_norm, &ray.dir);
+                    let mut bxdf = Bxdf::new(&hitinfo.material, scratch, rng)
+                        .with_vertex_color(hitinfo.color)
+                        .with_uv(hitinfo.uv);
+                    let bxdf_light_eval: C = bxdf.eval(&sgeom_light, sampled_lambdas);
+
+                    // Delta light, there is nothing to apply MIS to
+                    radiance += bxdf_light_eval
+                        * C::from_spectrum(&emission, sampled_lambdas)
+                        * transmittance(p_to_l_mag_sq.sqrt())
+                        * throughput
+                        * sgeom_light.cos_theta
+                        * (1. / p_to_l_mag_sq);
+                }
+            }
+
+            match russian_roulette(
+                depth,
+                self.rr_start_depth,
+                &scratch.uniform,
+                rng,
+                &throughput,
+            ) {
                 Some(compensation) => throughput *= 1. / compensation,
                 None => break,
             };
@@ -227,18 +525,62 @@ impl SimplePathIntegrator {
             last_pdf_bxdf = pdf_bxdf;
             ray = bxdf_ray;
             last_pos = hitinfo.pos;
+            hit = scene.trace_ray(&ray);
         }
 
         radiance
     }
+}
 
-    /// Adapted from PBRT. Specific case where 1 sample is taken from each distribution.
-    fn mis_power_heuristic(fpdf: f32, gpdf: f32) -> f32 {
-        sqr(fpdf) / (sqr(fpdf) + sqr(gpdf))
+pub enum DebugMode {
+    /// Colors primary hits by the light chosen by the light sampler for NEE
+    LightId,
+}
+
+/// Visualizes internal renderer state instead of computing radiance
+pub struct DebugIntegrator {
+    mode: DebugMode,
+}
+
+impl DebugIntegrator {
+    /// Categorical palette, light ids are wrapped around it
+    const PALETTE: [Vec3; 8] = [
+        vec3(0.12, 0.47, 0.71),
+        vec3(1., 0.5, 0.05),
+        vec3(0.17, 0.63, 0.17),
+        vec3(0.84, 0.15, 0.16),
+        vec3(0.58, 0.4, 0.74),
+        vec3(0.55, 0.34, 0.29),
+        vec3(0.89, 0.47, 0.76),
+        vec3(0.74, 0.74, 0.13),
+    ];
+
+    pub fn new(mode: DebugMode) -> Self {
+        Self { mode }
     }
 
-    fn mis_balance_heuristic(fpdf: f32, gpdf: f32) -> f32 {
-        fpdf / (fpdf + gpdf)
+    fn ray_l<C: Quantity>(
+        &self,
+        hit: Option<HitInfo>,
+        sampled_lambdas: &C::Lambdas,
+        scene: &Scene,
+        rng: &mut Sampler,
+        scratch: &RenderScratch,
+    ) -> C {
+        let rgb = match self.mode {
+            DebugMode::LightId => self.light_id_rgb(hit, scene, rng),
+        };
+
+        // Illuminant spectrum so that the palette colors are displayed as they are
+        let kind = RgbSpectrumKind::new_illuminant(ColorSpace::Srgb);
+        C::from_rgb(rgb, kind, scratch.rgbtospec, sampled_lambdas)
+    }
+
+    /// Takes one light sample at the primary hit, black if nothing was hit or sampled
+    fn light_id_rgb(&self, hit: Option<HitInfo>, scene: &Scene, rng: &mut Sampler) -> Vec3 {
+        hit.and_then(|hit| scene.sample_light(hit.pos, rng))
+            .map(|light_s| Self::PALETTE[light_s.light_id % Self::PALETTE.len()])
+            .unwrap_or(Vec3::ZERO)
     }
 }
 
@@ -249,13 +591,23 @@ fn spawn_ray(hitinfo: &HitInfo, dir: Vec3) -> Ray {
     Ray::new(ray_orig, dir)
 }
 
+/// Continues the ray on the other side of the surface, the normal has to face the incoming ray
+fn spawn_ray_through(hitinfo: &HitInfo, dir: Vec3) -> Ray {
+    Ray::new(hitinfo.pos - 0.008 * hitinfo.normal, dir)
+}
+
 /// Randomly selects if a ray should be terminated based on its throughput.
-/// Roulette is only applied after the first 3 bounces.
+/// Roulette is only applied after the first `start_depth` bounces.
 /// If ray shoould NOT be terminated, the roulette compensation is returned.
-fn russian_roulette(depth: u32, rng: &mut SmallRng, throughput: &SpectralQuantity) -> Option<f32> {
-    if depth > 3 {
-        let dist = Uniform::from(0f32..1f32);
-        let u = dist.sample(rng);
+fn russian_roulette<C: Quantity>(
+    depth: u32,
+    start_depth: u32,
+    uniform: &Uniform<f32>,
+    rng: &mut Sampler,
+    throughput: &C,
+) -> Option<f32> {
+    if depth > start_depth {
+        let u = uniform.sample(rng);
         let survival_prob = 1. - throughput.max_value().max(0.05);
 
         if u < survival_prob {
@@ -269,16 +621,918 @@ fn russian_roulette(depth: u32, rng: &mut SmallRng, throughput: &SpectralQuantit
     }
 }
 
-fn ray_nohit(
+fn ray_nohit<C: Quantity>(
     ray: &Ray,
     scene: &Scene,
     rgbtospec: &RGB2Spec,
-    lambdas: &SampledWavelengths,
-) -> SpectralQuantity {
+    lambdas: &C::Lambdas,
+) -> C {
     if let Some(infinite_light) = &scene.infinite_light {
-        let rgbspectrum = infinite_light.sample(ray.dir, rgbtospec);
-        rgbspectrum.eval(lambdas)
+        let (rgb, kind) = infinite_light.sample_rgb(ray.dir);
+        C::from_rgb(rgb, kind, rgbtospec, lambdas)
     } else {
-        SpectralQuantity::ZERO
+        C::ZERO
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use std::f32::consts::PI;
+
+    use glam::{vec3, Mat4};
+    use rand::SeedableRng;
+
+    use crate::{
+        color::spectrum::{
+            piecewise_spectrum::PiecewiseLinearSpectrum,
+            rgb_spectrum::{flat_rgbtospec, RgbSpectrum},
+            Observer, SampledWavelengths, SpectralQuantity, Spectrum,
+        },
+        pbrt_loader::scene_description::{
+            self, AreaLightSource, ConductorMaterial, DiffuseMaterial, HomogeneousMedium,
+            InfiniteLightSource, Material, MaterialRoughness, SceneDescription, ScreenWideOptions,
+            ShapeWithParams,
+        },
+        scene::octamap::write_test_hdr,
+        util::test_dir,
+        vecmath,
+    };
+
+    use super::*;
+
+    fn quad(corner: Vec3, edge_u: Vec3, edge_v: Vec3) -> scene_description::Shape {
+        scene_description::Shape::Quad(scene_description::Quad::new(corner, edge_u, edge_v))
+    }
+
+    /// An upwards-facing light between the floor and the ceiling,
+    /// the floor is only lit by light reflected from the ceiling.
+    fn ceiling_scene(rgbtospec: &RGB2Spec, ceiling_material: Material) -> Scene {
+        let diffuse = Material::Diffuse(DiffuseMaterial::new(rgbtospec, Vec3::splat(0.8)));
+        let light = AreaLightSource::new_default(rgbtospec, ColorSpace::Srgb);
+
+        let shape = |shape, material, area_light| {
+            ShapeWithParams::new(shape, material, area_light, Mat4::IDENTITY, false, None)
+        };
+
+        let shapes = vec![
+            shape(
+                quad(vec3(-2., -2., 0.), Vec3::X * 4., Vec3::Y * 4.),
+                diffuse.clone(),
+                None,
+            ),
+            shape(
+                quad(vec3(-2., -2., 2.), Vec3::Y * 4., Vec3::X * 4.),
+                ceiling_material,
+                None,
+            ),
+            shape(
+                quad(vec3(-0.25, -0.25, 1.), Vec3::X * 0.5, Vec3::Y * 0.5),
+                diffuse,
+                Some(light),
+            ),
+        ];
+
+        Scene::init(SceneDescription {
+            options: ScreenWideOptions::default(),
+            shapes,
+            infinite_light: None,
+            projection_lights: Vec::new(),
+        })
+        .unwrap()
+    }
+
+    fn floor_radiance(integrator: &Integrator, scene: &Scene, rgbtospec: &RGB2Spec) -> f32 {
+        let mut rng = Sampler::seed_from_u64(0);
+        let scratch = RenderScratch::new(rgbtospec);
+        let ray = Ray::new(vec3(1.5, 0., 0.5), -Vec3::Z);
+
+        let mut radiance = 0.;
+        for _ in 0..256 {
+            let mut lambdas = SampledWavelengths::new_sample_uniform(&mut rng);
+            let l: SpectralQuantity =
+                integrator.ray_l(&ray, &mut lambdas, scene, &scratch, &mut rng);
+            assert!(l.vals.iter().all(|v| v.is_finite()));
+            radiance += l.average();
+        }
+
+        radiance
+    }
+
+    #[test]
+    fn test_interface_passthrough() {
+        let rgbtospec = flat_rgbtospec();
+        let light = AreaLightSource::new_default(&rgbtospec, ColorSpace::Srgb);
+
+        // A small emitter facing the origin, optionally behind a large interface quad that is also
+        // marked as a light
+        let scene = |with_interface| {
+            let mut shapes = vec![ShapeWithParams::new(
+                quad(vec3(0.9, 0.9, 3.), Vec3::Y * 0.2, Vec3::X * 0.2),
+                Material::new_default(&rgbtospec),
+                Some(light.clone()),
+                Mat4::IDENTITY,
+                false,
+                None,
+            )];
+            if with_interface {
+                shapes.push(ShapeWithParams::new(
+                    quad(vec3(-2., -2., 1.), Vec3::Y * 4., Vec3::X * 4.),
+                    Material::Interface,
+                    Some(light.clone()),
+                    Mat4::IDENTITY,
+                    false,
+                    None,
+                ));
+            }
+
+            Scene::init(SceneDescription {
+                options: ScreenWideOptions::default(),
+                shapes,
+                infinite_light: None,
+                projection_lights: Vec::new(),
+            })
+            .unwrap()
+        };
+
+        let plain = scene(false);
+        let with_interface = scene(true);
+        assert_eq!(with_interface.lights.len(), 1);
+
+        // Only an undeviated ray hits the emitter
+        let ray = Ray::new(Vec3::ZERO, vec3(1., 1., 3.));
+        let hit = with_interface.trace_ray(&ray).unwrap();
+        assert!(hit.material.is_interface());
+        assert!(with_interface.is_unoccluded(
+            Vec3::ZERO,
+            vec3(1., 1., 3.),
+            &mut Sampler::seed_from_u64(0)
+        ));
+
+        let mut rng = Sampler::seed_from_u64(0);
+        let view_dir = -ray.dir;
+        let scratch = RenderScratch::new(&rgbtospec);
+        let sample_dir = Bxdf::new(&hit.material, &scratch, &mut rng).sample(-Vec3::Z, view_dir);
+        assert_eq!(sample_dir, Some(ray.dir));
+
+        for kind in ["simple-path", "random-walk"] {
+            let integrator = Integrator::new(kind).unwrap();
+            let mut radiance = |scene: &Scene| -> Vec3 {
+                integrator.ray_l(&ray, &mut (), scene, &scratch, &mut rng)
+            };
+
+            let expected = radiance(&plain);
+            assert!(expected.min_element() > 0.);
+            assert_eq!(radiance(&with_interface), expected, "{kind}");
+        }
+    }
+
+    #[test]
+    fn test_fog_attenuation() {
+        let rgbtospec = flat_rgbtospec();
+        let light = AreaLightSource::new_default(&rgbtospec, ColorSpace::Srgb);
+        let constant =
+            |value| Spectrum::PiecewiseLinear(PiecewiseLinearSpectrum::new_constant(value));
+
+        // A large emitter facing the origin at the distance
+        let scene = |distance: f32, medium: Option<HomogeneousMedium>| {
+            let mut options = ScreenWideOptions::default();
+            options.camera.medium = medium;
+
+            Scene::init(SceneDescription {
+                options,
+                shapes: vec![ShapeWithParams::new(
+                    quad(vec3(-5., -5., distance), Vec3::Y * 10., Vec3::X * 10.),
+                    Material::new_default(&rgbtospec),
+                    Some(light.clone()),
+                    Mat4::IDENTITY,
+                    false,
+                    None,
+                )],
+                infinite_light: None,
+                projection_lights: Vec::new(),
+            })
+            .unwrap()
+        };
+
+        let integrator = Integrator::new("simple-path").unwrap();
+        let scratch = RenderScratch::new(&rgbtospec);
+        let mut rng = Sampler::seed_from_u64(0);
+        let mut mean_radiance = |scene: &Scene, ray: &Ray| {
+            const SAMPLES: usize = 4096;
+            let mut radiance = Vec3::ZERO;
+            for _ in 0..SAMPLES {
+                let l: Vec3 = integrator.ray_l(ray, &mut (), scene, &scratch, &mut rng);
+                assert!(l.is_finite());
+                radiance += l;
+            }
+            radiance / SAMPLES as f32
+        };
+
+        // Absorption only, the radiance falls off exponentially with the distance
+        const SIGMA_A: f32 = 0.5;
+        let absorbing = HomogeneousMedium::new(constant(SIGMA_A), constant(0.), 1.);
+        let ray = Ray::new(Vec3::ZERO, Vec3::Z);
+        let emitted = mean_radiance(&scene(1., None), &ray);
+        assert!(emitted.min_element() > 0.);
+
+        for distance in [1., 2., 4.] {
+            let radiance = mean_radiance(&scene(distance, Some(absorbing.clone())), &ray);
+            let expected = emitted * (-SIGMA_A * distance).exp();
+            assert!(
+                ((radiance - expected) / expected).abs().max_element() < 0.05,
+                "{distance}: {radiance} != {expected}"
+            );
+        }
+
+        // A scattering medium lights up rays that miss the emitter
+        let scattering = HomogeneousMedium::new(constant(0.5), constant(0.5), 1.);
+        let ray = Ray::new(Vec3::ZERO, -Vec3::Z);
+        assert_eq!(mean_radiance(&scene(2., None), &ray), Vec3::ZERO);
+        assert!(mean_radiance(&scene(2., Some(scattering)), &ray).min_element() > 0.);
+    }
+
+    #[test]
+    fn test_force_diffuse_matte() {
+        let rgbtospec = flat_rgbtospec();
+        let conductor = Material::Conductor(ConductorMaterial::new(
+            &rgbtospec,
+            Vec3::splat(0.2),
+            Vec3::splat(3.),
+            MaterialRoughness::new(0.05, 0.05),
+        ));
+        let scene = ceiling_scene(&rgbtospec, conductor);
+        let integrator = Integrator::new("simple-path").unwrap();
+
+        // The same point of the ceiling, seen straight from below and from the direction of the
+        // light's mirror reflection
+        let ceiling_point = vec3(1., 0., 2.);
+        let radiance = |orig: Vec3, force_diffuse| {
+            let scratch = RenderScratch::new(&rgbtospec).with_force_diffuse(force_diffuse);
+            let mut rng = Sampler::seed_from_u64(0);
+            let ray = Ray::new(orig, ceiling_point - orig);
+
+            (0..1024)
+                .map(|_| {
+                    let l: Vec3 = integrator.ray_l(&ray, &mut (), &scene, &scratch, &mut rng);
+                    l.x
+                })
+                .sum::<f32>()
+        };
+
+        let below = vec3(1., 0., 0.5);
+        let mirror = vec3(2.5, 0., 0.5);
+
+        let specular_ratio = radiance(mirror, false) / radiance(below, false);
+        assert!(specular_ratio > 10., "{specular_ratio}");
+
+        // A matte surface looks the same from every direction
+        let matte_ratio = radiance(mirror, true) / radiance(below, true);
+        assert!((matte_ratio - 1.).abs() < 0.1, "{matte_ratio}");
+    }
+
+    #[test]
+    fn test_black_material_no_indirect_light() {
+        let rgbtospec = flat_rgbtospec();
+
+        let control = ceiling_scene(
+            &rgbtospec,
+            Material::Diffuse(DiffuseMaterial::new(&rgbtospec, Vec3::splat(0.8))),
+        );
+        let integrator = Integrator::new("simple-path").unwrap();
+        assert!(floor_radiance(&integrator, &control, &rgbtospec) > 0.);
+
+        let black = ceiling_scene(&rgbtospec, Material::Black);
+        for kind in ["simple-path", "random-walk"] {
+            let integrator = Integrator::new(kind).unwrap();
+            assert_eq!(floor_radiance(&integrator, &black, &rgbtospec), 0.);
+        }
+    }
+
+    #[test]
+    fn test_debug_light_id() {
+        let rgbtospec = flat_rgbtospec();
+        let light = AreaLightSource::new_default(&rgbtospec, ColorSpace::Srgb);
+
+        let shape = |shape, area_light| {
+            let material = Material::new_empty();
+            ShapeWithParams::new(shape, material, area_light, Mat4::IDENTITY, false, None)
+        };
+
+        let shapes = vec![
+            shape(quad(vec3(-2., -2., 0.), Vec3::X * 4., Vec3::Y * 4.), None),
+            shape(
+                quad(vec3(-1.5, -0.5, 2.), Vec3::Y, Vec3::X),
+                Some(light.clone()),
+            ),
+            shape(quad(vec3(0.5, -0.5, 2.), Vec3::Y, Vec3::X), Some(light)),
+        ];
+
+        let scene = Scene::init(SceneDescription {
+            options: ScreenWideOptions::default(),
+            shapes,
+            infinite_light: None,
+            projection_lights: Vec::new(),
+        })
+        .unwrap();
+
+        let debug = DebugIntegrator::new(DebugMode::LightId);
+        let mut rng = Sampler::seed_from_u64(0);
+
+        let ray = Ray::new(vec3(0., 0., 1.), -Vec3::Z);
+        let mut colors: Vec<Vec3> = Vec::new();
+        for _ in 0..64 {
+            let rgb = debug.light_id_rgb(scene.trace_ray(&ray), &scene, &mut rng);
+            if !colors.contains(&rgb) {
+                colors.push(rgb);
+            }
+        }
+
+        assert_eq!(colors.len(), 2);
+        assert!(colors.iter().all(|c| DebugIntegrator::PALETTE.contains(c)));
+
+        // Nothing is hit
+        let ray = Ray::new(vec3(0., 0., 1.), vec3(10., 0., -1.));
+        assert_eq!(
+            debug.light_id_rgb(scene.trace_ray(&ray), &scene, &mut rng),
+            Vec3::ZERO
+        );
+    }
+
+    #[test]
+    fn test_mis_heuristics_converge() {
+        let rgbtospec = flat_rgbtospec();
+        let glossy = Material::Conductor(ConductorMaterial::new(
+            &rgbtospec,
+            Vec3::ONE,
+            Vec3::ONE,
+            MaterialRoughness::new(0.3, 0.3),
+        ));
+        let light = AreaLightSource::new_default(&rgbtospec, ColorSpace::Srgb);
+
+        let shape = |shape, material, area_light| {
+            ShapeWithParams::new(shape, material, area_light, Mat4::IDENTITY, false, None)
+        };
+
+        // The downwards-facing light is around the mirror direction of the view ray
+        let shapes = vec![
+            shape(
+                quad(vec3(-2., -2., 0.), Vec3::X * 4., Vec3::Y * 4.),
+                glossy,
+                None,
+            ),
+            shape(
+                quad(vec3(0.5, -0.5, 1.), Vec3::Y, Vec3::X),
+                Material::new_empty(),
+                Some(light),
+            ),
+        ];
+
+        let scene = Scene::init(SceneDescription {
+            options: ScreenWideOptions::default(),
+            shapes,
+            infinite_light: None,
+            projection_lights: Vec::new(),
+        })
+        .unwrap();
+
+        let ray = Ray::new(vec3(-1., 0., 1.), vec3(1., 0., -1.).normalize());
+        let scratch = RenderScratch::new(&rgbtospec);
+        let mean_radiance = |heuristic| {
+            let integrator = Integrator::new("simple-path")
+                .unwrap()
+                .with_mis_heuristic(heuristic);
+            let mut rng = Sampler::seed_from_u64(0);
+
+            const SAMPLES: usize = 16384;
+            let mut radiance = 0.;
+            for _ in 0..SAMPLES {
+                let mut lambdas = SampledWavelengths::new_sample_uniform(&mut rng);
+                let l: SpectralQuantity =
+                    integrator.ray_l(&ray, &mut lambdas, &scene, &scratch, &mut rng);
+                radiance += l.average();
+            }
+
+            radiance / SAMPLES as f32
+        };
+
+        let power = mean_radiance(MisHeuristic::Power);
+        let balance = mean_radiance(MisHeuristic::Balance);
+        assert!(power > 0.);
+        assert!((power - balance).abs() < 0.02 * power, "{power} {balance}");
+    }
+
+    #[test]
+    fn test_sphere_light_falloff() {
+        let rgbtospec = flat_rgbtospec();
+        let light = AreaLightSource::new_default(&rgbtospec, ColorSpace::Srgb);
+        let (radius, height) = (0.5, 2.);
+
+        let floor = ShapeWithParams::new(
+            quad(vec3(-50., -50., 0.), Vec3::X * 100., Vec3::Y * 100.),
+            Material::new_default(&rgbtospec),
+            None,
+            Mat4::IDENTITY,
+            false,
+            None,
+        );
+        let sphere = ShapeWithParams::new(
+            scene_description::Shape::Sphere(scene_description::Sphere::new(radius)),
+            Material::Black,
+            Some(light),
+            Mat4::from_translation(Vec3::Z * height),
+            false,
+            None,
+        );
+
+        let scene = Scene::init(SceneDescription {
+            options: ScreenWideOptions::default(),
+            shapes: vec![floor, sphere],
+            infinite_light: None,
+            projection_lights: Vec::new(),
+        })
+        .unwrap();
+
+        let Material::Diffuse(floor_material) = Material::new_default(&rgbtospec) else {
+            unreachable!()
+        };
+        let reflectance = floor_material.reflectance.rgb().x;
+        let emission = scene.lights[0].emission.rgb().x;
+
+        let integrator = Integrator::new("simple-path").unwrap();
+        let scratch = RenderScratch::new(&rgbtospec);
+        let mut rng = Sampler::seed_from_u64(0);
+
+        for dist in [0., 1., 2., 4.] {
+            const SAMPLES: usize = 4096;
+            let ray = Ray::new(vec3(dist, 0., 0.1), -Vec3::Z);
+            let radiance = (0..SAMPLES)
+                .map(|_| {
+                    integrator
+                        .ray_l::<Vec3>(&ray, &mut (), &scene, &scratch, &mut rng)
+                        .x
+                })
+                .sum::<f32>()
+                / SAMPLES as f32;
+
+            // Irradiance from a fully visible sphere is π L r² cos θ / d², with the distance d
+            // to the center and cos θ = height / d
+            let center_dist = f32::sqrt(height * height + dist * dist);
+            let irradiance = PI * emission * sqr(radius) * height / center_dist.powi(3);
+            let expected = reflectance / PI * irradiance;
+            assert!(
+                (radiance - expected).abs() < 0.02 * expected,
+                "distance {dist}: {radiance} {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_sphere_light_mis() {
+        let rgbtospec = flat_rgbtospec();
+        let glossy = Material::Conductor(ConductorMaterial::new(
+            &rgbtospec,
+            Vec3::ONE,
+            Vec3::ONE,
+            MaterialRoughness::new(0.3, 0.3),
+        ));
+        let light = AreaLightSource::new_default(&rgbtospec, ColorSpace::Srgb);
+        let (center, radius) = (vec3(1.5, 0., 1.5), 0.5);
+
+        let floor = ShapeWithParams::new(
+            quad(vec3(-50., -50., 0.), Vec3::X * 100., Vec3::Y * 100.),
+            glossy.clone(),
+            None,
+            Mat4::IDENTITY,
+            false,
+            None,
+        );
+        let sphere = ShapeWithParams::new(
+            scene_description::Shape::Sphere(scene_description::Sphere::new(radius)),
+            Material::Black,
+            Some(light),
+            Mat4::from_translation(center),
+            false,
+            None,
+        );
+
+        let scene = Scene::init(SceneDescription {
+            options: ScreenWideOptions::default(),
+            shapes: vec![floor, sphere],
+            infinite_light: None,
+            projection_lights: Vec::new(),
+        })
+        .unwrap();
+        let emission = scene.lights[0].emission.rgb().x;
+
+        // The sphere is around the mirror direction of the view ray, which hits the origin
+        let ray = Ray::new(vec3(-2., 0., 2.), vec3(1., 0., -1.).normalize());
+        let scratch = RenderScratch::new(&rgbtospec);
+        let mut rng = Sampler::seed_from_u64(0);
+
+        // The BxDF branch uses the pdf of the light sampler, uniform over the visible cone
+        let cos_theta_max = (1. - sqr(radius / center.length())).sqrt();
+        let cone_pdf = sampling::pdf_uniform_cone(cos_theta_max);
+        let on_sphere = center - Vec3::X * radius;
+        let sample = ShapeSample::new(on_sphere, -Vec3::X);
+        let pdf = scene.light_pdf(0, Vec3::ZERO, &sample);
+        assert!((pdf - cone_pdf).abs() < 1e-4 * cone_pdf, "{pdf} {cone_pdf}");
+
+        // Reference without MIS, the sphere covers the whole cone
+        const REFERENCE_SAMPLES: usize = 1 << 18;
+        let mut bxdf_rng = Sampler::seed_from_u64(1);
+        let mut bxdf = Bxdf::new(&glossy, &scratch, &mut bxdf_rng);
+        let (axis, b1, b2) = vecmath::coordinate_system(center.normalize());
+        let reference = (0..REFERENCE_SAMPLES)
+            .map(|_| {
+                let local = sampling::sample_uniform_cone(cos_theta_max, &mut rng);
+                let light_dir = b1 * local.x + b2 * local.y + axis * local.z;
+                let sgeom = ShadingGeometry::new(&Vec3::Z, &light_dir, &ray.dir);
+                let f: Vec3 = bxdf.eval(&sgeom, &());
+                f.x * emission * sgeom.cos_theta / cone_pdf
+            })
+            .sum::<f32>()
+            / REFERENCE_SAMPLES as f32;
+
+        for heuristic in [MisHeuristic::Power, MisHeuristic::Balance] {
+            let integrator = Integrator::new("simple-path")
+                .unwrap()
+                .with_mis_heuristic(heuristic);
+
+            const SAMPLES: usize = 16384;
+            let values: Vec<f32> = (0..SAMPLES)
+                .map(|_| {
+                    integrator
+                        .ray_l::<Vec3>(&ray, &mut (), &scene, &scratch, &mut rng)
+                        .x
+                })
+                .collect();
+            let mean = values.iter().sum::<f32>() / SAMPLES as f32;
+            let variance = values.iter().map(|v| sqr(v - mean)).sum::<f32>() / (SAMPLES - 1) as f32;
+
+            assert!(
+                (mean - reference).abs() < 0.02 * reference,
+                "{heuristic:?}: {mean} {reference}"
+            );
+            // Neither strategy alone has outliers, the relative deviation stays small
+            assert!(variance.sqrt() < reference, "{heuristic:?}: {variance}");
+        }
+    }
+
+    #[test]
+    fn test_reversed_emitter() {
+        // Two periods of a steep sine along U, the shading normals tilt almost into the surface
+        let bump_path = test_dir("reversed-emitter").join("sine.png");
+        let image = image::GrayImage::from_fn(64, 1, |x, _| {
+            let height = 0.5 + 0.5 * (4. * PI * (x as f32 + 0.5) / 64.).sin();
+            image::Luma([(height * 255.).round() as u8])
+        });
+        image.save(&bump_path).unwrap();
+
+        let rgbtospec = flat_rgbtospec();
+        // A quad light halfway between the floor and the ceiling, facing up unless reversed
+        let scene = |reverse_orientation: bool, bump_map: Option<std::path::PathBuf>| {
+            let diffuse = Material::Diffuse(DiffuseMaterial::new(&rgbtospec, Vec3::splat(0.8)));
+            let light = AreaLightSource::new_default(&rgbtospec, ColorSpace::Srgb);
+            let shape = |shape, material, area_light, reverse_normals| {
+                ShapeWithParams::new(
+                    shape,
+                    material,
+                    area_light,
+                    Mat4::IDENTITY,
+                    reverse_normals,
+                    None,
+                )
+            };
+
+            let shapes = vec![
+                shape(
+                    quad(vec3(-5., -5., 0.), Vec3::X * 10., Vec3::Y * 10.),
+                    diffuse.clone(),
+                    None,
+                    false,
+                ),
+                shape(
+                    quad(vec3(-5., -5., 2.), Vec3::Y * 10., Vec3::X * 10.),
+                    diffuse,
+                    None,
+                    false,
+                ),
+                shape(
+                    quad(vec3(-0.5, -0.5, 1.), Vec3::X, Vec3::Y),
+                    Material::Black,
+                    Some(light),
+                    reverse_orientation,
+                )
+                .with_bump_map(bump_map),
+            ];
+
+            Scene::init(SceneDescription {
+                options: ScreenWideOptions::default(),
+                shapes,
+                infinite_light: None,
+                projection_lights: Vec::new(),
+            })
+            .unwrap()
+        };
+
+        let scratch = RenderScratch::new(&rgbtospec);
+        let mut rng = Sampler::seed_from_u64(0);
+
+        for kind in ["simple-path", "random-walk"] {
+            let integrator = Integrator::new(kind).unwrap();
+            let mut radiance = |scene: &Scene, ray: &Ray, samples: usize| {
+                (0..samples)
+                    .map(|_| integrator.ray_l::<Vec3>(ray, &mut (), scene, &scratch, &mut rng))
+                    .sum::<Vec3>()
+                    .x
+                    / samples as f32
+            };
+
+            for bump_map in [None, Some(bump_path.clone())] {
+                let reversed = scene(true, bump_map);
+                let emission = reversed.lights[0].emission.rgb().x;
+
+                // Looking at the light, also at grazing angles where the bump-mapped normals
+                // face away from the ray. The light itself is black, only its emission is seen.
+                for i in 0..32 {
+                    let target = vec3(-0.45 + 0.9 * i as f32 / 31., 0.1, 1.);
+                    let dir = vec3(1., 0., 0.2).normalize();
+                    let from_below = Ray::new(target - dir, dir);
+                    let from_above = Ray::new(target + dir, -dir);
+                    assert_eq!(radiance(&reversed, &from_below, 1), emission, "{kind} {i}");
+                    assert_eq!(radiance(&reversed, &from_above, 1), 0., "{kind} {i}");
+                }
+            }
+
+            // The reversed light illuminates the floor instead of the ceiling
+            let floor = Ray::new(vec3(0., 0., 0.5), -Vec3::Z);
+            let ceiling = Ray::new(vec3(0., 0., 1.5), Vec3::Z);
+            for (reverse_orientation, lit, dark) in
+                [(true, &floor, &ceiling), (false, &ceiling, &floor)]
+            {
+                let scene = scene(reverse_orientation, None);
+                let lit = radiance(&scene, lit, 1024);
+                let dark = radiance(&scene, dark, 1024);
+                assert!(
+                    lit > 2. * dark,
+                    "{kind} {reverse_orientation}: {lit} {dark}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rgb_matches_spectral() {
+        let rgbtospec = flat_rgbtospec();
+        let light = AreaLightSource::new_default(&rgbtospec, ColorSpace::Srgb);
+
+        // The flat table maps every reflectance to 0.5, so the default gray is the same in both modes
+        let shape = |shape, area_light| {
+            let material = Material::new_default(&rgbtospec);
+            ShapeWithParams::new(shape, material, area_light, Mat4::IDENTITY, false, None)
+        };
+
+        let shapes = vec![
+            shape(quad(vec3(-2., -2., 0.), Vec3::X * 4., Vec3::Y * 4.), None),
+            shape(quad(vec3(-0.5, -0.5, 1.), Vec3::Y, Vec3::X), Some(light)),
+        ];
+
+        let scene = Scene::init(SceneDescription {
+            options: ScreenWideOptions::default(),
+            shapes,
+            infinite_light: None,
+            projection_lights: Vec::new(),
+        })
+        .unwrap();
+
+        const SAMPLES: usize = 16384;
+        let ray = Ray::new(vec3(1., 0., 0.5), -Vec3::Z);
+        let scratch = RenderScratch::new(&rgbtospec);
+
+        let mean_luminance = |to_luminance: &dyn Fn(&mut Sampler) -> f64| {
+            let mut rng = Sampler::seed_from_u64(0);
+            (0..SAMPLES).map(|_| to_luminance(&mut rng)).sum::<f64>() / SAMPLES as f64
+        };
+
+        for kind in ["simple-path", "random-walk"] {
+            let integrator = Integrator::new(kind).unwrap();
+
+            let spectral = mean_luminance(&|rng| {
+                let mut lambdas = SampledWavelengths::new_sample_uniform(rng);
+                let l: SpectralQuantity =
+                    integrator.ray_l(&ray, &mut lambdas, &scene, &scratch, rng);
+                l.to_xyz(&lambdas, Observer::Cie1931.cmfs()).y
+            });
+
+            let rgb = mean_luminance(&|rng| {
+                let l: Vec3 = integrator.ray_l(&ray, &mut (), &scene, &scratch, rng);
+                l.to_xyz(&(), Observer::Cie1931.cmfs()).y
+            });
+
+            assert!(spectral > 0.);
+            assert!(
+                (spectral - rgb).abs() < 0.03 * spectral,
+                "{kind}: {spectral} {rgb}"
+            );
+        }
+    }
+
+    /// White furnace: a unit sphere with the material inside of a uniform environment.
+    /// Returns the ratio of the radiance reflected by the sphere and the environment radiance,
+    /// which is 1 for a lossless material.
+    fn furnace_ratio(kind: &str, material: Material, rgbtospec: &RGB2Spec) -> f32 {
+        furnace_ratio_with(&Integrator::new(kind).unwrap(), material, rgbtospec)
+    }
+
+    fn furnace_ratio_with(
+        integrator: &Integrator,
+        material: Material,
+        rgbtospec: &RGB2Spec,
+    ) -> f32 {
+        let env_path = test_dir("furnace").join("env.hdr");
+        write_test_hdr(&env_path, 1, &[Vec3::ONE]);
+
+        let sphere = scene_description::Shape::Sphere(scene_description::Sphere::new(1.));
+        let shape = ShapeWithParams::new(sphere, material, None, Mat4::IDENTITY, false, None);
+
+        let scene = Scene::init(SceneDescription {
+            options: ScreenWideOptions::default(),
+            shapes: vec![shape],
+            infinite_light: Some(InfiniteLightSource::new(1., env_path, None)),
+            projection_lights: Vec::new(),
+        })
+        .unwrap();
+
+        let env = scene.infinite_light.as_ref().unwrap();
+        let mut rng = Sampler::seed_from_u64(0);
+        let scratch = RenderScratch::new(rgbtospec);
+
+        let (mut radiance, mut env_radiance) = (0., 0.);
+        // Rays towards the sphere's silhouette, so every incident angle is covered
+        const STEPS: usize = 32;
+        for yi in 0..STEPS {
+            for xi in 0..STEPS {
+                let x = 2. * (xi as f32 + 0.5) / STEPS as f32 - 1.;
+                let y = 2. * (yi as f32 + 0.5) / STEPS as f32 - 1.;
+                if x * x + y * y > 0.95 {
+                    continue;
+                }
+
+                let ray = Ray::new(vec3(x, y, -5.), Vec3::Z);
+                for _ in 0..4 {
+                    let mut lambdas = SampledWavelengths::new_sample_uniform(&mut rng);
+                    let l: SpectralQuantity =
+                        integrator.ray_l(&ray, &mut lambdas, &scene, &scratch, &mut rng);
+                    assert!(l.vals.iter().all(|v| v.is_finite() && *v >= 0.));
+
+                    radiance += l.average();
+                    env_radiance += env.sample(ray.dir, rgbtospec).eval(&lambdas).average();
+                }
+            }
+        }
+
+        radiance / env_radiance
+    }
+
+    #[test]
+    fn test_white_furnace_diffuse() {
+        let rgbtospec = flat_rgbtospec();
+
+        // The flat table maps every reflectance to 0.5, unbounded spectra are scaled back to 1
+        let white = RgbSpectrum::new(&rgbtospec, Vec3::ONE, RgbSpectrumKind::Unbounded);
+        let material = Material::Diffuse(DiffuseMaterial {
+            reflectance: white,
+            use_vertex_colors: false,
+        });
+
+        for kind in ["simple-path", "random-walk"] {
+            let ratio = furnace_ratio(kind, material.clone(), &rgbtospec);
+            assert!((ratio - 1.).abs() < 0.01, "{kind}: {ratio}");
+        }
+    }
+
+    #[test]
+    fn test_white_furnace_conductor() {
+        let rgbtospec = flat_rgbtospec();
+
+        // The high absorption makes the Fresnel reflectance close to 1
+        let conductor = |roughness| {
+            Material::Conductor(ConductorMaterial::new(
+                &rgbtospec,
+                Vec3::ONE,
+                Vec3::splat(100.),
+                MaterialRoughness::new(roughness, roughness),
+            ))
+        };
+
+        // The multiple-scattering compensation keeps even the rough conductors lossless
+        for kind in ["simple-path", "random-walk"] {
+            for roughness in [0.05, 0.3, 0.6, 1.] {
+                let ratio = furnace_ratio(kind, conductor(roughness), &rgbtospec);
+                assert!((ratio - 1.).abs() < 0.02, "{kind} {roughness}: {ratio}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_russian_roulette_start_depth() {
+        let uniform = Uniform::from(0f32..1f32);
+        let mut rng = Sampler::seed_from_u64(0);
+
+        // Zero throughput, the roulette terminates 95% of the paths once it's applied
+        for start_depth in [0, 3, 7] {
+            for depth in 0..=start_depth {
+                for _ in 0..1000 {
+                    let res = russian_roulette(depth, start_depth, &uniform, &mut rng, &Vec3::ZERO);
+                    assert_eq!(res, Some(1.), "{start_depth} {depth}");
+                }
+            }
+
+            let terminated = (0..1000)
+                .filter(|_| {
+                    russian_roulette(
+                        start_depth + 1,
+                        start_depth,
+                        &uniform,
+                        &mut rng,
+                        &Vec3::ZERO,
+                    )
+                    .is_none()
+                })
+                .count();
+            assert!(terminated > 900, "{start_depth}: {terminated}");
+        }
+
+        // A start depth past any path length disables the roulette
+        for depth in [4, 100, 10_000] {
+            let res = russian_roulette(depth, u32::MAX, &uniform, &mut rng, &Vec3::ZERO);
+            assert_eq!(res, Some(1.));
+        }
+    }
+
+    #[test]
+    fn test_projection_light_support() {
+        assert!(Integrator::new("simple-path")
+            .unwrap()
+            .supports_projection_lights());
+        assert!(!Integrator::new("random-walk")
+            .unwrap()
+            .supports_projection_lights());
+        assert!(!Integrator::new("debug-light-id")
+            .unwrap()
+            .supports_projection_lights());
+    }
+
+    #[test]
+    fn test_rr_start_depth_config() {
+        let simple = Integrator::new("simple-path")
+            .unwrap()
+            .with_rr_start_depth(8)
+            .with_mis_heuristic(MisHeuristic::Balance);
+        match simple {
+            Integrator::SimplePath(simple) => {
+                assert_eq!(simple.rr_start_depth, 8);
+                assert_eq!(simple.mis_heuristic, MisHeuristic::Balance);
+            }
+            _ => unreachable!(),
+        }
+
+        match Integrator::new("random-walk").unwrap() {
+            Integrator::RandomWalk(random_walk) => {
+                assert_eq!(random_walk.rr_start_depth, DEFAULT_RR_START_DEPTH)
+            }
+            _ => unreachable!(),
+        }
+        match Integrator::new("random-walk")
+            .unwrap()
+            .with_rr_start_depth(0)
+        {
+            Integrator::RandomWalk(random_walk) => assert_eq!(random_walk.rr_start_depth, 0),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_uniform_hemisphere_sampling() {
+        let rgbtospec = flat_rgbtospec();
+        let diffuse = Material::new_default(&rgbtospec);
+
+        // A convex diffuse object in a white furnace reflects its albedo
+        let mut ratios = Vec::new();
+        for sampling in [HemisphereSampling::Bxdf, HemisphereSampling::Uniform] {
+            let integrator = Integrator::new("random-walk")
+                .unwrap()
+                .with_hemisphere_sampling(sampling);
+            let ratio = furnace_ratio_with(&integrator, diffuse.clone(), &rgbtospec);
+            assert!((ratio - 0.5).abs() < 0.02, "{sampling:?}: {ratio}");
+            ratios.push(ratio);
+        }
+
+        assert!((ratios[0] - ratios[1]).abs() < 0.02, "{ratios:?}");
+        assert_eq!(
+            HemisphereSampling::new("uniform").unwrap(),
+            HemisphereSampling::Uniform
+        );
+        assert!(HemisphereSampling::new("cosine").is_err());
     }
 }
@@ -0,0 +1,72 @@
+use rand::{distributions::Uniform, prelude::Distribution};
+
+use crate::{
+    color::quantity::Quantity, pbrt_loader::scene_description::HomogeneousMedium, sampler::Sampler,
+};
+
+/// What happens to a ray on its way to the next surface
+pub enum MediumEvent<C> {
+    /// The ray reaches the surface, the throughput is multiplied by the weight
+    Pass { weight: C },
+    /// The ray scatters after the distance t, the throughput is multiplied by the weight
+    Scatter { t: f32, weight: C },
+}
+
+/// Coefficients of a homogeneous medium at the sampled wavelengths
+pub struct MediumCoefficients<C> {
+    sigma_s: C,
+    sigma_t: C,
+    /// Average of the extinction over the wavelengths, used for sampling the distances
+    sigma_sampling: f32,
+}
+
+impl<C: Quantity> MediumCoefficients<C> {
+    pub fn new(medium: &HomogeneousMedium, lambdas: &C::Lambdas) -> Self {
+        let sigma_a = medium.sigma_a.eval::<C>(lambdas) * medium.scale;
+        let sigma_s = medium.sigma_s.eval::<C>(lambdas) * medium.scale;
+        let sigma_t = sigma_a + sigma_s;
+
+        Self {
+            sigma_s,
+            sigma_t,
+            sigma_sampling: sigma_t.average(),
+        }
+    }
+
+    /// Fraction of light that passes through the distance without being absorbed or scattered
+    pub fn transmittance(&self, distance: f32) -> C {
+        self.sigma_t.map(|sigma_t| (-sigma_t * distance).exp())
+    }
+
+    /// Samples an exponential free-flight distance, the surface is `max_distance` away
+    /// (infinity if nothing was hit). All wavelengths share the distance, which is sampled
+    /// with the average extinction and weighted for each wavelength.
+    pub fn sample_event(
+        &self,
+        max_distance: f32,
+        uniform: &Uniform<f32>,
+        rng: &mut Sampler,
+    ) -> MediumEvent<C> {
+        let sigma = self.sigma_sampling;
+        if sigma <= 0. {
+            return MediumEvent::Pass { weight: C::ONE };
+        }
+
+        // The ratios of the exponentials are computed directly, so that they don't underflow
+        let t = -(1. - uniform.sample(rng)).ln() / sigma;
+        if t >= max_distance {
+            // Transmittance divided by the probability of passing, exp(-sigma * max_distance)
+            let weight = self
+                .sigma_t
+                .map(|sigma_t| (-(sigma_t - sigma) * max_distance).exp());
+            MediumEvent::Pass { weight }
+        } else {
+            // Transmittance times sigma_s divided by the pdf, sigma * exp(-sigma * t)
+            let ratio = self.sigma_t.map(|sigma_t| (-(sigma_t - sigma) * t).exp());
+            MediumEvent::Scatter {
+                t,
+                weight: ratio * self.sigma_s * (1. / sigma),
+            }
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use rand::distributions::Uniform;
+use rgb2spec::RGB2Spec;
+
+use crate::pbrt_loader::scene_description::Material;
+
+/// Per-thread state that is passed to every `Integrator::ray_l()` call,
+/// so that it doesn't have to be recreated for every ray
+pub struct RenderScratch<'r> {
+    /// Uniform distribution on [0, 1)
+    pub uniform: Uniform<f32>,
+    /// Fetched once, so that the OnceLock isn't accessed for every ray
+    pub rgbtospec: &'r RGB2Spec,
+    /// Every material except interfaces is rendered as a diffuse one with its albedo,
+    /// set by the forcediffuse option
+    pub force_diffuse: bool,
+    /// Replaces every material except interfaces, set by --override-material
+    pub override_material: Option<&'r Material>,
+}
+
+impl<'r> RenderScratch<'r> {
+    pub fn new(rgbtospec: &'r RGB2Spec) -> Self {
+        Self {
+            uniform: Uniform::from(0f32..1f32),
+            rgbtospec,
+            force_diffuse: false,
+            override_material: None,
+        }
+    }
+
+    pub fn with_force_diffuse(mut self, force_diffuse: bool) -> Self {
+        self.force_diffuse = force_diffuse;
+        self
+    }
+
+    pub fn with_override_material(mut self, material: Option<&'r Material>) -> Self {
+        self.override_material = material;
+        self
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use rand::{prelude::Distribution, rngs::SmallRng, SeedableRng};
+
+    use crate::color::spectrum::rgb_spectrum::flat_rgbtospec;
+
+    use super::*;
+
+    #[test]
+    fn test_reused_uniform_matches_new() {
+        let rgbtospec = flat_rgbtospec();
+        let scratch = RenderScratch::new(&rgbtospec);
+
+        let mut rng_a = SmallRng::seed_from_u64(0);
+        let mut rng_b = SmallRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let reused = scratch.uniform.sample(&mut rng_a);
+            let new = Uniform::from(0f32..1f32).sample(&mut rng_b);
+            assert_eq!(reused, new);
+        }
+    }
+}
@@ -1,6 +1,10 @@
 use glam::Vec3;
 
 pub struct ShadingGeometry {
+    /// Shading normal
+    pub n: Vec3,
+    /// Sampled direction, towards the light
+    pub l: Vec3,
     pub cos_theta: f32,
     /// Halfway vector
     pub h: Vec3,
@@ -19,6 +23,8 @@ impl ShadingGeometry {
         let hov = h.dot(-*hit_ray_dir);
 
         Self {
+            n: *normal,
+            l: *sample_dir,
             cos_theta,
             h,
             noh,
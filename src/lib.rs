@@ -0,0 +1,122 @@
+#![feature(array_chunks)]
+#![feature(result_option_inspect)]
+#![feature(float_next_up_down)]
+#![feature(iter_partition_in_place)]
+#![feature(allocator_api)]
+#![allow(dead_code)]
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use eyre::{eyre, Result};
+
+use color::quantity::ColorMode;
+use film::Film;
+use integrator::{HemisphereSampling, Integrator, MisHeuristic, DEFAULT_RR_START_DEPTH};
+use pbrt_loader::scene_description::SceneDescription;
+use render_threads::{RenderBudget, RenderContext, RenderThreads};
+use sampler::SamplerKind;
+
+pub mod bvh;
+pub mod bxdf;
+pub mod camera;
+pub mod color;
+pub mod film;
+pub mod geometry;
+pub mod image_writer;
+pub mod integrator;
+pub mod math;
+pub mod pbrt_loader;
+pub mod render_threads;
+pub mod sampler;
+pub mod sampling;
+pub mod scene;
+pub mod stats;
+pub mod texture;
+pub mod tonemap;
+pub mod util;
+pub mod vecmath;
+
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub num_threads: usize,
+    pub integrator: String,
+    pub mis_heuristic: MisHeuristic,
+    /// Number of bounces before russian roulette can terminate the paths
+    pub rr_start_depth: u32,
+    /// Only used by the random walk integrator
+    pub hemisphere_sampling: HemisphereSampling,
+    pub color_mode: ColorMode,
+    pub sampler: SamplerKind,
+    /// Number of samples taken for each pixel
+    pub samples: u32,
+    /// Stops the render early when the next sample wouldn't fit into this time
+    pub time_limit: Option<Duration>,
+    /// Fixed RNG seed, the render is reproducible when used together with a single thread
+    pub seed: Option<u64>,
+    /// Renders only these pixels, the rest of the film stays black
+    pub pixels: Option<Vec<(usize, usize)>>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            num_threads: num_cpus::get(),
+            integrator: "simple-path".to_string(),
+            mis_heuristic: MisHeuristic::default(),
+            rr_start_depth: DEFAULT_RR_START_DEPTH,
+            hemisphere_sampling: HemisphereSampling::default(),
+            color_mode: ColorMode::default(),
+            sampler: SamplerKind::default(),
+            samples: 16,
+            time_limit: None,
+            seed: None,
+            pixels: None,
+        }
+    }
+}
+
+/// Renders the scene synchronously without opening a window.
+/// Returns the Film and the number of samples taken, which can be lower than `options.samples`
+/// with a time limit. The Film contains the pixel estimates, the means of their samples.
+pub fn render_scene(scene_desc: SceneDescription, options: &RenderOptions) -> Result<(Film, u32)> {
+    let integrator = Integrator::new(&options.integrator)?
+        .with_mis_heuristic(options.mis_heuristic)
+        .with_rr_start_depth(options.rr_start_depth)
+        .with_hemisphere_sampling(options.hemisphere_sampling);
+    let mut render_context = RenderContext::new(scene_desc, integrator)?
+        .with_color_mode(options.color_mode)?
+        .with_sampler(options.sampler);
+    if let Some(pixels) = &options.pixels {
+        render_context = render_context.with_pixels(pixels.clone())?;
+    }
+    let render_context = Arc::new(render_context);
+
+    let mut threads = RenderThreads::new(
+        options.num_threads,
+        options.seed,
+        Arc::clone(&render_context),
+    )?;
+
+    let budget = RenderBudget::new(Some(options.samples), options.time_limit);
+    let start = Instant::now();
+    let mut last_pass = Duration::ZERO;
+    let mut samples = 0;
+
+    while budget.allows_next_pass(samples, start.elapsed(), last_pass) {
+        let pass_start = Instant::now();
+        threads.render_once();
+        last_pass = pass_start.elapsed();
+        samples += 1;
+    }
+
+    // Joins the threads, which drops their references to the context
+    drop(threads);
+
+    let render_context = Arc::try_unwrap(render_context)
+        .map_err(|_| eyre!("Render context is still shared after the render threads stopped"))?;
+
+    Ok((render_context.film, samples))
+}
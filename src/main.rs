@@ -1,44 +1,29 @@
-#![feature(array_chunks)]
-#![feature(result_option_inspect)]
-#![feature(float_next_up_down)]
-#![feature(iter_partition_in_place)]
-#![feature(allocator_api)]
-#![allow(dead_code)]
-
-use color::color_space::ColorSpace;
-use integrator::Integrator;
-use std::{sync::Arc, time::Duration, vec};
-
-use camera::Camera;
-use eyre::Result;
-use image_writer::ImageWriter;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+    vec,
+};
+
+use eyre::{eyre, Result};
 use lexopt::{
     Arg::{Long, Short},
     ValueExt,
 };
 use minifb::{Key, Window, WindowOptions};
 
-use film::Film;
-use render_threads::RenderContext;
-
-use crate::scene::Scene;
-
-mod bvh;
-mod bxdf;
-mod camera;
-mod color;
-mod film;
-mod geometry;
-mod image_writer;
-mod integrator;
-mod math;
-mod pbrt_loader;
-mod render_threads;
-mod sampling;
-mod scene;
-mod texture;
-mod util;
-mod vecmath;
+use rt_summer::{
+    camera::FovAxis,
+    color::{quantity::ColorMode, spectrum::Observer},
+    film::{DepthMode, Film},
+    image_writer::{ExrLayer, ImageFormat, ImageWriter},
+    integrator::{HemisphereSampling, Integrator, MisHeuristic, DEFAULT_RR_START_DEPTH},
+    pbrt_loader::{self, scene_description::MaterialOverride},
+    render_threads::{RenderBudget, RenderContext, RenderReport, RenderThreads, DEFAULT_TILE_SIZE},
+    sampler::SamplerKind,
+    stats::{Stage, STATS},
+    tonemap::Tonemapper,
+    util,
+};
 
 struct FrameBuffer {
     pub buffer: Vec<u32>,
@@ -51,20 +36,11 @@ impl FrameBuffer {
         }
     }
 
-    fn copy_from_film(&mut self, film: &Film, samples: u32) {
+    fn copy_from_film(&mut self, film: &Film, image_writer: &ImageWriter) {
         for y in 0..film.height() {
             for x in 0..film.width() {
-                let c = film.get_rgb(x, y);
-
-                // Divide by the number of samples
-                let c = c / samples as f32;
-
-                // Tonemapping
-                let c = c / (c + 1.);
-
-                // Gamma correction
-                const GAMMA: f32 = 2.2;
-                let c = c.powf(1. / GAMMA);
+                // Use the same film scale, clamp and tonemapping as the written image
+                let c = image_writer.display_rgb(film, x, y);
 
                 // Floating point to bytes
                 let c = c.to_array().map(|f| (f * 255.0) as u8);
@@ -80,6 +56,57 @@ pub struct CmdArgs {
     num_threads: usize,
     scene_path: String,
     integrator: String,
+    mis_heuristic: MisHeuristic,
+    /// Number of bounces before russian roulette can terminate the paths
+    rr_start_depth: u32,
+    /// Uniform sampling validates the BxDF sampling of the random walk integrator
+    hemisphere_sampling: HemisphereSampling,
+    /// RGB is faster, but less accurate than spectral rendering
+    color_mode: ColorMode,
+    /// Color-matching functions used for converting the spectra to XYZ
+    observer: Observer,
+    sampler: SamplerKind,
+    seed: Option<u64>,
+    /// Exposure in stops, a compensation on top of the metered exposure with auto_exposure
+    exposure: f32,
+    /// Sets the exposure so that the log-average luminance is middle gray
+    auto_exposure: bool,
+    white_point: Option<f32>,
+    /// Write full-float EXR regardless of the film's savefp16
+    save_fp32: bool,
+    /// Write 16 bits per channel PNG instead of 8 bits
+    png_16bit: bool,
+    /// Overrides the camera's "fovaxis"
+    fov_axis: Option<FovAxis>,
+    /// Renders every material except interfaces with this one
+    override_material: Option<MaterialOverride>,
+    /// Samples per pixel, the render doesn't stop by itself if neither this nor time_limit is set
+    spp: Option<u32>,
+    time_limit: Option<Duration>,
+    /// Equal-time benchmark, renders headless for this long regardless of the other limits
+    bench_time: Option<Duration>,
+    /// Also writes the primary-ray hit distances
+    depth_pass: Option<DepthMode>,
+    /// Also writes the estimated variance of the pixels' luminance
+    variance_pass: bool,
+    /// Layers of the EXR image, the selected passes are enabled and not written separately
+    exr_layers: Option<Vec<ExrLayer>>,
+    /// Overrides the film's filename, the format is chosen by the extension
+    output: Option<String>,
+    /// Overwrite existing output files
+    force: bool,
+    /// Don't open the preview window, also used automatically when there's no display
+    headless: bool,
+    /// Collect timings and ray counts and print them when the render finishes
+    stats: bool,
+    /// Writes the BVH node bounds as an OBJ file before rendering
+    export_bvh: Option<String>,
+    /// Side of the square tiles distributed between the render threads
+    tile_size: usize,
+    /// Fail on scene params the renderer doesn't know instead of skipping them
+    strict: bool,
+    /// Only renders these pixels and prints their values instead of writing the images
+    pixels: Option<Vec<(usize, usize)>>,
 }
 
 impl Default for CmdArgs {
@@ -88,14 +115,41 @@ impl Default for CmdArgs {
             num_threads: num_cpus::get(),
             scene_path: "resources/scenes/cornell-box/scene-v4.pbrt".to_string(),
             integrator: "simple-path".to_string(),
+            mis_heuristic: MisHeuristic::default(),
+            rr_start_depth: DEFAULT_RR_START_DEPTH,
+            hemisphere_sampling: HemisphereSampling::default(),
+            color_mode: ColorMode::default(),
+            observer: Observer::default(),
+            sampler: SamplerKind::default(),
+            seed: None,
+            exposure: 0.,
+            auto_exposure: false,
+            white_point: None,
+            save_fp32: false,
+            png_16bit: false,
+            fov_axis: None,
+            override_material: None,
+            spp: None,
+            time_limit: None,
+            bench_time: None,
+            depth_pass: None,
+            variance_pass: false,
+            exr_layers: None,
+            output: None,
+            force: false,
+            headless: false,
+            stats: false,
+            export_bvh: None,
+            tile_size: DEFAULT_TILE_SIZE,
+            strict: false,
+            pixels: None,
         }
     }
 }
 
-fn parse_cmdargs() -> Result<CmdArgs> {
+fn parse_cmdargs(mut parser: lexopt::Parser) -> Result<CmdArgs> {
     let mut cmdargs = CmdArgs::default();
 
-    let mut parser = lexopt::Parser::from_env();
     while let Some(arg) = parser.next()? {
         match arg {
             Short('t') | Long("threads") => {
@@ -107,78 +161,397 @@ fn parse_cmdargs() -> Result<CmdArgs> {
             Short('i') | Long("integrator") => {
                 cmdargs.integrator = parser.value()?.parse()?;
             }
+            Long("mis") => {
+                cmdargs.mis_heuristic = MisHeuristic::new(&parser.value()?.string()?)?;
+            }
+            Long("rr-depth") => {
+                cmdargs.rr_start_depth = parser.value()?.parse()?;
+            }
+            Long("hemisphere-sampling") => {
+                cmdargs.hemisphere_sampling = HemisphereSampling::new(&parser.value()?.string()?)?;
+            }
+            Long("color-mode") => {
+                cmdargs.color_mode = ColorMode::new(&parser.value()?.string()?)?;
+            }
+            Long("observer") => {
+                cmdargs.observer = Observer::new(&parser.value()?.string()?)?;
+            }
+            Long("sampler") => {
+                cmdargs.sampler = SamplerKind::new(&parser.value()?.string()?)?;
+            }
+            Long("seed") => {
+                cmdargs.seed = Some(parser.value()?.parse()?);
+            }
+            Long("exposure") => {
+                cmdargs.exposure = parser.value()?.parse()?;
+            }
+            Long("auto-exposure") => {
+                cmdargs.auto_exposure = true;
+            }
+            Long("white-point") => {
+                cmdargs.white_point = Some(parser.value()?.parse()?);
+            }
+            Long("fp32") => {
+                cmdargs.save_fp32 = true;
+            }
+            Long("png16") => {
+                cmdargs.png_16bit = true;
+            }
+            Long("fov-axis") => {
+                cmdargs.fov_axis = Some(FovAxis::new(&parser.value()?.string()?)?);
+            }
+            Long("override-material") => {
+                cmdargs.override_material =
+                    Some(MaterialOverride::new(&parser.value()?.string()?)?);
+            }
+            Long("spp") => {
+                cmdargs.spp = Some(parser.value()?.parse()?);
+            }
+            Long("time-limit") => {
+                let seconds: f64 = parser.value()?.parse()?;
+                cmdargs.time_limit = Some(Duration::from_secs_f64(seconds));
+            }
+            Long("bench-seconds") => {
+                let seconds: f64 = parser.value()?.parse()?;
+                cmdargs.bench_time = Some(Duration::from_secs_f64(seconds));
+            }
+            Long("depth-pass") => {
+                cmdargs.depth_pass = Some(DepthMode::new(&parser.value()?.string()?)?);
+            }
+            Long("variance-pass") => {
+                cmdargs.variance_pass = true;
+            }
+            Long("exr-layers") => {
+                cmdargs.exr_layers = Some(ExrLayer::new_list(&parser.value()?.string()?)?);
+            }
+            Short('o') | Long("output") => {
+                cmdargs.output = Some(parser.value()?.string()?);
+            }
+            Long("force") => {
+                cmdargs.force = true;
+            }
+            Long("headless") => {
+                cmdargs.headless = true;
+            }
+            Long("stats") => {
+                cmdargs.stats = true;
+            }
+            Long("export-bvh") => {
+                cmdargs.export_bvh = Some(parser.value()?.string()?);
+            }
+            Long("tile-size") => {
+                cmdargs.tile_size = parser.value()?.parse()?;
+            }
+            Long("strict") => {
+                cmdargs.strict = true;
+            }
+            Long("pixels") => {
+                cmdargs.pixels = Some(parse_pixel_list(&parser.value()?.string()?)?);
+            }
             _ => return Err(arg.unexpected().into()),
         }
     }
 
+    if let Some(layers) = &cmdargs.exr_layers {
+        if layers.contains(&ExrLayer::Depth) && cmdargs.depth_pass.is_none() {
+            cmdargs.depth_pass = Some(DepthMode::default());
+        }
+        if layers.contains(&ExrLayer::Variance) {
+            cmdargs.variance_pass = true;
+        }
+    }
+
+    if cmdargs.seed.is_some() && cmdargs.num_threads != 1 {
+        eprintln!("Seeded renders are only reproducible with a single thread (--threads 1)");
+    }
+
     Ok(cmdargs)
 }
 
+/// Pixel coordinates in the form "x,y;x,y"
+fn parse_pixel_list(list: &str) -> Result<Vec<(usize, usize)>> {
+    list.split(';')
+        .map(|pixel| {
+            let (x, y) = pixel
+                .split_once(',')
+                .ok_or_else(|| eyre!("Expected a pixel as 'x,y', got '{}'", pixel))?;
+            Ok((x.trim().parse()?, y.trim().parse()?))
+        })
+        .collect()
+}
+
+/// Also meters the exposure, so that the preview matches the written image
+fn write_images(
+    image_writer: &mut ImageWriter,
+    render_context: &RenderContext,
+    samples: u32,
+) -> Result<()> {
+    image_writer.meter_exposure(&render_context.film);
+    image_writer.write_passes(
+        &render_context.film,
+        render_context.depth.as_ref(),
+        render_context.variance_pass,
+        samples,
+    )?;
+
+    Ok(())
+}
+
+/// Window creation fails without a display server on Linux and the BSDs
+fn display_available() -> bool {
+    if cfg!(all(unix, not(target_os = "macos"))) {
+        ["DISPLAY", "WAYLAND_DISPLAY"]
+            .iter()
+            .any(|var| std::env::var_os(var).is_some_and(|v| !v.is_empty()))
+    } else {
+        true
+    }
+}
+
+/// Returns None if there's no display to show the preview on
+fn open_window(width: usize, height: usize) -> Option<Window> {
+    if !display_available() {
+        eprintln!("No display found, rendering without the preview window");
+        return None;
+    }
+
+    match Window::new(
+        "Path tracing in one summer",
+        width,
+        height,
+        WindowOptions::default(),
+    ) {
+        Ok(window) => Some(window),
+        Err(e) => {
+            eprintln!("Couldn't open the preview window ({e}), rendering without it");
+            None
+        }
+    }
+}
+
+/// Renders the whole budget and writes the images once at the end.
+/// Unlimited renders would never finish without the window, so they take HEADLESS_SPP samples.
+/// Benchmarks take as many samples as fit into their time.
+fn render_headless(
+    mut threads: RenderThreads,
+    image_writer: &mut ImageWriter,
+    render_context: &RenderContext,
+    cmdargs: &CmdArgs,
+) -> Result<()> {
+    const HEADLESS_SPP: u32 = 16;
+
+    let mut budget = match cmdargs.bench_time {
+        Some(bench_time) => RenderBudget::new(None, Some(bench_time)),
+        None => RenderBudget::new(cmdargs.spp, cmdargs.time_limit),
+    };
+    if budget.is_unlimited() {
+        budget.samples = Some(HEADLESS_SPP);
+    }
+
+    // The passes are timed by timed_scope_duration, their sum is the render time
+    let mut elapsed = Duration::ZERO;
+    let mut last_pass = Duration::ZERO;
+    let mut samples = 0;
+
+    while budget.allows_next_pass(samples, elapsed, last_pass) {
+        let ((), pass_time) =
+            util::timed_scope_duration("1 sample render", || threads.render_once());
+        STATS.add_time(Stage::Render, pass_time);
+        elapsed += pass_time;
+        last_pass = pass_time;
+        samples += 1;
+    }
+
+    drop(threads);
+
+    let report = RenderReport { samples, elapsed };
+    println!("Render finished with {report}");
+    print_stats();
+    write_images(image_writer, render_context, samples)
+}
+
+/// Renders the budget for the listed pixels only and prints their values, nothing is written
+fn render_pixel_list(
+    mut threads: RenderThreads,
+    render_context: &RenderContext,
+    cmdargs: &CmdArgs,
+    pixels: &[(usize, usize)],
+) {
+    const PIXELS_SPP: u32 = 16;
+
+    let mut budget = RenderBudget::new(cmdargs.spp, cmdargs.time_limit);
+    if budget.is_unlimited() {
+        budget.samples = Some(PIXELS_SPP);
+    }
+
+    let start = Instant::now();
+    let mut last_pass = Duration::ZERO;
+    let mut samples = 0;
+
+    while budget.allows_next_pass(samples, start.elapsed(), last_pass) {
+        let pass_start = Instant::now();
+        threads.render_once();
+        last_pass = pass_start.elapsed();
+        STATS.add_time(Stage::Render, last_pass);
+        samples += 1;
+    }
+
+    drop(threads);
+
+    println!("Rendered {samples} samples in {:?}", start.elapsed());
+    for &(x, y) in pixels {
+        let rgb = render_context.film.get_rgb(x, y);
+        println!("{x},{y}: {} {} {}", rgb.x, rgb.y, rgb.z);
+    }
+    print_stats();
+}
+
+fn print_stats() {
+    if STATS.is_enabled() {
+        println!("{STATS}");
+    }
+}
+
 fn main() -> Result<()> {
-    let cmdargs = parse_cmdargs()?;
+    let cmdargs = parse_cmdargs(lexopt::Parser::from_env())?;
+    if cmdargs.stats {
+        STATS.enable();
+    }
 
-    let scene_desc = pbrt_loader::SceneLoader::load_from_path(&cmdargs.scene_path)?;
+    let mut scene_desc = STATS.timed(Stage::SceneLoad, || {
+        if cmdargs.strict {
+            pbrt_loader::SceneLoader::load_from_path_strict(&cmdargs.scene_path)
+        } else {
+            pbrt_loader::SceneLoader::load_from_path(&cmdargs.scene_path)
+        }
+    })?;
+    if cmdargs.save_fp32 {
+        scene_desc.options.film.save_fp16 = false;
+    }
+    if let Some(fov_axis) = cmdargs.fov_axis {
+        scene_desc.options.camera.fov_axis = fov_axis;
+    }
 
-    let image_writer = ImageWriter::new(&scene_desc.options.film);
+    let mut tonemapper = Tonemapper::new(cmdargs.exposure, cmdargs.white_point);
+    if cmdargs.auto_exposure {
+        tonemapper = tonemapper.with_auto_exposure();
+    }
+    let mut image_writer = ImageWriter::new(&scene_desc.options.film).with_tonemapper(tonemapper);
+    if let Some(output) = &cmdargs.output {
+        image_writer = image_writer.with_output(output)?;
+    }
+    if cmdargs.png_16bit {
+        image_writer = image_writer.with_png_16bit();
+    }
+    if let Some(layers) = &cmdargs.exr_layers {
+        if image_writer.format() != ImageFormat::Exr {
+            return Err(eyre!("--exr-layers needs an .exr output"));
+        }
+        image_writer = image_writer.with_exr_layers(layers.clone());
+    }
+    if !cmdargs.force && cmdargs.pixels.is_none() {
+        image_writer.check_overwrite(cmdargs.depth_pass.is_some(), cmdargs.variance_pass)?;
+    }
 
     let (width, height) = (
         scene_desc.options.film.xresolution as usize,
         scene_desc.options.film.yresolution as usize,
     );
 
-    let world_to_cam = scene_desc.options.camera.camera_from_world_transform;
-
     let mut framebuffer = FrameBuffer::new(width, height);
-    let film = Film::new(width, height, ColorSpace::Srgb);
-    let cam = Camera::new(width, height, scene_desc.options.camera.fov);
     // TODO: construct the Integrator based on the PBRT file input in the future
-    let integrator = Integrator::new(&cmdargs.integrator)?;
-
-    let scene = Scene::init(scene_desc)?;
+    let integrator = Integrator::new(&cmdargs.integrator)?
+        .with_mis_heuristic(cmdargs.mis_heuristic)
+        .with_rr_start_depth(cmdargs.rr_start_depth)
+        .with_hemisphere_sampling(cmdargs.hemisphere_sampling);
 
     // TODO: think about if some of these should be stored in the integrator itself
-    let render_context = Arc::new(RenderContext {
-        cam,
-        film,
-        scene,
-        integrator,
-        camera_from_world: world_to_cam,
-    });
+    let mut render_context = RenderContext::new(scene_desc, integrator)?
+        .with_color_mode(cmdargs.color_mode)?
+        .with_observer(cmdargs.observer)
+        .with_sampler(cmdargs.sampler)
+        .with_tile_size(cmdargs.tile_size);
+    if let Some(material) = cmdargs.override_material {
+        render_context = render_context.with_material_override(material);
+    }
+    if let Some(mode) = cmdargs.depth_pass {
+        render_context = render_context.with_depth_pass(mode);
+    }
+    if cmdargs.variance_pass {
+        render_context = render_context.with_variance_pass();
+    }
+    if let Some(pixels) = &cmdargs.pixels {
+        render_context = render_context.with_pixels(pixels.clone())?;
+    }
+    if let Some(path) = &cmdargs.export_bvh {
+        render_context.scene.bvh().export_obj(path)?;
+        println!("BVH exported to '{path}'");
+    }
+    let render_context = Arc::new(render_context);
 
     let mut threads =
-        render_threads::RenderThreads::new(&cmdargs, width, height, render_context.clone())?;
+        RenderThreads::new(cmdargs.num_threads, cmdargs.seed, render_context.clone())?;
 
-    let mut window = Window::new(
-        "Path tracing in one summer",
-        width,
-        height,
-        WindowOptions::default(),
-    )?;
+    if let Some(pixels) = &cmdargs.pixels {
+        render_pixel_list(threads, &render_context, &cmdargs, pixels);
+        return Ok(());
+    }
+
+    let window = if cmdargs.headless || cmdargs.bench_time.is_some() {
+        None
+    } else {
+        open_window(width, height)
+    };
+    let Some(mut window) = window else {
+        return render_headless(threads, &mut image_writer, &render_context, &cmdargs);
+    };
 
-    window.limit_update_rate(Some(std::time::Duration::from_secs(1)));
+    // A single pass can take seconds, the window handles its events in between
+    const EVENT_INTERVAL: Duration = Duration::from_millis(100);
+    window.limit_update_rate(Some(EVENT_INTERVAL));
 
     let mut samples = 0;
     let mut update_screen = 1;
 
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        util::timed_scope("1 sample render", || threads.render_once());
+    let budget = RenderBudget::new(cmdargs.spp, cmdargs.time_limit);
+    let start = Instant::now();
+    let mut last_pass = Duration::ZERO;
+    let mut pass_start = Instant::now();
 
-        //threads.render_once();
-
-        samples += 1;
-        println!("Samples: {samples}");
+    if budget.allows_next_pass(samples, start.elapsed(), last_pass) {
+        threads.start_pass();
+    }
 
-        if samples == update_screen {
-            if update_screen >= 512 {
-                update_screen += 256;
+    while window.is_open() && !window.is_key_down(Key::Escape) && threads.pass_in_progress() {
+        if !threads.wait_pass(EVENT_INTERVAL) {
+            window.update();
+        } else {
+            last_pass = pass_start.elapsed();
+            println!("1 sample render took: {last_pass:?}");
+            STATS.add_time(Stage::Render, last_pass);
+
+            samples += 1;
+            println!("Samples: {samples}");
+
+            if samples == update_screen {
+                if update_screen >= 512 {
+                    update_screen += 256;
+                } else {
+                    update_screen *= 2;
+                }
+
+                println!("Updating");
+                write_images(&mut image_writer, &render_context, samples)?;
+                framebuffer.copy_from_film(&render_context.film, &image_writer);
+                window.update_with_buffer(&framebuffer.buffer, width, height)?;
             } else {
-                update_screen *= 2;
+                window.update();
             }
 
-            println!("Updating");
-            image_writer.write_film(&render_context.film, samples)?;
-            framebuffer.copy_from_film(&render_context.film, samples);
-            window.update_with_buffer(&framebuffer.buffer, width, height)?;
+            if budget.allows_next_pass(samples, start.elapsed(), last_pass) {
+                pass_start = Instant::now();
+                threads.start_pass();
+            }
         }
 
         if window.is_key_down(Key::P) {
@@ -186,10 +559,110 @@ fn main() -> Result<()> {
         }
     }
 
+    // The film only holds whole passes, an interrupted one is finished and counted
+    if threads.pass_in_progress() {
+        threads.finish_pass();
+        STATS.add_time(Stage::Render, pass_start.elapsed());
+        samples += 1;
+    }
+
     drop(threads);
 
+    if !budget.is_unlimited() {
+        let report = RenderReport {
+            samples,
+            elapsed: start.elapsed(),
+        };
+        println!("Render finished with {report}");
+        print_stats();
+        write_images(&mut image_writer, &render_context, samples)?;
+        return Ok(());
+    }
+
     loop {
         std::thread::sleep(Duration::from_secs(15));
         window.update_with_buffer(&framebuffer.buffer, width, height)?;
     }
 }
+
+#[cfg(test)]
+mod test_super {
+    use glam::Vec3;
+
+    use super::*;
+
+    #[test]
+    fn test_output_args() {
+        let args = ["--output", "foo.exr"];
+        let cmdargs = parse_cmdargs(lexopt::Parser::from_args(args)).unwrap();
+        assert_eq!(cmdargs.output.as_deref(), Some("foo.exr"));
+        assert!(!cmdargs.force);
+
+        let cmdargs =
+            parse_cmdargs(lexopt::Parser::from_args(["-o", "foo.png", "--force"])).unwrap();
+        assert_eq!(cmdargs.output.as_deref(), Some("foo.png"));
+        assert!(cmdargs.force);
+    }
+
+    #[test]
+    fn test_pixels_arg() {
+        let cmdargs =
+            parse_cmdargs(lexopt::Parser::from_args(["--pixels", "100,100;200, 150"])).unwrap();
+        assert_eq!(cmdargs.pixels, Some(vec![(100, 100), (200, 150)]));
+
+        for invalid in ["100", "100,100;", "a,1"] {
+            assert!(parse_cmdargs(lexopt::Parser::from_args(["--pixels", invalid])).is_err());
+        }
+    }
+
+    #[test]
+    fn test_exr_layers_arg() {
+        let args = ["--exr-layers", "rgb,depth"];
+        let cmdargs = parse_cmdargs(lexopt::Parser::from_args(args)).unwrap();
+        assert_eq!(
+            cmdargs.exr_layers,
+            Some(vec![ExrLayer::Rgb, ExrLayer::Depth])
+        );
+        // The selected passes are enabled
+        assert_eq!(cmdargs.depth_pass, Some(DepthMode::First));
+        assert!(!cmdargs.variance_pass);
+
+        assert!(parse_cmdargs(lexopt::Parser::from_args(["--exr-layers", "alpha"])).is_err());
+    }
+
+    #[test]
+    fn test_fov_axis_arg() {
+        let cmdargs = parse_cmdargs(lexopt::Parser::from_args(["--fov-axis", "vertical"])).unwrap();
+        assert_eq!(cmdargs.fov_axis, Some(FovAxis::Vertical));
+        assert!(parse_cmdargs(lexopt::Parser::from_args(["--fov-axis", "up"])).is_err());
+    }
+
+    #[test]
+    fn test_override_material_arg() {
+        let parse = |desc| {
+            parse_cmdargs(lexopt::Parser::from_args(["--override-material", desc]))
+                .map(|cmdargs| cmdargs.override_material.unwrap())
+        };
+
+        assert_eq!(
+            parse("diffuse:0.8,0.8, 0.7").unwrap(),
+            MaterialOverride::Diffuse(Vec3::new(0.8, 0.8, 0.7))
+        );
+        assert_eq!(
+            parse("diffuse").unwrap(),
+            MaterialOverride::Diffuse(Vec3::splat(0.5))
+        );
+        assert_eq!(
+            parse("conductor").unwrap(),
+            MaterialOverride::Conductor(0.2)
+        );
+        assert_eq!(
+            parse("conductor:0.05").unwrap(),
+            MaterialOverride::Conductor(0.05)
+        );
+
+        for invalid in ["glass", "diffuse:0.8,0.8", "diffuse:a,b,c", "conductor:x"] {
+            assert!(parse(invalid).is_err());
+        }
+    }
+}
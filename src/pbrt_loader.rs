@@ -1,6 +1,7 @@
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use eyre::{eyre, Result};
@@ -9,11 +10,20 @@ use rgb2spec::RGB2Spec;
 use smallvec::SmallVec;
 
 use crate::{
+    bvh::{BvhOptions, SplitMethod},
+    bxdf::measured::MeasuredBrdf,
+    camera::FovAxis,
     color::{
         color_space::ColorSpace,
-        spectrum::rgb_spectrum::{self, RgbSpectrum, RgbSpectrumKind, RGBTOSPEC},
+        spectrum::{
+            piecewise_spectrum::PiecewiseLinearSpectrum,
+            rgb_spectrum::{self, RgbSpectrum, RgbSpectrumKind},
+            Spectrum,
+        },
     },
     pbrt_loader::lexer::Lexeme,
+    scene::primitive::PrimitiveLayout,
+    texture::ImageTexture,
     vecmath,
 };
 
@@ -21,9 +31,11 @@ use self::{
     lexer::Lexer,
     params::{ListParam, ListParamValue, ParamList, SingleValueOrList, Value, ValueList, ValueVec},
     scene_description::{
-        AreaLightSource, Camera, CameraTyp, ConductorMaterial, DiffuseMaterial, Film, FilmType,
-        InfiniteLightSource, LightSource, Material, MaterialRoughness, SceneDescription,
-        ScreenWideOptions, Shape, ShapeWithParams, Sphere, TriMesh,
+        Alpha, AreaLightSource, Camera, CameraTyp, ConductorMaterial, Cuboid, DiffuseMaterial,
+        EnvMapping, Film, FilmType, HomogeneousMedium, InfiniteLightSource, InfinitePlane,
+        LightSource, Material, MaterialRoughness, MixAmount, MixMaterial, ProjectionLightSource,
+        Quad, RenderingOptions, SceneDescription, ScreenWideOptions, Shape, ShapeWithParams,
+        Sphere, ThinFilm, TriMesh,
     },
 };
 
@@ -41,6 +53,8 @@ struct GraphicsState<'t> {
     area_light_source: Option<AreaLightSource>,
     material: Option<&'t str>,
     color_space: ColorSpace,
+    /// Set by MediumInterface, None is vacuum
+    outside_medium: Option<&'t str>,
 }
 
 impl<'t> Default for GraphicsState<'t> {
@@ -51,26 +65,64 @@ impl<'t> Default for GraphicsState<'t> {
             area_light_source: None,
             material: None,
             color_space: ColorSpace::Srgb,
+            outside_medium: None,
         }
     }
 }
 
+/// Named material with the parameters that are stored on the shapes using it
+#[derive(Clone)]
+struct MaterialDefinition {
+    material: Material,
+    bump_map: Option<PathBuf>,
+}
+
 pub struct SceneLoader<'t, 'r> {
     lexer: Lexer<'t>,
     saved_gstates: Vec<GraphicsState<'t>>,
     gstate: GraphicsState<'t>,
     file_directory: PathBuf,
-    materials: HashMap<&'t str, Material>,
+    materials: HashMap<&'t str, MaterialDefinition>,
+    media: HashMap<&'t str, HomogeneousMedium>,
+    /// Paths of float image textures, other textures aren't supported yet.
+    /// Filenames with "<UDIM>" refer to UDIM tiles.
+    float_textures: HashMap<&'t str, PathBuf>,
+    /// Shapes (their index) whose named material wasn't defined yet. PBRT allows that, they're
+    /// resolved after the whole scene is parsed.
+    unresolved_materials: Vec<(usize, &'t str)>,
+    /// Referenced textures that weren't defined, all of them are reported after parsing
+    missing_textures: Vec<&'t str>,
+    /// CTMs saved by CoordinateSystem
+    named_coordinate_systems: HashMap<&'t str, Mat4>,
     rgbtospec: &'r RGB2Spec,
+    /// Returns the RGB to spectrum table of the color space set by the ColorSpace directive.
+    /// Without it, the table passed to new() is used for every color space.
+    rgbtospec_tables: Option<&'r dyn Fn(ColorSpace) -> Result<&'r RGB2Spec>>,
+    /// Unknown shape, material and light params are errors instead of being skipped
+    strict: bool,
 }
 
 impl<'t, 'r> SceneLoader<'t, 'r> {
+    /// Skips shape, material and light params the renderer doesn't know with a warning
     pub fn load_from_path<T: AsRef<Path>>(file: T) -> Result<SceneDescription>
     where
         PathBuf: From<T>,
     {
-        rgb_spectrum::init_rgbtospec()?;
+        Self::load_file(file, false)
+    }
 
+    /// Fails on any shape, material or light param the renderer doesn't know
+    pub fn load_from_path_strict<T: AsRef<Path>>(file: T) -> Result<SceneDescription>
+    where
+        PathBuf: From<T>,
+    {
+        Self::load_file(file, true)
+    }
+
+    fn load_file<T: AsRef<Path>>(file: T, strict: bool) -> Result<SceneDescription>
+    where
+        PathBuf: From<T>,
+    {
         let txt = std::fs::read_to_string(&file)?;
         if !txt.is_ascii() {
             return Err(eyre!("Input text contains non-ASCII characters"));
@@ -79,19 +131,46 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         let mut file_path = PathBuf::from(file);
         file_path.pop();
 
-        let rgbtospec = RGBTOSPEC.get().unwrap();
+        let rgbtospec = rgb_spectrum::init_rgbtospec(ColorSpace::Srgb)?;
+
+        let mut s = SceneLoader::new(&txt, file_path, rgbtospec)
+            .with_rgbtospec_tables(&rgb_spectrum::init_rgbtospec)
+            .with_strict(strict);
+        let scene = s.load()?;
+
+        Ok(scene)
+    }
 
-        let mut s = SceneLoader {
-            lexer: Lexer::new(&txt),
+    /// Relative paths in the scene are resolved against file_directory
+    pub fn new(txt: &'t str, file_directory: PathBuf, rgbtospec: &'r RGB2Spec) -> Self {
+        SceneLoader {
+            lexer: Lexer::new(txt),
             saved_gstates: Vec::new(),
             gstate: GraphicsState::default(),
-            file_directory: file_path,
+            file_directory,
             materials: HashMap::new(),
+            media: HashMap::new(),
+            float_textures: HashMap::new(),
+            unresolved_materials: Vec::new(),
+            missing_textures: Vec::new(),
+            named_coordinate_systems: HashMap::new(),
             rgbtospec,
-        };
-        let scene = s.load()?;
+            rgbtospec_tables: None,
+            strict: false,
+        }
+    }
 
-        Ok(scene)
+    pub fn with_rgbtospec_tables(
+        mut self,
+        tables: &'r dyn Fn(ColorSpace) -> Result<&'r RGB2Spec>,
+    ) -> Self {
+        self.rgbtospec_tables = Some(tables);
+        self
+    }
+
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
     }
 
     pub fn load(&mut self) -> Result<SceneDescription> {
@@ -103,15 +182,54 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         // TODO: anything else needs to be reset ?
         self.gstate.ctm = Mat4::IDENTITY;
 
-        let (shapes, infinite_light) = self.parse_scene().inspect_err(|e| self.report_error(e))?;
+        let (mut shapes, infinite_light, projection_lights) =
+            self.parse_scene().inspect_err(|e| self.report_error(e))?;
+        self.resolve_references(&mut shapes)
+            .inspect_err(|e| self.report_error(e))?;
 
         Ok(SceneDescription {
             options,
             shapes,
             infinite_light,
+            projection_lights,
         })
     }
 
+    /// Assigns the named materials that were defined after their shapes and reports all of the
+    /// materials and textures that were never defined at once
+    fn resolve_references(&self, shapes: &mut [ShapeWithParams]) -> Result<()> {
+        let mut missing_materials = Vec::new();
+        for &(shape_index, name) in &self.unresolved_materials {
+            match self.materials.get(name) {
+                Some(def) => {
+                    shapes[shape_index].material = def.material.clone();
+                    shapes[shape_index].bump_map = def.bump_map.clone();
+                }
+                None => missing_materials.push(name),
+            }
+        }
+
+        let mut missing_textures = self.missing_textures.clone();
+        let mut errors = Vec::new();
+        for (kind, names) in [
+            ("materials", &mut missing_materials),
+            ("textures", &mut missing_textures),
+        ] {
+            names.sort_unstable();
+            names.dedup();
+            if !names.is_empty() {
+                let names: Vec<String> = names.iter().map(|name| format!("'{name}'")).collect();
+                errors.push(format!("undefined {}: {}", kind, names.join(", ")));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!("Scene references {}", errors.join("; ")))
+        }
+    }
+
     fn report_error(&self, report: &eyre::Report) {
         eprintln!("Scene loading error: '{report}'");
 
@@ -134,12 +252,13 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
     fn parse_screen_wide_options(&mut self) -> Result<ScreenWideOptions> {
         let mut screen_cam = None;
         let mut screen_film = None;
+        let mut rendering_options = RenderingOptions::default();
 
         loop {
             let dir = self.expect(Lexeme::Str(""))?.unwrap_str();
             match dir {
                 // Options exclusive to pre-WorldBegin
-                "Option" => todo!(),
+                "Option" => self.parse_option(&mut rendering_options)?,
                 "Camera" => {
                     let cam = self.parse_camera()?;
                     if screen_cam.is_some() {
@@ -151,7 +270,7 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
                     let _sampler = self.parse_sampler()?;
                     eprintln!("Sampler settings are ignored as of yet.");
                 }
-                "ColorSpace" => todo!(),
+                "ColorSpace" => self.parse_color_space()?,
                 "Film" => {
                     let film = self.parse_film()?;
                     if screen_film.is_some() {
@@ -167,35 +286,48 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
                     self.parse_param_list()?;
                     eprintln!("Integrator setting is ignored");
                 }
-                "Accelerator" => todo!(),
+                "Accelerator" => rendering_options.bvh = self.parse_accelerator()?,
                 // WorldBegin
                 "WorldBegin" => break,
                 // Mediums
-                "MakeNamedMedium" => todo!(),
-                "MediumInterface" => todo!(),
+                "MakeNamedMedium" => self.parse_make_named_medium()?,
+                "MediumInterface" => self.parse_medium_interface()?,
                 // Transformations
                 "Transform" => self.parse_transform()?,
                 "Scale" => self.parse_scale()?,
+                "Translate" => self.parse_translate()?,
+                "Rotate" => self.parse_rotate()?,
                 "LookAt" => self.parse_look_at()?,
+                "Identity" => self.gstate.ctm = Mat4::IDENTITY,
+                "CoordinateSystem" => self.parse_coordinate_system()?,
+                "CoordSysTransform" => self.parse_coord_sys_transform()?,
                 option => return Err(eyre!("Unkown or unimplemented directive: '{}'", option)),
             }
         }
 
         let swo = ScreenWideOptions {
+            general_options: rendering_options,
             camera: screen_cam.ok_or_else(|| eyre!("No Camera was provided"))?,
             film: screen_film.ok_or_else(|| eyre!("No Film was provided"))?,
+            color_space: self.gstate.color_space,
             ..ScreenWideOptions::default()
         };
 
         Ok(swo)
     }
 
+    /// Like in PBRT, the CTM at the point of the Camera directive transforms world space to camera
+    /// space, no matter which combination of transformations created it.
     fn parse_camera(&mut self) -> Result<Camera> {
         let mut cam = Camera {
             camera_from_world_transform: self.gstate.ctm,
             ..Camera::default()
         };
 
+        // Lets objects be placed relative to the camera with CoordSysTransform "camera"
+        self.named_coordinate_systems
+            .insert("camera", self.gstate.ctm.inverse());
+
         let mut params = self.parse_param_list()?;
 
         // TODO: not great parsing
@@ -209,10 +341,15 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
             cam => return Err(eyre!("Unkown camera type: '{}'", cam)),
         };
 
+        if let Some(name) = self.gstate.outside_medium {
+            cam.medium = Some(self.media[name].clone());
+        }
+
         for p in params.params() {
             match (p.name, &p.value) {
-                ("fov", ListParamValue::Single(Value::Float(fov))) => {
-                    cam.fov = *fov;
+                ("fov", ListParamValue::Single(fov)) => cam.fov = fov.expect_float()?,
+                ("fovaxis", ListParamValue::Single(axis)) => {
+                    cam.fov_axis = FovAxis::new(axis.expect_string()?)?
                 }
                 p => return Err(eyre!("Wrong Camera parameter: '{:?}'", p)),
             }
@@ -221,6 +358,92 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         Ok(cam)
     }
 
+    /// https://pbrt.org/fileformat-v4#general-options
+    fn parse_option(&mut self, options: &mut RenderingOptions) -> Result<()> {
+        let params = self.parse_param_list()?;
+
+        for p in params.params() {
+            match (p.name, &p.value) {
+                ("disablepixeljitter", ListParamValue::Single(Value::Bool(b))) => {
+                    options.disablepixeljitter = *b
+                }
+                ("disabletexturefiltering", ListParamValue::Single(Value::Bool(b))) => {
+                    options.disabletexturefiltering = *b
+                }
+                ("disablewavelengthjitter", ListParamValue::Single(Value::Bool(b))) => {
+                    options.disablewavelengthjitter = *b
+                }
+                ("displacementedgescale", ListParamValue::Single(value)) => {
+                    options.displacementedgescale = value.expect_float()?
+                }
+                ("msereferenceimage", ListParamValue::Single(Value::String(s))) => {
+                    options.msereferenceimage = s.to_string()
+                }
+                ("msereferenceout", ListParamValue::Single(Value::String(s))) => {
+                    options.msereferenceout = s.to_string()
+                }
+                ("rendercoordsys", ListParamValue::Single(Value::String(s))) => {
+                    options.rendercoordsys = s.to_string()
+                }
+                ("seed", ListParamValue::Single(Value::Integer(seed))) => options.seed = *seed,
+                ("forcediffuse", ListParamValue::Single(Value::Bool(b))) => {
+                    options.forcediffuse = *b
+                }
+                ("pixelstats", ListParamValue::Single(Value::Bool(b))) => options.pixelstats = *b,
+                ("wavefront", ListParamValue::Single(Value::Bool(b))) => options.wavefront = *b,
+                _ => self.unknown_param("Option", p)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_accelerator(&mut self) -> Result<BvhOptions> {
+        let mut params = self.parse_param_list()?;
+        let mut options = BvhOptions::default();
+
+        let typ = params.expect_simple()?;
+        if typ != "bvh" {
+            eprintln!(
+                "Accelerator '{}' isn't supported, using the default BVH",
+                typ
+            );
+            return Ok(options);
+        }
+
+        for p in params.params() {
+            match (p.name, &p.value) {
+                ("splitmethod", ListParamValue::Single(Value::String(method))) => {
+                    options.split_method = match *method {
+                        "sah" => SplitMethod::Sah,
+                        "middle" => SplitMethod::Middle,
+                        "equal" => SplitMethod::EqualCounts,
+                        "hlbvh" => {
+                            eprintln!("HLBVH split method isn't implemented, using the SAH");
+                            SplitMethod::Sah
+                        }
+                        m => return Err(eyre!("Unknown BVH split method: '{}'", m)),
+                    }
+                }
+                ("maxnodeprims", ListParamValue::Single(Value::Integer(max_prims))) => {
+                    if *max_prims < 1 {
+                        return Err(eyre!("Invalid maxnodeprims: '{}'", max_prims));
+                    }
+                    options.max_prims_in_node = *max_prims as usize;
+                }
+                ("cachetriangles", ListParamValue::Single(Value::Bool(cache))) => {
+                    options.cache_triangles = *cache
+                }
+                ("primitivelayout", ListParamValue::Single(Value::String(layout))) => {
+                    options.primitive_layout = PrimitiveLayout::new(layout)?
+                }
+                _ => return Err(eyre!("Unexpected Accelerator param: '{:?}'", p)),
+            }
+        }
+
+        Ok(options)
+    }
+
     fn parse_sampler(&mut self) -> Result<()> {
         let _params = self.parse_param_list()?;
 
@@ -277,6 +500,13 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
                 ("xresolution", ListParamValue::Single(Value::Integer(xres))) => {
                     film.xresolution = *xres
                 }
+                ("scale", ListParamValue::Single(scale)) => film.scale = scale.expect_float()?,
+                ("maxcomponentvalue", ListParamValue::Single(max)) => {
+                    film.max_component_value = max.expect_float()?
+                }
+                ("savefp16", ListParamValue::Single(Value::Bool(save_fp16))) => {
+                    film.save_fp16 = *save_fp16
+                }
                 _ => return Err(eyre!("Unknown / unimplemented Film param: '{:?}'", p)),
             }
         }
@@ -284,7 +514,13 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         Ok(film)
     }
 
-    fn parse_scene(&mut self) -> Result<(Vec<ShapeWithParams>, Option<InfiniteLightSource>)> {
+    fn parse_scene(
+        &mut self,
+    ) -> Result<(
+        Vec<ShapeWithParams>,
+        Option<InfiniteLightSource>,
+        Vec<ProjectionLightSource>,
+    )> {
         // let shape_attr = None;
         // let light_attr = None;
         // let material_attr = None;
@@ -293,17 +529,21 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
 
         let mut shapes = Vec::new();
         let mut infinite_light = None;
+        let mut projection_lights = Vec::new();
 
         loop {
             if self.peek()? == &Lexeme::Eof {
-                return Ok((shapes, infinite_light));
+                return Ok((shapes, infinite_light, projection_lights));
             }
 
             let name = self.expect(Lexeme::Str(""))?.unwrap_str();
             match name {
                 "AttributeBegin" => self.saved_gstates.push(self.gstate.clone()),
                 "AttributeEnd" => match self.saved_gstates.pop() {
-                    Some(gstate) => self.gstate = gstate,
+                    Some(gstate) => {
+                        self.set_color_space(gstate.color_space)?;
+                        self.gstate = gstate;
+                    }
                     None => return Err(eyre!("Non-matching AttributeEnd directive")),
                 },
                 "Attribute" => {
@@ -312,42 +552,58 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
                 }
                 "Shape" => {
                     let s = self.parse_shape()?;
+                    if let Some(name) = self.gstate.material {
+                        if !self.materials.contains_key(name) {
+                            self.unresolved_materials.push((shapes.len(), name));
+                        }
+                    }
                     shapes.push(s);
                 }
                 "ObjectBegin" => todo!(),
                 "ObjectEnd" => todo!(),
                 "LightSource" => {
                     let light = self.parse_light_source()?;
-                    #[allow(irrefutable_let_patterns)]
-                    if let LightSource::Infinite(ils) = light {
-                        infinite_light = Some(ils);
+                    match light {
+                        LightSource::Infinite(ils) => infinite_light = Some(ils),
+                        LightSource::Projection(pls) => projection_lights.push(pls),
                     }
                 }
                 "AreaLightSource" => self.parse_area_light_source()?,
+                "ColorSpace" => self.parse_color_space()?,
                 "Material" => self.parse_material_pre()?,
                 "Texture" => self.parse_texture()?,
                 // Materials
                 "MakeNamedMaterial" => {
                     let (name, material) = self.parse_make_named_material()?;
-                    self.materials.insert(name, material);
+                    if self.materials.insert(name, material).is_some() {
+                        self.duplicate_material(name)?;
+                    }
                 }
                 "NamedMaterial" => {
                     let name = self.parse_named_material()?;
                     self.gstate.material = Some(name);
                 }
                 // Mediums
-                "MakeNamedMedium" => todo!(),
-                "MediumInterface" => todo!(),
+                "MakeNamedMedium" => self.parse_make_named_medium()?,
+                "MediumInterface" => {
+                    self.parse_medium_interface()?;
+                    eprintln!("Media of shapes aren't supported yet, only the camera medium is");
+                }
                 // Transformations
                 "Scale" => self.parse_scale()?,
+                "Translate" => self.parse_translate()?,
+                "Rotate" => self.parse_rotate()?,
                 "Transform" => self.parse_transform()?,
+                "Identity" => self.gstate.ctm = Mat4::IDENTITY,
+                "CoordinateSystem" => self.parse_coordinate_system()?,
+                "CoordSysTransform" => self.parse_coord_sys_transform()?,
                 "ReverseOrientation" => {
                     let ori = &mut self.gstate.reverse_orientation;
                     *ori = !*ori;
                 }
                 // Invalid attributes
-                opt @ ("Option" | "Camera" | "Samplesr" | "ColorSpace" | "Film" | "PixelFilter"
-                | "Integrator" | "Accelerator" | "WorldBegin") => {
+                opt @ ("Option" | "Camera" | "Samplesr" | "Film" | "PixelFilter" | "Integrator"
+                | "Accelerator" | "WorldBegin") => {
                     return Err(eyre!("Directive '{}' is invalid after WorldBegin", opt))
                 }
                 option => return Err(eyre!("Unkown option: '{}'", option)),
@@ -359,12 +615,22 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         let mut params = self.parse_param_list()?;
 
         let typ = params.expect_simple()?;
+        let alpha = self.parse_alpha(&mut params)?;
+        // Only flips this shape, the graphics state is left as it is
+        let reverse_orientation = match params.take("reverseorientation") {
+            Some(p) => p.expect_single()?.expect_bool()?,
+            None => false,
+        };
+
         let shape = match typ {
             "bilinearmesh" => todo!(),
             "curve" => todo!(),
             "cylinder" => todo!(),
             "disk" => todo!(),
             "sphere" => Shape::Sphere(self.parse_sphere(&params)?),
+            "infiniteplane" => Shape::InfinitePlane(self.parse_infinite_plane(&params)?),
+            "quad" => Shape::Quad(self.parse_quad(&params)?),
+            "box" => Shape::Cuboid(self.parse_box(&params)?),
             "trianglemesh" => Shape::TriMesh(self.parse_trianglemesh(&params)?),
             "plymesh" => Shape::TriMesh(self.parse_plymesh(&params)?),
             "loopsubdiv" => todo!(),
@@ -372,10 +638,14 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         };
 
         // TODO: if materials and lights get large consider using something like Arc
-        let material = if let Some(mat_name) = self.gstate.material {
-            self.materials.get(mat_name).unwrap().clone()
-        } else {
-            Material::new_default(&self.rgbtospec)
+        // Materials that aren't defined yet are assigned in resolve_references()
+        let (material, bump_map) = match self
+            .gstate
+            .material
+            .and_then(|mat_name| self.materials.get(mat_name))
+        {
+            Some(def) => (def.material.clone(), def.bump_map.clone()),
+            None => (Material::new_default(self.rgbtospec), None),
         };
 
         Ok(ShapeWithParams::new(
@@ -383,8 +653,53 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
             material,
             self.gstate.area_light_source.clone(),
             self.gstate.ctm,
-            self.gstate.reverse_orientation,
-        ))
+            self.gstate.reverse_orientation || reverse_orientation,
+            alpha,
+        )
+        .with_bump_map(bump_map))
+    }
+
+    fn parse_alpha(&mut self, params: &mut ParamList<'t>) -> Result<Option<Alpha>> {
+        let Some(p) = params.take("alpha") else {
+            return Ok(None);
+        };
+
+        match &p.value {
+            ListParamValue::Single(Value::Texture(name)) => match self.float_textures.get(name) {
+                Some(path) => Ok(Some(Alpha::ImageTexture(path.clone()))),
+                None => {
+                    self.missing_textures.push(name);
+                    Ok(None)
+                }
+            },
+            ListParamValue::Single(alpha) => match alpha.expect_float()? {
+                // Fully opaque
+                alpha if alpha >= 1. => Ok(None),
+                alpha => Ok(Some(Alpha::Constant(alpha))),
+            },
+            _ => Err(eyre!("Unexpected alpha param: '{:?}'", p)),
+        }
+    }
+
+    /// PBRT calls the height texture of materials "displacement", "bumpmap" is accepted as well
+    fn parse_bump_map(&mut self, params: &mut ParamList<'t>) -> Result<Option<PathBuf>> {
+        let Some(p) = params
+            .take("displacement")
+            .or_else(|| params.take("bumpmap"))
+        else {
+            return Ok(None);
+        };
+
+        match &p.value {
+            ListParamValue::Single(Value::Texture(name)) => match self.float_textures.get(name) {
+                Some(path) => Ok(Some(path.clone())),
+                None => {
+                    self.missing_textures.push(name);
+                    Ok(None)
+                }
+            },
+            _ => Err(eyre!("Unexpected bump map param: '{:?}'", p)),
+        }
     }
 
     fn parse_sphere(&mut self, params: &ParamList) -> Result<Sphere> {
@@ -392,14 +707,75 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
 
         for p in params.params() {
             match (p.name, &p.value) {
-                ("radius", ListParamValue::Single(Value::Float(p_radius))) => radius = *p_radius,
-                _ => return Err(eyre!("Unexpected sphere param: '{:?}'", p)),
+                ("radius", ListParamValue::Single(p_radius)) => radius = p_radius.expect_float()?,
+                _ => self.unknown_param("sphere", p)?,
             }
         }
 
         Ok(Sphere::new(radius))
     }
 
+    fn parse_infinite_plane(&mut self, params: &ParamList) -> Result<InfinitePlane> {
+        if self.gstate.area_light_source.is_some() {
+            return Err(eyre!("Infinite planes can't be area lights"));
+        }
+
+        let mut point = Vec3::ZERO;
+        let mut normal = Vec3::Z;
+
+        for p in params.params() {
+            match (p.name, &p.value) {
+                ("point", ListParamValue::Single(Value::Point3(p_point))) => point = *p_point,
+                ("normal", ListParamValue::Single(Value::Normal3(p_normal))) => normal = *p_normal,
+                _ => self.unknown_param("infinite plane", p)?,
+            }
+        }
+
+        Ok(InfinitePlane::new(point, normal))
+    }
+
+    fn parse_box(&mut self, params: &ParamList) -> Result<Cuboid> {
+        if self.gstate.area_light_source.is_some() {
+            return Err(eyre!("Boxes can't be area lights"));
+        }
+
+        let mut p0 = Vec3::splat(-1.);
+        let mut p1 = Vec3::ONE;
+
+        for p in params.params() {
+            match (p.name, &p.value) {
+                ("p0", ListParamValue::Single(Value::Point3(p_p0))) => p0 = *p_p0,
+                ("p1", ListParamValue::Single(Value::Point3(p_p1))) => p1 = *p_p1,
+                _ => self.unknown_param("box", p)?,
+            }
+        }
+
+        Ok(Cuboid::new(p0, p1))
+    }
+
+    fn parse_quad(&mut self, params: &ParamList) -> Result<Quad> {
+        let mut corner = Vec3::ZERO;
+        let mut edge_u = Vec3::X;
+        let mut edge_v = Vec3::Y;
+
+        for p in params.params() {
+            match (p.name, &p.value) {
+                ("corner", ListParamValue::Single(Value::Point3(p_corner))) => corner = *p_corner,
+                ("edgeu", ListParamValue::Single(Value::Vector3(p_edge))) => edge_u = *p_edge,
+                ("edgev", ListParamValue::Single(Value::Vector3(p_edge))) => edge_v = *p_edge,
+                _ => self.unknown_param("quad", p)?,
+            }
+        }
+
+        // Solid-angle sampling only works for rectangles
+        let cos_edges = edge_u.normalize().dot(edge_v.normalize());
+        if cos_edges.abs() > 0.0001 {
+            return Err(eyre!("Quad edges have to be perpendicular"));
+        }
+
+        Ok(Quad::new(corner, edge_u, edge_v))
+    }
+
     fn parse_trianglemesh(&mut self, params: &ParamList) -> Result<TriMesh> {
         // TODO: be more robust when loading params ? Kinda annoying to do with this format...
         let mut indices: Option<Vec<i32>> = None;
@@ -407,6 +783,7 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         let mut normals: Option<Vec<Vec3>> = None;
         let mut tangents: Option<Vec<Vec3>> = None;
         let mut uvs: Option<Vec<Vec2>> = None;
+        let mut colors: Option<Vec<Vec3>> = None;
 
         for p in params.params() {
             match (p.name, &p.value) {
@@ -417,20 +794,30 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
                 ("N", ListParamValue::List(ValueList::Normal3(n))) => normals = Some(n.to_vec()),
                 ("S", ListParamValue::List(ValueList::Vector3(t))) => tangents = Some(t.to_vec()),
                 ("uv", ListParamValue::List(ValueList::Point2(uv))) => uvs = Some(uv.to_vec()),
-                _ => return Err(eyre!("Unexpected triangle mesh param: '{:?}'", p)),
+                ("Cs", ListParamValue::List(ValueList::Rgb(c))) => colors = Some(c.to_vec()),
+                _ => self.unknown_param("triangle mesh", p)?,
             }
         }
 
         let (indices, vertices) = match (indices, points) {
-            (None, Some(vertices)) if vertices.len() == 3 => {
-                let indices = vec![1, 2, 3];
+            // Triangle soup, every 3 consecutive points form a triangle
+            (None, Some(vertices)) if !vertices.is_empty() && vertices.len() % 3 == 0 => {
+                let indices = (0..vertices.len() as i32).collect();
                 (indices, vertices)
             }
+            (None, Some(vertices)) => {
+                return Err(eyre!(
+                    "Triangle mesh without indices has {} points, expected a multiple of 3",
+                    vertices.len()
+                ))
+            }
             (Some(indices), Some(vertices)) => (indices, vertices),
             _ => return Err(eyre!("Triangle mesh vertices or indices not specified")),
         };
 
-        Ok(TriMesh::new(indices, vertices, normals, tangents, uvs))
+        let mesh = TriMesh::new(indices, vertices, normals, tangents, uvs).with_colors(colors);
+        mesh.validate()?;
+        Ok(mesh)
     }
 
     fn parse_plymesh(&mut self, params: &ParamList) -> Result<TriMesh> {
@@ -442,10 +829,13 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
 
         let typ = params.next_param()?.expect_empty()?;
 
-        let scale = if let Some(p) = params.get("scale") {
-            p.expect_single()?.expect_float()?
-        } else {
-            1.0
+        // An RGB scale tints the environment map
+        let (scale, tint) = match params.get("scale") {
+            Some(p) => match p.expect_single()? {
+                Value::Rgb(tint) => (1.0, Some(tint)),
+                v => (v.expect_float()?, None),
+            },
+            None => (1.0, None),
         };
 
         match typ {
@@ -454,7 +844,7 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
             "infinite" => {
                 let filepath = if let Some(p) = params.get("filename") {
                     let filename = p.expect_single()?.expect_string()?;
-                    self.file_directory.join(filename)
+                    resolve_path(&self.file_directory, filename)
                 } else {
                     todo!("infinite light source without texture");
                 };
@@ -463,12 +853,60 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
                     todo!("inifite light illuminance");
                 }
 
-                return Ok(LightSource::Infinite(InfiniteLightSource::new(
-                    scale, filepath,
-                )));
+                let mapping = if let Some(p) = params.get("mapping") {
+                    match p.expect_single()?.expect_string()? {
+                        "octahedral" | "equalarea" => Some(EnvMapping::Octahedral),
+                        "equirect" | "latlong" => Some(EnvMapping::Equirect),
+                        m => return Err(eyre!("Unknown environment map mapping: '{}'", m)),
+                    }
+                } else {
+                    None
+                };
+
+                let ils = InfiniteLightSource::new(scale, filepath, mapping);
+                return Ok(LightSource::Infinite(match tint {
+                    Some(tint) => ils.with_tint(tint),
+                    None => ils,
+                }));
             }
             "point" => todo!(),
-            "projection" => todo!(),
+            "projection" => {
+                let filepath = if let Some(p) = params.get("filename") {
+                    let filename = p.expect_single()?.expect_string()?;
+                    resolve_path(&self.file_directory, filename)
+                } else {
+                    return Err(eyre!("Projection light source without an image"));
+                };
+
+                let fov = if let Some(p) = params.get("fov") {
+                    p.expect_single()?.expect_float()?
+                } else {
+                    90.
+                };
+
+                let from = if let Some(p) = params.get("from") {
+                    p.expect_single()?.expect_point3()?
+                } else {
+                    Vec3::ZERO
+                };
+
+                let to = if let Some(p) = params.get("to") {
+                    p.expect_single()?.expect_point3()?
+                } else {
+                    Vec3::Z
+                };
+
+                if tint.is_some() {
+                    return Err(eyre!("Projection light scale has to be a float"));
+                }
+
+                let from = self.gstate.ctm.transform_point3(from);
+                let to = self.gstate.ctm.transform_point3(to);
+
+                return Ok(LightSource::Projection(ProjectionLightSource::new(
+                    scale, filepath, fov, from, to,
+                )));
+            }
             "spot" => todo!(),
             _ => return Err(eyre!("Unknown LightSource type: '{}'", typ)),
         }
@@ -495,7 +933,11 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
                     );
                     light.radiance = spectrum;
                 }
-                p => return Err(eyre!("Unknown AreaLightSourceParam: '{:?}'", p)),
+                ("scale", ListParamValue::Single(scale)) => light.scale = scale.expect_float()?,
+                ("power", ListParamValue::Single(power)) => {
+                    light.power = Some(power.expect_float()?)
+                }
+                _ => self.unknown_param("AreaLightSource", p)?,
             }
         }
 
@@ -510,13 +952,29 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         Ok(())
     }
 
-    fn parse_material(&mut self, material_type: &str, mut params: ParamList) -> Result<Material> {
+    /// The height texture is a parameter of every material type
+    fn parse_material(
+        &mut self,
+        material_type: &str,
+        mut params: ParamList<'t>,
+    ) -> Result<MaterialDefinition> {
+        let bump_map = self.parse_bump_map(&mut params)?;
+        let material = self.parse_material_type(material_type, params)?;
+        Ok(MaterialDefinition { material, bump_map })
+    }
+
+    fn parse_material_type(
+        &mut self,
+        material_type: &str,
+        params: ParamList<'t>,
+    ) -> Result<Material> {
         let placeholder_material = || {
             eprintln!("Using a placeholder material");
             Ok(Material::new_default(&self.rgbtospec))
         };
 
         match material_type {
+            "black" => Ok(Material::Black),
             "coateddiffuse" => return placeholder_material(),
             "coatedconductor" => return placeholder_material(),
             "conductor" => {
@@ -533,14 +991,25 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
                     (0., 0.)
                 };
 
+                let mut roughness = MaterialRoughness::new(vroughness, uroughness);
+                // PBRT remaps by default and its scenes are authored for that. Scenes that
+                // relied on the roughness being the alpha need "remaproughness" false.
+                let remap_roughness = match params.get("remaproughness") {
+                    Some(p) => p.expect_single()?.expect_bool()?,
+                    None => true,
+                };
+                if remap_roughness {
+                    roughness = roughness.remapped();
+                }
+
                 let (ior, absorbtion_k) = if let Some(_) = params.get("reflectance") {
                     todo!()
                 } else if let (Some(iorp), Some(absorbtionkp)) =
-                    (params.get("k"), params.get("eta"))
+                    (params.get("eta"), params.get("k"))
                 {
                     (
-                        iorp.expect_single()?.expect_rgb()?,
-                        absorbtionkp.expect_single()?.expect_rgb()?,
+                        self.parse_spectrum_param(iorp)?,
+                        self.parse_spectrum_param(absorbtionkp)?,
                     )
                 } else {
                     return Err(eyre!(
@@ -548,37 +1017,204 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
                     ));
                 };
 
-                return Ok(Material::Conductor(ConductorMaterial::new(
-                    self.rgbtospec,
-                    ior,
-                    absorbtion_k,
-                    MaterialRoughness::new(vroughness, uroughness),
-                )));
+                self.check_params(
+                    "conductor material",
+                    &params,
+                    &[
+                        "roughness",
+                        "vroughness",
+                        "uroughness",
+                        "remaproughness",
+                        "k",
+                        "eta",
+                        "reflectance",
+                        "filmthickness",
+                        "filmeta",
+                    ],
+                )?;
+
+                // Not PBRT parameters, the "float filmthickness" (in nanometers) and
+                // "float filmeta" extensions
+                let thin_film = match params.get("filmthickness") {
+                    Some(p) => {
+                        let thickness = p.expect_single()?.expect_float()?;
+                        if thickness < 0. {
+                            return Err(eyre!("Invalid thin film thickness: '{}'", thickness));
+                        }
+
+                        let ior = match params.get("filmeta") {
+                            Some(p) => p.expect_single()?.expect_float()?,
+                            // Soap water
+                            None => 1.33,
+                        };
+                        Some(ThinFilm::new(thickness, ior))
+                    }
+                    None => None,
+                };
+
+                return Ok(Material::Conductor(
+                    ConductorMaterial::new_spectral(ior, absorbtion_k, roughness)
+                        .with_thin_film(thin_film),
+                ));
+            }
+            "dielectric" => {
+                // Validated, so that broken spectra aren't hidden by the placeholder
+                if let Some(p) = params.get("eta") {
+                    self.parse_spectrum_param(p)?;
+                }
+                return placeholder_material();
             }
-            "dielectric" => return placeholder_material(),
             "diffuse" => {
-                let reflectance = params
-                    .next_param()?
-                    .expect_single_named("reflectance")?
-                    .expect_rgb()?;
-
-                return Ok(Material::Diffuse(DiffuseMaterial::new(
-                    &self.rgbtospec,
-                    reflectance,
-                )));
+                self.check_params("diffuse material", &params, &["reflectance"])?;
+
+                // Without a reflectance, the vertex colors of meshes are used
+                let material = match params.get("reflectance") {
+                    Some(p) => {
+                        let reflectance = p.expect_single()?.expect_rgb()?;
+                        DiffuseMaterial::new(self.rgbtospec, reflectance)
+                    }
+                    None => DiffuseMaterial::new_vertex_colors(self.rgbtospec),
+                };
+
+                Ok(Material::Diffuse(material))
             }
             "diffusetransmission" => return placeholder_material(),
             "hair" => return placeholder_material(),
-            "interface" => return placeholder_material(),
-            "measured" => return placeholder_material(),
-            "mix" => return placeholder_material(),
+            "interface" => Ok(Material::Interface),
+            "measured" => {
+                self.check_params("measured material", &params, &["filename"])?;
+
+                let filename = params
+                    .get("filename")
+                    .ok_or_else(|| eyre!("Measured material has no filename"))?
+                    .expect_single()?
+                    .expect_string()?;
+                let path = resolve_path(&self.file_directory, filename);
+
+                // PBRT-v4 also reads the RGL .bsdf files, which aren't supported yet
+                if !MeasuredBrdf::is_merl_path(&path) {
+                    if self.strict {
+                        return Err(eyre!(
+                            "Unsupported measured BRDF format: '{}'",
+                            path.display()
+                        ));
+                    }
+                    eprintln!("Unsupported measured BRDF format: '{}'", path.display());
+                    return placeholder_material();
+                }
+
+                Ok(Material::Measured(Arc::new(MeasuredBrdf::load(&path)?)))
+            }
+            "mix" => {
+                self.check_params("mix material", &params, &["materials", "amount"])?;
+
+                let names = match params.get("materials").map(|p| p.expect_list()) {
+                    Some(Ok(ValueList::String(names))) if names.len() == 2 => names,
+                    _ => return Err(eyre!("Mix material needs two material names")),
+                };
+
+                let amount = match params.get("amount") {
+                    Some(p) => self.parse_mix_amount(p)?,
+                    None => MixAmount::Constant(0.5),
+                };
+
+                // Only already defined materials can be mixed
+                let material = |name: &str| match self.materials.get(name).map(|d| &d.material) {
+                    Some(Material::Interface) => {
+                        Err(eyre!("Interface material can't be mixed: '{}'", name))
+                    }
+                    Some(material) => Ok(material.clone()),
+                    None => Err(eyre!("Unknown material in mix: '{}'", name)),
+                };
+
+                Ok(Material::Mix(MixMaterial::new(
+                    material(names[0])?,
+                    material(names[1])?,
+                    amount,
+                )))
+            }
             "subsurface" => return placeholder_material(),
             "thindielectric" => return placeholder_material(),
             typ => return Err(eyre!("Unknown material type: '{}'", typ)),
         }
     }
 
-    fn parse_make_named_material(&mut self) -> Result<(&'t str, Material)> {
+    fn parse_mix_amount(&mut self, p: &ListParam<'t>) -> Result<MixAmount> {
+        match p.expect_single()? {
+            Value::Texture(name) => match self.float_textures.get(name) {
+                Some(path) => {
+                    let texture = ImageTexture::load(path)?;
+                    Ok(MixAmount::Texture(Arc::new(texture)))
+                }
+                None => {
+                    self.missing_textures.push(name);
+                    Ok(MixAmount::Constant(0.5))
+                }
+            },
+            amount => Ok(MixAmount::Constant(amount.expect_float()?)),
+        }
+    }
+
+    /// Unbounded spectra like the IOR, given either as an RGB, a constant or wavelength-value pairs
+    fn parse_spectrum_param(&self, p: &ListParam) -> Result<Spectrum> {
+        let spectrum = match &p.value {
+            ListParamValue::Single(Value::Rgb(rgb)) => Spectrum::Rgb(RgbSpectrum::new(
+                self.rgbtospec,
+                *rgb,
+                RgbSpectrumKind::Unbounded,
+            )),
+            ListParamValue::Single(value @ (Value::Float(_) | Value::Integer(_))) => {
+                Spectrum::PiecewiseLinear(PiecewiseLinearSpectrum::new_constant(
+                    value.expect_float()?,
+                ))
+            }
+            ListParamValue::Single(Value::Spectrum(sample)) => {
+                Spectrum::PiecewiseLinear(PiecewiseLinearSpectrum::new(&[*sample])?)
+            }
+            ListParamValue::List(ValueList::Spectrum(samples)) => {
+                Spectrum::PiecewiseLinear(PiecewiseLinearSpectrum::new(samples)?)
+            }
+            _ => return Err(eyre!("Expected a spectrum param, got '{:?}'", p)),
+        };
+
+        Ok(spectrum)
+    }
+
+    /// Errors in strict mode, otherwise only warns that the param is skipped
+    fn unknown_param(&self, context: &str, p: &ListParam) -> Result<()> {
+        if self.strict {
+            Err(eyre!("Unexpected {} param: '{:?}'", context, p))
+        } else {
+            eprintln!("Skipping unknown {} param: '{:?}'", context, p);
+            Ok(())
+        }
+    }
+
+    /// PBRT doesn't allow redefining materials
+    fn duplicate_material(&self, name: &str) -> Result<()> {
+        if self.strict {
+            Err(eyre!("Duplicate material definition: '{}'", name))
+        } else {
+            eprintln!(
+                "Material '{}' is redefined, the following shapes use the new one",
+                name
+            );
+            Ok(())
+        }
+    }
+
+    /// Reports the params that aren't in the known list
+    fn check_params(&self, context: &str, params: &ParamList, known: &[&str]) -> Result<()> {
+        for p in params.params() {
+            if !known.contains(&p.name) {
+                self.unknown_param(context, p)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_make_named_material(&mut self) -> Result<(&'t str, MaterialDefinition)> {
         let mut params = self.parse_param_list()?;
         let name = params.expect_simple()?;
 
@@ -591,14 +1227,132 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         Ok((name, material))
     }
 
+    /// Only homogeneous media are supported
+    fn parse_make_named_medium(&mut self) -> Result<()> {
+        let mut params = self.parse_param_list()?;
+        let name = params.expect_simple()?;
+
+        let medium_type = params
+            .next_param()?
+            .expect_single_named("type")?
+            .expect_string()?;
+        if medium_type != "homogeneous" {
+            return Err(eyre!("Unsupported medium type: '{}'", medium_type));
+        }
+
+        self.check_params(
+            "homogeneous medium",
+            &params,
+            &["sigma_a", "sigma_s", "scale", "g"],
+        )?;
+
+        // PBRT's defaults are 1 for both coefficients
+        let coefficient = |name| match params.get(name) {
+            Some(p) => self.parse_spectrum_param(p),
+            None => Ok(Spectrum::PiecewiseLinear(
+                PiecewiseLinearSpectrum::new_constant(1.),
+            )),
+        };
+        let sigma_a = coefficient("sigma_a")?;
+        let sigma_s = coefficient("sigma_s")?;
+
+        let scale = match params.get("scale") {
+            Some(p) => p.expect_single()?.expect_float()?,
+            None => 1.,
+        };
+
+        if let Some(p) = params.get("g") {
+            if p.expect_single()?.expect_float()? != 0. {
+                eprintln!(
+                    "Only isotropic media are supported, ignoring g of '{}'",
+                    name
+                );
+            }
+        }
+
+        let medium = HomogeneousMedium::new(sigma_a, sigma_s, scale);
+        self.media.insert(name, medium);
+        Ok(())
+    }
+
+    /// `MediumInterface "inside" "outside"`, a single name is used for both sides.
+    /// The empty name "" is vacuum.
+    fn parse_medium_interface(&mut self) -> Result<()> {
+        let mut names = SmallVec::<[&'t str; 2]>::new();
+        while self.peek()? == &Lexeme::Qoutes {
+            self.next()?;
+            let name = if self.peek()? == &Lexeme::Qoutes {
+                ""
+            } else {
+                self.expect(Lexeme::Str(""))?.unwrap_str()
+            };
+            self.expect(Lexeme::Qoutes)?;
+            names.push(name);
+        }
+
+        let (inside, outside) = match names[..] {
+            [name] => (name, name),
+            [inside, outside] => (inside, outside),
+            _ => {
+                return Err(eyre!(
+                    "MediumInterface needs one or two media: '{:?}'",
+                    names
+                ))
+            }
+        };
+
+        for name in [inside, outside] {
+            if !name.is_empty() && !self.media.contains_key(name) {
+                return Err(eyre!("Unknown medium: '{}'", name));
+            }
+        }
+
+        self.gstate.outside_medium = Some(outside).filter(|name| !name.is_empty());
+        Ok(())
+    }
+
+    /// The space of the following RGB values. Like in PBRT, it's part of the graphics state, the
+    /// image is rendered in the color space that's set at WorldBegin.
+    fn parse_color_space(&mut self) -> Result<()> {
+        let mut params = self.parse_param_list()?;
+        let color_space = ColorSpace::new(params.expect_simple()?)?;
+        self.set_color_space(color_space)
+    }
+
+    fn set_color_space(&mut self, color_space: ColorSpace) -> Result<()> {
+        if let Some(tables) = &self.rgbtospec_tables {
+            self.rgbtospec = tables(color_space)?;
+        }
+        self.gstate.color_space = color_space;
+        Ok(())
+    }
+
     fn parse_named_material(&mut self) -> Result<&'t str> {
         let mut params = self.parse_param_list()?;
         params.expect_simple()
     }
 
     fn parse_texture(&mut self) -> Result<()> {
-        let _params = self.parse_param_list()?;
-        eprintln!("Textures aren't loaded properly yet");
+        let mut params = self.parse_param_list()?;
+
+        let name = params.expect_simple()?;
+        let typ = params.expect_simple()?;
+        let class = params.expect_simple()?;
+
+        match (typ, class) {
+            ("float", "imagemap") => {
+                let filename = params
+                    .get("filename")
+                    .ok_or_else(|| eyre!("Image texture '{}' has no filename", name))?
+                    .expect_single()?
+                    .expect_string()?;
+
+                let path = resolve_path(&self.file_directory, filename);
+                self.float_textures.insert(name, path);
+            }
+            _ => eprintln!("Textures aren't loaded properly yet"),
+        }
+
         Ok(())
     }
 
@@ -609,6 +1363,24 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         Ok(())
     }
 
+    fn parse_translate(&mut self) -> Result<()> {
+        let t = self.parse_vec3()?;
+        self.modify_ctm(Mat4::from_translation(t));
+        Ok(())
+    }
+
+    /// The angle is in degrees
+    fn parse_rotate(&mut self) -> Result<()> {
+        let angle = self.parse_float()?;
+        let axis = self.parse_vec3()?;
+        if axis == Vec3::ZERO {
+            return Err(eyre!("Rotation axis can't be zero"));
+        }
+
+        self.modify_ctm(Mat4::from_axis_angle(axis.normalize(), angle.to_radians()));
+        Ok(())
+    }
+
     fn parse_transform(&mut self) -> Result<()> {
         let mut cols = [0f32; 16];
 
@@ -626,6 +1398,26 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         Ok(())
     }
 
+    fn parse_coordinate_system(&mut self) -> Result<()> {
+        let mut params = self.parse_param_list()?;
+        let name = params.expect_simple()?;
+
+        self.named_coordinate_systems.insert(name, self.gstate.ctm);
+        Ok(())
+    }
+
+    fn parse_coord_sys_transform(&mut self) -> Result<()> {
+        let mut params = self.parse_param_list()?;
+        let name = params.expect_simple()?;
+
+        match self.named_coordinate_systems.get(name) {
+            Some(ctm) => self.gstate.ctm = *ctm,
+            None => return Err(eyre!("Unknown coordinate system: '{}'", name)),
+        }
+
+        Ok(())
+    }
+
     fn parse_look_at(&mut self) -> Result<()> {
         let eye = self.parse_vec3()?;
         let look = self.parse_vec3()?;
@@ -729,20 +1521,36 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
                 might_be_list,
             ),
             "spectrum" => {
-                // TODO: can contain a filename
+                if self.peek()? == &Lexeme::Qoutes {
+                    let name = self.parse_quoted_string()?;
+                    return Err(eyre!(
+                        "Named and file spectra aren't supported yet: '{}'",
+                        name
+                    ));
+                }
 
-                todo!()
+                self.parse_value_list(
+                    Self::parse_spectrum_sample,
+                    Value::Spectrum,
+                    ValueList::Spectrum,
+                    might_be_list,
+                )
             }
+            // Lists of colors are per-vertex colors of meshes
             "rgb" => {
-                let v = self.parse_vec3()?;
-                Ok(SingleValueOrList::Value(Value::Rgb(v)))
+                self.parse_value_list(Self::parse_vec3, Value::Rgb, ValueList::Rgb, might_be_list)
             }
             "blackbody" => {
                 let num = self.parse_int()?;
                 Ok(SingleValueOrList::Value(Value::Blackbody(num)))
             }
             "bool" => {
-                let s = self.expect(Lexeme::Str(""))?.unwrap_str();
+                // PBRT-v3 files quote the value
+                let s = if self.peek()? == &Lexeme::Qoutes {
+                    self.parse_quoted_string()?
+                } else {
+                    self.expect(Lexeme::Str(""))?.unwrap_str()
+                };
                 let b = match s {
                     "true" => true,
                     "false" => false,
@@ -751,10 +1559,13 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
 
                 Ok(SingleValueOrList::Value(Value::Bool(b)))
             }
-            "string" => {
-                let s = self.parse_quoted_string()?;
-                Ok(SingleValueOrList::Value(Value::String(s)))
-            }
+            // Lists of strings name the materials of mixes
+            "string" => self.parse_value_list(
+                Self::parse_quoted_string,
+                Value::String,
+                ValueList::String,
+                might_be_list,
+            ),
             // Exclusive to materials
             "texture" => {
                 let s = self.parse_quoted_string()?;
@@ -768,18 +1579,20 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         &mut self,
         parse: fn(&mut Self) -> Result<T>,
         wrap_value: fn(T) -> Value<'t>,
-        wrap_list: fn(ValueVec<T>) -> ValueList,
+        wrap_list: fn(ValueVec<T>) -> ValueList<'t>,
         might_be_list: bool,
     ) -> Result<SingleValueOrList<'t>> {
+        // Without brackets there's exactly one value, even if it consists of several numbers
+        if !might_be_list {
+            let value = parse(self)?;
+            return Ok(SingleValueOrList::Value(wrap_value(value)));
+        }
+
         let mut valuevec = ValueVec::new();
 
         while self.peek()? != &Lexeme::CloseBracket {
             let ele = parse(self)?;
             valuevec.push(ele);
-
-            if !might_be_list {
-                break;
-            }
         }
 
         if valuevec.len() > 1 {
@@ -809,6 +1622,13 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         Ok(Vec2::new(x, y))
     }
 
+    /// Wavelength and value pair
+    fn parse_spectrum_sample(&mut self) -> Result<(f32, f32)> {
+        let lambda = self.parse_float()?;
+        let value = self.parse_float()?;
+        Ok((lambda, value))
+    }
+
     fn parse_vec3(&mut self) -> Result<Vec3> {
         let x = self.parse_float()?;
         let y = self.parse_float()?;
@@ -845,3 +1665,1048 @@ impl<'t, 'r> SceneLoader<'t, 'r> {
         }
     }
 }
+
+/// Resolves a filename from the scene against the directory of the scene file.
+/// Absolute paths are kept as they are.
+fn resolve_path(file_directory: &Path, filename: &str) -> PathBuf {
+    let path = Path::new(filename);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        file_directory.join(path)
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use std::sync::Arc;
+
+    use glam::{vec2, vec3};
+    use rand::SeedableRng;
+
+    use crate::{
+        bxdf::Bxdf,
+        color::spectrum::rgb_spectrum::flat_rgbtospec,
+        geometry::{
+            self,
+            trianglemesh::{Triangle, TriangleMesh},
+            Ray,
+        },
+        integrator::scratch::RenderScratch,
+        sampler::Sampler,
+        util::test_dir,
+    };
+
+    use super::*;
+
+    fn load_str(txt: &str) -> Result<SceneDescription> {
+        let rgbtospec = flat_rgbtospec();
+        SceneLoader::new(txt, PathBuf::new(), &rgbtospec).load()
+    }
+
+    #[test]
+    fn test_accelerator() {
+        let load_accelerator = |accelerator: &str| {
+            let scene = load_str(&format!(
+                r#"
+                Accelerator {accelerator}
+                Camera "perspective"
+                Film "rgb"
+                WorldBegin
+                "#
+            ))
+            .unwrap();
+            scene.options.general_options.bvh
+        };
+
+        let bvh =
+            load_accelerator(r#""bvh" "string splitmethod" "equal" "integer maxnodeprims" 2"#);
+        assert_eq!(bvh.split_method, SplitMethod::EqualCounts);
+        assert_eq!(bvh.max_prims_in_node, 2);
+
+        let bvh = load_accelerator(r#""bvh" "string splitmethod" "middle""#);
+        assert_eq!(bvh.split_method, SplitMethod::Middle);
+        assert!(!bvh.cache_triangles);
+
+        let bvh = load_accelerator(r#""bvh" "bool cachetriangles" true"#);
+        assert!(bvh.cache_triangles);
+        assert_eq!(bvh.primitive_layout, PrimitiveLayout::Boxed);
+
+        let bvh = load_accelerator(r#""bvh" "string primitivelayout" "soa""#);
+        assert_eq!(bvh.primitive_layout, PrimitiveLayout::Soa);
+
+        // Falls back to the default BVH
+        let bvh = load_accelerator(r#""kdtree" "integer maxprims" 8"#);
+        assert_eq!(bvh.split_method, SplitMethod::Sah);
+    }
+
+    #[test]
+    fn test_camera_medium() {
+        let load_medium = |directives: &str| {
+            load_str(&format!(
+                r#"
+                {directives}
+                Camera "perspective"
+                Film "rgb"
+                WorldBegin
+                "#
+            ))
+            .map(|scene| scene.options.camera.medium)
+        };
+
+        let medium = load_medium(
+            r#"MakeNamedMedium "fog" "string type" "homogeneous"
+                "rgb sigma_a" [0.5 0.5 0.5] "float scale" 2
+            MediumInterface "" "fog""#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(medium.scale, 2.);
+        assert_eq!(medium.sigma_a.eval::<Vec3>(&()), Vec3::splat(0.5));
+        // PBRT's default
+        assert_eq!(medium.sigma_s.eval::<Vec3>(&()), Vec3::ONE);
+
+        // A single name is both sides, vacuum is no medium
+        let fog = r#"MakeNamedMedium "fog" "string type" "homogeneous""#;
+        assert!(load_medium(&format!(r#"{fog} MediumInterface "fog""#))
+            .unwrap()
+            .is_some());
+        assert!(load_medium(&format!(r#"{fog} MediumInterface "fog" """#))
+            .unwrap()
+            .is_none());
+        assert!(load_medium(fog).unwrap().is_none());
+
+        assert!(load_medium(r#"MediumInterface "" "smoke""#).is_err());
+        assert!(load_medium(r#"MakeNamedMedium "cloud" "string type" "nanovdb""#).is_err());
+    }
+
+    #[test]
+    fn test_option() {
+        let load_option = |option: &str| {
+            load_str(&format!(
+                r#"
+                Option {option}
+                Camera "perspective"
+                Film "rgb"
+                WorldBegin
+                "#
+            ))
+            .map(|scene| scene.options.general_options)
+        };
+
+        let options = load_option(r#""bool forcediffuse" true"#).unwrap();
+        assert!(options.forcediffuse);
+
+        let options = load_option(r#""integer seed" 7"#).unwrap();
+        assert!(!options.forcediffuse);
+        assert_eq!(options.seed, 7);
+
+        // Several options in one directive, and repeated directives
+        let options = load_option(
+            r#""integer seed" 3 "bool forcediffuse" true
+            Option "bool disablepixeljitter" true"#,
+        )
+        .unwrap();
+        assert_eq!(options.seed, 3);
+        assert!(options.forcediffuse);
+        assert!(options.disablepixeljitter);
+        assert!(!options.wavefront);
+
+        // Unknown options are skipped, except in strict mode
+        let options = load_option(r#""bool forcespecular" true "integer seed" 5"#).unwrap();
+        assert_eq!(options.seed, 5);
+
+        let rgbtospec = flat_rgbtospec();
+        let txt = r#"Option "bool forcespecular" true Camera "perspective" Film "rgb" WorldBegin"#;
+        let strict = SceneLoader::new(txt, PathBuf::new(), &rgbtospec)
+            .with_strict(true)
+            .load();
+        assert!(strict.is_err());
+    }
+
+    #[test]
+    fn test_numeric_coercion() {
+        let load_fov = |fov: &str| {
+            let scene = load_str(&format!(
+                r#"
+                Camera "perspective" {fov}
+                Film "rgb"
+                WorldBegin
+                Shape "sphere" "integer radius" 2
+                "#
+            ))
+            .unwrap();
+
+            let radius = match &scene.shapes[0].shape {
+                Shape::Sphere(sphere) => sphere.radius,
+                _ => unreachable!(),
+            };
+            (scene.options.camera.fov, radius)
+        };
+
+        assert_eq!(load_fov(r#""float fov" [45]"#), (45., 2.));
+        assert_eq!(load_fov(r#""float fov" [45.0]"#), (45., 2.));
+        assert_eq!(load_fov(r#""float fov" 45"#), (45., 2.));
+        assert_eq!(load_fov(r#""integer fov" [45]"#), (45., 2.));
+
+        let err = load_str(
+            r#"
+            Camera "perspective" "string fov" "wide"
+            WorldBegin
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Expected float value"), "{err}");
+    }
+
+    #[test]
+    fn test_spectrum_ior() {
+        let load_conductor = |params: &str| {
+            let scene = load_str(&format!(
+                r#"
+                Camera "perspective"
+                Film "rgb"
+                WorldBegin
+                MakeNamedMaterial "metal" "string type" "conductor" {params}
+                NamedMaterial "metal"
+                Shape "sphere"
+                "#
+            ))?;
+
+            match &scene.shapes[0].material {
+                Material::Conductor(conductor) => Ok(conductor.clone()),
+                m => Err(eyre!("Expected a conductor, got '{:?}'", m)),
+            }
+        };
+
+        let conductor =
+            load_conductor(r#""spectrum eta" [400 1.5 700 1.6] "spectrum k" [400.0 3 700.0 4]"#)
+                .unwrap();
+        assert!((conductor.ior.eval_single(550.) - 1.55).abs() < 1e-5);
+        assert!((conductor.absorbtion_k.eval_single(475.) - 3.25).abs() < 1e-5);
+        assert_eq!(conductor.ior.eval_single(380.), 1.5);
+
+        // Constants and RGBs are spectra as well
+        let conductor = load_conductor(r#""float eta" 1.2 "rgb k" [2 2 2]"#).unwrap();
+        assert_eq!(conductor.ior.eval_single(550.), 1.2);
+        assert!(matches!(conductor.absorbtion_k, Spectrum::Rgb(_)));
+
+        assert!(load_conductor(r#""spectrum eta" [700 1.5 400 1.6] "float k" 3"#).is_err());
+        assert!(load_conductor(r#""spectrum eta" [400 1.5 700] "float k" 3"#).is_err());
+        assert!(load_conductor(r#""spectrum eta" "metal-Cu-eta" "float k" 3"#).is_err());
+
+        let conductor = load_conductor(r#""float eta" 1.2 "float k" 7"#).unwrap();
+        assert!(conductor.thin_film.is_none());
+        let conductor =
+            load_conductor(r#""float eta" 1.2 "float k" 7 "float filmthickness" 400"#).unwrap();
+        let film = conductor.thin_film.unwrap();
+        assert_eq!((film.thickness, film.ior), (400., 1.33));
+        let conductor = load_conductor(
+            r#""float eta" 1.2 "float k" 7 "float filmthickness" 250 "float filmeta" 1.5"#,
+        )
+        .unwrap();
+        let film = conductor.thin_film.unwrap();
+        assert_eq!((film.thickness, film.ior), (250., 1.5));
+        assert!(load_conductor(r#""float eta" 1.2 "float k" 7 "float filmthickness" -1"#).is_err());
+
+        // Dielectrics are still placeholders, but their IOR is checked
+        let load_dielectric = |eta: &str| {
+            load_str(&format!(
+                r#"
+                Camera "perspective"
+                Film "rgb"
+                WorldBegin
+                MakeNamedMaterial "glass" "string type" "dielectric" {eta}
+                NamedMaterial "glass"
+                Shape "sphere"
+                "#
+            ))
+        };
+        assert!(load_dielectric(r#""spectrum eta" [400 1.5 700 1.6]"#).is_ok());
+        assert!(load_dielectric(r#""spectrum eta" [700 1.5 400 1.6]"#).is_err());
+    }
+
+    #[test]
+    fn test_rec2020_color_space() {
+        use rgb2spec::optimize::{gamut::Gamut, optimize};
+
+        use crate::color::spectrum::{Observer, CIE_D65, LAMBDA_MAX, LAMBDA_MIN};
+
+        // Low-resolution tables are enough for one color, the full ones are loaded from files
+        let srgb = optimize(Gamut::SRGB, 16).unwrap();
+        let rec2020 = optimize(Gamut::REC2020, 16).unwrap();
+        let dci_p3 =
+            rgb_spectrum::resample_rgbtospec(&rec2020, ColorSpace::Rec2020, ColorSpace::DciP3, 16);
+
+        let load_txt = |txt: &str| {
+            SceneLoader::new(txt, PathBuf::new(), &srgb)
+                .with_rgbtospec_tables(&|color_space| match color_space {
+                    ColorSpace::Srgb => Ok(&srgb),
+                    ColorSpace::Rec2020 => Ok(&rec2020),
+                    ColorSpace::DciP3 => Ok(&dci_p3),
+                })
+                .load()
+        };
+        let load = |directive: &str| {
+            load_txt(&format!(
+                r#"
+                {directive}
+                Camera "perspective"
+                Film "rgb"
+                WorldBegin
+                MakeNamedMaterial "red" "string type" "diffuse" "rgb reflectance" [0.4 0.02 0.02]
+                NamedMaterial "red"
+                Shape "sphere"
+                AttributeBegin
+                AreaLightSource "diffuse" "rgb L" [1 0.1 0.1]
+                Shape "sphere"
+                AttributeEnd
+                "#
+            ))
+        };
+
+        // Color of the shape's reflectance under the white point illuminant
+        let reflected_xyz_of = |scene: &SceneDescription, shape: usize| {
+            let Material::Diffuse(diffuse) = &scene.shapes[shape].material else {
+                panic!("Expected a diffuse material");
+            };
+            let cmfs = Observer::Cie1931.cmfs();
+            let (mut xyz, mut white_y) = (Vec3::ZERO, 0.);
+            for lambda in LAMBDA_MIN..=LAMBDA_MAX {
+                let lambda = lambda as f32;
+                let cmf = vec3(
+                    cmfs.x.eval_single(lambda),
+                    cmfs.y.eval_single(lambda),
+                    cmfs.z.eval_single(lambda),
+                );
+                let illuminant = CIE_D65.eval_single(lambda);
+                xyz += cmf * illuminant * diffuse.reflectance.eval_single(lambda);
+                white_y += cmfs.y.eval_single(lambda) * illuminant;
+            }
+            xyz / white_y
+        };
+        let reflected_xyz = |scene: &SceneDescription| reflected_xyz_of(scene, 0);
+
+        // The saturated red is outside of the sRGB gamut
+        let scene = load(r#"ColorSpace "rec2020""#).unwrap();
+        assert_eq!(scene.options.color_space, ColorSpace::Rec2020);
+        assert!(scene.shapes[1].area_light.is_some());
+        let xyz = reflected_xyz(&scene);
+        let red = ColorSpace::Rec2020.from_xyz(xyz);
+        assert!(red.abs_diff_eq(vec3(0.4, 0.02, 0.02), 0.02), "{red}");
+        let clamped = ColorSpace::Srgb.to_xyz(ColorSpace::Srgb.from_xyz(xyz));
+        assert!(!clamped.abs_diff_eq(xyz, 0.01), "{clamped} {xyz}");
+
+        // The same RGB in sRGB is a darker, less saturated red
+        let scene = load("").unwrap();
+        assert_eq!(scene.options.color_space, ColorSpace::Srgb);
+        let srgb_red = ColorSpace::Rec2020.from_xyz(reflected_xyz(&scene));
+        assert!(srgb_red.x < 0.3 && srgb_red.y > red.y, "{srgb_red}");
+
+        // The DCI-P3 table is resampled from the Rec.2020 one
+        let scene = load(r#"ColorSpace "dci-p3""#).unwrap();
+        let p3_red = ColorSpace::DciP3.from_xyz(reflected_xyz(&scene));
+        assert!(p3_red.abs_diff_eq(vec3(0.4, 0.02, 0.02), 0.02), "{p3_red}");
+
+        // Only the supported spaces
+        assert!(load(r#"ColorSpace "adobe-rgb""#).is_err());
+
+        // Like in PBRT, the color space is a part of the graphics state and AttributeEnd restores
+        // it. The image keeps the color space from WorldBegin.
+        let scene = load_txt(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            AttributeBegin
+            ColorSpace "rec2020"
+            MakeNamedMaterial "wide" "string type" "diffuse" "rgb reflectance" [0.4 0.02 0.02]
+            NamedMaterial "wide"
+            Shape "sphere"
+            AttributeEnd
+            MakeNamedMaterial "red" "string type" "diffuse" "rgb reflectance" [0.4 0.02 0.02]
+            NamedMaterial "red"
+            Shape "sphere"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(scene.options.color_space, ColorSpace::Srgb);
+        let in_block = ColorSpace::Rec2020.from_xyz(reflected_xyz_of(&scene, 0));
+        assert!(in_block.abs_diff_eq(red, 1e-4), "{in_block}");
+        let after_block = ColorSpace::Rec2020.from_xyz(reflected_xyz_of(&scene, 1));
+        assert!(after_block.abs_diff_eq(srgb_red, 1e-4), "{after_block}");
+    }
+
+    #[test]
+    fn test_remap_roughness() {
+        let load_conductor = |params: &str| {
+            let scene = load_str(&format!(
+                r#"
+                Camera "perspective"
+                Film "rgb"
+                WorldBegin
+                MakeNamedMaterial "metal" "string type" "conductor" "float eta" 0.2 "float k" 3
+                    {params}
+                NamedMaterial "metal"
+                Shape "sphere"
+                "#
+            ))
+            .unwrap();
+            scene.shapes[0].material.clone()
+        };
+
+        let remapped = load_conductor(r#""float roughness" 0.04"#);
+        let explicit = load_conductor(r#""float roughness" 0.04 "bool remaproughness" true"#);
+        let raw = load_conductor(r#""float roughness" 0.04 "bool remaproughness" false"#);
+        let alpha = |m: &Material| match m {
+            Material::Conductor(c) => (c.roughness.vroughness, c.roughness.uroughness),
+            m => panic!("Expected a conductor, got '{:?}'", m),
+        };
+        assert!((alpha(&remapped).0 - 0.2).abs() < 1e-6);
+        assert_eq!(alpha(&remapped), alpha(&explicit));
+        assert_eq!(alpha(&raw), (0.04, 0.04));
+
+        let anisotropic = load_conductor(r#""float vroughness" 0.09 "float uroughness" 0.16"#);
+        let (v, u) = alpha(&anisotropic);
+        assert!((v - 0.3).abs() < 1e-6 && (u - 0.4).abs() < 1e-6);
+
+        // The remapped alpha is larger, the sampled directions spread further from the mirror one
+        let rgbtospec = flat_rgbtospec();
+        let scratch = RenderScratch::new(&rgbtospec);
+        let mut rng = Sampler::seed_from_u64(0);
+        let mut lobe_width = |material: &Material| {
+            let mut bxdf = Bxdf::new(material, &scratch, &mut rng);
+            let angles: Vec<f32> = (0..4096)
+                .filter_map(|_| bxdf.sample(Vec3::Z, Vec3::Z))
+                .map(|dir| dir.z.clamp(-1., 1.).acos())
+                .collect();
+            angles.iter().sum::<f32>() / angles.len() as f32
+        };
+        let (wide, narrow) = (lobe_width(&remapped), lobe_width(&raw));
+        assert!(wide > 2. * narrow, "{wide} {narrow}");
+    }
+
+    #[test]
+    fn test_mix_material() {
+        let load_mix = |params: &str| {
+            let scene = load_str(&format!(
+                r#"
+                Camera "perspective"
+                Film "rgb"
+                WorldBegin
+                MakeNamedMaterial "matte" "string type" "diffuse"
+                MakeNamedMaterial "metal" "string type" "conductor" "float eta" 0.2 "float k" 3
+                MakeNamedMaterial "glass" "string type" "interface"
+                MakeNamedMaterial "mixed" "string type" "mix" {params}
+                NamedMaterial "mixed"
+                Shape "sphere"
+                "#
+            ))?;
+
+            match &scene.shapes[0].material {
+                Material::Mix(mix) => Ok(mix.clone()),
+                m => Err(eyre!("Expected a mix, got '{:?}'", m)),
+            }
+        };
+
+        let mix = load_mix(r#""string materials" ["matte" "metal"] "float amount" 0.25"#).unwrap();
+        assert!(matches!(mix.materials[0], Material::Diffuse(_)));
+        assert!(matches!(mix.materials[1], Material::Conductor(_)));
+        assert_eq!(mix.amount.eval(None), 0.25);
+
+        let mix = load_mix(r#""string materials" ["metal" "matte"]"#).unwrap();
+        assert!(matches!(mix.materials[0], Material::Conductor(_)));
+        assert_eq!(mix.amount.eval(None), 0.5);
+
+        assert!(load_mix(r#""string materials" ["matte" "unknown"]"#).is_err());
+        assert!(load_mix(r#""string materials" ["matte" "glass"]"#).is_err());
+        assert!(load_mix(r#""string materials" "matte""#).is_err());
+        assert!(load_mix(r#""string materials" ["matte" "metal"] "texture amount" "t""#).is_err());
+    }
+
+    #[test]
+    fn test_camera_fov_axis() {
+        let load_axis = |params: &str| {
+            load_str(&format!(
+                r#"
+                Camera "perspective" "float fov" 45 {params}
+                Film "rgb"
+                WorldBegin
+                "#
+            ))
+            .map(|scene| scene.options.camera.fov_axis)
+        };
+
+        assert_eq!(load_axis("").unwrap(), FovAxis::Narrower);
+        assert_eq!(
+            load_axis(r#""string fovaxis" "vertical""#).unwrap(),
+            FovAxis::Vertical
+        );
+        assert_eq!(
+            load_axis(r#""string fovaxis" ["horizontal"]"#).unwrap(),
+            FovAxis::Horizontal
+        );
+        assert!(load_axis(r#""string fovaxis" "diagonal""#).is_err());
+    }
+
+    #[test]
+    fn test_bracketed_single_values() {
+        let load_sphere = |radius: &str| {
+            let scene = load_str(&format!(
+                r#"
+                Camera "perspective"
+                Film "rgb"
+                WorldBegin
+                AttributeBegin
+                AreaLightSource "diffuse" "float scale" {radius}
+                Shape "sphere" "float radius" {radius}
+                AttributeEnd
+                "#
+            ))
+            .unwrap();
+
+            let shape = &scene.shapes[0];
+            let radius = match &shape.shape {
+                Shape::Sphere(sphere) => sphere.radius,
+                _ => unreachable!(),
+            };
+            (radius, shape.area_light.as_ref().unwrap().scale)
+        };
+
+        assert_eq!(load_sphere("2.5"), (2.5, 2.5));
+        assert_eq!(load_sphere("[2.5]"), (2.5, 2.5));
+        assert_eq!(load_sphere("[ 2.5 ]"), load_sphere("2.5"));
+
+        // The accessors convert between single values and one-element lists
+        let single = ListParam::new("radius", ListParamValue::Single(Value::Float(2.5)));
+        let list = ListParam::new(
+            "radius",
+            ListParamValue::List(ValueList::Float(SmallVec::from_slice(&[2.5]))),
+        );
+        for p in [&single, &list] {
+            assert_eq!(p.expect_single().unwrap().expect_float().unwrap(), 2.5);
+            assert_eq!(
+                p.expect_single_named("radius")
+                    .unwrap()
+                    .expect_float()
+                    .unwrap(),
+                2.5
+            );
+            assert!(matches!(p.expect_list().unwrap(), ValueList::Float(f) if f[..] == [2.5]));
+        }
+
+        let long_list = ListParam::new(
+            "radius",
+            ListParamValue::List(ValueList::Float(SmallVec::from_slice(&[1., 2.]))),
+        );
+        assert!(long_list.expect_single().is_err());
+        assert!(single.expect_single_named("scale").is_err());
+    }
+
+    #[test]
+    fn test_unbracketed_values() {
+        let scene = load_str(
+            r#"
+            Camera "perspective" "float fov" 45
+            Film "rgb"
+            WorldBegin
+            LightSource "projection" "string filename" "slide.exr"
+                "point3 from" 1 2 3 "point3 to" [0 0 -1] "float fov" 30
+            AreaLightSource "diffuse" "bool twosided" "true" "rgb L" 1 1 1
+            Shape "sphere" "float radius" 2"#,
+        )
+        .unwrap();
+
+        assert_eq!(scene.options.camera.fov, 45.);
+        let light = &scene.projection_lights[0];
+        assert_eq!(light.from, vec3(1., 2., 3.));
+        assert_eq!(light.to, vec3(0., 0., -1.));
+        assert_eq!(light.fov, 30.);
+
+        // The last param of the file
+        let Shape::Sphere(sphere) = &scene.shapes[0].shape else {
+            panic!("expected a sphere");
+        };
+        assert_eq!(sphere.radius, 2.);
+
+        // Lists need brackets, the extra numbers aren't taken as another value
+        let err = load_str(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            Shape "sphere" "float radius" 1 2
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Expected token"), "{err}");
+
+        // A vector without enough numbers
+        assert!(load_str(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            LightSource "projection" "string filename" "slide.exr" "point3 from" 1 2
+            "#,
+        )
+        .is_err());
+    }
+
+    fn load_mesh(params: &str) -> Result<SceneDescription> {
+        load_str(&format!(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            Shape "trianglemesh" "point3 P" [0 0 0 1 0 0 1 1 0 0 1 0] {params}
+            "#
+        ))
+    }
+
+    #[test]
+    fn test_trianglemesh_attribute_lengths() {
+        let indices = r#""integer indices" [0 1 2 0 2 3]"#;
+
+        let scene = load_mesh(&format!(r#"{indices} "point2 uv" [0 0 1 0 1 1 0 1]"#)).unwrap();
+        assert_eq!(scene.shapes.len(), 1);
+
+        let err = load_mesh(&format!(r#"{indices} "point2 uv" [0 0 1 0 1 1]"#)).unwrap_err();
+        assert!(err.to_string().contains("3 UVs but 4 vertices"), "{err}");
+
+        let err = load_mesh(&format!(r#"{indices} "normal N" [0 0 1 0 0 1]"#)).unwrap_err();
+        assert!(
+            err.to_string().contains("2 normals but 4 vertices"),
+            "{err}"
+        );
+
+        // Caught while loading, not by a panic when the triangles are intersected
+        let err = load_mesh(r#""integer indices" [0 1 2 0 2 4]"#).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("index 4 is out of bounds for 4 vertices"),
+            "{err}"
+        );
+        let err = load_mesh(r#""integer indices" [0 1 2 0 -1 3]"#).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("index -1 is out of bounds for 4 vertices"),
+            "{err}"
+        );
+
+        let err = load_mesh(r#""integer indices" [0 1 2 0]"#).unwrap_err();
+        assert!(err.to_string().contains("multiple of 3"), "{err}");
+    }
+
+    #[test]
+    fn test_trianglemesh_implicit_indices() {
+        let scene = load_str(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            Shape "trianglemesh" "point3 P" [0 0 0 1 0 0 1 1 0  0 0 1 1 0 1 1 1 1]
+            "#,
+        )
+        .unwrap();
+
+        let Shape::TriMesh(mesh) = &scene.shapes[0].shape else {
+            panic!("expected a triangle mesh");
+        };
+        assert_eq!(mesh.indices, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(mesh.pos.len(), 6);
+        assert_eq!(mesh.pos[mesh.indices[3] as usize], vec3(0., 0., 1.));
+
+        let err = load_mesh("").unwrap_err();
+        assert!(
+            err.to_string().contains("expected a multiple of 3"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_vertex_colors() {
+        let ply = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\n\
+            property float z\nproperty uchar red\nproperty uchar green\nproperty uchar blue\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n\
+            0 0 0 255 0 0\n1 0 0 0 255 0\n0 1 0 0 0 255\n3 0 1 2\n";
+        let ply_path = test_dir("vertex-colors").join("colors.ply");
+        std::fs::write(&ply_path, ply).unwrap();
+
+        let scene = load_str(&format!(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            MakeNamedMaterial "colored" "string type" "diffuse"
+            NamedMaterial "colored"
+            Shape "trianglemesh" "point3 P" [0 0 0 1 0 0 0 1 0]
+                "rgb Cs" [1 0 0 0 1 0 0 0 1]
+            Shape "plymesh" "string filename" "{}"
+            "#,
+            ply_path.display()
+        ))
+        .unwrap();
+
+        for shape in &scene.shapes {
+            let Shape::TriMesh(mesh) = &shape.shape else {
+                panic!("expected a triangle mesh");
+            };
+            let Material::Diffuse(diffuse) = &shape.material else {
+                panic!("expected a diffuse material");
+            };
+            assert!(diffuse.use_vertex_colors);
+
+            let mesh = Arc::new(TriangleMesh::new(
+                mesh.clone(),
+                Arc::new(shape.material.clone()),
+                shape.object_to_world,
+                false,
+                None,
+            ));
+            let centroid = vec3(1. / 3., 1. / 3., 0.);
+            let ray = Ray::new(centroid + Vec3::Z, -Vec3::Z);
+            let triangle = Triangle::new(mesh, 0);
+            let hit = triangle.intersect(&ray).unwrap();
+            let color = triangle.color_at(hit.barycentrics.unwrap()).unwrap();
+            assert!(color.abs_diff_eq(Vec3::splat(1. / 3.), 1e-5), "{color}");
+        }
+
+        let err =
+            load_mesh(r#""integer indices" [0 1 2] "rgb Cs" [1 0 0 0 1 0 0 0 1]"#).unwrap_err();
+        assert!(err.to_string().contains("3 colors but 4 vertices"), "{err}");
+    }
+
+    #[test]
+    fn test_bump_map_param() {
+        let scene = load_str(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            Texture "bumps" "float" "imagemap" "string filename" "bumps.png"
+            MakeNamedMaterial "rough" "string type" "diffuse" "texture displacement" "bumps"
+            NamedMaterial "rough"
+            Shape "sphere"
+            NamedMaterial "metal"
+            Shape "sphere"
+            MakeNamedMaterial "metal" "string type" "conductor" "float eta" 0.2 "float k" 3
+                "texture bumpmap" "bumps"
+            MakeNamedMaterial "plain" "string type" "diffuse"
+            NamedMaterial "plain"
+            Shape "sphere"
+            "#,
+        )
+        .unwrap();
+
+        // Shapes whose material is defined later get the bump map as well
+        let bump_maps: Vec<_> = scene.shapes.iter().map(|s| s.bump_map.clone()).collect();
+        let path = Some(PathBuf::from("bumps.png"));
+        assert_eq!(bump_maps, [path.clone(), path, None]);
+
+        let err = load_str(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            MakeNamedMaterial "rough" "string type" "diffuse" "texture displacement" "missing"
+            "#,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("undefined textures: 'missing'"),
+            "{err}"
+        );
+
+        // PBRT doesn't have the parameter on shapes
+        let txt = r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            Texture "bumps" "float" "imagemap" "string filename" "bumps.png"
+            Shape "sphere" "texture displacement" "bumps"
+            "#;
+        assert_eq!(load_str(txt).unwrap().shapes[0].bump_map, None);
+        let rgbtospec = flat_rgbtospec();
+        let strict = SceneLoader::new(txt, PathBuf::new(), &rgbtospec)
+            .with_strict(true)
+            .load();
+        assert!(strict.is_err());
+    }
+
+    #[test]
+    fn test_material_references() {
+        // Named materials can be defined after the shapes that use them
+        let scene = load_str(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            NamedMaterial "metal"
+            Shape "sphere"
+            MakeNamedMaterial "metal" "string type" "conductor" "float eta" 0.2 "float k" 3
+            Shape "sphere"
+            "#,
+        )
+        .unwrap();
+        assert!(scene
+            .shapes
+            .iter()
+            .all(|s| matches!(s.material, Material::Conductor(_))));
+
+        // All the undefined names are listed instead of panicking on the first one
+        let err = load_str(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            NamedMaterial "gold"
+            Shape "sphere" "texture alpha" "leaves"
+            NamedMaterial "silver"
+            Shape "sphere"
+            MakeNamedMaterial "rough" "string type" "diffuse" "texture displacement" "bumps"
+            NamedMaterial "gold"
+            Shape "sphere"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Scene references undefined materials: 'gold', 'silver'; \
+            undefined textures: 'bumps', 'leaves'"
+        );
+
+        let duplicate = r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            MakeNamedMaterial "matte" "string type" "diffuse"
+            MakeNamedMaterial "matte" "string type" "diffuse"
+            "#;
+        assert!(load_str(duplicate).is_ok());
+        let rgbtospec = flat_rgbtospec();
+        let strict = SceneLoader::new(duplicate, PathBuf::new(), &rgbtospec)
+            .with_strict(true)
+            .load();
+        assert!(strict.is_err());
+    }
+
+    #[test]
+    fn test_shape_reverse_orientation() {
+        let scene = load_str(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            Shape "sphere"
+            Shape "sphere" "bool reverseorientation" true
+            Shape "sphere"
+            ReverseOrientation
+            Shape "sphere" "bool reverseorientation" true
+            Shape "sphere" "bool reverseorientation" false
+            "#,
+        )
+        .unwrap();
+
+        let reversed: Vec<bool> = scene.shapes.iter().map(|s| s.reverse_normals).collect();
+        assert_eq!(reversed, [false, true, false, true, true]);
+
+        let ray = Ray::new(vec3(0., 0., -3.), Vec3::Z);
+        let normal = |shape: &ShapeWithParams| {
+            let Shape::Sphere(desc) = &shape.shape else {
+                panic!("expected a sphere");
+            };
+            geometry::sphere::Sphere::new(shape, desc)
+                .hit(&ray)
+                .unwrap()
+                .normal
+        };
+        assert_eq!(normal(&scene.shapes[0]), -Vec3::Z);
+        assert_eq!(normal(&scene.shapes[1]), Vec3::Z);
+        assert_eq!(normal(&scene.shapes[2]), -Vec3::Z);
+    }
+
+    #[test]
+    fn test_coordinate_systems() {
+        let scene = load_str(
+            r#"
+            Scale 2 2 2
+            CoordinateSystem "scaled"
+            Identity
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            CoordSysTransform "scaled"
+            Shape "sphere"
+            Scale 3 3 3
+            CoordinateSystem "world-scaled"
+            Identity
+            Shape "sphere"
+            CoordSysTransform "world-scaled"
+            Shape "sphere"
+            CoordSysTransform "camera"
+            Shape "sphere"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            scene.options.camera.camera_from_world_transform,
+            Mat4::IDENTITY
+        );
+
+        let transforms: Vec<Mat4> = scene.shapes.iter().map(|s| s.object_to_world).collect();
+        assert_eq!(transforms[0], Mat4::from_scale(Vec3::splat(2.)));
+        assert_eq!(transforms[1], Mat4::IDENTITY);
+        assert_eq!(transforms[2], Mat4::from_scale(Vec3::splat(6.)));
+        assert_eq!(transforms[3], Mat4::IDENTITY);
+
+        assert!(load_str(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            CoordSysTransform "unknown"
+            "#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_camera_transform_chain() {
+        let camera_scene = |transforms: &str| {
+            let scene = load_str(&format!(
+                r#"
+                {transforms}
+                Camera "perspective"
+                Film "rgb"
+                WorldBegin
+                "#
+            ))
+            .unwrap();
+            scene.options.camera.camera_from_world_transform
+        };
+
+        // Camera at (3, 1, 0) looking along +X
+        let look_at = camera_scene("LookAt 3 1 0  4 1 0  0 1 0");
+        // World +X has to end up as camera +Z
+        let chain = camera_scene("Rotate -90 0 1 0 Translate -3 -1 0");
+        assert!(look_at.abs_diff_eq(chain, 1e-5), "{look_at} {chain}");
+
+        let cam = crate::camera::Camera::new(64, 48, 60.);
+        for uv in [Vec2::ZERO, Vec2::splat(0.5), vec2(0.9, 0.2), Vec2::ONE] {
+            let mut look_at_ray = cam.gen_ray(uv);
+            look_at_ray.transform(look_at.inverse());
+            let mut chain_ray = cam.gen_ray(uv);
+            chain_ray.transform(chain.inverse());
+
+            assert!(look_at_ray.orig.abs_diff_eq(chain_ray.orig, 1e-5));
+            assert!(look_at_ray.dir.abs_diff_eq(chain_ray.dir, 1e-5));
+        }
+
+        let mut center = cam.gen_ray(Vec2::splat(0.5));
+        center.transform(chain.inverse());
+        assert!(center.orig.abs_diff_eq(vec3(3., 1., 0.), 1e-5));
+        assert!(center.dir.normalize().abs_diff_eq(Vec3::X, 1e-5));
+
+        assert!(load_str(r#"Rotate 90 0 0 0 Camera "perspective" Film "rgb" WorldBegin"#).is_err());
+    }
+
+    #[test]
+    fn test_asset_path_resolution() {
+        let absolute = std::env::temp_dir().join("projector.exr");
+        let txt = format!(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            LightSource "infinite" "string filename" "textures/sky.exr"
+            LightSource "projection" "string filename" "{}"
+            "#,
+            absolute.display()
+        );
+
+        let rgbtospec = flat_rgbtospec();
+        let scene = SceneLoader::new(&txt, PathBuf::from("scenes/room"), &rgbtospec)
+            .load()
+            .unwrap();
+
+        assert_eq!(
+            scene.infinite_light.unwrap().filepath,
+            Path::new("scenes/room/textures/sky.exr")
+        );
+        assert_eq!(scene.projection_lights[0].filepath, absolute);
+    }
+
+    #[test]
+    fn test_infinite_light_tint() {
+        let load = |scale: &str| {
+            let txt = format!(
+                r#"Camera "perspective" Film "rgb" WorldBegin
+                LightSource "infinite" "string filename" "sky.exr" {scale}"#
+            );
+            let rgbtospec = flat_rgbtospec();
+            SceneLoader::new(&txt, PathBuf::new(), &rgbtospec)
+                .load()
+                .map(|scene| scene.infinite_light.unwrap())
+        };
+
+        let light = load(r#""rgb scale" [1 0.5 0.25]"#).unwrap();
+        assert_eq!(light.scale, 1.);
+        assert_eq!(light.tint, vec3(1., 0.5, 0.25));
+
+        let light = load(r#""float scale" 2"#).unwrap();
+        assert_eq!(light.scale, 2.);
+        assert_eq!(light.tint, Vec3::ONE);
+
+        let light = load("").unwrap();
+        assert_eq!((light.scale, light.tint), (1., Vec3::ONE));
+    }
+
+    #[test]
+    fn test_unknown_params() {
+        let txt = r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            AreaLightSource "diffuse" "rgb L" [1 1 1] "bool twosided" true
+            Shape "sphere" "float radius" 2 "float zmax" 0.5
+            "#;
+
+        let rgbtospec = flat_rgbtospec();
+        let scene = SceneLoader::new(txt, PathBuf::new(), &rgbtospec)
+            .load()
+            .unwrap();
+        match &scene.shapes[0].shape {
+            Shape::Sphere(sphere) => assert_eq!(sphere.radius, 2.),
+            _ => panic!("Expected a sphere"),
+        }
+        assert!(scene.shapes[0].area_light.is_some());
+
+        let strict = SceneLoader::new(txt, PathBuf::new(), &rgbtospec)
+            .with_strict(true)
+            .load();
+        assert!(strict.is_err());
+    }
+
+    #[test]
+    fn test_measured_unsupported_format() {
+        let txt = r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            MakeNamedMaterial "paint" "string type" "measured"
+                "string filename" "gold-metallic-paint.bsdf"
+            NamedMaterial "paint"
+            Shape "sphere"
+            "#;
+        let scene = load_str(txt).unwrap();
+        assert!(matches!(scene.shapes[0].material, Material::Diffuse(_)));
+
+        let rgbtospec = flat_rgbtospec();
+        let strict = SceneLoader::new(txt, PathBuf::new(), &rgbtospec)
+            .with_strict(true)
+            .load();
+        assert!(strict.is_err());
+    }
+}
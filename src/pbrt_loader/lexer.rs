@@ -80,6 +80,9 @@ impl<'t> Lexer<'t> {
                     Lexeme::CloseBracket
                 }
                 ch if ch.is_alphabetic() => self.lex_str(),
+                // File paths
+                '/' | '\\' | '_' => self.lex_str(),
+                '.' if self.txt.starts_with("./") || self.txt.starts_with("../") => self.lex_str(),
                 '-' | '.' => self.lex_num(),
                 ch if ch.is_ascii_digit() => self.lex_num(),
                 ch => return Err(eyre!("Invalid character: '{}'", ch)),
@@ -269,4 +272,27 @@ mod test_super {
         assert_eq!(lexer.next().unwrap(), Lexeme::Num("1.91069e-15"));
         assert_eq!(lexer.next().unwrap(), Lexeme::Eof);
     }
+
+    #[test]
+    fn test_paths() {
+        let input = r#""string filename" "/abs/sky.exr" "../tex/a.png" "./b.png" .5"#;
+
+        let mut lexer = Lexer::new(input);
+
+        assert_eq!(lexer.next().unwrap(), Lexeme::Qoutes);
+        assert_eq!(lexer.next().unwrap(), Lexeme::Str("string"));
+        assert_eq!(lexer.next().unwrap(), Lexeme::Str("filename"));
+        assert_eq!(lexer.next().unwrap(), Lexeme::Qoutes);
+        assert_eq!(lexer.next().unwrap(), Lexeme::Qoutes);
+        assert_eq!(lexer.next().unwrap(), Lexeme::Str("/abs/sky.exr"));
+        assert_eq!(lexer.next().unwrap(), Lexeme::Qoutes);
+        assert_eq!(lexer.next().unwrap(), Lexeme::Qoutes);
+        assert_eq!(lexer.next().unwrap(), Lexeme::Str("../tex/a.png"));
+        assert_eq!(lexer.next().unwrap(), Lexeme::Qoutes);
+        assert_eq!(lexer.next().unwrap(), Lexeme::Qoutes);
+        assert_eq!(lexer.next().unwrap(), Lexeme::Str("./b.png"));
+        assert_eq!(lexer.next().unwrap(), Lexeme::Qoutes);
+        assert_eq!(lexer.next().unwrap(), Lexeme::Num(".5"));
+        assert_eq!(lexer.next().unwrap(), Lexeme::Eof);
+    }
 }
@@ -1,6 +1,6 @@
 use eyre::{eyre, Result};
 use glam::{Vec2, Vec3};
-use smallvec::SmallVec;
+use smallvec::{smallvec, SmallVec};
 
 use super::Int;
 
@@ -46,6 +46,14 @@ impl<'t> ParamList<'t> {
     pub fn get(&self, name: &str) -> Option<&ListParam<'t>> {
         self.params[self.index..].iter().find(|p| p.name == name)
     }
+
+    /// Removes the param from the list, so that it isn't visited by params() anymore
+    pub fn take(&mut self, name: &str) -> Option<ListParam<'t>> {
+        let pos = self.params[self.index..]
+            .iter()
+            .position(|p| p.name == name)?;
+        Some(self.params.remove(self.index + pos))
+    }
 }
 
 #[derive(Debug)]
@@ -73,23 +81,30 @@ impl<'t> ListParam<'t> {
         }
     }
 
-    pub fn expect_single(&self) -> Result<&Value<'t>> {
+    /// A one-element list is accepted as well, PBRT treats `1` and `[1]` the same
+    pub fn expect_single(&self) -> Result<Value<'t>> {
         match &self.value {
-            ListParamValue::Single(value) => Ok(value),
+            ListParamValue::Single(value) => Ok(value.clone()),
+            ListParamValue::List(values) if values.len() == 1 => Ok(values.first()),
             var => Err(eyre!("Expected single value param, got '{:?}'", var)),
         }
     }
 
-    pub fn expect_single_named(&self, name: &str) -> Result<&Value<'t>> {
-        match &self.value {
-            ListParamValue::Single(value) if self.name == name => Ok(value),
-            var => Err(eyre!("Expected single value param, got '{:?}'", var)),
+    pub fn expect_single_named(&self, name: &str) -> Result<Value<'t>> {
+        if self.name != name {
+            return Err(eyre!("Expected param '{}', got '{:?}'", name, self));
         }
+
+        self.expect_single()
     }
 
-    pub fn expect_list(&self) -> Result<&ValueList> {
+    /// A single value is accepted as a one-element list
+    pub fn expect_list(&self) -> Result<ValueList<'t>> {
         match &self.value {
-            ListParamValue::List(values) => Ok(values),
+            ListParamValue::List(values) => Ok(values.clone()),
+            ListParamValue::Single(value) => value
+                .to_list()
+                .ok_or_else(|| eyre!("Value '{:?}' can't be a list", value)),
             var => Err(eyre!("Expected list value param, got '{:?}'", var)),
         }
     }
@@ -102,7 +117,7 @@ pub enum ListParamValue<'t> {
     /// Param with a type, name and a single value
     Single(Value<'t>),
     /// Param with a type, name and a list of values
-    List(ValueList),
+    List(ValueList<'t>),
 }
 
 #[derive(Debug, Clone)]
@@ -114,7 +129,8 @@ pub enum Value<'t> {
     Point3(Vec3),
     Vector3(Vec3),
     Normal3(Vec3),
-    Spectrum((Int, f32)),
+    /// Wavelength in nm and the value
+    Spectrum((f32, f32)),
     Rgb(Vec3),
     Blackbody(Int),
     Bool(bool),
@@ -126,9 +142,11 @@ impl<'t> Value<'t> {
     pub fn expect_integer(&self) -> Result<Int> {
         todo!()
     }
+    /// Integers are coerced to floats
     pub fn expect_float(&self) -> Result<f32> {
         match self {
             Value::Float(f) => Ok(*f),
+            Value::Integer(i) => Ok(*i as f32),
             _ => Err(eyre!("Expected float value, got '{:?}'", self)),
         }
     }
@@ -139,7 +157,10 @@ impl<'t> Value<'t> {
         todo!()
     }
     pub fn expect_point3(&self) -> Result<Vec3> {
-        todo!()
+        match self {
+            Value::Point3(p) => Ok(*p),
+            _ => Err(eyre!("Expected point3 value, got '{:?}'", self)),
+        }
     }
     pub fn expect_vector3(&self) -> Result<Vec3> {
         todo!()
@@ -147,8 +168,11 @@ impl<'t> Value<'t> {
     pub fn expect_normal3(&self) -> Result<Vec3> {
         todo!()
     }
-    pub fn expect_spectrum(&self) -> Result<(Int, f32)> {
-        todo!()
+    pub fn expect_spectrum(&self) -> Result<(f32, f32)> {
+        match self {
+            Value::Spectrum(sample) => Ok(*sample),
+            _ => Err(eyre!("Expected spectrum value, got '{:?}'", self)),
+        }
     }
     pub fn expect_rgb(&self) -> Result<Vec3> {
         match self {
@@ -160,7 +184,10 @@ impl<'t> Value<'t> {
         todo!()
     }
     pub fn expect_bool(&self) -> Result<bool> {
-        todo!()
+        match self {
+            Value::Bool(b) => Ok(*b),
+            _ => Err(eyre!("Expected bool value, got '{:?}'", self)),
+        }
     }
     pub fn expect_string(&self) -> Result<&'t str> {
         match self {
@@ -171,12 +198,29 @@ impl<'t> Value<'t> {
     pub fn expect_texture(&self) -> Result<&'t str> {
         todo!()
     }
+
+    /// One-element list of the value, None for types that can't be lists
+    pub fn to_list(&self) -> Option<ValueList<'t>> {
+        Some(match self {
+            Value::Integer(i) => ValueList::Integer(smallvec![*i]),
+            Value::Float(f) => ValueList::Float(smallvec![*f]),
+            Value::Point2(p) => ValueList::Point2(smallvec![*p]),
+            Value::Vector2(v) => ValueList::Vector2(smallvec![*v]),
+            Value::Point3(p) => ValueList::Point3(smallvec![*p]),
+            Value::Vector3(v) => ValueList::Vector3(smallvec![*v]),
+            Value::Normal3(n) => ValueList::Normal3(smallvec![*n]),
+            Value::Spectrum(s) => ValueList::Spectrum(smallvec![*s]),
+            Value::Rgb(rgb) => ValueList::Rgb(smallvec![*rgb]),
+            Value::String(s) => ValueList::String(smallvec![*s]),
+            _ => return None,
+        })
+    }
 }
 
 pub type ValueVec<T> = SmallVec<[T; 4]>;
 
 #[derive(Debug, Clone)]
-pub enum ValueList {
+pub enum ValueList<'t> {
     Integer(ValueVec<Int>),
     Float(ValueVec<f32>),
     Point2(ValueVec<Vec2>),
@@ -184,12 +228,47 @@ pub enum ValueList {
     Point3(ValueVec<Vec3>),
     Vector3(ValueVec<Vec3>),
     Normal3(ValueVec<Vec3>),
-    Spectrum(ValueVec<(Int, f32)>),
+    Spectrum(ValueVec<(f32, f32)>),
+    Rgb(ValueVec<Vec3>),
+    String(ValueVec<&'t str>),
+}
+
+impl<'t> ValueList<'t> {
+    fn len(&self) -> usize {
+        match self {
+            ValueList::Integer(v) => v.len(),
+            ValueList::Float(v) => v.len(),
+            ValueList::Point2(v) => v.len(),
+            ValueList::Vector2(v) => v.len(),
+            ValueList::Point3(v) => v.len(),
+            ValueList::Vector3(v) => v.len(),
+            ValueList::Normal3(v) => v.len(),
+            ValueList::Spectrum(v) => v.len(),
+            ValueList::Rgb(v) => v.len(),
+            ValueList::String(v) => v.len(),
+        }
+    }
+
+    /// The first element as a single value, the list can't be empty
+    fn first(&self) -> Value<'t> {
+        match self {
+            ValueList::Integer(v) => Value::Integer(v[0]),
+            ValueList::Float(v) => Value::Float(v[0]),
+            ValueList::Point2(v) => Value::Point2(v[0]),
+            ValueList::Vector2(v) => Value::Vector2(v[0]),
+            ValueList::Point3(v) => Value::Point3(v[0]),
+            ValueList::Vector3(v) => Value::Vector3(v[0]),
+            ValueList::Normal3(v) => Value::Normal3(v[0]),
+            ValueList::Spectrum(v) => Value::Spectrum(v[0]),
+            ValueList::Rgb(v) => Value::Rgb(v[0]),
+            ValueList::String(v) => Value::String(v[0]),
+        }
+    }
 }
 
 pub enum SingleValueOrList<'t> {
     Value(Value<'t>),
-    List(ValueList),
+    List(ValueList<'t>),
 }
 
 #[derive(PartialEq, Eq)]
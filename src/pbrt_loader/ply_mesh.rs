@@ -1,7 +1,7 @@
 use std::{
     fs::File,
     io::{BufReader, Read},
-    path::{Path, PathBuf},
+    path::Path,
 };
 
 use eyre::{eyre, Result};
@@ -67,8 +67,27 @@ pub struct PlyVertex {
     pub pos: Vec3,
     pub normal: Vec3,
     pub uv: Vec2,
+    pub color: Vec3,
     pub has_normal: bool,
     pub has_uv: bool,
+    pub has_color: bool,
+}
+
+impl PlyVertex {
+    /// 8-bit channels are normalized to [0, 1]
+    fn set_color_channel(&mut self, channel: usize, property: ply::Property) {
+        let value = match property {
+            ply::Property::UChar(v) => v as f32 / 255.,
+            ply::Property::Float(v) => v,
+            _ => {
+                eprintln!("Vertex: Unexpected color channel type");
+                return;
+            }
+        };
+
+        self.has_color = true;
+        self.color[channel] = value;
+    }
 }
 
 impl ply::PropertyAccess for PlyVertex {
@@ -77,8 +96,10 @@ impl ply::PropertyAccess for PlyVertex {
             pos: Vec3::new(0.0, 0.0, 0.0),
             normal: Vec3::new(0.0, 0.0, 0.0),
             uv: Vec2::new(0.0, 0.0),
+            color: Vec3::new(0.0, 0.0, 0.0),
             has_normal: false,
             has_uv: false,
+            has_color: false,
         }
     }
 
@@ -131,6 +152,9 @@ impl ply::PropertyAccess for PlyVertex {
                 self.has_uv = true;
                 self.uv.y = v
             }
+            ("red", p) => self.set_color_channel(0, p),
+            ("green", p) => self.set_color_channel(1, p),
+            ("blue", p) => self.set_color_channel(2, p),
             (k, _) => eprintln!("Face: Unexpected key/value combination: key: {}", k),
         }
     }
@@ -142,13 +166,13 @@ pub(super) fn parse_plymesh(file_directory: &Path, params: &[ListParam]) -> Resu
     let mut normals: Option<Vec<Vec3>> = None;
     let mut _tangents: Option<Vec<Vec3>> = None;
     let mut uvs: Option<Vec<Vec2>> = None;
+    let mut colors: Option<Vec<Vec3>> = None;
 
     for p in params {
         match (p.name, &p.value) {
             ("filename", ListParamValue::Single(Value::String(filepath))) => {
                 // TODO: couldn't extract this into it's own function becuase read_ply() returns non-exported type
-                let mut path = PathBuf::from(file_directory);
-                path.push(filepath);
+                let path = super::resolve_path(file_directory, filepath);
 
                 let ply_file = File::open(&path)?;
                 let mut reader = BufReader::new(ply_file);
@@ -187,6 +211,9 @@ pub(super) fn parse_plymesh(file_directory: &Path, params: &[ListParam]) -> Resu
                                 if vertices[0].has_uv {
                                     uvs = Some(vertices.iter().map(|v| v.uv).collect())
                                 }
+                                if vertices[0].has_color {
+                                    colors = Some(vertices.iter().map(|v| v.color).collect())
+                                }
                             }
                         }
                         "face" => {
@@ -232,18 +259,22 @@ pub(super) fn parse_plymesh(file_directory: &Path, params: &[ListParam]) -> Resu
 
     let (indices, vertices) = match (indices.len(), points) {
         (0, Some(vertices)) if vertices.len() == 3 => {
-            let indices = vec![1, 2, 3];
+            let indices = vec![0, 1, 2];
             (indices, vertices)
         }
         (len, Some(vertices)) if len >= 3 => (indices, vertices),
         _ => return Err(eyre!("Triangle mesh vertices or indices not specified")),
     };
 
-    Ok(TriMesh {
+    let mesh = TriMesh {
         indices,
         pos: vertices,
         normals,
         tangents: None,
         uvs,
-    })
+        colors,
+    };
+    mesh.validate()?;
+
+    Ok(mesh)
 }
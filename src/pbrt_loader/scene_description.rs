@@ -1,11 +1,21 @@
-use std::path::PathBuf;
+use std::{f32::consts::PI, path::PathBuf, sync::Arc};
 
-use glam::{Mat4, Vec2, Vec3};
+use eyre::{eyre, Result};
+use glam::{Mat3, Mat4, Vec2, Vec3};
 use rgb2spec::RGB2Spec;
 
-use crate::color::{
-    color_space::ColorSpace,
-    spectrum::rgb_spectrum::{RgbSpectrum, RgbSpectrumKind},
+use crate::{
+    bvh::BvhOptions,
+    bxdf::measured::MeasuredBrdf,
+    camera::FovAxis,
+    color::{
+        color_space::ColorSpace,
+        spectrum::{
+            rgb_spectrum::{RgbSpectrum, RgbSpectrumKind},
+            Spectrum,
+        },
+    },
+    texture::ImageTexture,
 };
 
 // TODO: support loading general spectra, not just RGBSpectrum...
@@ -15,6 +25,7 @@ pub struct SceneDescription {
     pub options: ScreenWideOptions,
     pub shapes: Vec<ShapeWithParams>,
     pub infinite_light: Option<InfiniteLightSource>,
+    pub projection_lights: Vec<ProjectionLightSource>,
 }
 
 #[derive(Debug, Default)]
@@ -24,13 +35,19 @@ pub struct ScreenWideOptions {
     pub sampler: Sampler,
     pub film: Film,
     pub filter: PixelFilter,
+    /// Set by the ColorSpace directive, the RGB values of the scene and the film are in it
+    pub color_space: ColorSpace,
 }
 
 #[derive(Debug)]
 pub struct Camera {
     pub typ: CameraTyp,
     pub fov: f32,
+    /// Not a PBRT parameter, the "string fovaxis" extension
+    pub fov_axis: FovAxis,
     pub camera_from_world_transform: Mat4,
+    /// Medium the camera is in, the outside medium of the MediumInterface at the Camera directive
+    pub medium: Option<HomogeneousMedium>,
 }
 
 impl Default for Camera {
@@ -38,7 +55,9 @@ impl Default for Camera {
         Self {
             typ: CameraTyp::Perspective,
             fov: 90.,
+            fov_axis: FovAxis::default(),
             camera_from_world_transform: Mat4::ZERO,
+            medium: None,
         }
     }
 }
@@ -78,6 +97,12 @@ pub struct Film {
     pub xresolution: i32,
     pub yresolution: i32,
     pub filename: String,
+    /// Multiplies the pixel values before they are written out
+    pub scale: f32,
+    /// Pixel values with a larger component are scaled down so that it doesn't exceed this
+    pub max_component_value: f32,
+    /// Write the EXR output with half-precision floats
+    pub save_fp16: bool,
 }
 
 impl Default for Film {
@@ -87,6 +112,9 @@ impl Default for Film {
             xresolution: 1280,
             yresolution: 720,
             filename: String::from("pbrt.exr"),
+            scale: 1.,
+            max_component_value: f32::INFINITY,
+            save_fp16: true,
         }
     }
 }
@@ -122,20 +150,22 @@ enum PixelFilterType {
     Triangle,
 }
 
-#[allow(dead_code)]
 #[derive(Debug)]
 pub struct RenderingOptions {
-    disablepixeljitter: bool,
-    disabletexturefiltering: bool,
-    disablewavelengthjitter: bool,
-    displacementedgescale: f32,
-    msereferenceimage: String,
-    msereferenceout: String,
-    rendercoordsys: String,
-    seed: i32,
-    forcediffuse: bool,
-    pixelstats: bool,
-    wavefront: bool,
+    pub disablepixeljitter: bool,
+    pub disabletexturefiltering: bool,
+    pub disablewavelengthjitter: bool,
+    pub displacementedgescale: f32,
+    pub msereferenceimage: String,
+    pub msereferenceout: String,
+    pub rendercoordsys: String,
+    pub seed: i32,
+    /// Every material except interfaces is replaced by a 50% gray diffuse one
+    pub forcediffuse: bool,
+    pub pixelstats: bool,
+    pub wavefront: bool,
+    /// Set by the Accelerator directive
+    pub bvh: BvhOptions,
 }
 
 impl Default for RenderingOptions {
@@ -152,6 +182,7 @@ impl Default for RenderingOptions {
             forcediffuse: false,
             pixelstats: false,
             wavefront: false,
+            bvh: BvhOptions::default(),
         }
     }
 }
@@ -163,6 +194,9 @@ pub struct ShapeWithParams {
     pub area_light: Option<AreaLightSource>,
     pub object_to_world: Mat4,
     pub reverse_normals: bool,
+    pub alpha: Option<Alpha>,
+    /// Path of a float "imagemap" texture with the heights used for bump mapping
+    pub bump_map: Option<PathBuf>,
 }
 
 impl ShapeWithParams {
@@ -172,6 +206,7 @@ impl ShapeWithParams {
         area_light: Option<AreaLightSource>,
         object_to_world: Mat4,
         reverse_normals: bool,
+        alpha: Option<Alpha>,
     ) -> Self {
         Self {
             shape,
@@ -179,14 +214,38 @@ impl ShapeWithParams {
             area_light,
             object_to_world,
             reverse_normals,
+            alpha,
+            bump_map: None,
         }
     }
+
+    pub fn with_bump_map(mut self, bump_map: Option<PathBuf>) -> Self {
+        self.bump_map = bump_map;
+        self
+    }
+
+    /// Transforms with a negative determinant mirror the geometry. Normals computed as cross
+    /// products of transformed vectors then have to be flipped to keep their orientation.
+    pub fn transform_swaps_handedness(&self) -> bool {
+        Mat3::from_mat4(self.object_to_world).determinant() < 0.
+    }
+}
+
+/// Alpha of a shape for cutouts, fully opaque shapes don't store it
+#[derive(Debug, Clone)]
+pub enum Alpha {
+    Constant(f32),
+    /// Path of a float "imagemap" texture
+    ImageTexture(PathBuf),
 }
 
 #[derive(Debug)]
 pub enum Shape {
     TriMesh(TriMesh),
     Sphere(Sphere),
+    InfinitePlane(InfinitePlane),
+    Quad(Quad),
+    Cuboid(Cuboid),
 }
 
 #[derive(Debug, Clone)]
@@ -196,6 +255,8 @@ pub struct TriMesh {
     pub normals: Option<Vec<Vec3>>,
     pub tangents: Option<Vec<Vec3>>,
     pub uvs: Option<Vec<Vec2>>,
+    /// Linear RGB reflectance of the vertices
+    pub colors: Option<Vec<Vec3>>,
 }
 
 impl TriMesh {
@@ -212,7 +273,57 @@ impl TriMesh {
             normals,
             tangents,
             uvs,
+            colors: None,
+        }
+    }
+
+    pub fn with_colors(mut self, colors: Option<Vec<Vec3>>) -> Self {
+        self.colors = colors;
+        self
+    }
+
+    /// Checks that the indices are in bounds and that the vertex attributes have an element for
+    /// every vertex
+    pub fn validate(&self) -> Result<()> {
+        if !self.indices.len().is_multiple_of(3) {
+            return Err(eyre!("Index buffer length is not a multiple of 3"));
+        }
+
+        let vertex_count = self.pos.len();
+        if let Some(i) = self
+            .indices
+            .iter()
+            .find(|i| **i < 0 || **i as usize >= vertex_count)
+        {
+            return Err(eyre!(
+                "Triangle mesh index {} is out of bounds for {} vertices",
+                i,
+                vertex_count
+            ));
         }
+
+        let attribute_lens = [
+            ("normals", self.normals.as_ref().map(|n| n.len())),
+            ("tangents", self.tangents.as_ref().map(|t| t.len())),
+            ("UVs", self.uvs.as_ref().map(|uv| uv.len())),
+            ("colors", self.colors.as_ref().map(|c| c.len())),
+        ];
+
+        for (name, len) in attribute_lens {
+            match len {
+                Some(len) if len != vertex_count => {
+                    return Err(eyre!(
+                        "Triangle mesh has {} {} but {} vertices",
+                        len,
+                        name,
+                        vertex_count
+                    ))
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -227,42 +338,150 @@ impl Sphere {
     }
 }
 
+#[derive(Debug)]
+pub struct InfinitePlane {
+    pub point: Vec3,
+    pub normal: Vec3,
+}
+
+impl InfinitePlane {
+    pub fn new(point: Vec3, normal: Vec3) -> Self {
+        Self { point, normal }
+    }
+}
+
+/// An axis-aligned box in object space, given by two opposite corners
+#[derive(Debug)]
+pub struct Cuboid {
+    pub p0: Vec3,
+    pub p1: Vec3,
+}
+
+impl Cuboid {
+    pub fn new(p0: Vec3, p1: Vec3) -> Self {
+        Self { p0, p1 }
+    }
+}
+
+#[derive(Debug)]
+pub struct Quad {
+    pub corner: Vec3,
+    pub edge_u: Vec3,
+    pub edge_v: Vec3,
+}
+
+impl Quad {
+    pub fn new(corner: Vec3, edge_u: Vec3, edge_v: Vec3) -> Self {
+        Self {
+            corner,
+            edge_u,
+            edge_v,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AreaLightSource {
     /// Spectral distribution of the light's emitted radiance.
     pub radiance: RgbSpectrum,
+    /// Multiplies the radiance
+    pub scale: f32,
+    /// Total emitted power, the radiance is renormalized by the shape's area if present
+    pub power: Option<f32>,
 }
 
 impl AreaLightSource {
     pub fn new(radiance: RgbSpectrum) -> Self {
-        Self { radiance }
+        Self {
+            radiance,
+            scale: 1.,
+            power: None,
+        }
     }
 
     pub fn new_default(rgbtospec: &RGB2Spec, color_space: ColorSpace) -> Self {
-        Self {
-            radiance: RgbSpectrum::new(
-                rgbtospec,
-                Vec3::ONE,
-                RgbSpectrumKind::new_illuminant(color_space),
-            ),
+        Self::new(RgbSpectrum::new(
+            rgbtospec,
+            Vec3::ONE,
+            RgbSpectrumKind::new_illuminant(color_space),
+        ))
+    }
+
+    /// Radiance with the scale and power normalization applied.
+    /// Area is the total area of the emitting shape.
+    pub fn effective_radiance(&self, area: f32) -> RgbSpectrum {
+        let mut scale = self.scale;
+        if let Some(power) = self.power {
+            // The power of a one-sided diffuse emitter is π * area * radiance
+            scale *= power / (PI * area);
         }
+
+        self.radiance.scaled(scale)
     }
 }
 
 #[derive(Debug)]
 pub enum LightSource {
     Infinite(InfiniteLightSource),
+    Projection(ProjectionLightSource),
 }
 
 #[derive(Debug)]
 pub struct InfiniteLightSource {
     pub scale: f32,
+    /// Multiplies the RGB of the environment map, set by an "rgb scale" param
+    pub tint: Vec3,
     pub filepath: PathBuf,
+    /// Selected by the image's aspect ratio when not set
+    pub mapping: Option<EnvMapping>,
 }
 
 impl InfiniteLightSource {
-    pub fn new(scale: f32, filepath: PathBuf) -> Self {
-        Self { scale, filepath }
+    pub fn new(scale: f32, filepath: PathBuf, mapping: Option<EnvMapping>) -> Self {
+        Self {
+            scale,
+            tint: Vec3::ONE,
+            filepath,
+            mapping,
+        }
+    }
+
+    pub fn with_tint(mut self, tint: Vec3) -> Self {
+        self.tint = tint;
+        self
+    }
+}
+
+/// How directions are mapped to the environment map image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvMapping {
+    /// Equal-area octahedral mapping used by PBRTv4, the image is square
+    Octahedral,
+    /// Latitude-longitude mapping, the image is twice as wide as it is tall
+    Equirect,
+}
+
+#[derive(Debug)]
+pub struct ProjectionLightSource {
+    pub scale: f32,
+    pub filepath: PathBuf,
+    /// Spread angle of the projection frustum along the narrower of the image's width and height.
+    pub fov: f32,
+    /// World-space position of the light
+    pub from: Vec3,
+    /// World-space point the light is projecting onto
+    pub to: Vec3,
+}
+
+impl ProjectionLightSource {
+    pub fn new(scale: f32, filepath: PathBuf, fov: f32, from: Vec3, to: Vec3) -> Self {
+        Self {
+            scale,
+            filepath,
+            fov,
+            from,
+            to,
+        }
     }
 }
 
@@ -270,9 +489,23 @@ impl InfiniteLightSource {
 pub enum Material {
     Diffuse(DiffuseMaterial),
     Conductor(ConductorMaterial),
+    /// Tabulated BRDF loaded from a file, shared by every shape that uses it.
+    /// Only MERL files are supported. The directions are still sampled from the cosine
+    /// distribution, not from the measured data.
+    Measured(Arc<MeasuredBrdf>),
+    Mix(MixMaterial),
+    /// Absorbs all light, paths are terminated when they hit it
+    Black,
+    /// Doesn't scatter, rays continue straight through it. Marks the boundaries of participating
+    /// media and makes geometry invisible.
+    Interface,
 }
 
 impl Material {
+    pub fn is_interface(&self) -> bool {
+        matches!(self, Self::Interface)
+    }
+
     pub fn new_default(rgbtospec: &RGB2Spec) -> Self {
         Self::Diffuse(DiffuseMaterial::new(rgbtospec, Vec3::splat(0.5)))
     }
@@ -280,28 +513,173 @@ impl Material {
     pub fn new_empty() -> Self {
         Self::Diffuse(DiffuseMaterial {
             reflectance: RgbSpectrum::new_empty(),
+            use_vertex_colors: false,
         })
     }
 }
 
+/// A single material that replaces all the materials of the scene except interfaces, for
+/// evaluating the geometry and lighting during look-dev. Unlike forcediffuse, which keeps the
+/// albedo of the scene's materials, the material is chosen by the user.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaterialOverride {
+    /// Reflectance
+    Diffuse(Vec3),
+    /// Copper with the roughness
+    Conductor(f32),
+}
+
+impl MaterialOverride {
+    /// RGB approximation of the IOR and absorbtion of copper
+    const COPPER_ETA: Vec3 = Vec3::new(0.2004, 0.924, 1.1022);
+    const COPPER_K: Vec3 = Vec3::new(3.9129, 2.4528, 2.1421);
+
+    /// "diffuse", "diffuse:r,g,b", "conductor" or "conductor:roughness"
+    pub fn new(desc: &str) -> Result<Self> {
+        let (kind, params) = match desc.split_once(':') {
+            Some((kind, params)) => (kind, Some(params)),
+            None => (desc, None),
+        };
+
+        let invalid = || eyre!("Invalid override material: '{}'", desc);
+        Ok(match (kind, params) {
+            ("diffuse", None) => Self::Diffuse(Vec3::splat(0.5)),
+            ("diffuse", Some(rgb)) => {
+                let rgb = rgb
+                    .split(',')
+                    .map(|c| c.trim().parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| invalid())?;
+                let rgb: [f32; 3] = rgb.try_into().map_err(|_| invalid())?;
+                Self::Diffuse(Vec3::from_array(rgb))
+            }
+            ("conductor", None) => Self::Conductor(0.2),
+            ("conductor", Some(roughness)) => {
+                Self::Conductor(roughness.trim().parse().map_err(|_| invalid())?)
+            }
+            _ => {
+                return Err(eyre!(
+                    "Unknown override material: '{}', expected diffuse or conductor",
+                    desc
+                ))
+            }
+        })
+    }
+
+    pub fn material(&self, rgbtospec: &RGB2Spec) -> Material {
+        match *self {
+            Self::Diffuse(reflectance) => {
+                Material::Diffuse(DiffuseMaterial::new(rgbtospec, reflectance))
+            }
+            Self::Conductor(roughness) => Material::Conductor(ConductorMaterial::new(
+                rgbtospec,
+                Self::COPPER_ETA,
+                Self::COPPER_K,
+                MaterialRoughness::new(roughness, roughness),
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DiffuseMaterial {
     pub reflectance: RgbSpectrum,
+    /// No reflectance was given, meshes with vertex colors use them instead
+    pub use_vertex_colors: bool,
 }
 
 impl DiffuseMaterial {
     pub fn new(rgbtospec: &RGB2Spec, reflectance: Vec3) -> Self {
         Self {
             reflectance: RgbSpectrum::new(rgbtospec, reflectance, RgbSpectrumKind::Reflectance),
+            use_vertex_colors: false,
+        }
+    }
+
+    /// Takes the reflectance from the vertex colors, shapes without them use the PBRT default
+    pub fn new_vertex_colors(rgbtospec: &RGB2Spec) -> Self {
+        Self {
+            use_vertex_colors: true,
+            ..Self::new(rgbtospec, Vec3::splat(0.5))
+        }
+    }
+}
+
+/// Participating medium with constant coefficients and an isotropic phase function
+#[derive(Debug, Clone)]
+pub struct HomogeneousMedium {
+    /// Absorption coefficient
+    pub sigma_a: Spectrum,
+    /// Scattering coefficient
+    pub sigma_s: Spectrum,
+    /// Multiplies both coefficients
+    pub scale: f32,
+}
+
+impl HomogeneousMedium {
+    pub fn new(sigma_a: Spectrum, sigma_s: Spectrum, scale: f32) -> Self {
+        Self {
+            sigma_a,
+            sigma_s,
+            scale,
+        }
+    }
+}
+
+/// Stochastic blend of two materials, the BxDF is the amount-weighted combination of theirs
+#[derive(Debug, Clone)]
+pub struct MixMaterial {
+    pub materials: Box<[Material; 2]>,
+    pub amount: MixAmount,
+}
+
+impl MixMaterial {
+    pub fn new(first: Material, second: Material, amount: MixAmount) -> Self {
+        Self {
+            materials: Box::new([first, second]),
+            amount,
+        }
+    }
+}
+
+/// Probability of using the second material of a mix
+#[derive(Clone)]
+pub enum MixAmount {
+    Constant(f32),
+    Texture(Arc<ImageTexture>),
+}
+
+impl MixAmount {
+    /// Amount of RGB textures is the average of the channels
+    pub fn eval(&self, uv: Option<Vec2>) -> f32 {
+        let amount = match self {
+            MixAmount::Constant(amount) => *amount,
+            MixAmount::Texture(texture) => {
+                let rgb = texture.fetch_nearest(uv.unwrap_or(Vec2::ZERO));
+                (rgb.x + rgb.y + rgb.z) / 3.
+            }
+        };
+
+        amount.clamp(0., 1.)
+    }
+}
+
+impl std::fmt::Debug for MixAmount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MixAmount::Constant(amount) => f.debug_tuple("Constant").field(amount).finish(),
+            MixAmount::Texture(texture) => texture.fmt(f),
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct ConductorMaterial {
-    pub ior: RgbSpectrum,
-    pub absorbtion_k: RgbSpectrum,
+    pub ior: Spectrum,
+    pub absorbtion_k: Spectrum,
     pub roughness: MaterialRoughness,
+    /// Optional dielectric film on top of the conductor, causes iridescence
+    pub thin_film: Option<ThinFilm>,
 }
 
 impl ConductorMaterial {
@@ -310,13 +688,49 @@ impl ConductorMaterial {
         ior: Vec3,
         absorbtion_k: Vec3,
         roughness: MaterialRoughness,
+    ) -> Self {
+        Self::new_spectral(
+            Spectrum::Rgb(RgbSpectrum::new(rgbtospec, ior, RgbSpectrumKind::Unbounded)),
+            Spectrum::Rgb(RgbSpectrum::new(
+                rgbtospec,
+                absorbtion_k,
+                RgbSpectrumKind::Unbounded,
+            )),
+            roughness,
+        )
+    }
+
+    pub fn new_spectral(
+        ior: Spectrum,
+        absorbtion_k: Spectrum,
+        roughness: MaterialRoughness,
     ) -> Self {
         Self {
-            ior: RgbSpectrum::new(rgbtospec, ior, RgbSpectrumKind::Unbounded),
-            absorbtion_k: RgbSpectrum::new(rgbtospec, absorbtion_k, RgbSpectrumKind::Unbounded),
+            ior,
+            absorbtion_k,
             roughness,
+            thin_film: None,
         }
     }
+
+    pub fn with_thin_film(mut self, thin_film: Option<ThinFilm>) -> Self {
+        self.thin_film = thin_film;
+        self
+    }
+}
+
+/// Thin dielectric layer, the light reflected from its two sides interferes
+#[derive(Debug, Clone)]
+pub struct ThinFilm {
+    /// In nanometers
+    pub thickness: f32,
+    pub ior: f32,
+}
+
+impl ThinFilm {
+    pub fn new(thickness: f32, ior: f32) -> Self {
+        Self { thickness, ior }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -332,4 +746,10 @@ impl MaterialRoughness {
             uroughness,
         }
     }
+
+    /// PBRT's RoughnessToAlpha, maps the authored roughness to the GGX alpha.
+    /// The square root makes the roughness perceptually closer to linear.
+    pub fn remapped(self) -> Self {
+        Self::new(self.vroughness.sqrt(), self.uroughness.sqrt())
+    }
 }
@@ -1,20 +1,35 @@
 use std::{
+    array, fmt,
+    ops::Range,
     sync::{
         atomic::{AtomicUsize, Ordering},
-        mpsc::{self, Receiver, SyncSender},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
         Arc,
     },
     thread,
+    time::{Duration, Instant},
 };
 
 use bus::{Bus, BusReader};
-use eyre::Result;
-use glam::{vec2, BVec3, DVec3, Mat4};
-use rand::{distributions::Uniform, prelude::Distribution, rngs::SmallRng, SeedableRng};
+use eyre::{eyre, Result};
+use glam::{vec2, BVec3, DVec3, Mat4, Vec3};
+use rand::{rngs::SmallRng, RngCore, SeedableRng};
+use rgb2spec::RGB2Spec;
 
 use crate::{
-    camera::Camera, color::spectrum::SampledWavelengths, film::Film, integrator::Integrator,
-    scene::Scene, CmdArgs,
+    bvh::PACKET_SIZE,
+    camera::Camera,
+    color::color_space::ColorSpace,
+    color::quantity::{ColorMode, Quantity},
+    color::spectrum::{rgb_spectrum, ColorMatchingFunctions, Observer, SpectralQuantity},
+    film::{DepthFilm, DepthMode, Film, FilmSnapshot, FilmTile},
+    geometry::Ray,
+    integrator::{scratch::RenderScratch, Integrator},
+    pbrt_loader::scene_description::{Material, MaterialOverride, SceneDescription},
+    sampler::{Sampler, SamplerKind},
+    sampling,
+    scene::{HitInfo, Scene},
+    stats::{RayKind, STATS},
 };
 
 type ThreadId = usize;
@@ -24,22 +39,36 @@ pub struct RenderThreads {
     render_state: Arc<FilmRenderState>,
     start_notify_bus: Bus<ThreadMsg>,
     completion_recv: Receiver<()>,
+    /// Threads that haven't finished the started pass yet, 0 if no pass is in progress
+    pending_threads: usize,
+    /// Number of samples per pixel rendered so far
+    sample_index: u32,
+    render_context: Arc<RenderContext>,
 }
 
 impl RenderThreads {
+    /// If a seed is supplied, every thread seeds its RNG from it. The image is then only
+    /// reproducible when rendering with a single thread, because tiles are distributed between
+    /// the threads dynamically. The Sobol scrambling only depends on the seed and the pixel,
+    /// so it is shared by all threads even without a seed.
     pub fn new(
-        cmdargs: &CmdArgs,
-        width: usize,
-        height: usize,
+        num_threads: usize,
+        seed: Option<u64>,
         render_context: Arc<RenderContext>,
     ) -> Result<Self> {
-        let render_state = Arc::new(FilmRenderState::new(width, height));
+        let (width, height) = (render_context.film.width(), render_context.film.height());
+        let mut render_state = FilmRenderState::new(width, height, render_context.tile_size);
+        if let Some(pixels) = &render_context.pixels {
+            render_state = render_state.with_pixels(pixels.clone());
+        }
+        let render_state = Arc::new(render_state);
+        let scramble_seed = seed.unwrap_or_else(|| SmallRng::from_entropy().next_u64());
 
         let mut threads = Vec::new();
-        let (competion_send, completion_recv) = mpsc::sync_channel::<()>(cmdargs.num_threads);
-        let mut start_notify_bus = Bus::new(cmdargs.num_threads);
+        let (competion_send, completion_recv) = mpsc::sync_channel::<()>(num_threads);
+        let mut start_notify_bus = Bus::new(num_threads);
 
-        for thread_id in 0..cmdargs.num_threads {
+        for thread_id in 0..num_threads {
             let render_state = Arc::clone(&render_state);
             let render_utils = render_context.clone();
             let start_rx = start_notify_bus.add_rx();
@@ -51,6 +80,8 @@ impl RenderThreads {
                     .spawn(move || {
                         render(
                             thread_id,
+                            seed,
+                            scramble_seed,
                             start_rx,
                             render_state,
                             render_utils,
@@ -65,24 +96,146 @@ impl RenderThreads {
             render_state,
             start_notify_bus,
             completion_recv,
+            pending_threads: 0,
+            sample_index: 0,
+            render_context,
         })
     }
 
     pub fn render_once(&mut self) {
+        self.start_pass();
+        self.finish_pass();
+    }
+
+    /// Starts rendering the next sample of every pixel without waiting for it,
+    /// `wait_pass()` polls for its completion. A single pass can be in progress at a time.
+    pub fn start_pass(&mut self) {
+        assert!(!self.pass_in_progress(), "The previous pass isn't finished");
+
         self.render_state.reset();
-        self.start_notify_bus.broadcast(ThreadMsg::Render);
+        self.start_notify_bus
+            .broadcast(ThreadMsg::Render(self.sample_index));
+        self.pending_threads = self.threads.len();
+    }
 
-        let mut completed_threads = 0;
-        while completed_threads != self.threads.len() {
-            self.completion_recv.recv().unwrap();
-            completed_threads += 1;
+    /// Waits at most `timeout` for the started pass, so that the caller can do other work
+    /// (e.g. handle window events) in between. Returns true once the pass is finished,
+    /// or if there's no pass in progress.
+    pub fn wait_pass(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        while self.pending_threads > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.completion_recv.recv_timeout(remaining) {
+                Ok(()) => {
+                    self.pending_threads -= 1;
+                    if self.pending_threads == 0 {
+                        self.sample_index += 1;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => return false,
+                Err(RecvTimeoutError::Disconnected) => panic!("Render threads disconnected"),
+            }
         }
+
+        true
+    }
+
+    /// Blocks until the pass in progress, if any, is finished
+    pub fn finish_pass(&mut self) {
+        while !self.wait_pass(Duration::from_secs(1)) {}
+    }
+
+    pub fn pass_in_progress(&self) -> bool {
+        self.pending_threads > 0
+    }
+
+    /// Renders one pass and returns the normalized film.
+    /// Lets external code drive the render loop without a window.
+    pub fn render_pass(&mut self) -> FilmSnapshot {
+        self.render_once();
+        self.snapshot()
+    }
+
+    pub fn snapshot(&self) -> FilmSnapshot {
+        FilmSnapshot::new(&self.render_context.film, self.sample_index)
+    }
+
+    /// Number of samples per pixel rendered so far
+    pub fn samples(&self) -> u32 {
+        self.sample_index
+    }
+}
+
+/// Stopping condition of a render, it ends when the first of the limits is reached
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenderBudget {
+    pub samples: Option<u32>,
+    pub time_limit: Option<Duration>,
+}
+
+impl RenderBudget {
+    pub fn new(samples: Option<u32>, time_limit: Option<Duration>) -> Self {
+        Self {
+            samples,
+            time_limit,
+        }
+    }
+
+    /// Checks if another pass fits into the budget, the last pass is used as an estimate of how
+    /// long the next one takes. At least one pass is always allowed.
+    pub fn allows_next_pass(&self, samples: u32, elapsed: Duration, last_pass: Duration) -> bool {
+        if samples == 0 {
+            return true;
+        }
+
+        let samples_left = self.samples.is_none_or(|max| samples < max);
+        let time_left = self
+            .time_limit
+            .is_none_or(|limit| elapsed + last_pass <= limit);
+
+        samples_left && time_left
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.samples.is_none() && self.time_limit.is_none()
+    }
+}
+
+/// Achieved samples of a finished render, used for equal-time comparisons
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderReport {
+    pub samples: u32,
+    pub elapsed: Duration,
+}
+
+impl RenderReport {
+    pub fn samples_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0. {
+            self.samples as f64 / seconds
+        } else {
+            0.
+        }
+    }
+}
+
+impl fmt::Display for RenderReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} samples in {:?} ({:.2} samples/s)",
+            self.samples,
+            self.elapsed,
+            self.samples_per_second()
+        )
     }
 }
 
 #[derive(Clone)]
 pub enum ThreadMsg {
-    Render,
+    /// Render one sample of every pixel with the given sample index
+    Render(u32),
     Stop,
 }
 
@@ -102,46 +255,226 @@ pub struct RenderContext {
     pub film: Film,
     pub scene: Scene,
     pub integrator: Integrator,
-    pub camera_from_world: Mat4,
+    /// Optional pass with the primary-ray hit distances
+    pub depth: Option<DepthFilm>,
+    /// Write the estimated variance of the pixels, the film tracks it for every pixel
+    pub variance_pass: bool,
+    /// Inverse of the scene's camera_from_world, computed once for all camera rays
+    pub world_from_camera: Mat4,
+    /// Fetched once, so that the OnceLock isn't accessed for every ray
+    pub rgbtospec: &'static RGB2Spec,
+    /// Color-matching functions of the observer, fetched once like the rgbtospec
+    pub cmfs: &'static ColorMatchingFunctions,
+    /// The forcediffuse option of the scene
+    pub force_diffuse: bool,
+    /// Replaces every material of the scene except interfaces
+    pub override_material: Option<Material>,
+    pub color_mode: ColorMode,
+    pub tile_size: usize,
+    /// Threads accumulate a tile into their own buffer and merge it into the film when it's
+    /// finished. Writing the film directly is only kept for comparing the two in benchmarks.
+    pub tile_buffers: bool,
+    pub sampler: SamplerKind,
+    /// Only these pixels are rendered if set, the rest of the film stays black
+    pub pixels: Option<Vec<(usize, usize)>>,
+}
+
+impl RenderContext {
+    pub fn new(scene_desc: SceneDescription, integrator: Integrator) -> Result<Self> {
+        let (width, height) = (
+            scene_desc.options.film.xresolution as usize,
+            scene_desc.options.film.yresolution as usize,
+        );
+
+        let world_from_camera = scene_desc
+            .options
+            .camera
+            .camera_from_world_transform
+            .inverse();
+        let cam = Camera::new_with_fov_axis(
+            width,
+            height,
+            scene_desc.options.camera.fov,
+            scene_desc.options.camera.fov_axis,
+        );
+        let color_space = scene_desc.options.color_space;
+        let film = Film::new(width, height, color_space);
+        let force_diffuse = scene_desc.options.general_options.forcediffuse;
+
+        let scene = Scene::init(scene_desc)?;
+        if !scene.projection_lights.is_empty() && !integrator.supports_projection_lights() {
+            eprintln!(
+                "Projection lights are only rendered by the simple-path integrator, \
+                they are ignored"
+            );
+        }
+
+        let rgbtospec = rgb_spectrum::rgbtospec(color_space)
+            .ok_or_else(|| eyre!("The RGB to spectrum table isn't loaded"))?;
+
+        Ok(Self {
+            cam,
+            film,
+            scene,
+            integrator,
+            depth: None,
+            variance_pass: false,
+            world_from_camera,
+            rgbtospec,
+            cmfs: Observer::default().cmfs(),
+            force_diffuse,
+            override_material: None,
+            color_mode: ColorMode::default(),
+            tile_size: DEFAULT_TILE_SIZE,
+            tile_buffers: true,
+            sampler: SamplerKind::default(),
+            pixels: None,
+        })
+    }
+
+    /// The RGB mode multiplies and outputs linear sRGB, scenes in other color spaces have to be
+    /// rendered spectrally
+    pub fn with_color_mode(mut self, color_mode: ColorMode) -> Result<Self> {
+        let color_space = self.film.color_space();
+        if color_mode == ColorMode::Rgb && color_space != ColorSpace::Srgb {
+            return Err(eyre!(
+                "The RGB color mode only supports sRGB scenes, the scene is in {:?}",
+                color_space
+            ));
+        }
+
+        self.color_mode = color_mode;
+        Ok(self)
+    }
+
+    pub fn with_tile_size(mut self, tile_size: usize) -> Self {
+        self.tile_size = tile_size.max(1);
+        self
+    }
+
+    pub fn with_observer(mut self, observer: Observer) -> Self {
+        self.cmfs = observer.cmfs();
+        self
+    }
+
+    pub fn with_material_override(mut self, material: MaterialOverride) -> Self {
+        self.override_material = Some(material.material(self.rgbtospec));
+        self
+    }
+
+    pub fn with_tile_buffers(mut self, tile_buffers: bool) -> Self {
+        self.tile_buffers = tile_buffers;
+        self
+    }
+
+    pub fn with_sampler(mut self, sampler: SamplerKind) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    /// Renders only the listed pixels, which is much cheaper than the whole film when just a few
+    /// values are needed, e.g. for regression tests. Pixels listed more than once are rendered once.
+    pub fn with_pixels(mut self, mut pixels: Vec<(usize, usize)>) -> Result<Self> {
+        let (width, height) = (self.film.width(), self.film.height());
+        if let Some((x, y)) = pixels.iter().find(|(x, y)| *x >= width || *y >= height) {
+            return Err(eyre!(
+                "Pixel ({x}, {y}) is outside of the {width}x{height} film"
+            ));
+        }
+
+        pixels.sort_unstable();
+        pixels.dedup();
+        self.pixels = Some(pixels);
+        Ok(self)
+    }
+
+    /// Enables the depth pass, the distances are taken from the primary hits
+    pub fn with_depth_pass(mut self, mode: DepthMode) -> Self {
+        self.depth = Some(DepthFilm::new(self.film.width(), self.film.height(), mode));
+        self
+    }
+
+    pub fn with_variance_pass(mut self) -> Self {
+        self.variance_pass = true;
+        self
+    }
 }
 
-const TILE_SIZE: usize = 8;
+/// Side of the square tiles the film is divided into between the render threads
+pub const DEFAULT_TILE_SIZE: usize = 8;
+
+/// Pixels with x in xs and y in ys, the tiles at the right and bottom edges can be smaller
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tile {
+    pub xs: Range<usize>,
+    pub ys: Range<usize>,
+}
 
+/// Hands out the tiles of a pass to the render threads.
+/// The tiles are handed out in Morton (Z-curve) order, so that the tiles rendered at the same time
+/// are close to each other and touch similar parts of the scene.
 pub struct FilmRenderState {
+    /// Index of the next tile
     index: AtomicUsize,
     width: usize,
     height: usize,
-    max_index: usize,
+    tile_size: usize,
+    /// Column and row of every tile, in the order they are handed out
+    tile_order: Vec<(usize, usize)>,
+    num_tiles: usize,
+    /// Every listed pixel is a separate 1x1 tile, replaces the tiling of the whole film
+    pixels: Option<Vec<(usize, usize)>>,
 }
 
 impl FilmRenderState {
-    pub fn new(width: usize, height: usize) -> Self {
-        let max_index = width * height - TILE_SIZE;
+    pub fn new(width: usize, height: usize, tile_size: usize) -> Self {
+        let tiles_x = width.div_ceil(tile_size);
+        let tiles_y = height.div_ceil(tile_size);
+
+        let mut tile_order: Vec<(usize, usize)> = (0..tiles_y)
+            .flat_map(|ty| (0..tiles_x).map(move |tx| (tx, ty)))
+            .collect();
+        tile_order.sort_unstable_by_key(|&(tx, ty)| morton_encode(tx as u32, ty as u32));
 
         Self {
             index: AtomicUsize::new(0),
             width,
             height,
-            max_index,
+            tile_size,
+            num_tiles: tile_order.len(),
+            tile_order,
+            pixels: None,
         }
     }
 
-    pub fn next_index(&self) -> Option<usize> {
-        let index = self.index.fetch_add(TILE_SIZE, Ordering::Relaxed);
-        if index >= self.max_index {
-            None
-        } else {
-            Some(index)
-        }
+    /// Duplicates are removed, two threads must never render the same pixel at once
+    pub fn with_pixels(mut self, mut pixels: Vec<(usize, usize)>) -> Self {
+        pixels.sort_unstable();
+        pixels.dedup();
+        self.num_tiles = pixels.len();
+        self.pixels = Some(pixels);
+        self
     }
 
-    pub fn next_xy_coords(&self) -> Option<(usize, usize)> {
-        self.next_index().map(|i| {
-            let y = i / self.width;
-            let x = i % self.width;
-            debug_assert!(x < self.width);
-            debug_assert!(y < self.height);
-            (x, y)
+    pub fn next_tile(&self) -> Option<Tile> {
+        let index = self.index.fetch_add(1, Ordering::Relaxed);
+        if index >= self.num_tiles {
+            return None;
+        }
+
+        if let Some(pixels) = &self.pixels {
+            let (x, y) = pixels[index];
+            return Some(Tile {
+                xs: x..x + 1,
+                ys: y..y + 1,
+            });
+        }
+
+        let (tx, ty) = self.tile_order[index];
+        let (x, y) = (tx * self.tile_size, ty * self.tile_size);
+        Some(Tile {
+            xs: x..(x + self.tile_size).min(self.width),
+            ys: y..(y + self.tile_size).min(self.height),
         })
     }
 
@@ -150,79 +483,375 @@ impl FilmRenderState {
     }
 }
 
+/// Interleaves the bits of the coordinates, x takes the even bits
+fn morton_encode(x: u32, y: u32) -> u64 {
+    fn spread_bits(v: u32) -> u64 {
+        let mut v = v as u64;
+        v = (v | (v << 16)) & 0x0000_ffff_0000_ffff;
+        v = (v | (v << 8)) & 0x00ff_00ff_00ff_00ff;
+        v = (v | (v << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+        v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+        v = (v | (v << 1)) & 0x5555_5555_5555_5555;
+        v
+    }
+
+    spread_bits(x) | (spread_bits(y) << 1)
+}
+
 pub fn render(
-    _thread_id: ThreadId,
+    thread_id: ThreadId,
+    seed: Option<u64>,
+    scramble_seed: u64,
     mut start_rx: BusReader<ThreadMsg>,
     render_state: Arc<FilmRenderState>,
     render_context: Arc<RenderContext>,
     completion_send: SyncSender<()>,
 ) {
-    let mut rng = SmallRng::from_entropy();
-
-    let (cam, film) = (&render_context.cam, &render_context.film);
-
-    let mut sample = 0;
+    let rng = match seed {
+        Some(seed) => SmallRng::seed_from_u64(seed.wrapping_add(thread_id as u64)),
+        None => SmallRng::from_entropy(),
+    };
+    let mut rng = Sampler::new(render_context.sampler, rng).with_seed(scramble_seed);
+    let scratch = RenderScratch::new(render_context.rgbtospec)
+        .with_force_diffuse(render_context.force_diffuse)
+        .with_override_material(render_context.override_material.as_ref());
 
     loop {
         let msg = start_rx
             .recv()
             .expect("Master thread dropped, waiting for start");
 
-        if let ThreadMsg::Stop = msg {
-            return;
+        let sample_index = match msg {
+            ThreadMsg::Render(sample_index) => sample_index,
+            ThreadMsg::Stop => return,
+        };
+
+        match render_context.color_mode {
+            ColorMode::Spectral => render_pass::<SpectralQuantity>(
+                &render_state,
+                &render_context,
+                &scratch,
+                sample_index,
+                &mut rng,
+            ),
+            ColorMode::Rgb => render_pass::<Vec3>(
+                &render_state,
+                &render_context,
+                &scratch,
+                sample_index,
+                &mut rng,
+            ),
         }
 
-        while let Some((px, py)) = render_state.next_xy_coords() {
-            for px in px..(px + TILE_SIZE) {
-                //----------------------------------------------------------------
-                const STRATA_SQRT: usize = 4;
-                let stratum_width = 1. / STRATA_SQRT as f32;
+        STATS.flush_thread_tests();
 
-                let stratum = sample % (STRATA_SQRT * STRATA_SQRT);
-                let stratum_offset_x = (stratum % STRATA_SQRT) as f32 * stratum_width;
-                let stratum_offset_y = (stratum / STRATA_SQRT) as f32 * stratum_width;
+        completion_send
+            .send(())
+            .expect("Master thread dropped, sending completion message");
+    }
+}
 
-                let dist = Uniform::from(0f32..stratum_width);
-                let stratum_x = dist.sample(&mut rng);
-                let stratum_y = dist.sample(&mut rng);
+/// Renders tiles until there are none left
+fn render_pass<C: Quantity>(
+    render_state: &FilmRenderState,
+    render_context: &RenderContext,
+    scratch: &RenderScratch,
+    sample_index: u32,
+    rng: &mut Sampler,
+) {
+    let mut sampled_lambdas = C::sample_lambdas(rng);
+    let mut film_tile = FilmTile::default();
+    let film = &render_context.film;
+
+    while let Some(tile) = render_state.next_tile() {
+        film_tile.reset(tile.xs.clone(), tile.ys.clone());
+
+        for py in tile.ys.clone() {
+            for packet_x in tile.xs.clone().step_by(PACKET_SIZE) {
+                let xs = packet_x..(packet_x + PACKET_SIZE).min(tile.xs.end);
+                // A partial packet at the tile edge repeats its last ray
+                let rays: [Ray; PACKET_SIZE] = array::from_fn(|i| {
+                    pixel_ray(
+                        &render_context.cam,
+                        render_context.world_from_camera,
+                        (render_state.width, render_state.height),
+                        ((xs.start + i).min(xs.end - 1), py),
+                        sample_index,
+                    )
+                });
+                let hits = trace_primary_rays(&render_context.scene, &rays, xs.len());
+
+                for ((px, ray), hit) in xs.zip(&rays).zip(hits) {
+                    rng.start_pixel_sample(px, py, sample_index);
+                    let xyz = render_pixel::<C>(
+                        render_context,
+                        scratch,
+                        (px, py),
+                        ray,
+                        hit,
+                        &mut sampled_lambdas,
+                        rng,
+                    );
+
+                    if render_context.tile_buffers {
+                        film_tile.set(px, py, xyz);
+                    } else {
+                        unsafe {
+                            // SAFETY: x, y coords are unique, we're good
+                            film.accumulate(px, py, xyz);
+                        }
+                    }
+                }
+            }
+        }
 
-                let mut offset_x = stratum_offset_x + stratum_x;
-                let mut offset_y = stratum_offset_y + stratum_y;
-                offset_x = offset_x.clamp(0., 1f32.next_down());
-                offset_y = offset_y.clamp(0., 1f32.next_down());
-                //----------------------------------------------------------------
+        if render_context.tile_buffers {
+            unsafe {
+                // SAFETY: tiles don't overlap
+                film.merge_tile(&film_tile);
+            }
+        }
+    }
+}
 
-                let u = (offset_x + px as f32) / (render_state.width - 1) as f32;
-                let v = (offset_y + py as f32) / (render_state.height - 1) as f32;
+/// Renders one sample of the pixel and returns it as XYZ, the primary ray has already been traced
+fn render_pixel<C: Quantity>(
+    render_context: &RenderContext,
+    scratch: &RenderScratch,
+    (px, py): (usize, usize),
+    ray: &Ray,
+    hit: Option<HitInfo>,
+    sampled_lambdas: &mut C::Lambdas,
+    rng: &mut Sampler,
+) -> DVec3 {
+    STATS.count_ray(RayKind::Primary);
+
+    if let Some(depth) = &render_context.depth {
+        unsafe {
+            // SAFETY: x, y coords are unique, we're good
+            depth.add_sample(px, py, primary_depth(ray, hit.as_ref()));
+        }
+    }
+
+    C::resample_lambdas(sampled_lambdas, rng);
+
+    let radiance: C = render_context.integrator.ray_l_from_hit(
+        ray,
+        hit,
+        sampled_lambdas,
+        &render_context.scene,
+        scratch,
+        rng,
+    );
+
+    let xyz = radiance.to_xyz(sampled_lambdas, render_context.cmfs);
 
-                let mut ray = cam.gen_ray(vec2(u, v));
+    assert!(xyz.cmpge(DVec3::ZERO) == BVec3::TRUE);
+    assert!(!xyz.is_nan());
+
+    xyz
+}
+
+/// World-space camera ray through the jittered sample position of the pixel
+fn pixel_ray(
+    cam: &Camera,
+    world_from_camera: Mat4,
+    (width, height): (usize, usize),
+    (px, py): (usize, usize),
+    sample_index: u32,
+) -> Ray {
+    let offset = sampling::sample_pixel_offset(sample_index, px, py);
+
+    let u = (offset.x + px as f32) / (width - 1) as f32;
+    let v = (offset.y + py as f32) / (height - 1) as f32;
+
+    let mut ray = cam.gen_ray(vec2(u, v));
+    ray.transform(world_from_camera);
+    ray
+}
 
-                ray.transform(render_context.camera_from_world);
-                let mut sampled_lambdas = SampledWavelengths::new_sample_uniform(&mut rng);
+/// Camera rays of neighbouring pixels are coherent, so they are traced as a packet.
+/// Secondary rays diverge and are traced one by one by the integrators.
+/// Only the first `count` rays are traced if the packet isn't full.
+fn trace_primary_rays(
+    scene: &Scene,
+    rays: &[Ray; PACKET_SIZE],
+    count: usize,
+) -> [Option<HitInfo>; PACKET_SIZE] {
+    if count == PACKET_SIZE {
+        scene.trace_packet(rays)
+    } else {
+        array::from_fn(|i| {
+            if i < count {
+                scene.trace_ray(&rays[i])
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Distance to the primary hit, the ray direction doesn't have to be normalized
+fn primary_depth(ray: &Ray, hit: Option<&HitInfo>) -> Option<f32> {
+    hit.map(|hitinfo| hitinfo.t * ray.dir.length())
+}
+
+#[cfg(test)]
+mod test_super {
+    use glam::{vec3, Vec3};
+
+    use crate::pbrt_loader::scene_description::{
+        self, Material, ScreenWideOptions, ShapeWithParams,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_tiles_cover_film() {
+        for (width, height, tile_size) in
+            [(16, 16, 8), (17, 9, 8), (5, 3, 4), (3, 7, 16), (7, 5, 1)]
+        {
+            let render_state = FilmRenderState::new(width, height, tile_size);
+
+            // Every pass visits every pixel exactly once
+            for _ in 0..2 {
+                let mut visits = vec![0; width * height];
+                while let Some(tile) = render_state.next_tile() {
+                    assert!(tile.xs.len() <= tile_size && tile.ys.len() <= tile_size);
+                    for y in tile.ys.clone() {
+                        for x in tile.xs.clone() {
+                            visits[y * width + x] += 1;
+                        }
+                    }
+                }
 
-                let radiance = render_context.integrator.ray_l(
-                    &ray,
-                    &mut sampled_lambdas,
-                    &render_context.scene,
-                    &mut rng,
+                assert!(
+                    visits.iter().all(|&v| v == 1),
+                    "{width}x{height} {tile_size}"
                 );
+                render_state.reset();
+            }
+        }
+    }
+
+    #[test]
+    fn test_pixel_list_tiles() {
+        let render_state =
+            FilmRenderState::new(8, 8, 4).with_pixels(vec![(5, 1), (2, 3), (5, 1), (0, 7)]);
+
+        let mut tiles: Vec<Tile> = std::iter::from_fn(|| render_state.next_tile()).collect();
+        tiles.sort_unstable_by_key(|tile| (tile.xs.start, tile.ys.start));
+        assert_eq!(
+            tiles,
+            [(0, 7), (2, 3), (5, 1)].map(|(x, y)| Tile {
+                xs: x..x + 1,
+                ys: y..y + 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_tiles_morton_order() {
+        assert_eq!(morton_encode(0b101, 0b011), 0b011011);
+        assert_eq!(morton_encode(u32::MAX, 0), 0x5555_5555_5555_5555);
+
+        // 16x16 tiles of one pixel
+        let render_state = FilmRenderState::new(16, 16, 1);
+        let tiles: Vec<(usize, usize)> = std::iter::from_fn(|| render_state.next_tile())
+            .map(|tile| (tile.xs.start, tile.ys.start))
+            .collect();
+        assert_eq!(tiles.len(), 256);
+
+        // Every aligned run of 4^k tiles is a 2^k square, not a strip of a row
+        for k in 1..4 {
+            let (run, side) = (1 << (2 * k), 1 << k);
+            for block in tiles.chunks(run) {
+                let extent = |coord: fn(&(usize, usize)) -> usize| {
+                    let min = block.iter().map(coord).min().unwrap();
+                    block.iter().map(coord).max().unwrap() - min + 1
+                };
+                assert_eq!(extent(|t| t.0), side);
+                assert_eq!(extent(|t| t.1), side);
+            }
+        }
+    }
 
-                let xyz = sampled_lambdas.to_xyz(&radiance);
+    #[test]
+    fn test_render_budget() {
+        let ms = Duration::from_millis;
 
-                assert!(xyz.cmpge(DVec3::ZERO) == BVec3::TRUE);
-                assert!(!xyz.is_nan());
+        let budget = RenderBudget::new(Some(4), None);
+        assert!(budget.allows_next_pass(3, ms(1000), ms(100)));
+        assert!(!budget.allows_next_pass(4, ms(0), ms(0)));
 
-                unsafe {
-                    // SAFETY: x, y coords are unique, we're good
-                    film.accumulate(px, py, xyz);
+        // The first pass is rendered even if it doesn't fit
+        let budget = RenderBudget::new(None, Some(ms(1)));
+        assert!(budget.allows_next_pass(0, ms(0), ms(0)));
+        assert!(!budget.allows_next_pass(1, ms(0), ms(10)));
+
+        // Stops before the next pass would exceed the limit
+        let budget = RenderBudget::new(Some(100), Some(ms(1000)));
+        assert!(budget.allows_next_pass(5, ms(500), ms(100)));
+        assert!(!budget.allows_next_pass(9, ms(950), ms(100)));
+        assert!(!budget.allows_next_pass(100, ms(0), ms(1)));
+
+        assert!(RenderBudget::default().allows_next_pass(1_000_000, ms(1_000_000), ms(1)));
+    }
+
+    #[test]
+    fn test_depth_pass() {
+        // A wall at z = 3 covering the left half of the image and one at z = 5 on the right
+        let quad = |corner, edge_u, edge_v| {
+            let quad = scene_description::Quad::new(corner, edge_u, edge_v);
+            let shape = scene_description::Shape::Quad(quad);
+            ShapeWithParams::new(
+                shape,
+                Material::new_empty(),
+                None,
+                Mat4::IDENTITY,
+                false,
+                None,
+            )
+        };
+        let scene = Scene::init(SceneDescription {
+            options: ScreenWideOptions::default(),
+            shapes: vec![
+                quad(vec3(-10., -10., 3.), Vec3::Y * 20., Vec3::X * 10.),
+                quad(vec3(0., -10., 5.), Vec3::Y * 20., Vec3::X * 10.),
+            ],
+            infinite_light: None,
+            projection_lights: Vec::new(),
+        })
+        .unwrap();
+
+        let (width, height) = (9, 9);
+        let cam = Camera::new(width, height, 90.);
+        let depth = DepthFilm::new(width, height, DepthMode::Min);
+
+        for sample_index in 0..4 {
+            for py in 0..height {
+                for px in 0..width {
+                    let ray = pixel_ray(
+                        &cam,
+                        Mat4::IDENTITY,
+                        (width, height),
+                        (px, py),
+                        sample_index,
+                    );
+                    let hit = scene.trace_ray(&ray);
+                    unsafe { depth.add_sample(px, py, primary_depth(&ray, hit.as_ref())) };
                 }
             }
         }
 
-        sample += 1;
+        // Distance along the view direction grows towards the edges
+        let (left, right) = (depth.get(2, 4), depth.get(5, 4));
+        assert!((3. ..3. * 1.2).contains(&left), "{left}");
+        assert!((5. ..5. * 1.2).contains(&right), "{right}");
+        assert!(depth.get(0, 4) > left);
 
-        completion_send
-            .send(())
-            .expect("Master thread dropped, sending completion message");
+        // Nothing is hit behind the camera
+        let ray = Ray::new(Vec3::ZERO, -Vec3::Z);
+        assert_eq!(primary_depth(&ray, scene.trace_ray(&ray).as_ref()), None);
     }
 }
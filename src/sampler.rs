@@ -0,0 +1,318 @@
+use eyre::{eyre, Result};
+use rand::{rngs::SmallRng, RngCore, SeedableRng};
+
+use crate::sampling;
+
+/// Which numbers the integrators get from the `Sampler`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SamplerKind {
+    /// Independent pseudo-random numbers
+    #[default]
+    Independent,
+    /// Owen-scrambled Sobol sequence, decorrelated between the pixels
+    Sobol,
+}
+
+impl SamplerKind {
+    pub fn new(kind: &str) -> Result<Self> {
+        Ok(match kind {
+            "independent" => Self::Independent,
+            "sobol" => Self::Sobol,
+            _ => return Err(eyre!("Unknown sampler: '{}'", kind)),
+        })
+    }
+}
+
+/// Source of the random numbers of one render thread.
+/// With `SamplerKind::Sobol`, every number drawn after `start_pixel_sample()` is the next
+/// dimension of the sample's Sobol point. Dimensions past the direction-vector table fall back
+/// to the pseudo-random generator, so long paths still get valid, just not stratified, numbers.
+/// Implements `RngCore`, so that the sampling routines can use the usual `rand` distributions.
+pub struct Sampler {
+    kind: SamplerKind,
+    rng: SmallRng,
+    sample_index: u32,
+    /// Seed of the whole render, has to be the same in every thread
+    seed: u64,
+    /// Hash of the pixel and `seed`, seeds the scrambling of every dimension
+    pixel_seed: u64,
+    dimension: usize,
+}
+
+impl Sampler {
+    pub fn new(kind: SamplerKind, rng: SmallRng) -> Self {
+        Self {
+            kind,
+            rng,
+            sample_index: 0,
+            seed: 0,
+            pixel_seed: 0,
+            dimension: FIRST_DIMENSION,
+        }
+    }
+
+    /// Changes the scrambling of the Sobol sequence
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn kind(&self) -> SamplerKind {
+        self.kind
+    }
+
+    /// Has to be called before the integrator is run for the pixel sample
+    pub fn start_pixel_sample(&mut self, px: usize, py: usize, sample_index: u32) {
+        self.sample_index = sample_index;
+        self.pixel_seed =
+            sampling::hash_u64(sampling::hash_u64(((px as u64) << 32) | py as u64) ^ self.seed);
+        self.dimension = FIRST_DIMENSION;
+    }
+
+    fn next_sobol(&mut self) -> Option<u32> {
+        if self.dimension >= SOBOL_DIMENSIONS {
+            return None;
+        }
+
+        let seed = sampling::hash_u64(self.pixel_seed ^ self.dimension as u64) as u32;
+        let value = sobol_sample(self.sample_index, self.dimension);
+        self.dimension += 1;
+        Some(owen_scramble(value, seed))
+    }
+}
+
+impl RngCore for Sampler {
+    fn next_u32(&mut self) -> u32 {
+        match self.kind {
+            SamplerKind::Independent => self.rng.next_u32(),
+            SamplerKind::Sobol => self.next_sobol().unwrap_or_else(|| self.rng.next_u32()),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self.kind {
+            SamplerKind::Independent => self.rng.next_u64(),
+            // The high bits come from the sequence, the low bits only add resolution
+            SamplerKind::Sobol => ((self.next_u32() as u64) << 32) | self.rng.next_u32() as u64,
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+/// Independent sampler, mostly for tests and tools that don't render pixels
+impl SeedableRng for Sampler {
+    type Seed = <SmallRng as SeedableRng>::Seed;
+
+    fn from_seed(seed: Self::Seed) -> Self {
+        Self::new(SamplerKind::Independent, SmallRng::from_seed(seed))
+    }
+
+    fn seed_from_u64(state: u64) -> Self {
+        Self::new(SamplerKind::Independent, SmallRng::seed_from_u64(state))
+    }
+}
+
+/// The first two dimensions are the pixel offset, see `sampling::sample_pixel_offset()`
+const FIRST_DIMENSION: usize = 2;
+
+const SOBOL_BITS: usize = 32;
+
+/// Degree, coefficients and initial direction numbers of the primitive polynomials,
+/// starting with the second dimension (the first one is the van der Corput sequence).
+/// Taken from the new-joe-kuo-6.21201 table, S. Joe and F. Y. Kuo.
+const JOE_KUO: [(u32, u32, &[u32]); 20] = [
+    (1, 0, &[1]),
+    (2, 1, &[1, 3]),
+    (3, 1, &[1, 3, 1]),
+    (3, 2, &[1, 1, 1]),
+    (4, 1, &[1, 1, 3, 3]),
+    (4, 4, &[1, 3, 5, 13]),
+    (5, 2, &[1, 1, 5, 5, 17]),
+    (5, 4, &[1, 1, 5, 5, 5]),
+    (5, 7, &[1, 1, 7, 11, 19]),
+    (5, 11, &[1, 1, 5, 1, 1]),
+    (5, 13, &[1, 1, 1, 3, 11]),
+    (5, 14, &[1, 3, 5, 5, 31]),
+    (6, 1, &[1, 3, 3, 9, 7, 49]),
+    (6, 13, &[1, 1, 1, 15, 21, 21]),
+    (6, 16, &[1, 3, 1, 13, 27, 49]),
+    (6, 19, &[1, 1, 1, 15, 7, 5]),
+    (6, 22, &[1, 3, 1, 15, 13, 25]),
+    (6, 25, &[1, 1, 5, 5, 19, 61]),
+    (7, 1, &[1, 3, 7, 11, 23, 15, 103]),
+    (7, 4, &[1, 3, 7, 13, 13, 15, 69]),
+];
+
+pub const SOBOL_DIMENSIONS: usize = JOE_KUO.len() + 1;
+
+static SOBOL_MATRICES: [[u32; SOBOL_BITS]; SOBOL_DIMENSIONS] = sobol_matrices();
+
+/// Direction vectors of every dimension, the i-th bit of the sample index flips the i-th vector
+const fn sobol_matrices() -> [[u32; SOBOL_BITS]; SOBOL_DIMENSIONS] {
+    let mut matrices = [[0; SOBOL_BITS]; SOBOL_DIMENSIONS];
+
+    let mut i = 0;
+    while i < SOBOL_BITS {
+        matrices[0][i] = 1 << (31 - i);
+        i += 1;
+    }
+
+    let mut dim = 1;
+    while dim < SOBOL_DIMENSIONS {
+        let (s, a, m) = JOE_KUO[dim - 1];
+        let s = s as usize;
+        let v = &mut matrices[dim];
+
+        let mut i = 0;
+        while i < SOBOL_BITS {
+            if i < s {
+                v[i] = m[i] << (31 - i);
+            } else {
+                v[i] = v[i - s] ^ (v[i - s] >> s);
+                let mut k = 1;
+                while k < s {
+                    if (a >> (s - 1 - k)) & 1 != 0 {
+                        v[i] ^= v[i - k];
+                    }
+                    k += 1;
+                }
+            }
+            i += 1;
+        }
+
+        dim += 1;
+    }
+
+    matrices
+}
+
+/// The unscrambled Sobol sample, as a fixed-point number in [0, 1)
+pub fn sobol_sample(mut index: u32, dimension: usize) -> u32 {
+    let mut res = 0;
+    let mut i = 0;
+
+    while index != 0 {
+        if index & 1 != 0 {
+            res ^= SOBOL_MATRICES[dimension][i];
+        }
+
+        index >>= 1;
+        i += 1;
+    }
+
+    res
+}
+
+/// Taken from PBRTv4 - FastOwenScrambler.
+/// Original: Practical Hash-based Owen Scrambling, Brent Burley.
+/// Randomly flips the bits based on all the higher bits, so the stratification of the sequence
+/// is kept.
+fn owen_scramble(mut v: u32, seed: u32) -> u32 {
+    v = v.reverse_bits();
+    v ^= v.wrapping_mul(0x3d20adea);
+    v = v.wrapping_add(seed);
+    v = v.wrapping_mul((seed >> 16) | 1);
+    v ^= v.wrapping_mul(0x05526c56);
+    v ^= v.wrapping_mul(0x53a22864);
+    v.reverse_bits()
+}
+
+#[cfg(test)]
+mod test_super {
+    use rand::{distributions::Uniform, prelude::Distribution};
+
+    use crate::math::sqr;
+
+    use super::*;
+
+    #[test]
+    fn test_sobol_first_dimensions() {
+        for i in 0..1024 {
+            assert_eq!(sobol_sample(i, 0), i.reverse_bits());
+            assert_eq!(sobol_sample(i, 1), sampling::sobol_second_dim(i));
+        }
+    }
+
+    #[test]
+    fn test_sobol_dimensions_stratified() {
+        // Every dimension is a (0, 1)-sequence, 2^k points hit each of the 2^k intervals once,
+        // the scrambling has to keep that
+        for dim in 0..SOBOL_DIMENSIONS {
+            for seed in [0, 0xdeadbeef] {
+                let mut strata = [false; 64];
+                for i in 0..64 {
+                    let v = owen_scramble(sobol_sample(i, dim), seed);
+                    let stratum = (v >> 26) as usize;
+                    assert!(!strata[stratum], "dimension {dim}");
+                    strata[stratum] = true;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sampler_dimensions() {
+        let mut sampler = Sampler::new(SamplerKind::Sobol, SmallRng::seed_from_u64(0));
+        sampler.start_pixel_sample(3, 5, 7);
+        let first: Vec<u32> = (0..4).map(|_| sampler.next_u32()).collect();
+
+        // The same pixel sample gives the same numbers, another pixel doesn't
+        sampler.start_pixel_sample(3, 5, 7);
+        let again: Vec<u32> = (0..4).map(|_| sampler.next_u32()).collect();
+        assert_eq!(first, again);
+
+        sampler.start_pixel_sample(4, 5, 7);
+        let other: Vec<u32> = (0..4).map(|_| sampler.next_u32()).collect();
+        assert_ne!(first, other);
+
+        // Another seed scrambles the sequence differently
+        let mut seeded = Sampler::new(SamplerKind::Sobol, SmallRng::seed_from_u64(0)).with_seed(1);
+        seeded.start_pixel_sample(3, 5, 7);
+        let other: Vec<u32> = (0..4).map(|_| seeded.next_u32()).collect();
+        assert_ne!(first, other);
+
+        // Past the table, the numbers come from the pseudo-random generator
+        for _ in 0..2 * SOBOL_DIMENSIONS {
+            sampler.next_u32();
+        }
+        assert!(sampler.dimension == SOBOL_DIMENSIONS);
+    }
+
+    #[test]
+    fn test_sobol_lower_error() {
+        const SAMPLES: u32 = 16;
+        const PIXELS: usize = 256;
+
+        // Smooth 4D integrand, the exact integral is 1
+        let f = |u: [f32; 4]| u.iter().map(|u| 2. * u).product::<f32>();
+
+        let dist = Uniform::from(0f32..1f32);
+        let mse = |kind| {
+            let mut sampler = Sampler::new(kind, SmallRng::seed_from_u64(0));
+            let mut error = 0.;
+            for pixel in 0..PIXELS {
+                let mut estimate = 0.;
+                for sample in 0..SAMPLES {
+                    sampler.start_pixel_sample(pixel, 0, sample);
+                    estimate += f([(); 4].map(|_| dist.sample(&mut sampler)));
+                }
+                error += sqr(estimate / SAMPLES as f32 - 1.);
+            }
+            error / PIXELS as f32
+        };
+
+        let independent_mse = mse(SamplerKind::Independent);
+        let sobol_mse = mse(SamplerKind::Sobol);
+        assert!(
+            sobol_mse < independent_mse / 2.,
+            "independent MSE: {independent_mse}, Sobol MSE: {sobol_mse}"
+        );
+    }
+}
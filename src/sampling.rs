@@ -1,15 +1,16 @@
 use std::f32::consts::PI;
 
 use glam::{vec2, vec3, Vec2, Vec3};
-use rand::{distributions::Uniform, prelude::Distribution, rngs::SmallRng};
+use rand::{distributions::Uniform, prelude::Distribution};
 
 use crate::{
     math::{self, sqr},
+    sampler::Sampler,
     vecmath::orient_dir,
 };
 
 /// Sampling: https://pbr-book.org/3ed-2018/Monte_Carlo_Integration/2D_Sampling_with_Multidimensional_Transformations#UniformlySamplingaHemisphere
-pub fn sample_uniform_hemisphere(rng: &mut SmallRng) -> Vec3 {
+pub fn sample_uniform_hemisphere(rng: &mut Sampler) -> Vec3 {
     // Coordinate frame: https://pbr-book.org/3ed-2018/Reflection_Models/Specular_Reflection_and_Transmission
     let dist = Uniform::from(0f32..1f32);
     let u = dist.sample(rng);
@@ -21,7 +22,12 @@ pub fn sample_uniform_hemisphere(rng: &mut SmallRng) -> Vec3 {
     Vec3::new(r * f32::cos(phi), r * f32::sin(phi), z).normalize()
 }
 
-pub fn sample_uniform_sphere(rng: &mut SmallRng) -> Vec3 {
+/// PDF of sample_uniform_hemisphere()
+pub fn pdf_uniform_hemisphere() -> f32 {
+    1. / (2. * PI)
+}
+
+pub fn sample_uniform_sphere(rng: &mut Sampler) -> Vec3 {
     let dist = Uniform::from(0f32..1f32);
     let u = dist.sample(rng);
     let v = dist.sample(rng);
@@ -32,7 +38,12 @@ pub fn sample_uniform_sphere(rng: &mut SmallRng) -> Vec3 {
     Vec3::new(r * phi.cos(), r * phi.sin(), z).normalize()
 }
 
-pub fn sample_cosine_hemisphere(rng: &mut SmallRng) -> Vec3 {
+/// PDF of sample_uniform_sphere()
+pub fn pdf_uniform_sphere() -> f32 {
+    1. / (4. * PI)
+}
+
+pub fn sample_cosine_hemisphere(rng: &mut Sampler) -> Vec3 {
     let dist = Uniform::from(0f32..1f32);
     let u = dist.sample(rng);
     let v = dist.sample(rng);
@@ -42,6 +53,28 @@ pub fn sample_cosine_hemisphere(rng: &mut SmallRng) -> Vec3 {
     vec3(d.x, d.y, z)
 }
 
+/// PDF of sample_cosine_hemisphere(), zero below the horizon
+pub fn pdf_cosine_hemisphere(cos_theta: f32) -> f32 {
+    cos_theta.max(0.) / PI
+}
+
+/// Taken from PBRTv4 - SampleUniformCone.
+/// Samples a direction inside of a cone around +Z uniformly with respect to solid angle.
+pub fn sample_uniform_cone(cos_theta_max: f32, rng: &mut Sampler) -> Vec3 {
+    let dist = Uniform::from(0f32..1f32);
+    let u = dist.sample(rng);
+    let v = dist.sample(rng);
+
+    let cos_theta = (1. - u) + u * cos_theta_max;
+    let sin_theta = math::safe_sqrt(1. - sqr(cos_theta));
+    let phi = 2. * PI * v;
+    vec3(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta)
+}
+
+pub fn pdf_uniform_cone(cos_theta_max: f32) -> f32 {
+    1. / (2. * PI * (1. - cos_theta_max))
+}
+
 fn sample_uniform_disk_concentric(u: Vec2) -> Vec2 {
     // Map _u_ to $[-1,1]^2$ and handle degeneracy at the origin
     let u_offset = 2. * u - vec2(1., 1.);
@@ -63,7 +96,7 @@ fn sample_uniform_disk_concentric(u: Vec2) -> Vec2 {
 }
 
 /// Taken from "Real Shading in Unreal Engine 4".
-pub fn sample_trowbridge_reitz(rng: &mut SmallRng, normal: Vec3, roughness: f32) -> Vec3 {
+pub fn sample_trowbridge_reitz(rng: &mut Sampler, normal: Vec3, roughness: f32) -> Vec3 {
     let a = roughness;
 
     let dist = Uniform::from(0f32..1f32);
@@ -83,7 +116,7 @@ pub fn sample_trowbridge_reitz(rng: &mut SmallRng, normal: Vec3, roughness: f32)
 
 /// Samples the CMF, return an index into the CMF slice.
 /// Expects a normalized CMF.
-pub fn sample_discrete_cmf(cmf: &[f32], rng: &mut SmallRng) -> usize {
+pub fn sample_discrete_cmf(cmf: &[f32], rng: &mut Sampler) -> usize {
     let dist = Uniform::from(0f32..1f32);
     let u = dist.sample(rng);
 
@@ -92,7 +125,7 @@ pub fn sample_discrete_cmf(cmf: &[f32], rng: &mut SmallRng) -> usize {
 
 /// Taken from PBRT - UniformSampleTriangle.
 /// Return barycentric coordinates that can be used to sample any triangle.
-pub fn sample_uniform_triangle(rng: &mut SmallRng) -> [f32; 3] {
+pub fn sample_uniform_triangle(rng: &mut Sampler) -> [f32; 3] {
     let dist = Uniform::from(0f32..1f32);
     let u = dist.sample(rng);
     let v = dist.sample(rng);
@@ -107,3 +140,348 @@ pub fn sample_uniform_triangle(rng: &mut SmallRng) -> [f32; 3] {
 
     [b0, b1, b2]
 }
+
+/// Position of a sample inside of a pixel, in [0, 1)^2.
+/// Uses the first two dimensions of the Sobol sequence, so every power-of-two prefix of the
+/// per-pixel sample indices is stratified. A per-pixel Cranley-Patterson rotation decorrelates
+/// neighbouring pixels, that would otherwise all sample the same strata in the same order.
+pub fn sample_pixel_offset(sample_index: u32, px: usize, py: usize) -> Vec2 {
+    let sobol = vec2(
+        u32_to_unit_f32(sample_index.reverse_bits()),
+        u32_to_unit_f32(sobol_second_dim(sample_index)),
+    );
+
+    let rotation = pixel_rotation(px, py);
+    let offset = (sobol + rotation).fract();
+    offset.clamp(Vec2::ZERO, Vec2::splat(1f32.next_down()))
+}
+
+/// Second dimension of the Sobol sequence.
+/// Taken from: Efficient Multidimensional Sampling, Kollig and Keller.
+pub(crate) fn sobol_second_dim(mut index: u32) -> u32 {
+    let mut v = 1 << 31;
+    let mut res = 0;
+
+    while index != 0 {
+        if index & 1 != 0 {
+            res ^= v;
+        }
+
+        index >>= 1;
+        v ^= v >> 1;
+    }
+
+    res
+}
+
+/// Deterministic pseudo-random offset of a pixel, so that the rotation stays the same for all
+/// samples of the pixel.
+fn pixel_rotation(px: usize, py: usize) -> Vec2 {
+    let hash = hash_u64(((px as u64) << 32) | py as u64);
+    vec2(
+        u32_to_unit_f32(hash as u32),
+        u32_to_unit_f32((hash >> 32) as u32),
+    )
+}
+
+/// SplitMix64 finalizer
+pub(crate) fn hash_u64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+fn u32_to_unit_f32(v: u32) -> f32 {
+    // Only the upper 24 bits fit into the mantissa
+    (v >> 8) as f32 / (1 << 24) as f32
+}
+
+/// The rectangle expressed in a local frame centered at the reference point, shared by
+/// the sampling and the PDF evaluation.
+struct SphericalRectangle {
+    frame_x: Vec3,
+    frame_y: Vec3,
+    frame_z: Vec3,
+    x0: f32,
+    x1: f32,
+    y0: f32,
+    y1: f32,
+    z0: f32,
+    /// Normals of the planes going through p_ref and the rectangle edges
+    n0: Vec3,
+    n2: Vec3,
+    /// Internal angles
+    g: [f32; 4],
+    solid_angle: f32,
+}
+
+impl SphericalRectangle {
+    /// None if p_ref lies in the plane of the rectangle, it can't be seen from there
+    fn new(p_ref: Vec3, corner: Vec3, ex: Vec3, ey: Vec3) -> Option<Self> {
+        // Compute local reference frame and transform rectangle coordinates
+        let exl = ex.length();
+        let eyl = ey.length();
+        let frame_x = ex / exl;
+        let frame_y = ey / eyl;
+        let mut frame_z = frame_x.cross(frame_y);
+
+        let d_local = corner - p_ref;
+        let mut z0 = d_local.dot(frame_z);
+
+        // Flip z to make it point against the rectangle
+        if z0 > 0. {
+            frame_z = -frame_z;
+            z0 = -z0;
+        }
+
+        if z0 > -math::EPS {
+            return None;
+        }
+
+        let x0 = d_local.dot(frame_x);
+        let y0 = d_local.dot(frame_y);
+        let x1 = x0 + exl;
+        let y1 = y0 + eyl;
+
+        // Find plane normals to rectangle edges and compute internal angles
+        let v00 = vec3(x0, y0, z0);
+        let v01 = vec3(x0, y1, z0);
+        let v10 = vec3(x1, y0, z0);
+        let v11 = vec3(x1, y1, z0);
+        let n0 = v00.cross(v10).normalize();
+        let n1 = v10.cross(v11).normalize();
+        let n2 = v11.cross(v01).normalize();
+        let n3 = v01.cross(v00).normalize();
+
+        let g = [
+            angle_between(-n0, n1),
+            angle_between(-n1, n2),
+            angle_between(-n2, n3),
+            angle_between(-n3, n0),
+        ];
+
+        // Compute spherical rectangle solid angle
+        let solid_angle = g.iter().sum::<f32>() - 2. * PI;
+
+        Some(Self {
+            frame_x,
+            frame_y,
+            frame_z,
+            x0,
+            x1,
+            y0,
+            y1,
+            z0,
+            n0,
+            n2,
+            g,
+            solid_angle,
+        })
+    }
+
+    fn pdf(&self) -> f32 {
+        if self.solid_angle > 0. {
+            1. / self.solid_angle
+        } else {
+            0.
+        }
+    }
+}
+
+/// Taken from PBRTv4 - SampleSphericalRectangle.
+/// Original: An Area-Preserving Parametrization for Spherical Rectangles, Ureña et al.
+/// Samples a point on the rectangle uniformly with respect to the solid angle subtended
+/// at p_ref. The edges have to be perpendicular.
+/// Returns the sampled point and the solid-angle PDF.
+pub fn sample_spherical_rectangle(
+    rng: &mut Sampler,
+    p_ref: Vec3,
+    corner: Vec3,
+    ex: Vec3,
+    ey: Vec3,
+) -> (Vec3, f32) {
+    let dist = Uniform::from(0f32..1f32);
+    let u = vec2(dist.sample(rng), dist.sample(rng));
+
+    let rect = match SphericalRectangle::new(p_ref, corner, ex, ey) {
+        Some(rect) => rect,
+        None => return (corner + u.x * ex + u.y * ey, 0.),
+    };
+
+    let pdf = rect.pdf();
+    // Practically uniform area sampling for tiny solid angles
+    if rect.solid_angle < 1e-3 {
+        return (corner + u.x * ex + u.y * ey, pdf);
+    }
+
+    let SphericalRectangle {
+        x0,
+        x1,
+        y0,
+        y1,
+        z0,
+        g,
+        ..
+    } = rect;
+
+    // Sample cu for spherical rectangle sample
+    let b0 = rect.n0.z;
+    let b1 = rect.n2.z;
+    let au = u.x * (g[0] + g[1] - 2. * PI) + (u.x - 1.) * (g[2] + g[3]);
+    let fu = (au.cos() * b0 - b1) / au.sin();
+    let mut cu = f32::copysign(1. / (sqr(fu) + sqr(b0)).sqrt(), fu);
+    cu = cu.clamp(-1f32.next_down(), 1f32.next_down());
+
+    // Find xu along x edge for spherical rectangle sample
+    let mut xu = -(cu * z0) / math::safe_sqrt(1. - sqr(cu));
+    xu = xu.clamp(x0, x1);
+
+    // Find yv along y edge for spherical rectangle sample
+    let dd = (sqr(xu) + sqr(z0)).sqrt();
+    let h0 = y0 / (sqr(dd) + sqr(y0)).sqrt();
+    let h1 = y1 / (sqr(dd) + sqr(y1)).sqrt();
+    let hv = h0 + u.y * (h1 - h0);
+    let hvsq = sqr(hv);
+    let yv = if hvsq < 1. - math::EPS {
+        (hv * dd) / (1. - hvsq).sqrt()
+    } else {
+        y1
+    };
+
+    let pos = p_ref + rect.frame_x * xu + rect.frame_y * yv + rect.frame_z * z0;
+    (pos, pdf)
+}
+
+/// Solid-angle PDF of sample_spherical_rectangle(), the same for every point on the rectangle.
+pub fn spherical_rectangle_pdf(p_ref: Vec3, corner: Vec3, ex: Vec3, ey: Vec3) -> f32 {
+    SphericalRectangle::new(p_ref, corner, ex, ey).map_or(0., |rect| rect.pdf())
+}
+
+/// Numerically stable angle between two normalized vectors, taken from PBRTv4.
+fn angle_between(v1: Vec3, v2: Vec3) -> f32 {
+    if v1.dot(v2) < 0. {
+        PI - 2. * ((v1 + v2).length() / 2.).clamp(-1., 1.).asin()
+    } else {
+        2. * ((v2 - v1).length() / 2.).clamp(-1., 1.).asin()
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn sobol_points(count: u32) -> Vec<Vec2> {
+        (0..count)
+            .map(|i| {
+                vec2(
+                    u32_to_unit_f32(i.reverse_bits()),
+                    u32_to_unit_f32(sobol_second_dim(i)),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sobol_strata() {
+        // 16 points of the sequence cover every cell of a 4x4 grid exactly once
+        let mut strata = [false; 16];
+        for p in sobol_points(16) {
+            let stratum = (p.y * 4.) as usize * 4 + (p.x * 4.) as usize;
+            assert!(!strata[stratum]);
+            strata[stratum] = true;
+        }
+
+        assert!(strata.iter().all(|s| *s));
+    }
+
+    #[test]
+    fn test_uniform_cone() {
+        let mut rng = Sampler::seed_from_u64(0);
+
+        for cos_theta_max in [0.99, 0.7, 0., -0.5] {
+            for _ in 0..1000 {
+                let dir = sample_uniform_cone(cos_theta_max, &mut rng);
+                assert!((dir.length() - 1.).abs() < 0.0001);
+                assert!(dir.z >= cos_theta_max - 0.0001);
+            }
+
+            // Integrate the PDF over the sphere with uniform sphere samples
+            let samples = 100_000;
+            let pdf = pdf_uniform_cone(cos_theta_max);
+            let integral = (0..samples)
+                .map(|_| sample_uniform_sphere(&mut rng))
+                .filter(|dir| dir.z >= cos_theta_max)
+                .map(|_| pdf * 4. * PI)
+                .sum::<f32>()
+                / samples as f32;
+            assert!((integral - 1.).abs() < 0.05, "{cos_theta_max} {integral}");
+        }
+    }
+
+    #[test]
+    fn test_cosine_hemisphere_pdf() {
+        assert_eq!(pdf_cosine_hemisphere(-0.5), 0.);
+        assert_eq!(pdf_cosine_hemisphere(1.), 1. / PI);
+
+        // Same as the uniform-cone integral, over the hemisphere
+        let mut rng = Sampler::seed_from_u64(1);
+        let samples = 100_000;
+        let integral = (0..samples)
+            .map(|_| pdf_cosine_hemisphere(sample_uniform_sphere(&mut rng).z) * 4. * PI)
+            .sum::<f32>()
+            / samples as f32;
+        assert!((integral - 1.).abs() < 0.02, "{integral}");
+    }
+
+    /// The previous scheme: the stratum only advanced with the pass index and was the same for
+    /// all pixels.
+    fn old_pixel_offset(sample_index: u32, rng: &mut Sampler) -> Vec2 {
+        const STRATA_SQRT: u32 = 4;
+        let stratum_width = 1. / STRATA_SQRT as f32;
+
+        let stratum = sample_index % (STRATA_SQRT * STRATA_SQRT);
+        let stratum_offset_x = (stratum % STRATA_SQRT) as f32 * stratum_width;
+        let stratum_offset_y = (stratum / STRATA_SQRT) as f32 * stratum_width;
+
+        let dist = Uniform::from(0f32..stratum_width);
+        vec2(
+            stratum_offset_x + dist.sample(rng),
+            stratum_offset_y + dist.sample(rng),
+        )
+    }
+
+    #[test]
+    fn test_pixel_sampling_variance() {
+        const SAMPLES: u32 = 8;
+        const PIXELS: usize = 64;
+
+        // Smooth gradient over the pixel, the exact integral is 1
+        let f = |p: Vec2| p.x + p.y;
+
+        let mut rng = Sampler::seed_from_u64(0);
+        let mut old_error = 0.;
+        let mut new_error = 0.;
+
+        for py in 0..PIXELS {
+            for px in 0..PIXELS {
+                let mut old_estimate = 0.;
+                let mut new_estimate = 0.;
+                for sample in 0..SAMPLES {
+                    old_estimate += f(old_pixel_offset(sample, &mut rng));
+                    new_estimate += f(sample_pixel_offset(sample, px, py));
+                }
+
+                old_error += sqr(old_estimate / SAMPLES as f32 - 1.);
+                new_error += sqr(new_estimate / SAMPLES as f32 - 1.);
+            }
+        }
+
+        let old_mse = old_error / (PIXELS * PIXELS) as f32;
+        let new_mse = new_error / (PIXELS * PIXELS) as f32;
+        assert!(
+            new_mse < old_mse / 10.,
+            "old MSE: {old_mse}, new MSE: {new_mse}"
+        );
+    }
+}
@@ -2,29 +2,40 @@ use std::sync::Arc;
 
 use eyre::Result;
 use glam::{Vec2, Vec3};
-use rand::rngs::SmallRng;
 use rgb2spec::RGB2Spec;
 
 use crate::{
-    bvh::Bvh,
+    bvh::{Bvh, BvhOptions, PACKET_SIZE},
     color::spectrum::rgb_spectrum::{RgbSpectrum, RgbSpectrumKind},
     geometry::{
+        cuboid::Cuboid,
+        infinite_plane::InfinitePlane,
+        quad::Quad,
         sphere::Sphere,
         trianglemesh::{Triangle, TriangleMesh},
         Ray, Shape, ShapeHitInfo,
     },
-    pbrt_loader::scene_description::{self, InfiniteLightSource, Material, SceneDescription},
+    pbrt_loader::scene_description::{
+        self, HomogeneousMedium, InfiniteLightSource, Material, SceneDescription,
+    },
+    sampler::Sampler,
     scene::primitive::{
-        LightPrimitive, MeshTriangleLightPrimitive, MeshTrianglePrimitive, SimplePrimtive,
+        LightPrimitive, MeshTriangleLightPrimitive, ScenePrimitives, SimplePrimtive,
     },
+    stats::{RayKind, Stage, STATS},
+    texture::{AlphaMask, BumpMap},
     util::TaggedPtr,
 };
 
-use self::{light_sampler::LightSampler, octamap::OctaMap, primitive::Primitive};
+use self::{
+    light_sampler::LightSampler, octamap::OctaMap, primitive::Primitive,
+    projection_light::ProjectionLight,
+};
 
 mod light_sampler;
-mod octamap;
+pub(crate) mod octamap;
 pub mod primitive;
+pub mod projection_light;
 
 pub type LightId = usize;
 pub type PrimitiveId = usize;
@@ -35,9 +46,15 @@ const SCENE_ALLOC: std::alloc::Global = std::alloc::Global;
 pub struct Scene {
     pub infinite_light: Option<InfiniteLight>,
     pub lights: Vec<Light, SceneAlloc>,
+    pub projection_lights: Vec<ProjectionLight>,
+    /// Medium filling the whole scene, only the simple path integrator takes it into account
+    pub camera_medium: Option<HomogeneousMedium>,
     /// TODO: custom allocator for Arc https://github.com/rust-lang/rust/pull/89132
     triangle_meshes: Vec<Arc<TriangleMesh>, SceneAlloc>,
-    primitives: Vec<TaggedPtr<Primitive>, SceneAlloc>,
+    primitives: ScenePrimitives,
+    /// Primitives with an infinite extent (infinite planes) can't be stored in the BVH,
+    /// they are kept separate and are tested against every ray after the BVH traversal.
+    unbounded_primitives: Vec<TaggedPtr<Primitive>, SceneAlloc>,
     bvh: Bvh,
     light_sampler: LightSampler,
 }
@@ -46,89 +63,150 @@ impl Scene {
     pub fn init(scene_desc: SceneDescription) -> Result<Self> {
         let mut lights = Vec::new_in(SCENE_ALLOC);
         let mut triangle_meshes = Vec::new_in(SCENE_ALLOC);
-        let mut primitives = Vec::new_in(SCENE_ALLOC);
+        let bvh_options = scene_desc.options.general_options.bvh;
+        let mut primitives = ScenePrimitives::new(bvh_options.primitive_layout);
+        let mut unbounded_primitives = Vec::new_in(SCENE_ALLOC);
+        let camera_medium = scene_desc.options.camera.medium.clone();
 
         // TODO: calculate primitives len up front
         // TODO: benchmark creating the BVH
         // let triangle_count: usize = triangle_meshes.iter().map(|tm| tm.triangle_count()).sum();
 
-        for shape_with_params in scene_desc.shapes {
+        for mut shape_with_params in scene_desc.shapes {
+            // Interfaces only separate media, they don't emit
+            if shape_with_params.material.is_interface() {
+                shape_with_params.area_light = None;
+            }
+
+            let alpha = match &shape_with_params.alpha {
+                Some(alpha) => Some(Arc::new(AlphaMask::init(alpha)?)),
+                None => None,
+            };
+            let bump_map = match &shape_with_params.bump_map {
+                Some(path) => Some(Arc::new(BumpMap::load(path)?)),
+                None => None,
+            };
+
             match shape_with_params.shape {
                 scene_description::Shape::TriMesh(mesh) => {
-                    let trimesh = Arc::new(TriangleMesh::new(
+                    let mut trimesh = TriangleMesh::new(
                         mesh,
                         Arc::new(shape_with_params.material),
+                        shape_with_params.object_to_world,
                         shape_with_params.reverse_normals,
-                    ));
+                        alpha,
+                    )
+                    .with_bump_map(bump_map);
+                    if bvh_options.cache_triangles {
+                        trimesh.precompute_triangles();
+                    }
+                    let trimesh = Arc::new(trimesh);
+
+                    // The whole mesh is one emitter, so the power is normalized by its total area
+                    let light_radiance = shape_with_params.area_light.as_ref().map(|light| {
+                        let area = (0..trimesh.triangle_count())
+                            .map(|id| Triangle::new(Arc::clone(&trimesh), id as u64).area())
+                            .filter(|area| *area > 0.)
+                            .sum();
+                        light.effective_radiance(area)
+                    });
 
+                    let mut degenerate = 0;
                     for triangle_id in 0..trimesh.triangle_count() {
-                        let triangle = Triangle::new(Arc::clone(&trimesh), triangle_id as u64);
+                        let triangle = Triangle::new(trimesh.as_ref(), triangle_id as u64);
+                        // Degenerate triangles can't be hit and would be sampled as zero-area lights.
+                        // NaN areas come from non-finite positions.
+                        let area = triangle.area();
+                        if area.is_nan() || area <= 0. {
+                            degenerate += 1;
+                            continue;
+                        }
 
-                        let primitive = if let Some(light) = &shape_with_params.area_light {
-                            let l = Light::new(primitives.len(), light.radiance.clone());
+                        if let Some(radiance) = &light_radiance {
+                            let triangle = Triangle::new(Arc::clone(&trimesh), triangle_id as u64);
                             let light_id = lights.len();
-                            lights.push(l);
+                            let primitive = Primitive::MeshTriangleLight(Box::new(
+                                MeshTriangleLightPrimitive::new(triangle, light_id),
+                            ));
 
-                            Primitive::MeshTriangleLight(Box::new(MeshTriangleLightPrimitive::new(
-                                triangle, light_id,
-                            )))
+                            let primitive_id = primitives.push_boxed(TaggedPtr::new(primitive));
+                            lights.push(Light::new(primitive_id, radiance.clone()));
                         } else {
-                            Primitive::MeshTriangle(Box::new(MeshTrianglePrimitive::new(triangle)))
-                        };
+                            primitives.push_mesh_triangle(&trimesh, triangle_id);
+                        }
+                    }
 
-                        primitives.push(TaggedPtr::new(primitive));
+                    if degenerate > 0 {
+                        eprintln!(
+                            "Skipping {} degenerate triangles of a mesh with {} triangles",
+                            degenerate,
+                            trimesh.triangle_count()
+                        );
                     }
 
                     triangle_meshes.push(Arc::clone(&trimesh));
                 }
                 ref shape => {
-                    let mut light_id = None;
-                    if let Some(light) = &shape_with_params.area_light {
-                        let l = Light::new(primitives.len(), light.radiance.clone());
-                        light_id = Some(lights.len());
-                        lights.push(l);
-                    }
-
                     let shape = match shape {
                         scene_description::Shape::TriMesh(_) => unreachable!(),
                         scene_description::Shape::Sphere(ref sphere) => {
                             let sphere = Sphere::new(&shape_with_params, sphere);
                             TaggedPtr::new(Shape::Sphere(Box::new(sphere)))
                         }
+                        scene_description::Shape::InfinitePlane(ref plane) => {
+                            let plane = InfinitePlane::new(&shape_with_params, plane);
+                            TaggedPtr::new(Shape::InfinitePlane(Box::new(plane)))
+                        }
+                        scene_description::Shape::Cuboid(ref cuboid) => {
+                            let cuboid = Cuboid::new(&shape_with_params, cuboid);
+                            TaggedPtr::new(Shape::Cuboid(Box::new(cuboid)))
+                        }
+                        scene_description::Shape::Quad(ref quad) => {
+                            let quad = Quad::new(&shape_with_params, quad);
+                            TaggedPtr::new(Shape::Quad(Box::new(quad)))
+                        }
                     };
 
+                    let radiance = shape_with_params
+                        .area_light
+                        .as_ref()
+                        .filter(|_| shape.area() > 0.)
+                        .map(|light| light.effective_radiance(shape.area()));
+                    let light_id = radiance.as_ref().map(|_| lights.len());
+
                     let primitive = if let Some(light) = light_id {
                         Primitive::Light(Box::new(LightPrimitive::new(
                             shape,
                             Arc::new(shape_with_params.material),
                             light,
+                            alpha,
+                            bump_map,
                         )))
                     } else {
                         Primitive::Simple(Box::new(SimplePrimtive::new(
                             shape,
                             Arc::new(shape_with_params.material),
+                            alpha,
+                            bump_map,
                         )))
                     };
 
-                    primitives.push(TaggedPtr::new(primitive));
+                    let primitive = TaggedPtr::new(primitive);
+                    if primitive.aabb().is_bounded() {
+                        let primitive_id = primitives.push_boxed(primitive);
+                        if let Some(radiance) = radiance {
+                            lights.push(Light::new(primitive_id, radiance));
+                        }
+                    } else {
+                        debug_assert!(light_id.is_none());
+                        unbounded_primitives.push(primitive);
+                    }
                 }
             }
         }
 
-        let my_bvh = crate::bvh::Bvh::build(&mut primitives);
-
-        // Fixup the light indices because building the BVH reorders primitives
-        for (i, prim) in primitives.iter().enumerate() {
-            prim.0.map_ref(|prim| match prim {
-                Primitive::MeshTriangleLight(tri_light) => {
-                    lights[tri_light.light()].primitive = i;
-                }
-                Primitive::Light(light_prim) => {
-                    lights[light_prim.light()].primitive = i;
-                }
-                _ => (),
-            });
-        }
+        let my_bvh = STATS.timed(Stage::BvhBuild, || primitives.build_bvh(bvh_options));
+        Self::fixup_light_indices(&primitives, &mut lights);
 
         let infinite_light = if let Some(ils) = scene_desc.infinite_light {
             Some(InfiniteLight::init(ils)?)
@@ -136,12 +214,21 @@ impl Scene {
             None
         };
 
+        let projection_lights = scene_desc
+            .projection_lights
+            .into_iter()
+            .map(ProjectionLight::init)
+            .collect::<Result<Vec<_>>>()?;
+
         Ok(Self {
             infinite_light,
+            projection_lights,
+            camera_medium,
             triangle_meshes,
-            light_sampler: LightSampler::new(&primitives, &lights),
+            light_sampler: LightSampler::new(primitives.boxed(), &lights),
             lights,
             primitives,
+            unbounded_primitives,
             bvh: my_bvh,
         })
     }
@@ -151,16 +238,62 @@ impl Scene {
     }
 
     pub fn trace_ray_bounded(&self, ray: &Ray, maxt: f32) -> Option<HitInfo> {
-        self.bvh.intersect(ray, maxt, &self.primitives)
+        STATS.count_ray(RayKind::Intersection);
+        let mut closest_hitinfo = self.primitives.intersect(&self.bvh, ray, maxt);
+
+        for primitive in self.unbounded_primitives.iter() {
+            let tmax = closest_hitinfo.as_ref().map_or(maxt, |hit| hit.t);
+            if let Some(hitinfo) = primitive.intersect(ray) {
+                if hitinfo.t < tmax {
+                    closest_hitinfo = Some(hitinfo);
+                }
+            }
+        }
+
+        closest_hitinfo
     }
 
-    pub fn is_unoccluded(&self, start: Vec3, end: Vec3) -> bool {
-        let dir = end - start;
-        let ray = Ray::new(start, dir);
+    /// Traces the packet through the BVH at once, the unbounded primitives are tested per ray
+    pub fn trace_packet(&self, rays: &[Ray; PACKET_SIZE]) -> [Option<HitInfo>; PACKET_SIZE] {
+        let mut closest_hitinfos = self.primitives.intersect_packet(&self.bvh, rays);
+
+        for (ray, closest_hitinfo) in rays.iter().zip(closest_hitinfos.iter_mut()) {
+            STATS.count_ray(RayKind::Intersection);
+
+            for primitive in self.unbounded_primitives.iter() {
+                let tmax = closest_hitinfo.as_ref().map_or(f32::INFINITY, |hit| hit.t);
+                if let Some(hitinfo) = primitive.intersect(ray) {
+                    if hitinfo.t < tmax {
+                        *closest_hitinfo = Some(hitinfo);
+                    }
+                }
+            }
+        }
+
+        closest_hitinfos
+    }
+
+    /// Shadow rays continue through transparent parts of alpha-masked surfaces
+    pub fn is_unoccluded(&self, start: Vec3, end: Vec3, rng: &mut Sampler) -> bool {
+        STATS.count_ray(RayKind::Shadow);
+        let mut orig = start;
+
+        loop {
+            let dir = end - orig;
+            let ray = Ray::new(orig, dir);
 
-        match self.bvh.intersect(&ray, f32::INFINITY, &self.primitives) {
-            Some(hit) => hit.t >= dir.length() - 0.01,
-            None => true,
+            match self.trace_ray(&ray) {
+                Some(hit) if hit.t < dir.length() - 0.01 => match &hit.alpha {
+                    Some(alpha) if alpha.is_transparent(hit.uv, rng) => {
+                        orig = hit.pos + ray.dir * 0.001;
+                    }
+                    _ if hit.material.is_interface() => {
+                        orig = hit.pos + ray.dir * 0.001;
+                    }
+                    _ => return false,
+                },
+                _ => return true,
+            }
         }
 
         // FIXME: ray shortening seems to be off
@@ -171,17 +304,53 @@ impl Scene {
         .is_some() */
     }
 
-    pub fn sample_light(&self, rng: &mut SmallRng) -> Option<LightSample> {
+    /// Samples a point on one of the area lights for illuminating p_ref
+    pub fn sample_light(&self, p_ref: Vec3, rng: &mut Sampler) -> Option<LightSample> {
         self.light_sampler
-            .sample(&self.primitives, &self.lights, rng)
+            .sample(self.primitives.boxed(), &self.lights, p_ref, rng)
+    }
+
+    /// Solid-angle PDF of sample_light() returning the point on the light, used for MIS
+    pub fn light_pdf(&self, light_id: LightId, p_ref: Vec3, sample: &ShapeSample) -> f32 {
+        let primitive = &self.primitives.boxed()[self.lights[light_id].primitive];
+        self.light_sampler.pmf(light_id) * primitive.pdf_from(p_ref, sample)
     }
 
     pub fn light_area(&self, light: &Light) -> f32 {
-        self.primitives[light.primitive].area()
+        self.primitives.boxed()[light.primitive].area()
     }
 
+    /// With the SoA layout, the mesh triangles aren't among these
     pub fn primitives(&self) -> &[TaggedPtr<Primitive>] {
-        self.primitives.as_ref()
+        self.primitives.boxed()
+    }
+
+    pub fn bvh(&self) -> &Bvh {
+        &self.bvh
+    }
+
+    /// Builds the BVH again from the current primitives, used for benchmarking the BVH build.
+    pub fn rebuild_bvh(&mut self, options: BvhOptions) {
+        self.bvh = self.primitives.build_bvh(options);
+        Self::fixup_light_indices(&self.primitives, &mut self.lights);
+    }
+
+    /// Fixup the light indices because building the BVH reorders primitives.
+    /// The SoA layout doesn't move the boxed primitives.
+    fn fixup_light_indices(primitives: &ScenePrimitives, lights: &mut [Light]) {
+        if let ScenePrimitives::Boxed(primitives) = primitives {
+            for (i, prim) in primitives.iter().enumerate() {
+                prim.0.map_ref(|prim| match prim {
+                    Primitive::MeshTriangleLight(tri_light) => {
+                        lights[tri_light.light()].primitive = i;
+                    }
+                    Primitive::Light(light_prim) => {
+                        lights[light_prim.light()].primitive = i;
+                    }
+                    _ => (),
+                });
+            }
+        }
     }
 }
 
@@ -191,8 +360,17 @@ pub struct HitInfo {
     pub normal: Vec3,
     pub t: f32,
     pub uv: Option<Vec2>,
+    /// Shading tangent, perpendicular to the normal when present
+    pub tangent: Option<Vec3>,
+    /// dp/du and dp/dv, scale the bump map gradient
+    pub uv_derivatives: Option<[Vec3; 2]>,
+    pub barycentrics: Option<[f32; 3]>,
+    /// Linear RGB vertex color of meshes
+    pub color: Option<Vec3>,
     pub light: Option<LightId>,
     pub material: Arc<Material>,
+    pub alpha: Option<Arc<AlphaMask>>,
+    pub bump_map: Option<Arc<BumpMap>>,
 }
 
 impl HitInfo {
@@ -203,14 +381,21 @@ impl HitInfo {
         uv: Option<Vec2>,
         light: Option<LightId>,
         material: Arc<Material>,
+        alpha: Option<Arc<AlphaMask>>,
     ) -> Self {
         Self {
             pos,
             normal,
             t,
             uv,
+            tangent: None,
+            uv_derivatives: None,
+            barycentrics: None,
+            color: None,
             light,
             material,
+            alpha,
+            bump_map: None,
         }
     }
 
@@ -218,16 +403,45 @@ impl HitInfo {
         shape_hitinfo: ShapeHitInfo,
         material: Arc<Material>,
         light: Option<LightId>,
+        alpha: Option<Arc<AlphaMask>>,
     ) -> Self {
         Self {
             pos: shape_hitinfo.pos,
             normal: shape_hitinfo.normal,
             t: shape_hitinfo.t,
             uv: shape_hitinfo.uv,
+            tangent: shape_hitinfo.tangent,
+            uv_derivatives: shape_hitinfo.uv_derivatives,
+            barycentrics: shape_hitinfo.barycentrics,
+            color: None,
             light,
             material,
+            alpha,
+            bump_map: None,
         }
     }
+
+    pub fn with_bump_map(mut self, bump_map: Option<Arc<BumpMap>>) -> Self {
+        self.bump_map = bump_map;
+        self
+    }
+
+    /// Replaces the shading normal with the bump-mapped one. Surfaces without UVs or their
+    /// derivatives are left as they are, an arbitrary frame would tilt the normal in the
+    /// wrong direction. The tangent is kept perpendicular to the new normal.
+    pub fn apply_bump_map(&mut self) {
+        let (Some(bump_map), Some(uv), Some([dpdu, dpdv])) =
+            (&self.bump_map, self.uv, self.uv_derivatives)
+        else {
+            return;
+        };
+
+        self.normal = bump_map.perturb_normal(uv, self.normal.normalize(), dpdu, dpdv);
+        self.tangent = self
+            .tangent
+            .map(|tangent| (tangent - self.normal * self.normal.dot(tangent)).normalize())
+            .filter(|t| t.is_finite());
+    }
 }
 
 pub struct ShapeSample {
@@ -239,22 +453,43 @@ impl ShapeSample {
     pub fn new(pos: Vec3, normal: Vec3) -> Self {
         Self { pos, normal }
     }
+
+    /// Converts a PDF with respect to area to a PDF with respect to the solid angle at p_ref.
+    /// Zero for samples facing away from p_ref.
+    pub fn area_to_solid_angle_pdf(&self, p_ref: Vec3, pdf_area: f32) -> f32 {
+        let to_sample = self.pos - p_ref;
+        let cos = self.normal.dot(-to_sample.normalize());
+        if cos > 0. {
+            pdf_area * to_sample.length_squared() / cos
+        } else {
+            0.
+        }
+    }
 }
 
 pub struct LightSample<'r> {
+    pub light_id: LightId,
     pub shape_sample: ShapeSample,
     pub emission: &'r RgbSpectrum,
-    pub area: f32,
+    /// Solid-angle PDF of the sample at the reference point, including the pmf
+    pub pdf: f32,
     /// Probability of choosing this light
     pub pmf: f32,
 }
 
 impl<'r> LightSample<'r> {
-    pub fn new(shape_sample: ShapeSample, emission: &'r RgbSpectrum, area: f32, pmf: f32) -> Self {
+    pub fn new(
+        light_id: LightId,
+        shape_sample: ShapeSample,
+        emission: &'r RgbSpectrum,
+        pdf: f32,
+        pmf: f32,
+    ) -> Self {
         Self {
+            light_id,
             shape_sample,
             emission,
-            area,
+            pdf,
             pmf,
         }
     }
@@ -277,21 +512,561 @@ impl Light {
 
 pub struct InfiniteLight {
     iblmap: OctaMap,
-    scale: f32,
+    /// The scalar scale multiplied by the RGB tint
+    scale: Vec3,
 }
 
 impl InfiniteLight {
     pub fn init(ils: InfiniteLightSource) -> Result<Self> {
         Ok(Self {
-            iblmap: OctaMap::load(&ils.filepath)?,
-            scale: ils.scale,
+            iblmap: OctaMap::load(&ils.filepath, ils.mapping)?,
+            scale: ils.scale * ils.tint,
         })
     }
 
     pub fn sample(&self, dir: Vec3, rgbtospec: &RGB2Spec) -> RgbSpectrum {
+        let (rgb, spectrum_kind) = self.sample_rgb(dir);
+        RgbSpectrum::new(rgbtospec, rgb, spectrum_kind)
+    }
+
+    /// The radiance as RGB, without creating the spectrum
+    pub fn sample_rgb(&self, dir: Vec3) -> (Vec3, RgbSpectrumKind) {
         let rgb = self.iblmap.sample(dir) * self.scale;
         let color_space = self.iblmap.color_space();
-        let spectrum_kind = RgbSpectrumKind::new_illuminant(*color_space);
-        RgbSpectrum::new(rgbtospec, rgb, spectrum_kind)
+        (rgb, RgbSpectrumKind::new_illuminant(*color_space))
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use glam::{vec3, Mat4};
+    use rand::SeedableRng;
+
+    use std::f32::consts::PI;
+
+    use crate::{
+        bvh::SplitMethod,
+        color::{
+            color_space::ColorSpace,
+            spectrum::{rgb_spectrum::flat_rgbtospec, SampledWavelengths, SpectralQuantity},
+        },
+        integrator::{scratch::RenderScratch, Integrator},
+        pbrt_loader::{
+            scene_description::{self, Alpha, AreaLightSource, ScreenWideOptions, ShapeWithParams},
+            SceneLoader,
+        },
+        util::test_dir,
+    };
+
+    use super::{octamap::write_test_hdr, *};
+
+    /// A 2x2 quad at z = 1, the left half is a hole and the right half is opaque
+    fn cutout_quad() -> ShapeWithParams {
+        let alpha_path = test_dir("alpha").join("cutout.png");
+        let mut image = image::GrayImage::new(2, 1);
+        image.put_pixel(0, 0, image::Luma([0]));
+        image.put_pixel(1, 0, image::Luma([255]));
+        image.save(&alpha_path).unwrap();
+
+        let quad = scene_description::Quad::new(vec3(-1., -1., 1.), vec3(2., 0., 0.), Vec3::Y * 2.);
+        ShapeWithParams::new(
+            scene_description::Shape::Quad(quad),
+            Material::new_empty(),
+            None,
+            Mat4::IDENTITY,
+            false,
+            Some(Alpha::ImageTexture(alpha_path)),
+        )
+    }
+
+    #[test]
+    fn test_alpha_shadow_rays() {
+        let scene_desc = SceneDescription {
+            options: ScreenWideOptions::default(),
+            shapes: vec![cutout_quad()],
+            infinite_light: None,
+            projection_lights: Vec::new(),
+        };
+        let scene = Scene::init(scene_desc).unwrap();
+        let mut rng = Sampler::seed_from_u64(0);
+
+        // Through the hole
+        assert!(scene.is_unoccluded(vec3(-0.5, 0., 0.), vec3(-0.5, 0., 2.), &mut rng));
+        // Through the opaque part
+        assert!(!scene.is_unoccluded(vec3(0.5, 0., 0.), vec3(0.5, 0., 2.), &mut rng));
+        // In front of the quad
+        assert!(scene.is_unoccluded(vec3(0.5, 0., 0.), vec3(0.5, 0., 0.5), &mut rng));
+    }
+
+    #[test]
+    fn test_alpha_primary_rays() {
+        let behind = scene_description::Quad::new(vec3(-2., -2., 3.), Vec3::X * 4., Vec3::Y * 4.);
+        let behind = ShapeWithParams::new(
+            scene_description::Shape::Quad(behind),
+            Material::new_empty(),
+            None,
+            Mat4::IDENTITY,
+            false,
+            None,
+        );
+
+        let scene = Scene::init(SceneDescription {
+            options: ScreenWideOptions::default(),
+            shapes: vec![cutout_quad(), behind],
+            infinite_light: None,
+            projection_lights: Vec::new(),
+        })
+        .unwrap();
+
+        // Through the hole to the quad behind
+        let hit = scene.trace_ray(&Ray::new(vec3(-0.5, 0., 0.), Vec3::Z));
+        assert_eq!(hit.unwrap().t, 3.);
+        // The opaque part is hit
+        let hit = scene.trace_ray(&Ray::new(vec3(0.5, 0., 0.), Vec3::Z));
+        assert_eq!(hit.unwrap().t, 1.);
+
+        // The cut-out quad alone isn't hit at all
+        let prim = scene
+            .primitives()
+            .iter()
+            .find(|prim| prim.aabb().max.z == 1.)
+            .unwrap();
+        assert!(prim
+            .intersect(&Ray::new(vec3(-0.5, 0., 0.), Vec3::Z))
+            .is_none());
+    }
+
+    #[test]
+    fn test_bump_map_shading() {
+        // Two periods of a sine along U
+        let bump_path = test_dir("bump").join("sine.png");
+        let image = image::GrayImage::from_fn(64, 1, |x, _| {
+            let height = 0.5 + 0.5 * (4. * PI * (x as f32 + 0.5) / 64.).sin();
+            image::Luma([(height * 255.).round() as u8])
+        });
+        image.save(&bump_path).unwrap();
+
+        let new_scene = |quad, bump_map: Option<std::path::PathBuf>| {
+            let shape = ShapeWithParams::new(
+                scene_description::Shape::Quad(quad),
+                Material::new_empty(),
+                None,
+                Mat4::IDENTITY,
+                false,
+                None,
+            )
+            .with_bump_map(bump_map);
+
+            Scene::init(SceneDescription {
+                options: ScreenWideOptions::default(),
+                shapes: vec![shape],
+                infinite_light: None,
+                projection_lights: Vec::new(),
+            })
+            .unwrap()
+        };
+
+        let light_dir = vec3(1., 0., 1.).normalize();
+        // Samples the unit square in the XY plane along the axis
+        let normals = |scene: &Scene, axis: Vec3| -> Vec<Vec3> {
+            (0..64)
+                .map(|i| {
+                    let x = (i as f32 + 0.5) / 64.;
+                    let orig = vec3(0.5, 0.5, -1.) + (x - 0.5) * axis;
+                    let mut hit = scene.trace_ray(&Ray::new(orig, Vec3::Z)).unwrap();
+                    hit.apply_bump_map();
+                    hit.normal
+                })
+                .collect()
+        };
+        let shading = |scene: &Scene| -> Vec<f32> {
+            normals(scene, Vec3::X)
+                .iter()
+                .map(|n| n.dot(light_dir))
+                .collect()
+        };
+
+        let quad = || scene_description::Quad::new(Vec3::ZERO, Vec3::X, Vec3::Y);
+
+        // The flat quad is shaded uniformly
+        let flat = shading(&new_scene(quad(), None));
+        assert!(flat.iter().all(|cos| (cos - flat[0]).abs() < 1e-5));
+
+        // U runs along X, the shading has to swing around the flat value twice
+        let bumped = shading(&new_scene(quad(), Some(bump_path.clone())));
+        let deviation: Vec<f32> = bumped.iter().map(|cos| cos - flat[0]).collect();
+        let sign_changes = deviation
+            .windows(2)
+            .filter(|w| w[0].signum() != w[1].signum())
+            .count();
+        assert!((3..=6).contains(&sign_changes), "{deviation:?}");
+        assert!(deviation.iter().any(|d| *d > 0.1) && deviation.iter().any(|d| *d < -0.1));
+
+        // The height is in world units, the same bumps stretched over a larger quad are flatter
+        let slope = |scale: f32| {
+            let quad = scene_description::Quad::new(Vec3::ZERO, scale * Vec3::X, scale * Vec3::Y);
+            let scene = new_scene(quad, Some(bump_path.clone()));
+            let orig = vec3(0.3, 0.5, -1.) * vec3(scale, scale, 1.);
+            let mut hit = scene.trace_ray(&Ray::new(orig, Vec3::Z)).unwrap();
+            hit.apply_bump_map();
+            hit.normal.x / hit.normal.z
+        };
+        let (small, large) = (slope(1.), slope(2.));
+        assert!(small.abs() > 0.1, "{small}");
+        assert!((large - small / 2.).abs() < 1e-4, "{small} {large}");
+
+        // U runs along Y, the normal has to tilt along Y only
+        let rotated = scene_description::Quad::new(Vec3::X, Vec3::Y, -Vec3::X);
+        let bumped = normals(&new_scene(rotated, Some(bump_path)), Vec3::Y);
+        assert!(bumped.iter().all(|n| n.x.abs() < 1e-5), "{bumped:?}");
+        assert!(bumped.iter().any(|n| n.y.abs() > 0.1), "{bumped:?}");
+    }
+
+    fn light_scene(quad: scene_description::Quad, light: AreaLightSource) -> Scene {
+        let shape = ShapeWithParams::new(
+            scene_description::Shape::Quad(quad),
+            Material::new_empty(),
+            Some(light),
+            Mat4::IDENTITY,
+            false,
+            None,
+        );
+
+        Scene::init(SceneDescription {
+            options: ScreenWideOptions::default(),
+            shapes: vec![shape],
+            infinite_light: None,
+            projection_lights: Vec::new(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_area_light_power() {
+        let rgbtospec = flat_rgbtospec();
+        let light = AreaLightSource {
+            power: Some(10.),
+            ..AreaLightSource::new_default(&rgbtospec, ColorSpace::Srgb)
+        };
+
+        let small = light_scene(
+            scene_description::Quad::new(Vec3::ZERO, Vec3::X, Vec3::Y),
+            light.clone(),
+        );
+        let large = light_scene(
+            scene_description::Quad::new(Vec3::ZERO, Vec3::X * 4., Vec3::Y * 2.),
+            light.clone(),
+        );
+
+        let mut rng = Sampler::seed_from_u64(0);
+        let lambdas: SampledWavelengths = SampledWavelengths::new_sample_uniform(&mut rng);
+        let power = |scene: &Scene| {
+            let light = &scene.lights[0];
+            light
+                .emission
+                .eval(&lambdas)
+                .vals
+                .map(|l| l * PI * scene.light_area(light))
+        };
+
+        let (power_small, power_large) = (power(&small), power(&large));
+        for (s, l) in power_small.iter().zip(power_large.iter()) {
+            assert!((s - l).abs() < 1e-4 * s, "{s} {l}");
+        }
+
+        // Scale is applied on top of the power normalization
+        let scaled = light_scene(
+            scene_description::Quad::new(Vec3::ZERO, Vec3::X, Vec3::Y),
+            AreaLightSource { scale: 2., ..light },
+        );
+        for (s, d) in power_small.iter().zip(power(&scaled).iter()) {
+            assert!((2. * s - d).abs() < 1e-4 * d);
+        }
+    }
+
+    #[test]
+    fn test_degenerate_emitter_triangles() {
+        let rgbtospec = flat_rgbtospec();
+        let load = |emitter_points: &str| {
+            let scene_desc = SceneLoader::new(
+                &format!(
+                    r#"
+                    Camera "perspective"
+                    Film "rgb"
+                    WorldBegin
+                    Shape "trianglemesh" "point3 P" [-5 -5 0  5 -5 0  0 5 0]
+                    AttributeBegin
+                    AreaLightSource "diffuse" "rgb L" [1 1 1] "float power" 10
+                    Shape "trianglemesh" "point3 P" [{emitter_points}]
+                    AttributeEnd
+                    "#
+                ),
+                std::path::PathBuf::new(),
+                &rgbtospec,
+            )
+            .load()
+            .unwrap();
+            Scene::init(scene_desc).unwrap()
+        };
+
+        let integrator = Integrator::new("simple-path").unwrap();
+        let scratch = RenderScratch::new(&rgbtospec);
+        let floor_radiance = |scene: &Scene| {
+            let mut rng = Sampler::seed_from_u64(0);
+            let ray = Ray::new(vec3(0., 0., 1.), -Vec3::Z);
+            (0..64)
+                .map(|_| {
+                    let mut lambdas = SampledWavelengths::new_sample_uniform(&mut rng);
+                    let l: SpectralQuantity =
+                        integrator.ray_l(&ray, &mut lambdas, scene, &scratch, &mut rng);
+                    assert!(l.vals.iter().all(|v| v.is_finite()), "{:?}", l.vals);
+                    l.average()
+                })
+                .sum::<f32>()
+        };
+
+        // The second triangle of the emitter has collinear points
+        let mixed = load("-1 -1 2  0 1 2  1 -1 2  0 0 2  1 0 2  2 0 2");
+        assert_eq!(mixed.lights.len(), 1);
+        assert_eq!(mixed.light_sampler.pmf(0), 1.);
+        assert!(floor_radiance(&mixed) > 0.);
+
+        // Nothing is left to sample
+        let degenerate = load("0 0 2  1 0 2  2 0 2");
+        assert!(degenerate.lights.is_empty());
+        let mut rng = Sampler::seed_from_u64(0);
+        assert!(degenerate.sample_light(Vec3::ZERO, &mut rng).is_none());
+        assert_eq!(floor_radiance(&degenerate), 0.);
+    }
+
+    #[test]
+    fn test_translated_mesh() {
+        let rgbtospec = flat_rgbtospec();
+        let scene_desc = SceneLoader::new(
+            r#"
+            Camera "perspective"
+            Film "rgb"
+            WorldBegin
+            Transform [2 0 0 0  0 2 0 0  0 0 2 0  0 0 5 1]
+            Shape "trianglemesh" "point3 P" [0 0 0 1 0 0 0 1 0] "integer indices" [0 1 2]
+            "#,
+            std::path::PathBuf::new(),
+            &rgbtospec,
+        )
+        .load()
+        .unwrap();
+        let scene = Scene::init(scene_desc).unwrap();
+
+        let hitinfo = scene
+            .trace_ray(&Ray::new(vec3(1.5, 0.2, 0.), Vec3::Z))
+            .unwrap();
+        assert_eq!(hitinfo.t, 5.);
+        assert_eq!(hitinfo.pos, vec3(1.5, 0.2, 5.));
+
+        // Nothing is left at the untransformed position
+        let ray = Ray::new(vec3(0.2, 0.2, -1.), Vec3::Z);
+        assert_eq!(scene.trace_ray(&ray).unwrap().t, 6.);
+        assert!(scene
+            .trace_ray(&Ray::new(vec3(0.2, 0.2, 0.5), Vec3::X))
+            .is_none());
+    }
+
+    #[test]
+    fn test_infinite_light_tint() {
+        let env_path = test_dir("tint").join("env.hdr");
+        write_test_hdr(&env_path, 1, &[Vec3::ONE]);
+
+        let plain = InfiniteLight::init(InfiniteLightSource::new(2., env_path.clone(), None));
+        let tinted = InfiniteLight::init(
+            InfiniteLightSource::new(2., env_path, None).with_tint(vec3(1., 0.25, 0.25)),
+        );
+        let (plain, tinted) = (plain.unwrap(), tinted.unwrap());
+
+        let rgbtospec = flat_rgbtospec();
+        for dir in [Vec3::X, -Vec3::Y, vec3(0.3, 0.4, -0.5).normalize()] {
+            let plain_rgb = plain.sample(dir, &rgbtospec).rgb();
+            let tinted_rgb = tinted.sample(dir, &rgbtospec).rgb();
+            assert!(plain_rgb.abs_diff_eq(Vec3::splat(2.), 1e-5), "{plain_rgb}");
+            assert!(
+                tinted_rgb.abs_diff_eq(vec3(2., 0.5, 0.5), 1e-5),
+                "{tinted_rgb}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_rebuild_bvh_lights() {
+        // Emitting spheres between plain triangles, light i is at center(i)
+        let center = |i: usize| vec3((i % 3) as f32, i as f32, (7 - i) as f32);
+        let mut txt = r#"Camera "perspective" Film "rgb" WorldBegin"#.to_string();
+        for i in 0..8 {
+            txt += &format!(
+                r#"
+                Shape "trianglemesh" "point3 P" [{x} 0 0  {x} 1 0  {x} 0 1]
+                AttributeBegin
+                AreaLightSource "diffuse" "rgb L" [1 1 1]
+                Translate {c_x} {c_y} {c_z}
+                Shape "sphere" "float radius" 0.25
+                AttributeEnd
+                "#,
+                x = 7 - i,
+                c_x = center(i).x,
+                c_y = center(i).y,
+                c_z = center(i).z,
+            );
+        }
+
+        let rgbtospec = flat_rgbtospec();
+        let scene_desc = SceneLoader::new(&txt, std::path::PathBuf::new(), &rgbtospec)
+            .load()
+            .unwrap();
+        let mut scene = Scene::init(scene_desc).unwrap();
+        assert_eq!(scene.lights.len(), 8);
+
+        for split_method in [
+            SplitMethod::Middle,
+            SplitMethod::EqualCounts,
+            SplitMethod::Sah,
+        ] {
+            scene.rebuild_bvh(BvhOptions {
+                split_method,
+                ..BvhOptions::default()
+            });
+
+            for (light_id, light) in scene.lights.iter().enumerate() {
+                let primitive = &scene.primitives()[light.primitive];
+                primitive.0.map_ref(|prim| match prim {
+                    Primitive::Light(light_prim) => assert_eq!(light_prim.light(), light_id),
+                    _ => panic!("light {light_id} doesn't point to its primitive"),
+                });
+
+                let aabb = primitive.aabb();
+                let primitive_center = (aabb.min + aabb.max) / 2.;
+                assert!(primitive_center.abs_diff_eq(center(light_id), 1e-4));
+            }
+        }
+    }
+
+    #[test]
+    fn test_soa_layout_equivalence() {
+        // A bumpy grid of 2 * 8^2 triangles, an emissive mesh and two spheres
+        const RES: usize = 8;
+        let mut points = String::new();
+        for i in 0..(RES + 1) * (RES + 1) {
+            let (x, z) = ((i % (RES + 1)) as f32, (i / (RES + 1)) as f32);
+            let y = 0.1 * (3. * x).sin() * (2. * z).cos();
+            points += &format!("{} {y} {} ", x / RES as f32, z / RES as f32);
+        }
+        let mut indices = String::new();
+        for z in 0..RES {
+            for x in 0..RES {
+                let (i, row) = (z * (RES + 1) + x, RES + 1);
+                indices += &format!(
+                    "{} {} {} {} {} {} ",
+                    i,
+                    i + 1,
+                    i + row,
+                    i + 1,
+                    i + row + 1,
+                    i + row
+                );
+            }
+        }
+
+        let load = |layout: &str| {
+            let txt = format!(
+                r#"
+                Camera "perspective"
+                Film "rgb"
+                Accelerator "bvh" "string primitivelayout" "{layout}"
+                WorldBegin
+                Shape "trianglemesh" "point3 P" [{points}] "integer indices" [{indices}]
+                AttributeBegin
+                AreaLightSource "diffuse" "rgb L" [4 4 4]
+                Shape "trianglemesh" "point3 P" [0 2 0 1 2 0 0 2 1 1 2 1] "integer indices" [0 1 2 2 1 3]
+                    "rgb Cs" [1 0 0 0 1 0 0 0 1 1 1 1]
+                AttributeEnd
+                AttributeBegin
+                Translate 0.7 0.5 0.3
+                Shape "sphere" "float radius" 0.1
+                AttributeEnd
+                Translate 0.3 0.4 0.6
+                Shape "sphere" "float radius" 0.2
+                "#
+            );
+            let rgbtospec = flat_rgbtospec();
+            let scene_desc = SceneLoader::new(&txt, std::path::PathBuf::new(), &rgbtospec)
+                .load()
+                .unwrap();
+            Scene::init(scene_desc).unwrap()
+        };
+
+        let boxed = load("boxed");
+        let soa = load("soa");
+        assert!(matches!(boxed.primitives, ScenePrimitives::Boxed(_)));
+        assert!(matches!(soa.primitives, ScenePrimitives::Soa(_)));
+        // Only the spheres and the emissive triangles are boxed
+        assert_eq!(boxed.primitives().len(), 2 * RES * RES + 4);
+        assert_eq!(soa.primitives().len(), 4);
+        assert_eq!(boxed.lights.len(), soa.lights.len());
+
+        let hit_key = |hit: Option<HitInfo>| {
+            hit.map(|hit| {
+                (
+                    hit.t,
+                    hit.pos,
+                    hit.normal,
+                    hit.tangent,
+                    hit.color,
+                    hit.light,
+                )
+            })
+        };
+
+        let mut rng = Sampler::seed_from_u64(0);
+        let mut hits = 0;
+        let mut mesh_tangents = 0;
+        let mut vertex_colors = 0;
+        for i in 0..2000 {
+            use rand::Rng;
+            // Mostly towards the grid, some towards the light above it
+            let orig = vec3(rng.gen(), 1., rng.gen());
+            let target_y = if i % 4 == 0 { 2. } else { 0. };
+            let target = vec3(
+                rng.gen::<f32>() * 1.2 - 0.1,
+                target_y,
+                rng.gen::<f32>() * 1.2 - 0.1,
+            );
+            let dir = target - orig;
+            let ray = Ray::new(orig, dir);
+
+            let hit = hit_key(boxed.trace_ray(&ray));
+            assert_eq!(hit, hit_key(soa.trace_ray(&ray)));
+            hits += hit.is_some() as usize;
+            // The tangent and the color of the closest mesh hit are computed after the traversal
+            mesh_tangents += hit.is_some_and(|(.., tangent, _, _)| tangent.is_some()) as usize;
+            vertex_colors += hit.is_some_and(|(.., color, _)| color.is_some()) as usize;
+
+            let rays = [0., 0.01, 0.02, 0.03].map(|offset| Ray::new(orig + offset * Vec3::X, dir));
+            let boxed_packet = boxed.trace_packet(&rays).map(hit_key);
+            assert_eq!(boxed_packet, soa.trace_packet(&rays).map(hit_key));
+        }
+        assert!(hits > 1000, "{hits}");
+        assert!(mesh_tangents > 1000, "{mesh_tangents}");
+        assert!(vertex_colors > 100, "{vertex_colors}");
+
+        // The lights refer to the same primitives
+        for (boxed_light, soa_light) in boxed.lights.iter().zip(soa.lights.iter()) {
+            assert_eq!(boxed.light_area(boxed_light), soa.light_area(soa_light));
+        }
+        let (mut rng_boxed, mut rng_soa) = (Sampler::seed_from_u64(1), Sampler::seed_from_u64(1));
+        for _ in 0..100 {
+            let p_ref = vec3(0.5, 0.2, 0.5);
+            let boxed_sample = boxed.sample_light(p_ref, &mut rng_boxed).unwrap();
+            let soa_sample = soa.sample_light(p_ref, &mut rng_soa).unwrap();
+            assert_eq!(boxed_sample.light_id, soa_sample.light_id);
+            assert_eq!(boxed_sample.shape_sample.pos, soa_sample.shape_sample.pos);
+            assert_eq!(boxed_sample.pdf, soa_sample.pdf);
+        }
     }
 }
@@ -1,8 +1,8 @@
-use rand::rngs::SmallRng;
+use glam::Vec3;
 
-use crate::{sampling::sample_discrete_cmf, util::TaggedPtr};
+use crate::{sampler::Sampler, sampling::sample_discrete_cmf, util::TaggedPtr};
 
-use super::{primitive::Primitive, Light, LightSample};
+use super::{primitive::Primitive, Light, LightId, LightSample};
 
 pub struct LightSampler {
     total_area: f32,
@@ -12,14 +12,26 @@ pub struct LightSampler {
 
 impl LightSampler {
     pub fn new(primitives: &[TaggedPtr<Primitive>], lights: &[Light]) -> Self {
-        let total_area = lights.iter().map(|l| primitives[l.primitive].area()).sum();
+        let total_area: f32 = lights.iter().map(|l| primitives[l.primitive].area()).sum();
+
+        // The ratios would be NaN, none of the lights can be sampled then
+        if total_area.is_nan() || total_area <= 0. {
+            return Self {
+                total_area: 0.,
+                lights_cmf: Vec::new(),
+                lights_pmf: vec![0.; lights.len()],
+            };
+        }
 
         let primitive_area_ratios: Vec<f32> = lights
             .iter()
             .map(|l| primitives[l.primitive].area() / total_area)
             .collect();
 
-        debug_assert_eq!(primitive_area_ratios.iter().sum::<f32>(), 1.);
+        // Scenes without area lights are valid, sample() doesn't return anything then
+        debug_assert!(
+            lights.is_empty() || (primitive_area_ratios.iter().sum::<f32>() - 1.).abs() < 1e-4
+        );
 
         let mut primitives_cmf = primitive_area_ratios.clone();
 
@@ -31,7 +43,11 @@ impl LightSampler {
             *p = *p + sum_before;
         }
 
-        debug_assert_eq!(primitives_cmf.last(), Some(&1.));
+        // The sum is rounded, but sample_discrete_cmf() needs the last value to be exactly 1
+        if let Some(last) = primitives_cmf.last_mut() {
+            debug_assert!((*last - 1.).abs() < 1e-4);
+            *last = 1.;
+        }
 
         Self {
             total_area,
@@ -44,7 +60,8 @@ impl LightSampler {
         &'s self,
         primitives: &[TaggedPtr<Primitive>],
         lights: &'s [Light],
-        rng: &mut SmallRng,
+        p_ref: Vec3,
+        rng: &mut Sampler,
     ) -> Option<LightSample> {
         if self.lights_cmf.len() > 0 {
             let sampled_light = sample_discrete_cmf(&self.lights_cmf, rng);
@@ -52,15 +69,22 @@ impl LightSampler {
             let light = &lights[sampled_light];
 
             let primitive = &primitives[light.primitive];
+            let (shape_sample, pdf) = primitive.sample_point_from(p_ref, rng);
 
             Some(LightSample::new(
-                primitive.sample_point(rng),
+                sampled_light,
+                shape_sample,
                 &light.emission,
-                primitive.area(),
+                pmf * pdf,
                 pmf,
             ))
         } else {
             None
         }
     }
+
+    /// Probability of choosing the light
+    pub fn pmf(&self, light_id: LightId) -> f32 {
+        self.lights_pmf[light_id]
+    }
 }
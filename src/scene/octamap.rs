@@ -1,23 +1,64 @@
-use std::path::Path;
+use std::{f32::consts::PI, fs::File, io::BufReader, path::Path};
 
 use eyre::Result;
 use glam::{vec2, vec3, Vec2, Vec3};
+use image::codecs::hdr::HdrDecoder;
 
 use crate::{
     color::color_space::ColorSpace,
     math::{safe_sqrt, sqr},
+    pbrt_loader::scene_description::EnvMapping,
 };
 
-/// Octahedral map texture
+/// Environment map texture, either octahedral or lat-long
 pub struct OctaMap {
     width: usize,
     height: usize,
     pixels: Vec<Vec3>,
     color_space: ColorSpace,
+    mapping: EnvMapping,
 }
 
 impl OctaMap {
-    pub fn load(path: &Path) -> Result<Self> {
+    /// Loads an EXR or a Radiance HDR (.hdr) image.
+    /// Without an explicit mapping, images with a 2:1 aspect ratio are treated as lat-long maps.
+    pub fn load(path: &Path, mapping: Option<EnvMapping>) -> Result<Self> {
+        let mut map = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("hdr") => Self::load_hdr(path)?,
+            _ => Self::load_exr(path)?,
+        };
+
+        map.mapping = mapping.unwrap_or(if map.width == 2 * map.height {
+            EnvMapping::Equirect
+        } else {
+            EnvMapping::Octahedral
+        });
+
+        Ok(map)
+    }
+
+    fn load_hdr(path: &Path) -> Result<Self> {
+        let decoder = HdrDecoder::new(BufReader::new(File::open(path)?))?;
+        let metadata = decoder.metadata();
+
+        // RGBE pixels are decoded to linear floats as color * 2^(exponent - 136)
+        let pixels = decoder
+            .read_image_hdr()?
+            .into_iter()
+            .map(|rgb| Vec3::from_array(rgb.0))
+            .collect();
+
+        Ok(Self {
+            width: metadata.width as usize,
+            height: metadata.height as usize,
+            pixels,
+            color_space: ColorSpace::Srgb,
+            mapping: EnvMapping::Octahedral,
+        })
+    }
+
+    /// Only the first layer with RGB(A) channels is read, other layers and channels are ignored
+    fn load_exr(path: &Path) -> Result<Self> {
         let image = exr::prelude::read_first_rgba_layer_from_file(
             path,
             |resolution, _| {
@@ -29,6 +70,7 @@ impl OctaMap {
                     height: resolution.height(),
                     pixels: vec![Vec3::ZERO; size],
                     color_space: ColorSpace::Srgb,
+                    mapping: EnvMapping::Octahedral,
                 }
             },
             |pixels, position, (r, g, b, _): (f32, f32, f32, f32)| {
@@ -53,12 +95,36 @@ impl OctaMap {
     }
 
     pub fn sample(&self, dir: Vec3) -> Vec3 {
-        let [x, y] = self.sphere_to_square(dir).to_array();
+        match self.mapping {
+            EnvMapping::Octahedral => {
+                let [x, y] = self.sphere_to_square(dir).to_array();
+
+                let x = (x * ((self.width - 1) as f32)) as usize;
+                let y = (y * ((self.height - 1) as f32)) as usize;
 
-        let x = (x * ((self.width - 1) as f32)) as usize;
-        let y = (y * ((self.height - 1) as f32)) as usize;
+                self.get(x, y)
+            }
+            EnvMapping::Equirect => {
+                let [u, v] = Self::sphere_to_latlong(dir).to_array();
 
-        self.get(x, y)
+                let x = ((u * self.width as f32) as usize).min(self.width - 1);
+                // V = 0 is at the top of the image, the rows are stored from the bottom
+                let y = ((v * self.height as f32) as usize).min(self.height - 1);
+
+                self.get(x, self.height - 1 - y)
+            }
+        }
+    }
+
+    /// Longitude is measured from +X towards +Z, latitude from +Y (the top of the image).
+    pub fn sphere_to_latlong(dir: Vec3) -> Vec2 {
+        debug_assert!(dir.is_normalized());
+
+        let phi = dir.z.atan2(dir.x);
+        let u = if phi < 0. { phi + 2. * PI } else { phi } / (2. * PI);
+        let v = dir.y.clamp(-1., 1.).acos() / PI;
+
+        vec2(u, v)
     }
 
     /// Code taken from PBRTv4.
@@ -127,15 +193,105 @@ impl OctaMap {
     }
 }
 
+/// Writes the pixels as a Radiance HDR image, the first row is at the top.
+/// Used by tests that need an environment map without the resources.
+#[cfg(test)]
+pub(crate) fn write_test_hdr(path: &Path, width: usize, pixels: &[Vec3]) {
+    let pixels: Vec<image::Rgb<f32>> = pixels.iter().map(|p| image::Rgb(p.to_array())).collect();
+    let file = std::io::BufWriter::new(File::create(path).unwrap());
+    image::codecs::hdr::HdrEncoder::new(file)
+        .encode(&pixels, width, pixels.len() / width)
+        .unwrap();
+}
+
 #[cfg(test)]
 mod test_super {
-    use crate::vecmath::{spherical_to_cartesian, vec3_cmp_assert};
+    use crate::{
+        util::test_dir,
+        vecmath::{spherical_to_cartesian, vec3_cmp_assert},
+    };
 
     use super::*;
 
+    #[test]
+    fn test_load_hdr() {
+        // 2x2 pixels, all of them can be represented exactly in RGBE
+        let path = test_dir("octamap").join("env.hdr");
+        write_test_hdr(
+            &path,
+            2,
+            &[
+                vec3(1., 0.5, 0.25),
+                vec3(256., 512., 1024.),
+                Vec3::ZERO,
+                vec3(255. / 256., 0., 0.),
+            ],
+        );
+
+        let octamap = OctaMap::load(&path, None).unwrap();
+        assert_eq!((octamap.width, octamap.height), (2, 2));
+        assert_eq!(octamap.mapping, EnvMapping::Octahedral);
+
+        assert_eq!(octamap.pixels[0], vec3(1., 0.5, 0.25));
+        assert_eq!(octamap.pixels[1], vec3(256., 512., 1024.));
+        assert_eq!(octamap.pixels[2], Vec3::ZERO);
+        assert_eq!(octamap.pixels[3], vec3(255. / 256., 0., 0.));
+
+        // Y = 0 is at the bottom, the first row in the file is at the top
+        assert_eq!(octamap.get(0, 1), vec3(1., 0.5, 0.25));
+    }
+
+    #[test]
+    fn test_latlong_lookup() {
+        // 4x2 image, every texel has a different value
+        let path = test_dir("latlong").join("env.hdr");
+        let pixels: Vec<Vec3> = (0..8).map(|i| vec3(1. + i as f32 / 16., 0., 0.)).collect();
+        write_test_hdr(&path, 4, &pixels);
+
+        let latlong = OctaMap::load(&path, None).unwrap();
+        assert_eq!(latlong.mapping, EnvMapping::Equirect);
+        let texel = |x: usize, y: usize| latlong.pixels[y * 4 + x];
+
+        let up = 30f32.to_radians();
+        let down = 150f32.to_radians();
+        assert_eq!(
+            latlong.sample(spherical_to_cartesian(up, 10f32.to_radians())),
+            texel(0, 0)
+        );
+        assert_eq!(
+            latlong.sample(spherical_to_cartesian(up, 100f32.to_radians())),
+            texel(1, 0)
+        );
+        assert_eq!(
+            latlong.sample(spherical_to_cartesian(up, 190f32.to_radians())),
+            texel(2, 0)
+        );
+        assert_eq!(
+            latlong.sample(spherical_to_cartesian(up, 280f32.to_radians())),
+            texel(3, 0)
+        );
+        assert_eq!(
+            latlong.sample(spherical_to_cartesian(down, 100f32.to_radians())),
+            texel(1, 1)
+        );
+        assert_eq!(
+            latlong.sample(spherical_to_cartesian(down, 280f32.to_radians())),
+            texel(3, 1)
+        );
+
+        // Poles and the seam stay inside of the image
+        assert_eq!(latlong.sample(Vec3::Y), texel(0, 0));
+        assert_eq!(latlong.sample(Vec3::NEG_Y), texel(0, 1));
+        assert_eq!(latlong.sample(vec3(1., 0., -1e-7).normalize()), texel(3, 1));
+
+        // The mapping can be forced regardless of the aspect ratio
+        let forced = OctaMap::load(&path, Some(EnvMapping::Octahedral)).unwrap();
+        assert_eq!(forced.mapping, EnvMapping::Octahedral);
+    }
+
     #[test]
     fn test_sphere_to_square() {
-        let octamap = OctaMap::load(&Path::new("resources/test/equalareatest.exr")).unwrap();
+        let octamap = OctaMap::load(&Path::new("resources/test/equalareatest.exr"), None).unwrap();
 
         const DARKER_BLUE: Vec3 = vec3(0.01227, 0.462086, 0.665376);
         const LIGHT_BLUE: Vec3 = vec3(0.309455, 0.603845, 0.846861);
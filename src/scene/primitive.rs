@@ -1,15 +1,199 @@
-use std::sync::Arc;
+use std::{ops::Deref, sync::Arc};
 
 use enum_ptr::EnumPtr;
-use rand::rngs::SmallRng;
+use eyre::{eyre, Result};
+use glam::Vec3;
 
 use crate::{
-    geometry::{trianglemesh::Triangle, Ray, Shape, AABB},
+    bvh::{Bvh, BvhOptions, PrimitiveStorage, PACKET_SIZE},
+    geometry::{
+        trianglemesh::{Triangle, TriangleMesh},
+        Ray, Shape, ShapeHitInfo, AABB,
+    },
     pbrt_loader::scene_description::Material,
+    sampler::Sampler,
+    texture::{AlphaMask, BumpMap},
     util::TaggedPtr,
 };
 
-use super::{HitInfo, LightId, ShapeSample};
+use super::{HitInfo, LightId, PrimitiveId, SceneAlloc, ShapeSample, SCENE_ALLOC};
+
+/// How the primitives are stored for the BVH
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PrimitiveLayout {
+    /// Every primitive is a tagged pointer to a boxed enum
+    #[default]
+    Boxed,
+    /// Mesh triangles are flat arrays of mesh and triangle indices, which saves the allocation
+    /// and a pointer indirection per triangle. Lights and the other shapes stay boxed.
+    Soa,
+}
+
+impl PrimitiveLayout {
+    pub fn new(layout: &str) -> Result<Self> {
+        Ok(match layout {
+            "boxed" => Self::Boxed,
+            "soa" => Self::Soa,
+            _ => return Err(eyre!("Unknown primitive layout: '{}'", layout)),
+        })
+    }
+}
+
+/// The primitives of the scene in one of the layouts
+pub enum ScenePrimitives {
+    Boxed(Vec<TaggedPtr<Primitive>, SceneAlloc>),
+    Soa(SoaPrimitives),
+}
+
+impl ScenePrimitives {
+    pub fn new(layout: PrimitiveLayout) -> Self {
+        match layout {
+            PrimitiveLayout::Boxed => Self::Boxed(Vec::new_in(SCENE_ALLOC)),
+            PrimitiveLayout::Soa => Self::Soa(SoaPrimitives::new()),
+        }
+    }
+
+    /// Returns the id of the primitive, which lights refer to
+    pub fn push_boxed(&mut self, primitive: TaggedPtr<Primitive>) -> PrimitiveId {
+        match self {
+            Self::Boxed(primitives) => {
+                primitives.push(primitive);
+                primitives.len() - 1
+            }
+            Self::Soa(primitives) => primitives.push_boxed(primitive),
+        }
+    }
+
+    pub fn push_mesh_triangle(&mut self, mesh: &Arc<TriangleMesh>, triangle_id: usize) {
+        match self {
+            Self::Boxed(primitives) => {
+                let triangle = Triangle::new(Arc::clone(mesh), triangle_id as u64);
+                let primitive = MeshTrianglePrimitive::new(triangle);
+                primitives.push(TaggedPtr::new(Primitive::MeshTriangle(Box::new(primitive))));
+            }
+            Self::Soa(primitives) => primitives.push_mesh_triangle(mesh, triangle_id),
+        }
+    }
+
+    /// The boxed primitives, indexed by the primitive ids of the lights.
+    /// In the SoA layout these don't include the mesh triangles.
+    pub fn boxed(&self) -> &[TaggedPtr<Primitive>] {
+        match self {
+            Self::Boxed(primitives) => primitives,
+            Self::Soa(primitives) => &primitives.boxed,
+        }
+    }
+
+    pub fn build_bvh(&mut self, options: BvhOptions) -> Bvh {
+        match self {
+            Self::Boxed(primitives) => Bvh::build(primitives.as_mut_slice(), options),
+            Self::Soa(primitives) => Bvh::build(primitives, options),
+        }
+    }
+
+    pub fn intersect(&self, bvh: &Bvh, ray: &Ray, tmax: f32) -> Option<HitInfo> {
+        match self {
+            Self::Boxed(primitives) => bvh.intersect(ray, tmax, primitives.as_slice()),
+            Self::Soa(primitives) => bvh.intersect(ray, tmax, primitives),
+        }
+    }
+
+    pub fn intersect_packet(
+        &self,
+        bvh: &Bvh,
+        rays: &[Ray; PACKET_SIZE],
+    ) -> [Option<HitInfo>; PACKET_SIZE] {
+        match self {
+            Self::Boxed(primitives) => bvh.intersect_packet(rays, primitives.as_slice()),
+            Self::Soa(primitives) => bvh.intersect_packet(rays, primitives),
+        }
+    }
+}
+
+/// Primitives of the SoA layout. The BVH only reorders the index arrays, so the boxed primitives
+/// keep their place and the lights don't have to be fixed up after building it.
+pub struct SoaPrimitives {
+    meshes: Vec<Arc<TriangleMesh>>,
+    /// The mesh of every primitive, BOXED_PRIMITIVE for the boxed ones
+    mesh_ids: Vec<u32>,
+    /// The triangle index in the mesh, or the index into `boxed`
+    ids: Vec<u32>,
+    boxed: Vec<TaggedPtr<Primitive>>,
+}
+
+const BOXED_PRIMITIVE: u32 = u32::MAX;
+
+impl SoaPrimitives {
+    fn new() -> Self {
+        Self {
+            meshes: Vec::new(),
+            mesh_ids: Vec::new(),
+            ids: Vec::new(),
+            boxed: Vec::new(),
+        }
+    }
+
+    fn push_boxed(&mut self, primitive: TaggedPtr<Primitive>) -> PrimitiveId {
+        let id = self.boxed.len();
+        self.boxed.push(primitive);
+        self.mesh_ids.push(BOXED_PRIMITIVE);
+        self.ids.push(id as u32);
+        id
+    }
+
+    /// The triangles of a mesh are pushed one after another, the mesh is only stored once
+    fn push_mesh_triangle(&mut self, mesh: &Arc<TriangleMesh>, triangle_id: usize) {
+        if !self
+            .meshes
+            .last()
+            .is_some_and(|last| Arc::ptr_eq(last, mesh))
+        {
+            self.meshes.push(Arc::clone(mesh));
+        }
+
+        debug_assert!(self.meshes.len() <= BOXED_PRIMITIVE as usize);
+        debug_assert!(triangle_id <= u32::MAX as usize);
+        self.mesh_ids.push(self.meshes.len() as u32 - 1);
+        self.ids.push(triangle_id as u32);
+    }
+
+    fn triangle(&self, mesh_id: u32, index: usize) -> Triangle<&TriangleMesh> {
+        let mesh = self.meshes[mesh_id as usize].as_ref();
+        Triangle::new(mesh, self.ids[index] as u64)
+    }
+}
+
+impl PrimitiveStorage for SoaPrimitives {
+    fn primitive_count(&self) -> usize {
+        self.ids.len()
+    }
+
+    fn primitive_aabb(&self, index: usize) -> AABB {
+        match self.mesh_ids[index] {
+            BOXED_PRIMITIVE => self.boxed[self.ids[index] as usize].aabb(),
+            mesh_id => self.triangle(mesh_id, index).aabb(),
+        }
+    }
+
+    fn intersect_primitive(&self, index: usize, ray: &Ray) -> Option<HitInfo> {
+        match self.mesh_ids[index] {
+            BOXED_PRIMITIVE => self.boxed[self.ids[index] as usize].intersect(ray),
+            mesh_id => intersect_mesh_triangle(&self.triangle(mesh_id, index), ray, None),
+        }
+    }
+
+    fn complete_hit(&self, index: usize, hitinfo: &mut HitInfo) {
+        match self.mesh_ids[index] {
+            BOXED_PRIMITIVE => self.boxed[self.ids[index] as usize].complete_hit(hitinfo),
+            mesh_id => complete_mesh_triangle_hit(&self.triangle(mesh_id, index), hitinfo),
+        }
+    }
+
+    fn swap_primitives(&mut self, a: usize, b: usize) {
+        self.mesh_ids.swap(a, b);
+        self.ids.swap(a, b);
+    }
+}
 
 pub struct MeshTrianglePrimitive {
     triangle: Triangle,
@@ -39,11 +223,23 @@ impl MeshTriangleLightPrimitive {
 pub struct SimplePrimtive {
     shape: TaggedPtr<Shape>,
     material: Arc<Material>,
+    alpha: Option<Arc<AlphaMask>>,
+    bump_map: Option<Arc<BumpMap>>,
 }
 
 impl SimplePrimtive {
-    pub fn new(shape: TaggedPtr<Shape>, material: Arc<Material>) -> Self {
-        Self { shape, material }
+    pub fn new(
+        shape: TaggedPtr<Shape>,
+        material: Arc<Material>,
+        alpha: Option<Arc<AlphaMask>>,
+        bump_map: Option<Arc<BumpMap>>,
+    ) -> Self {
+        Self {
+            shape,
+            material,
+            alpha,
+            bump_map,
+        }
     }
 }
 
@@ -51,14 +247,24 @@ pub struct LightPrimitive {
     shape: TaggedPtr<Shape>,
     material: Arc<Material>,
     light: LightId,
+    alpha: Option<Arc<AlphaMask>>,
+    bump_map: Option<Arc<BumpMap>>,
 }
 
 impl LightPrimitive {
-    pub fn new(shape: TaggedPtr<Shape>, material: Arc<Material>, light: LightId) -> Self {
+    pub fn new(
+        shape: TaggedPtr<Shape>,
+        material: Arc<Material>,
+        light: LightId,
+        alpha: Option<Arc<AlphaMask>>,
+        bump_map: Option<Arc<BumpMap>>,
+    ) -> Self {
         Self {
             shape,
             material,
             light,
+            alpha,
+            bump_map,
         }
     }
 
@@ -78,45 +284,51 @@ pub enum Primitive {
 }
 
 impl TaggedPtr<Primitive> {
+    /// Hits in the cut-out parts of alpha-masked surfaces are rejected, so that the BVH traversal
+    /// continues to the geometry behind them
     pub fn intersect(&self, ray: &Ray) -> Option<HitInfo> {
         self.0.map_ref(|p| match p {
             Primitive::MeshTriangle(triangle) => {
-                let shape_hitinfo = triangle.triangle.intersect(ray);
-                shape_hitinfo.map(|sh| {
-                    HitInfo::from_shape_hitinfo(sh, triangle.triangle.mesh().material(), None)
-                })
+                intersect_mesh_triangle(&triangle.triangle, ray, None)
             }
             Primitive::MeshTriangleLight(light_triangle) => {
-                let shape_hitinfo = light_triangle.triangle.intersect(ray);
-                shape_hitinfo.map(|sh| {
-                    HitInfo::from_shape_hitinfo(
-                        sh,
-                        light_triangle.triangle.mesh().material(),
-                        Some(light_triangle.light),
-                    )
-                })
+                let light = Some(light_triangle.light);
+                intersect_mesh_triangle(&light_triangle.triangle, ray, light)
             }
             Primitive::Simple(primitive) => {
-                let shape_hitinfo = primitive.shape.intersect(ray);
-                shape_hitinfo.map(|sh| {
-                    HitInfo::from_shape_hitinfo(sh, Arc::clone(&primitive.material), None)
-                })
+                let sh = primitive.shape.intersect(ray)?;
+                let alpha = accept_alpha(primitive.alpha.clone(), &sh)?;
+                let material = Arc::clone(&primitive.material);
+                let hitinfo = HitInfo::from_shape_hitinfo(sh, material, None, alpha);
+                Some(hitinfo.with_bump_map(primitive.bump_map.clone()))
             }
             Primitive::Light(light_primitive) => {
-                let shape_hitinfo = light_primitive.shape.intersect(ray);
-                shape_hitinfo.map(|sh| {
-                    HitInfo::from_shape_hitinfo(
-                        sh,
-                        Arc::clone(&light_primitive.material),
-                        Some(light_primitive.light),
-                    )
-                })
+                let sh = light_primitive.shape.intersect(ray)?;
+                let alpha = accept_alpha(light_primitive.alpha.clone(), &sh)?;
+                let material = Arc::clone(&light_primitive.material);
+                let light = Some(light_primitive.light);
+                let hitinfo = HitInfo::from_shape_hitinfo(sh, material, light, alpha);
+                Some(hitinfo.with_bump_map(light_primitive.bump_map.clone()))
+            }
+        })
+    }
+
+    /// Computes the tangent and the vertex color of a mesh triangle's closest hit, the other
+    /// shapes provide their tangent in intersect()
+    pub fn complete_hit(&self, hitinfo: &mut HitInfo) {
+        self.0.map_ref(|p| match p {
+            Primitive::MeshTriangle(triangle) => {
+                complete_mesh_triangle_hit(&triangle.triangle, hitinfo)
             }
+            Primitive::MeshTriangleLight(light_triangle) => {
+                complete_mesh_triangle_hit(&light_triangle.triangle, hitinfo)
+            }
+            Primitive::Simple(_) | Primitive::Light(_) => (),
         })
     }
 
     /// Should not need to be called on non-light Hittables
-    pub fn sample_point(&self, rng: &mut SmallRng) -> ShapeSample {
+    pub fn sample_point(&self, rng: &mut Sampler) -> ShapeSample {
         self.0.map_ref(|p| match p {
             Primitive::MeshTriangle(_) => unreachable!(),
             Primitive::MeshTriangleLight(light_triangle) => {
@@ -127,6 +339,36 @@ impl TaggedPtr<Primitive> {
         })
     }
 
+    /// Samples a point as seen from p_ref, returns the sample and its solid-angle PDF.
+    /// Should not be called on non-light Hittables
+    pub fn sample_point_from(&self, p_ref: Vec3, rng: &mut Sampler) -> (ShapeSample, f32) {
+        self.0.map_ref(|p| match p {
+            Primitive::MeshTriangle(_) => unreachable!(),
+            Primitive::MeshTriangleLight(light_triangle) => {
+                let sample = light_triangle.triangle.sample_point(rng);
+                let pdf_area = 1. / light_triangle.triangle.area();
+                let pdf = sample.area_to_solid_angle_pdf(p_ref, pdf_area);
+                (sample, pdf)
+            }
+            Primitive::Simple(_) => unreachable!(),
+            Primitive::Light(light_primitive) => {
+                light_primitive.shape.sample_point_from(p_ref, rng)
+            }
+        })
+    }
+
+    /// Solid-angle PDF of sample_point_from() returning the sample
+    pub fn pdf_from(&self, p_ref: Vec3, sample: &ShapeSample) -> f32 {
+        self.0.map_ref(|p| match p {
+            Primitive::MeshTriangle(_) => unreachable!(),
+            Primitive::MeshTriangleLight(light_triangle) => {
+                sample.area_to_solid_angle_pdf(p_ref, 1. / light_triangle.triangle.area())
+            }
+            Primitive::Simple(_) => unreachable!(),
+            Primitive::Light(light_primitive) => light_primitive.shape.pdf_from(p_ref, sample),
+        })
+    }
+
     pub fn area(&self) -> f32 {
         self.0.map_ref(|p| match p {
             Primitive::MeshTriangle(triangle) => triangle.triangle.area(),
@@ -145,3 +387,38 @@ impl TaggedPtr<Primitive> {
         })
     }
 }
+
+/// Mesh triangles take the material, alpha and bump map from their mesh
+fn intersect_mesh_triangle<M: Deref<Target = TriangleMesh>>(
+    triangle: &Triangle<M>,
+    ray: &Ray,
+    light: Option<LightId>,
+) -> Option<HitInfo> {
+    let sh = triangle.intersect(ray)?;
+    let mesh = triangle.mesh();
+    let alpha = accept_alpha(mesh.alpha(), &sh)?;
+    let hitinfo = HitInfo::from_shape_hitinfo(sh, mesh.material(), light, alpha);
+    Some(hitinfo.with_bump_map(mesh.bump_map()))
+}
+
+fn complete_mesh_triangle_hit<M: Deref<Target = TriangleMesh>>(
+    triangle: &Triangle<M>,
+    hitinfo: &mut HitInfo,
+) {
+    if let Some(bar) = hitinfo.barycentrics {
+        hitinfo.tangent = Some(triangle.tangent_at(bar, hitinfo.normal));
+        hitinfo.uv_derivatives = triangle.uv_derivatives();
+        hitinfo.color = triangle.color_at(bar);
+    }
+}
+
+/// Returns None if the hit is in a cut-out part, otherwise passes the alpha through
+fn accept_alpha(
+    alpha: Option<Arc<AlphaMask>>,
+    shape_hitinfo: &ShapeHitInfo,
+) -> Option<Option<Arc<AlphaMask>>> {
+    match alpha {
+        Some(alpha) if alpha.is_cut_out(shape_hitinfo.uv) => None,
+        alpha => Some(alpha),
+    }
+}
@@ -0,0 +1,131 @@
+use eyre::Result;
+use glam::{vec2, Mat4, Vec2, Vec3, Vec3Swizzles};
+use rgb2spec::RGB2Spec;
+
+use crate::{
+    color::{
+        color_space::ColorSpace,
+        spectrum::rgb_spectrum::{RgbSpectrum, RgbSpectrumKind},
+    },
+    pbrt_loader::scene_description::ProjectionLightSource,
+    texture::{Texture, WrapMode},
+    vecmath,
+};
+
+/// Projects an image into the scene like a slide projector.
+/// This is a delta light, so it can only be reached by next event estimation.
+pub struct ProjectionLight {
+    image: Texture,
+    scale: f32,
+    pos: Vec3,
+    light_from_world: Mat4,
+    /// Half-extents of the image plane at unit distance from the light
+    screen_extent: Vec2,
+    color_space: ColorSpace,
+}
+
+impl ProjectionLight {
+    pub fn init(pls: ProjectionLightSource) -> Result<Self> {
+        let image = Texture::load_linear_rgb(&pls.filepath, WrapMode::Clamp, WrapMode::Clamp)?;
+        Ok(Self::new(image, pls.scale, pls.fov, pls.from, pls.to))
+    }
+
+    pub fn new(image: Texture, scale: f32, fov: f32, from: Vec3, to: Vec3) -> Self {
+        let dir = (to - from).normalize();
+        let up = if dir.y.abs() < 0.999 {
+            Vec3::Y
+        } else {
+            Vec3::Z
+        };
+        let light_from_world = vecmath::look_at(from, to, up);
+
+        // Same as for the camera, the FOV spans the narrower of the image's width and height
+        let aspect = image.width() as f32 / image.height() as f32;
+        let tan_half_fov = f32::tan(fov.to_radians() / 2.);
+        let screen_extent = if aspect > 1. {
+            vec2(aspect * tan_half_fov, tan_half_fov)
+        } else {
+            vec2(tan_half_fov, tan_half_fov / aspect)
+        };
+
+        Self {
+            image,
+            scale,
+            pos: from,
+            light_from_world,
+            screen_extent,
+            color_space: ColorSpace::Srgb,
+        }
+    }
+
+    pub fn pos(&self) -> Vec3 {
+        self.pos
+    }
+
+    /// Returns the RGB intensity emitted towards the point, zero outside of the frustum.
+    pub fn projected_rgb(&self, point: Vec3) -> Vec3 {
+        let p = self.light_from_world.transform_point3(point);
+        if p.z <= 0. {
+            return Vec3::ZERO;
+        }
+
+        let screen = p.xy() / (p.z * self.screen_extent);
+        if screen.abs().max_element() > 1. {
+            return Vec3::ZERO;
+        }
+
+        // The first row of the image is at the top of the frustum
+        let uv = vec2(0.5 * (screen.x + 1.), 0.5 * (1. - screen.y));
+        self.image.fetch_nearest(uv) * self.scale
+    }
+
+    /// Returns None if the point is outside of the frustum.
+    pub fn emission_towards(&self, point: Vec3, rgbtospec: &RGB2Spec) -> Option<RgbSpectrum> {
+        let rgb = self.projected_rgb(point);
+        if rgb == Vec3::ZERO {
+            return None;
+        }
+
+        let spectrum_kind = RgbSpectrumKind::new_illuminant(self.color_space);
+        Some(RgbSpectrum::new(rgbtospec, rgb, spectrum_kind))
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use glam::vec3;
+
+    use crate::{texture::Format, vecmath::vec3_cmp_assert};
+
+    use super::*;
+
+    #[test]
+    fn test_projection_frustum() {
+        #[rustfmt::skip]
+        let pixels = [
+            255, 0, 0,    0, 255, 0,
+            0, 0, 255,    255, 255, 255,
+        ];
+        let image = Texture::new(
+            2,
+            2,
+            Format::R8G8B8,
+            &pixels,
+            WrapMode::Clamp,
+            WrapMode::Clamp,
+        );
+
+        let light = ProjectionLight::new(image, 1., 90., Vec3::ZERO, vec3(0., 0., 1.));
+
+        // Inside of the frustum
+        vec3_cmp_assert(light.projected_rgb(vec3(-0.5, 0.5, 1.)), vec3(1., 0., 0.));
+        vec3_cmp_assert(light.projected_rgb(vec3(0.5, 0.5, 1.)), vec3(0., 1., 0.));
+        vec3_cmp_assert(light.projected_rgb(vec3(-1., -1., 2.)), vec3(0., 0., 1.));
+        vec3_cmp_assert(light.projected_rgb(vec3(1., -1., 2.)), vec3(1., 1., 1.));
+
+        // Outside of the frustum
+        assert_eq!(light.projected_rgb(vec3(3., 0., 1.)), Vec3::ZERO);
+        assert_eq!(light.projected_rgb(vec3(0., -1.5, 1.)), Vec3::ZERO);
+        assert_eq!(light.projected_rgb(vec3(0., 0., -1.)), Vec3::ZERO);
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    cell::Cell,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Global render statistics, collection has to be enabled with `STATS.enable()`
+pub static STATS: Stats = Stats::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    SceneLoad,
+    BvhBuild,
+    Render,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RayKind {
+    /// Camera rays
+    Primary,
+    /// Visibility tests between two points
+    Shadow,
+    /// Every traversal of the scene, including the primary and shadow rays
+    Intersection,
+}
+
+/// Individual intersection tests, only counted with the `intersection-stats` feature
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestKind {
+    /// Ray-box tests of the BVH nodes
+    Aabb,
+    Triangle,
+    Sphere,
+}
+
+impl TestKind {
+    const COUNT: usize = 3;
+}
+
+thread_local! {
+    /// Intersection tests are far too frequent for shared atomics, so every thread counts them
+    /// separately and adds them to the global stats with `Stats::flush_thread_tests()`
+    static THREAD_TESTS: [Cell<u64>; TestKind::COUNT] = const { [Cell::new(0), Cell::new(0), Cell::new(0)] };
+}
+
+/// Counts an intersection test of the current thread, a no-op without `intersection-stats`
+#[inline]
+pub fn count_tests(kind: TestKind, count: u64) {
+    if cfg!(feature = "intersection-stats") {
+        THREAD_TESTS.with(|tests| {
+            let counter = &tests[kind as usize];
+            counter.set(counter.get() + count);
+        });
+    }
+}
+
+/// Cumulative stage timings and ray counts.
+/// Relaxed atomics are enough, the values are only read after the render threads are joined.
+/// When disabled, recording is a single relaxed load.
+pub struct Stats {
+    enabled: AtomicBool,
+    scene_load_nanos: AtomicU64,
+    bvh_build_nanos: AtomicU64,
+    render_nanos: AtomicU64,
+    primary_rays: AtomicU64,
+    shadow_rays: AtomicU64,
+    intersections: AtomicU64,
+    tests: [AtomicU64; TestKind::COUNT],
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            scene_load_nanos: AtomicU64::new(0),
+            bvh_build_nanos: AtomicU64::new(0),
+            render_nanos: AtomicU64::new(0),
+            primary_rays: AtomicU64::new(0),
+            shadow_rays: AtomicU64::new(0),
+            intersections: AtomicU64::new(0),
+            tests: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+        }
+    }
+
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn stage_nanos(&self, stage: Stage) -> &AtomicU64 {
+        match stage {
+            Stage::SceneLoad => &self.scene_load_nanos,
+            Stage::BvhBuild => &self.bvh_build_nanos,
+            Stage::Render => &self.render_nanos,
+        }
+    }
+
+    fn ray_counter(&self, kind: RayKind) -> &AtomicU64 {
+        match kind {
+            RayKind::Primary => &self.primary_rays,
+            RayKind::Shadow => &self.shadow_rays,
+            RayKind::Intersection => &self.intersections,
+        }
+    }
+
+    pub fn add_time(&self, stage: Stage, time: Duration) {
+        if self.is_enabled() {
+            self.stage_nanos(stage)
+                .fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Runs the closure and adds its duration to the stage
+    pub fn timed<R, F: FnOnce() -> R>(&self, stage: Stage, fun: F) -> R {
+        if !self.is_enabled() {
+            return fun();
+        }
+
+        let start = Instant::now();
+        let res = fun();
+        self.add_time(stage, start.elapsed());
+        res
+    }
+
+    #[inline]
+    pub fn count_ray(&self, kind: RayKind) {
+        if self.is_enabled() {
+            self.ray_counter(kind).fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Adds the intersection tests counted by the current thread and resets its counters
+    pub fn flush_thread_tests(&self) {
+        THREAD_TESTS.with(|tests| {
+            for (total, counter) in self.tests.iter().zip(tests) {
+                let count = counter.replace(0);
+                if self.is_enabled() {
+                    total.fetch_add(count, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+
+    pub fn time(&self, stage: Stage) -> Duration {
+        Duration::from_nanos(self.stage_nanos(stage).load(Ordering::Relaxed))
+    }
+
+    pub fn rays(&self, kind: RayKind) -> u64 {
+        self.ray_counter(kind).load(Ordering::Relaxed)
+    }
+
+    pub fn tests(&self, kind: TestKind) -> u64 {
+        self.tests[kind as usize].load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let render_time = self.time(Stage::Render);
+        let intersections = self.rays(RayKind::Intersection);
+
+        writeln!(f, "Statistics:")?;
+        writeln!(f, "  Scene load:    {:?}", self.time(Stage::SceneLoad))?;
+        writeln!(f, "  BVH build:     {:?}", self.time(Stage::BvhBuild))?;
+        writeln!(f, "  Render:        {render_time:?}")?;
+        writeln!(f, "  Primary rays:  {}", self.rays(RayKind::Primary))?;
+        writeln!(f, "  Shadow rays:   {}", self.rays(RayKind::Shadow))?;
+        write!(f, "  Intersections: {intersections}")?;
+
+        let seconds = render_time.as_secs_f64();
+        if seconds > 0. {
+            write!(f, " ({:.2} M/s)", intersections as f64 / seconds / 1e6)?;
+        }
+
+        if cfg!(feature = "intersection-stats") {
+            writeln!(f)?;
+            writeln!(f, "  AABB tests:     {}", self.tests(TestKind::Aabb))?;
+            writeln!(f, "  Triangle tests: {}", self.tests(TestKind::Triangle))?;
+            write!(f, "  Sphere tests:   {}", self.tests(TestKind::Sphere))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_stats_disabled() {
+        let stats = Stats::new();
+        stats.count_ray(RayKind::Primary);
+        stats.add_time(Stage::Render, Duration::from_secs(1));
+        assert_eq!(stats.rays(RayKind::Primary), 0);
+        assert_eq!(stats.time(Stage::Render), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_stats_enabled() {
+        let stats = Stats::new();
+        stats.enable();
+
+        stats.count_ray(RayKind::Shadow);
+        stats.count_ray(RayKind::Shadow);
+        stats.count_ray(RayKind::Intersection);
+        stats.add_time(Stage::BvhBuild, Duration::from_millis(3));
+        stats.add_time(Stage::BvhBuild, Duration::from_millis(4));
+
+        assert_eq!(stats.rays(RayKind::Shadow), 2);
+        assert_eq!(stats.rays(RayKind::Intersection), 1);
+        assert_eq!(stats.rays(RayKind::Primary), 0);
+        assert_eq!(stats.time(Stage::BvhBuild), Duration::from_millis(7));
+
+        let value = stats.timed(Stage::SceneLoad, || 42);
+        assert_eq!(value, 42);
+    }
+
+    #[test]
+    fn test_thread_tests_flush() {
+        let stats = Stats::new();
+        stats.enable();
+
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    count_tests(TestKind::Aabb, 3);
+                    count_tests(TestKind::Sphere, 1);
+                    stats.flush_thread_tests();
+                    // The thread's counters were reset
+                    stats.flush_thread_tests();
+                });
+            }
+        });
+
+        let expected = |count| {
+            if cfg!(feature = "intersection-stats") {
+                count
+            } else {
+                0
+            }
+        };
+        assert_eq!(stats.tests(TestKind::Aabb), expected(6));
+        assert_eq!(stats.tests(TestKind::Sphere), expected(2));
+        assert_eq!(stats.tests(TestKind::Triangle), 0);
+    }
+}
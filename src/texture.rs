@@ -1,4 +1,11 @@
-use glam::{vec3, Vec2, Vec3};
+use std::{path::Path, sync::OnceLock};
+
+use eyre::Result;
+use image::DynamicImage;
+use rand::{distributions::Uniform, prelude::Distribution};
+
+use crate::{pbrt_loader::scene_description::Alpha, sampler::Sampler};
+use glam::{vec2, vec3, Vec2, Vec3};
 
 pub struct Texture {
     bytes: Vec<u8>,
@@ -32,6 +39,76 @@ impl Texture {
         }
     }
 
+    pub fn load(path: &Path, wrap_u: WrapMode, wrap_v: WrapMode) -> Result<Self> {
+        let image = image::open(path)?.into_rgb8();
+        let (width, height) = image.dimensions();
+
+        Ok(Self::new(
+            width,
+            height,
+            Format::R8G8B8,
+            image.as_raw(),
+            wrap_u,
+            wrap_v,
+        ))
+    }
+
+    /// Loads a color image as linear RGB floats. 8 and 16-bit images are sRGB-encoded and are
+    /// decoded, EXR and Radiance HDR images are already linear and aren't clamped.
+    /// Only the first RGB(A) layer of an EXR image is read.
+    pub fn load_linear_rgb(path: &Path, wrap_u: WrapMode, wrap_v: WrapMode) -> Result<Self> {
+        let (width, height, rgb) = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("exr") => {
+                let image = exr::prelude::read_first_rgba_layer_from_file(
+                    path,
+                    |resolution, _| {
+                        let (width, height) = (resolution.width(), resolution.height());
+                        (width, vec![0f32; 3 * width * height])
+                    },
+                    |(width, rgb), position, (r, g, b, _): (f32, f32, f32, f32)| {
+                        let i = 3 * (position.y() * *width + position.x());
+                        rgb[i..i + 3].copy_from_slice(&[r, g, b]);
+                    },
+                )?;
+                let size = image.layer_data.size;
+                let (_, rgb) = image.layer_data.channel_data.pixels;
+                (size.width() as u32, size.height() as u32, rgb)
+            }
+            _ => {
+                let image = image::open(path)?;
+                let linear = matches!(
+                    image,
+                    DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+                );
+                let image = image.into_rgb32f();
+                let (width, height) = image.dimensions();
+                let mut rgb = image.into_raw();
+                if !linear {
+                    rgb.iter_mut().for_each(|c| *c = srgb_to_linear(*c));
+                }
+                (width, height, rgb)
+            }
+        };
+
+        let bytes: Vec<u8> = rgb.iter().flat_map(|c| c.to_ne_bytes()).collect();
+        Ok(Self::new(
+            width,
+            height,
+            Format::R32G32B32F,
+            &bytes,
+            wrap_u,
+            wrap_v,
+        ))
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
     pub fn fetch_nearest(&self, uv: Vec2) -> Vec3 {
         let u = match self.wrap_u {
             WrapMode::Clamp => uv.x.clamp(0., 1.),
@@ -43,8 +120,8 @@ impl Texture {
             WrapMode::Repeat => uv.y.rem_euclid(1.),
         };
 
-        let x = ((self.width - 1) as f32 * u) as usize;
-        let y = ((self.height - 1) as f32 * v) as usize;
+        let x = ((self.width as f32 * u) as usize).min(self.width as usize - 1);
+        let y = ((self.height as f32 * v) as usize).min(self.height as usize - 1);
 
         let i = (x + (self.width as usize * y)) * self.format.size() as usize;
 
@@ -68,6 +145,273 @@ impl Texture {
 
                 vec3(r, g, b)
             }
+            Format::R32G32B32F => {
+                let channel = |c: usize| {
+                    let i = i + 4 * c;
+                    f32::from_ne_bytes(self.bytes[i..i + 4].try_into().unwrap())
+                };
+
+                vec3(channel(0), channel(1), channel(2))
+            }
+        }
+    }
+}
+
+/// The sRGB transfer function, from the encoded value to linear
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Image of a float texture, either a single repeating image or UDIM tiles
+pub enum ImageTexture {
+    Single(Texture),
+    Udim(UdimTexture),
+}
+
+impl ImageTexture {
+    /// Filenames with the "<UDIM>" placeholder are loaded as UDIM tiles
+    pub fn load(path: &Path) -> Result<Self> {
+        match path.to_str() {
+            Some(pattern) if pattern.contains(UdimTexture::PLACEHOLDER) => {
+                Ok(Self::Udim(UdimTexture::new(pattern)))
+            }
+            _ => Ok(Self::Single(Texture::load(
+                path,
+                WrapMode::Repeat,
+                WrapMode::Repeat,
+            )?)),
+        }
+    }
+
+    pub fn fetch_nearest(&self, uv: Vec2) -> Vec3 {
+        match self {
+            ImageTexture::Single(texture) => texture.fetch_nearest(uv),
+            ImageTexture::Udim(texture) => texture.fetch_nearest(uv),
+        }
+    }
+
+    /// Size of a texel in UV space, UDIM tiles can have different resolutions
+    pub fn texel_size(&self, uv: Vec2) -> Vec2 {
+        let texture = match self {
+            ImageTexture::Single(texture) => Some(texture),
+            ImageTexture::Udim(texture) => texture.tile_at(uv),
+        };
+
+        match texture {
+            Some(texture) => 1. / vec2(texture.width() as f32, texture.height() as f32),
+            // Missing tiles are constant
+            None => Vec2::splat(UdimTexture::MISSING_TILE_TEXEL),
+        }
+    }
+}
+
+impl std::fmt::Debug for ImageTexture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageTexture::Single(texture) => f
+                .debug_struct("Texture")
+                .field("width", &texture.width)
+                .field("height", &texture.height)
+                .finish(),
+            ImageTexture::Udim(texture) => f
+                .debug_struct("UdimTexture")
+                .field("path_pattern", &texture.path_pattern)
+                .finish(),
+        }
+    }
+}
+
+/// Alpha of a shape, used for cutouts
+pub enum AlphaMask {
+    Constant(f32),
+    Texture(ImageTexture),
+}
+
+impl AlphaMask {
+    /// Surfaces with alpha below this value don't occlude at all
+    const CUTOFF: f32 = 0.01;
+
+    pub fn init(alpha: &Alpha) -> Result<Self> {
+        Ok(match alpha {
+            Alpha::Constant(alpha) => Self::Constant(*alpha),
+            Alpha::ImageTexture(path) => Self::Texture(ImageTexture::load(path)?),
+        })
+    }
+
+    /// Alpha of RGB textures is the average of the channels
+    pub fn eval(&self, uv: Option<Vec2>) -> f32 {
+        match self {
+            AlphaMask::Constant(alpha) => *alpha,
+            AlphaMask::Texture(texture) => {
+                let rgb = texture.fetch_nearest(uv.unwrap_or(Vec2::ZERO));
+                (rgb.x + rgb.y + rgb.z) / 3.
+            }
+        }
+    }
+
+    /// Fully transparent parts, which are never hit by any ray
+    pub fn is_cut_out(&self, uv: Option<Vec2>) -> bool {
+        self.eval(uv) < Self::CUTOFF
+    }
+
+    /// Decides whether a ray passes through the surface at the given UV.
+    /// Partially transparent surfaces are passed through with the probability of 1 - alpha.
+    pub fn is_transparent(&self, uv: Option<Vec2>, rng: &mut Sampler) -> bool {
+        let alpha = self.eval(uv);
+        if alpha < Self::CUTOFF {
+            true
+        } else if alpha >= 1. {
+            false
+        } else {
+            Uniform::from(0f32..1f32).sample(rng) >= alpha
+        }
+    }
+}
+
+impl std::fmt::Debug for AlphaMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlphaMask::Constant(alpha) => f.debug_tuple("Constant").field(alpha).finish(),
+            AlphaMask::Texture(texture) => texture.fmt(f),
+        }
+    }
+}
+
+/// Height field that perturbs the shading normals of a surface
+pub struct BumpMap {
+    texture: ImageTexture,
+}
+
+impl BumpMap {
+    pub fn new(texture: ImageTexture) -> Self {
+        Self { texture }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self::new(ImageTexture::load(path)?))
+    }
+
+    /// Height of RGB textures is the average of the channels
+    pub fn height(&self, uv: Vec2) -> f32 {
+        let rgb = self.texture.fetch_nearest(uv);
+        (rgb.x + rgb.y + rgb.z) / 3.
+    }
+
+    /// Normal of the surface displaced along the (normalized) normal by the height, same as
+    /// PBRT's BumpMap. The height is in world units, so the UV gradient of the height field is
+    /// scaled by the surface derivatives dp/du and dp/dv. The gradient is a central difference
+    /// of one texel, the change of the normal over the surface is neglected.
+    pub fn perturb_normal(&self, uv: Vec2, normal: Vec3, dpdu: Vec3, dpdv: Vec3) -> Vec3 {
+        let Vec2 { x: du, y: dv } = self.texture.texel_size(uv);
+        let dh_du = (self.height(uv + vec2(du, 0.)) - self.height(uv - vec2(du, 0.))) / (2. * du);
+        let dh_dv = (self.height(uv + vec2(0., dv)) - self.height(uv - vec2(0., dv))) / (2. * dv);
+
+        // Interpolated shading normals aren't perpendicular to the derivatives
+        let dpdu = dpdu - normal * normal.dot(dpdu);
+        let dpdv = dpdv - normal * normal.dot(dpdv);
+
+        let bumped = (dpdu + dh_du * normal)
+            .cross(dpdv + dh_dv * normal)
+            .normalize();
+        if !bumped.is_finite() {
+            return normal;
+        }
+
+        // The derivatives are left-handed on mirrored surfaces
+        if bumped.dot(normal) < 0. {
+            -bumped
+        } else {
+            bumped
+        }
+    }
+}
+
+impl std::fmt::Debug for BumpMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BumpMap")
+            .field("texture", &self.texture)
+            .finish()
+    }
+}
+
+/// Texture split into UDIM tiles, which are loaded lazily on the first access.
+/// Tile 1001 covers UVs in [0, 1)^2, the tile number increases by 1 in U and by 10 in V.
+pub struct UdimTexture {
+    /// Path of the tiles with a "<UDIM>" placeholder for the tile number
+    path_pattern: String,
+    /// None if the tile couldn't be loaded
+    tiles: Vec<OnceLock<Option<Texture>>>,
+}
+
+impl UdimTexture {
+    const FIRST_TILE: u32 = 1001;
+    const TILES_U: u32 = 10;
+    const TILES_V: u32 = 10;
+    pub const PLACEHOLDER: &'static str = "<UDIM>";
+    /// Debug color for UVs that fall into tiles that don't exist
+    pub const MISSING_TILE_COLOR: Vec3 = vec3(1., 0., 1.);
+    /// Missing tiles have no resolution, this is used for finite differences instead
+    const MISSING_TILE_TEXEL: f32 = 1. / 1024.;
+
+    pub fn new(path_pattern: &str) -> Self {
+        let tiles = (0..Self::TILES_U * Self::TILES_V)
+            .map(|_| OnceLock::new())
+            .collect();
+
+        Self {
+            path_pattern: path_pattern.to_string(),
+            tiles,
+        }
+    }
+
+    /// Returns the UDIM tile number and the UV within that tile
+    pub fn tile_coords(uv: Vec2) -> Option<(u32, Vec2)> {
+        let tile = uv.floor();
+        if tile.x < 0.
+            || tile.y < 0.
+            || tile.x >= Self::TILES_U as f32
+            || tile.y >= Self::TILES_V as f32
+        {
+            return None;
+        }
+
+        let tile_number = Self::FIRST_TILE + tile.x as u32 + Self::TILES_U * tile.y as u32;
+        Some((tile_number, uv - tile))
+    }
+
+    pub fn tile_path(&self, tile_number: u32) -> String {
+        self.path_pattern
+            .replace(Self::PLACEHOLDER, &tile_number.to_string())
+    }
+
+    /// The tile is loaded on the first access, None if it doesn't exist
+    fn tile(&self, tile_number: u32) -> Option<&Texture> {
+        self.tiles[(tile_number - Self::FIRST_TILE) as usize]
+            .get_or_init(|| {
+                let path = self.tile_path(tile_number);
+                Texture::load(Path::new(&path), WrapMode::Clamp, WrapMode::Clamp).ok()
+            })
+            .as_ref()
+    }
+
+    /// The tile that the UV falls into
+    pub fn tile_at(&self, uv: Vec2) -> Option<&Texture> {
+        let (tile_number, _) = Self::tile_coords(uv)?;
+        self.tile(tile_number)
+    }
+
+    pub fn fetch_nearest(&self, uv: Vec2) -> Vec3 {
+        let Some((tile_number, tile_uv)) = Self::tile_coords(uv) else {
+            return Self::MISSING_TILE_COLOR;
+        };
+
+        match self.tile(tile_number) {
+            Some(texture) => texture.fetch_nearest(tile_uv),
+            None => Self::MISSING_TILE_COLOR,
         }
     }
 }
@@ -78,6 +422,8 @@ pub enum Format {
     R8G8,
     R8G8B8,
     R8G8B8A8,
+    /// Linear floats in native byte order
+    R32G32B32F,
 }
 
 impl Format {
@@ -87,6 +433,7 @@ impl Format {
             Format::R8G8 => 2,
             Format::R8G8B8 => 3,
             Format::R8G8B8A8 => 4,
+            Format::R32G32B32F => 12,
         }
     }
 }
@@ -96,3 +443,76 @@ pub enum WrapMode {
     Clamp,
     Repeat,
 }
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::util::test_dir;
+    use glam::vec2;
+
+    #[test]
+    fn test_udim_tiles() {
+        let dir = test_dir("udim");
+
+        image::RgbImage::from_pixel(2, 2, image::Rgb([255, 0, 0]))
+            .save(dir.join("mesh.1001.png"))
+            .unwrap();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([0, 255, 0]))
+            .save(dir.join("mesh.1002.png"))
+            .unwrap();
+
+        let pattern = dir.join("mesh.<UDIM>.png");
+        let texture = UdimTexture::new(pattern.to_str().unwrap());
+
+        assert_eq!(
+            UdimTexture::tile_coords(vec2(1.5, 0.25)),
+            Some((1002, vec2(0.5, 0.25)))
+        );
+        assert_eq!(UdimTexture::tile_coords(vec2(0.5, 1.5)).unwrap().0, 1011);
+        assert!(UdimTexture::tile_coords(vec2(-0.5, 0.5)).is_none());
+
+        assert_eq!(texture.fetch_nearest(vec2(0.5, 0.5)), vec3(1., 0., 0.));
+        assert_eq!(texture.fetch_nearest(vec2(1.5, 0.5)), vec3(0., 1., 0.));
+        assert_eq!(
+            texture.fetch_nearest(vec2(2.5, 0.5)),
+            UdimTexture::MISSING_TILE_COLOR
+        );
+
+        // Float textures of the scene pick the UDIM tiles by the filename
+        let texture = ImageTexture::load(&pattern).unwrap();
+        assert!(matches!(texture, ImageTexture::Udim(_)));
+        assert_eq!(texture.fetch_nearest(vec2(1.5, 0.5)), vec3(0., 1., 0.));
+        assert_eq!(texture.texel_size(vec2(0.5, 0.5)), Vec2::splat(0.5));
+        assert_eq!(texture.texel_size(vec2(1.5, 0.5)), Vec2::splat(0.25));
+        assert!(texture.texel_size(vec2(2.5, 0.5)).x > 0.);
+
+        let single = ImageTexture::load(&dir.join("mesh.1001.png")).unwrap();
+        assert!(matches!(single, ImageTexture::Single(_)));
+    }
+
+    #[test]
+    fn test_load_linear_rgb() {
+        let dir = test_dir("linear-rgb");
+
+        // Mid-gray is about 0.21 in linear sRGB, not 0.5
+        let png = dir.join("gray.png");
+        image::RgbImage::from_pixel(2, 2, image::Rgb([128, 128, 128]))
+            .save(&png)
+            .unwrap();
+        let texture = Texture::load_linear_rgb(&png, WrapMode::Clamp, WrapMode::Clamp).unwrap();
+        let gray = texture.fetch_nearest(vec2(0.5, 0.5));
+        assert!(gray.abs_diff_eq(Vec3::splat(0.2158), 1e-4), "{gray}");
+
+        // EXR values are linear and can be brighter than 1
+        let exr = dir.join("bright.exr");
+        exr::prelude::write_rgb_file(&exr, 2, 1, |x, _| match x {
+            0 => (4f32, 0.5f32, 0f32),
+            _ => (0.25, 0.25, 0.25),
+        })
+        .unwrap();
+        let texture = Texture::load_linear_rgb(&exr, WrapMode::Clamp, WrapMode::Clamp).unwrap();
+        assert_eq!((texture.width(), texture.height()), (2, 1));
+        assert_eq!(texture.fetch_nearest(vec2(0.25, 0.5)), vec3(4., 0.5, 0.));
+        assert_eq!(texture.fetch_nearest(vec2(0.75, 0.5)), Vec3::splat(0.25));
+    }
+}
@@ -0,0 +1,127 @@
+use glam::Vec3;
+
+use crate::film::LuminanceStats;
+
+/// Maps linear radiance to displayable values in [0, 1].
+/// Shared by the preview window and tonemapped image outputs, so that they look the same.
+#[derive(Debug, Clone, Copy)]
+pub struct Tonemapper {
+    /// Exposure in stops, linear radiance is multiplied by 2^exposure
+    exposure: f32,
+    /// Smallest value that is mapped to white.
+    /// Infinite white point is the same as the simple Reinhard operator.
+    white_point: f32,
+    auto_exposure: bool,
+    /// Exposure set by `meter()`, the manual exposure is added to it as a compensation
+    metered_exposure: f32,
+}
+
+impl Tonemapper {
+    /// Reinhard's key value, the log-average luminance is mapped to middle gray
+    pub const KEY: f32 = 0.18;
+
+    pub fn new(exposure: f32, white_point: Option<f32>) -> Self {
+        Self {
+            exposure,
+            white_point: white_point.unwrap_or(f32::INFINITY),
+            auto_exposure: false,
+            metered_exposure: 0.,
+        }
+    }
+
+    /// The exposure is set from the image's luminance, see `meter()`
+    pub fn with_auto_exposure(mut self) -> Self {
+        self.auto_exposure = true;
+        self
+    }
+
+    /// With auto exposure, scales the log-average luminance to the key, as in Reinhard et al.
+    /// Black images are left as is.
+    pub fn meter(&mut self, stats: &LuminanceStats) {
+        if self.auto_exposure && stats.mean > 0. {
+            self.metered_exposure = (Self::KEY / stats.log_average).log2();
+        }
+    }
+
+    pub fn expose(&self, rgb: Vec3) -> Vec3 {
+        rgb * (self.exposure + self.metered_exposure).exp2()
+    }
+
+    pub fn tonemap(&self, rgb: Vec3) -> Vec3 {
+        let c = self.expose(rgb);
+
+        // Extended Reinhard: Photographic Tone Reproduction for Digital Images, Reinhard et al.
+        let c = c * (1. + c / (self.white_point * self.white_point)) / (1. + c);
+        c.clamp(Vec3::ZERO, Vec3::ONE)
+    }
+}
+
+impl Default for Tonemapper {
+    fn default() -> Self {
+        Self::new(0., None)
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_exposure() {
+        let rgb = Vec3::new(0.1, 0.5, 2.);
+
+        let tonemapper = Tonemapper::new(1., None);
+        assert_eq!(tonemapper.expose(rgb), rgb * 2.);
+
+        let tonemapper = Tonemapper::new(-2., None);
+        assert_eq!(tonemapper.expose(rgb), rgb / 4.);
+    }
+
+    #[test]
+    fn test_reinhard_extended() {
+        let tonemapper = Tonemapper::default();
+        assert_eq!(tonemapper.tonemap(Vec3::ONE), Vec3::splat(0.5));
+
+        let tonemapper = Tonemapper::new(0., Some(4.));
+        assert_eq!(tonemapper.tonemap(Vec3::splat(4.)), Vec3::ONE);
+        assert_eq!(tonemapper.tonemap(Vec3::splat(8.)), Vec3::ONE);
+        assert!(tonemapper.tonemap(Vec3::splat(3.)).x < 1.);
+    }
+
+    #[test]
+    fn test_auto_exposure() {
+        let stats = LuminanceStats {
+            mean: 0.72,
+            log_average: 0.36,
+            max: 4.,
+        };
+
+        // Only metered with auto exposure
+        let mut tonemapper = Tonemapper::new(0., None);
+        tonemapper.meter(&stats);
+        assert_eq!(tonemapper.expose(Vec3::ONE), Vec3::ONE);
+
+        let mut tonemapper = Tonemapper::new(0., None).with_auto_exposure();
+        tonemapper.meter(&stats);
+        let exposed = tonemapper.expose(Vec3::splat(stats.log_average));
+        assert!((exposed - Vec3::splat(Tonemapper::KEY)).abs().max_element() < 1e-6);
+
+        // Manual exposure compensates the metered one
+        let mut tonemapper = Tonemapper::new(1., None).with_auto_exposure();
+        tonemapper.meter(&stats);
+        let exposed = tonemapper.expose(Vec3::splat(stats.log_average));
+        assert!(
+            (exposed - Vec3::splat(2. * Tonemapper::KEY))
+                .abs()
+                .max_element()
+                < 1e-6
+        );
+
+        tonemapper.meter(&LuminanceStats {
+            mean: 0.,
+            log_average: LuminanceStats::LOG_DELTA as f32,
+            max: 0.,
+        });
+        assert!(tonemapper.expose(Vec3::ONE).is_finite());
+    }
+}
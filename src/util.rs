@@ -1,8 +1,17 @@
-use std::time::Instant;
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
 
 use enum_ptr::Compact;
 
 pub fn timed_scope<R, F: FnOnce() -> R>(label: &str, fun: F) -> R {
+    timed_scope_duration(label, fun).0
+}
+
+/// Same as timed_scope, but also returns the measured time
+pub fn timed_scope_duration<R, F: FnOnce() -> R>(label: &str, fun: F) -> (R, Duration) {
     let start = Instant::now();
 
     let res = fun();
@@ -10,7 +19,22 @@ pub fn timed_scope<R, F: FnOnce() -> R>(label: &str, fun: F) -> R {
     let time = Instant::now().duration_since(start);
     println!("{label} took: {time:?}");
 
-    res
+    (res, time)
+}
+
+/// Creates a new empty directory for the files of a test.
+/// Tests run in parallel, so every call gets its own directory, even for the same name.
+pub fn test_dir(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = std::env::temp_dir().join(format!(
+        "rt-summer-test-{name}-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
 }
 
 pub struct TaggedPtr<T>(pub Compact<T>)
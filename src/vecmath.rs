@@ -70,6 +70,7 @@ pub fn orient_dir(dir: Vec3, normal: Vec3) -> Vec3 {
     sample_dir
 }
 
+/// Camera-from-world transform, the camera space is left-handed and looks along +Z like in PBRT
 pub fn look_at(eye: Vec3, look: Vec3, up: Vec3) -> Mat4 {
     Mat4::look_at_lh(eye, look, up)
 }
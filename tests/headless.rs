@@ -0,0 +1,124 @@
+use std::process::Command;
+
+use rt_summer::util::test_dir;
+
+/// Runs the binary without a display, it has to fall back to rendering without the window
+#[test]
+fn test_render_without_display() {
+    let dir = test_dir("headless");
+
+    let scene_path = dir.join("scene.pbrt");
+    std::fs::write(
+        &scene_path,
+        r#"
+        Camera "perspective" "float fov" 45
+        Film "rgb" "integer xresolution" 8 "integer yresolution" 8
+        WorldBegin
+        AttributeBegin
+        AreaLightSource "diffuse" "rgb L" [1 1 1]
+        Transform [1 0 0 0  0 1 0 0  0 0 1 0  0 0 5 1]
+        Shape "sphere" "float radius" 1
+        AttributeEnd
+        "#,
+    )
+    .unwrap();
+
+    let output = dir.join("headless.exr");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_rt-summer"))
+        .env_remove("DISPLAY")
+        .env_remove("WAYLAND_DISPLAY")
+        .arg("--scene")
+        .arg(&scene_path)
+        .arg("--output")
+        .arg(&output)
+        .args(["--spp", "2", "--threads", "1"])
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert!(output.exists());
+
+    // The finished image isn't overwritten without --force
+    let status = Command::new(env!("CARGO_BIN_EXE_rt-summer"))
+        .env_remove("DISPLAY")
+        .env_remove("WAYLAND_DISPLAY")
+        .arg("--scene")
+        .arg(&scene_path)
+        .arg("--output")
+        .arg(&output)
+        .arg("--headless")
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
+
+/// The benchmark renders as many samples as fit into the time and reports them
+#[test]
+fn test_bench_seconds() {
+    let dir = test_dir("bench");
+
+    let scene_path = dir.join("scene.pbrt");
+    std::fs::write(
+        &scene_path,
+        r#"
+        Camera "perspective" "float fov" 45
+        Film "rgb" "integer xresolution" 8 "integer yresolution" 8
+        WorldBegin
+        AttributeBegin
+        AreaLightSource "diffuse" "rgb L" [1 1 1]
+        Transform [1 0 0 0  0 1 0 0  0 0 1 0  0 0 5 1]
+        Shape "sphere" "float radius" 1
+        AttributeEnd
+        "#,
+    )
+    .unwrap();
+
+    let output = dir.join("bench.exr");
+    let bench_seconds = 0.5;
+    let out = Command::new(env!("CARGO_BIN_EXE_rt-summer"))
+        .arg("--scene")
+        .arg(&scene_path)
+        .arg("--output")
+        .arg(&output)
+        .args(["--force", "--threads", "1", "--spp", "1"])
+        .args(["--bench-seconds", &bench_seconds.to_string()])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+
+    // "Render finished with N samples in T (X samples/s)"
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let report = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("Render finished with "))
+        .unwrap();
+    let samples: u32 = report.split(' ').next().unwrap().parse().unwrap();
+    let per_second: f64 = report
+        .rsplit_once('(')
+        .and_then(|(_, rate)| rate.strip_suffix(" samples/s)"))
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    // --spp is ignored, a tiny scene takes many samples. The time is over by at most one pass.
+    assert!(samples > 1, "{report}");
+    let elapsed = samples as f64 / per_second;
+    assert!(elapsed <= bench_seconds * 1.5, "{report}");
+
+    // The image is the average of the samples, the light is seen directly in the center
+    let center = exr::prelude::read_first_rgba_layer_from_file(
+        &output,
+        |_, _| Vec::new(),
+        |pixels: &mut Vec<f32>, position, (r, _, _, _): (f32, f32, f32, f32)| {
+            if position.x() == 4 && position.y() == 4 {
+                pixels.push(r);
+            }
+        },
+    )
+    .unwrap()
+    .layer_data
+    .channel_data
+    .pixels[0];
+    assert!(center > 0.5 && center < 2., "{center}");
+}
@@ -0,0 +1,417 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use glam::Vec3;
+use rt_summer::{
+    color::quantity::ColorMode,
+    integrator::Integrator,
+    pbrt_loader::SceneLoader,
+    render_scene,
+    render_threads::{RenderContext, RenderThreads},
+    sampler::SamplerKind,
+    util::test_dir,
+    RenderOptions,
+};
+
+#[test]
+fn test_render_cornell_box_to_buffer() {
+    let scene_desc =
+        SceneLoader::load_from_path("resources/scenes/cornell-box/scene-v4.pbrt").unwrap();
+
+    let options = RenderOptions {
+        samples: 4,
+        ..RenderOptions::default()
+    };
+
+    let (film, samples) = render_scene(scene_desc, &options).unwrap();
+    assert_eq!(samples, options.samples);
+
+    let center = film.get_rgb(film.width() / 2, film.height() / 2);
+    assert!(center.is_finite());
+    assert!(center.max_element() > 0.);
+}
+
+#[test]
+fn test_render_time_limit() {
+    let scene_desc =
+        SceneLoader::load_from_path("resources/scenes/cornell-box/scene-v4.pbrt").unwrap();
+
+    let options = RenderOptions {
+        samples: u32::MAX,
+        time_limit: Some(Duration::from_millis(1)),
+        ..RenderOptions::default()
+    };
+
+    let (film, samples) = render_scene(scene_desc, &options).unwrap();
+    assert!(samples >= 1);
+    assert!(samples < options.samples);
+
+    let center = film.get_rgb(film.width() / 2, film.height() / 2);
+    assert!(center.max_element() > 0.);
+}
+
+#[test]
+fn test_render_pass_snapshots() {
+    let scene_desc =
+        SceneLoader::load_from_path("resources/scenes/cornell-box/scene-v4.pbrt").unwrap();
+    let integrator = Integrator::new("simple-path").unwrap();
+    let render_context = Arc::new(RenderContext::new(scene_desc, integrator).unwrap());
+    let mut threads = RenderThreads::new(2, Some(0), render_context).unwrap();
+
+    assert_eq!(threads.samples(), 0);
+
+    let first = threads.render_pass();
+    assert_eq!(first.samples, 1);
+    assert_eq!(first.pixels.len(), first.width * first.height);
+
+    let second = threads.render_pass();
+    assert_eq!(second.samples, 2);
+    assert_eq!(threads.samples(), 2);
+
+    let center = second.get_rgb(second.width / 2, second.height / 2);
+    assert!(center.is_finite());
+    assert!(center.max_element() > 0.);
+}
+
+#[test]
+fn test_render_pass_polling() {
+    let dir = test_dir("polling");
+    let scene_path = dir.join("scene.pbrt");
+    std::fs::write(
+        &scene_path,
+        r#"
+        Camera "perspective" "float fov" 45
+        Film "rgb" "integer xresolution" 16 "integer yresolution" 16
+        WorldBegin
+        AttributeBegin
+        AreaLightSource "diffuse" "rgb L" [1 1 1]
+        Transform [1 0 0 0  0 1 0 0  0 0 1 0  0 0 5 1]
+        Shape "sphere" "float radius" 1
+        AttributeEnd
+        "#,
+    )
+    .unwrap();
+
+    let threads = || {
+        let scene_desc = SceneLoader::load_from_path(&scene_path).unwrap();
+        let integrator = Integrator::new("simple-path").unwrap();
+        let render_context = Arc::new(RenderContext::new(scene_desc, integrator).unwrap());
+        RenderThreads::new(1, Some(0), render_context).unwrap()
+    };
+
+    let mut blocking = threads();
+    blocking.render_once();
+    let blocking = blocking.render_pass();
+
+    // Polling without waiting renders exactly the same passes
+    let mut polling = threads();
+    assert!(polling.wait_pass(Duration::ZERO));
+    for pass in 0..2 {
+        polling.start_pass();
+        assert!(polling.pass_in_progress());
+        while !polling.wait_pass(Duration::ZERO) {
+            assert_eq!(polling.samples(), pass);
+        }
+        assert!(!polling.pass_in_progress());
+        assert_eq!(polling.samples(), pass + 1);
+    }
+
+    let polled = polling.snapshot();
+    assert_eq!(polled.samples, 2);
+    assert_eq!(polled.pixels, blocking.pixels);
+    assert!(polled.get_rgb(8, 8).max_element() > 0.);
+}
+
+#[test]
+fn test_tile_buffers() {
+    let dir = test_dir("tile-buffers");
+    let scene_path = dir.join("scene.pbrt");
+    std::fs::write(
+        &scene_path,
+        r#"
+        Camera "perspective" "float fov" 45
+        Film "rgb" "integer xresolution" 24 "integer yresolution" 16
+        WorldBegin
+        AttributeBegin
+        AreaLightSource "diffuse" "rgb L" [1 1 1]
+        Transform [1 0 0 0  0 1 0 0  0 0 1 0  0 0 5 1]
+        Shape "sphere" "float radius" 1
+        AttributeEnd
+        "#,
+    )
+    .unwrap();
+
+    let render = |tile_buffers: bool| {
+        let scene_desc = SceneLoader::load_from_path(&scene_path).unwrap();
+        let integrator = Integrator::new("simple-path").unwrap();
+        let render_context = RenderContext::new(scene_desc, integrator)
+            .unwrap()
+            .with_tile_size(7)
+            .with_tile_buffers(tile_buffers);
+        let mut threads = RenderThreads::new(1, Some(0), Arc::new(render_context)).unwrap();
+        threads.render_once();
+        threads.render_once();
+        threads.snapshot()
+    };
+
+    // Merging the tiles gives the same film as writing the samples directly
+    let direct = render(false);
+    let buffered = render(true);
+    assert_eq!(buffered.samples, 2);
+    assert_eq!(buffered.pixels, direct.pixels);
+    assert!(buffered.get_rgb(12, 8).max_element() > 0.);
+}
+
+fn render_pixels(options: &RenderOptions) -> (usize, usize, Vec<Vec3>) {
+    let scene_desc = SceneLoader::load_from_path(small_cornell_box_path()).unwrap();
+    let (film, _) = render_scene(scene_desc, options).unwrap();
+
+    let mut pixels = Vec::with_capacity(film.width() * film.height());
+    for y in 0..film.height() {
+        for x in 0..film.width() {
+            pixels.push(film.get_rgb(x, y));
+        }
+    }
+
+    (film.width(), film.height(), pixels)
+}
+
+/// Compares a seeded render of the small Cornell box with the reference image in tests/data.
+/// After an intended change in shading, re-create the reference with
+/// `RT_UPDATE_GOLDEN=1 cargo test --test render_scene test_cornell_box_golden_image`.
+#[test]
+fn test_cornell_box_golden_image() {
+    const REFERENCE_PATH: &str = "tests/data/cornell-box-golden.exr";
+    const RMSE_TOLERANCE: f32 = 0.01;
+
+    let options = RenderOptions {
+        num_threads: 1,
+        samples: 8,
+        seed: Some(0),
+        ..RenderOptions::default()
+    };
+
+    let (width, height, pixels) = render_pixels(&options);
+
+    // The seeded single-threaded render has to be reproducible
+    let (_, _, pixels_again) = render_pixels(&options);
+    assert!(pixels == pixels_again);
+
+    if std::env::var_os("RT_UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all("tests/data").unwrap();
+        exr::prelude::write_rgb_file(REFERENCE_PATH, width, height, |x, y| {
+            let p = pixels[y * width + x];
+            (p.x, p.y, p.z)
+        })
+        .unwrap();
+    }
+
+    let reference = exr::prelude::read_first_rgba_layer_from_file(
+        REFERENCE_PATH,
+        |resolution, _| vec![Vec3::ZERO; resolution.width() * resolution.height()],
+        move |buffer, position, (r, g, b, _): (f32, f32, f32, f32)| {
+            buffer[position.y() * width + position.x()] = Vec3::new(r, g, b);
+        },
+    )
+    .expect("The reference image is missing, create it with RT_UPDATE_GOLDEN")
+    .layer_data
+    .channel_data
+    .pixels;
+
+    assert_eq!(reference.len(), pixels.len());
+
+    let squared_error: f32 = pixels
+        .iter()
+        .zip(reference.iter())
+        .map(|(p, r)| (*p - *r).length_squared() / 3.)
+        .sum();
+    let rmse = (squared_error / pixels.len() as f32).sqrt();
+
+    assert!(rmse < RMSE_TOLERANCE, "RMSE to the reference image: {rmse}");
+}
+
+/// Every camera ray hits the same emitter head-on, so every pixel gets exactly one sample of the
+/// same radiance. Pixels that were skipped are black, pixels written twice are twice as bright.
+#[test]
+fn test_awkward_resolution_covers_every_pixel() {
+    let dir = test_dir("coverage");
+
+    let scene_path = dir.join("scene.pbrt");
+    std::fs::write(
+        &scene_path,
+        r#"
+        Camera "perspective" "float fov" 45
+        Film "rgb" "integer xresolution" 13 "integer yresolution" 7
+        WorldBegin
+        AttributeBegin
+        AreaLightSource "diffuse" "rgb L" [1 1 1]
+        Shape "trianglemesh" "point3 P" [-100 -100 5  100 -100 5  100 100 5  -100 100 5]
+            "integer indices" [0 2 1  0 3 2]
+        AttributeEnd
+        "#,
+    )
+    .unwrap();
+
+    let scene_desc = SceneLoader::load_from_path(scene_path).unwrap();
+    let options = RenderOptions {
+        samples: 1,
+        color_mode: ColorMode::Rgb,
+        ..RenderOptions::default()
+    };
+    let (film, _) = render_scene(scene_desc, &options).unwrap();
+
+    let expected = film.get_rgb(0, 0);
+    assert!(expected.min_element() > 0.);
+    for y in 0..film.height() {
+        for x in 0..film.width() {
+            let rgb = film.get_rgb(x, y);
+            assert!(
+                (rgb - expected).abs().max_element() < 1e-4,
+                "{x} {y}: {rgb}"
+            );
+        }
+    }
+}
+
+/// A small Cornell box, built in the test so that it doesn't need the scene files from the
+/// resources. Loading it still needs the RGB to spectrum table.
+fn small_cornell_box_path() -> PathBuf {
+    let dir = test_dir("small-cornell-box");
+
+    let scene_path = dir.join("scene.pbrt");
+    std::fs::write(
+        &scene_path,
+        r#"
+        Camera "perspective" "float fov" 40
+        Film "rgb" "integer xresolution" 16 "integer yresolution" 16
+        WorldBegin
+        MakeNamedMaterial "white" "string type" "diffuse" "rgb reflectance" [0.7 0.7 0.7]
+        MakeNamedMaterial "red" "string type" "diffuse" "rgb reflectance" [0.7 0.1 0.1]
+        MakeNamedMaterial "green" "string type" "diffuse" "rgb reflectance" [0.1 0.7 0.1]
+        AttributeBegin
+        NamedMaterial "white"
+        Shape "trianglemesh" "point3 P" [-1 -1 1  1 -1 1  1 -1 3  -1 -1 3
+                                         -1 1 1  1 1 1  1 1 3  -1 1 3]
+            "integer indices" [0 1 2  0 2 3  4 6 5  4 7 6  3 2 6  3 6 7]
+        NamedMaterial "red"
+        Shape "trianglemesh" "point3 P" [-1 -1 1  -1 -1 3  -1 1 3  -1 1 1]
+            "integer indices" [0 1 2  0 2 3]
+        NamedMaterial "green"
+        Shape "trianglemesh" "point3 P" [1 -1 1  1 -1 3  1 1 3  1 1 1]
+            "integer indices" [0 2 1  0 3 2]
+        AttributeEnd
+        AttributeBegin
+        AreaLightSource "diffuse" "rgb L" [10 10 10]
+        Shape "trianglemesh" "point3 P" [-0.3 0.99 1.7  0.3 0.99 1.7  0.3 0.99 2.3  -0.3 0.99 2.3]
+            "integer indices" [0 1 2  0 2 3]
+        AttributeEnd
+        "#,
+    )
+    .unwrap();
+
+    scene_path
+}
+
+fn render_small_cornell_box(sampler: SamplerKind, samples: u32, seed: u64) -> Vec<Vec3> {
+    let scene_desc = SceneLoader::load_from_path(small_cornell_box_path()).unwrap();
+    let options = RenderOptions {
+        num_threads: 1,
+        seed: Some(seed),
+        samples,
+        color_mode: ColorMode::Rgb,
+        sampler,
+        ..RenderOptions::default()
+    };
+    let (film, _) = render_scene(scene_desc, &options).unwrap();
+
+    let mut pixels = Vec::with_capacity(film.width() * film.height());
+    for y in 0..film.height() {
+        for x in 0..film.width() {
+            pixels.push(film.get_rgb(x, y));
+        }
+    }
+    pixels
+}
+
+#[test]
+fn test_sobol_converges_faster() {
+    const SAMPLES: u32 = 8;
+
+    // Independent samples with another seed, so that the reference doesn't share its error
+    // with either of the compared renders
+    let reference = render_small_cornell_box(SamplerKind::Independent, 1024, 1);
+    assert!(reference.iter().all(|p| p.max_element() > 0.));
+
+    let mse = |pixels: Vec<Vec3>| {
+        pixels
+            .iter()
+            .zip(&reference)
+            .map(|(p, r)| (*p - *r).length_squared() / 3.)
+            .sum::<f32>()
+            / pixels.len() as f32
+    };
+
+    let independent_mse = mse(render_small_cornell_box(
+        SamplerKind::Independent,
+        SAMPLES,
+        0,
+    ));
+    let sobol_mse = mse(render_small_cornell_box(SamplerKind::Sobol, SAMPLES, 0));
+    assert!(
+        sobol_mse < independent_mse,
+        "independent MSE: {independent_mse}, Sobol MSE: {sobol_mse}"
+    );
+}
+
+#[test]
+fn test_render_pixel_list() {
+    // The middle of the back wall, about (0.24, 0.25, 0.21) when converged
+    let pixels = vec![(8, 6)];
+
+    let scene_desc = SceneLoader::load_from_path(small_cornell_box_path()).unwrap();
+    let options = RenderOptions {
+        num_threads: 1,
+        seed: Some(0),
+        samples: 64,
+        color_mode: ColorMode::Rgb,
+        pixels: Some(pixels.clone()),
+        ..RenderOptions::default()
+    };
+    let (film, samples) = render_scene(scene_desc, &options).unwrap();
+
+    for y in 0..film.height() {
+        for x in 0..film.width() {
+            let rgb = film.get_rgb(x, y);
+            if pixels.contains(&(x, y)) {
+                assert!(
+                    rgb.min_element() > 0.18 && rgb.max_element() < 0.3,
+                    "{x} {y}: {rgb}"
+                );
+                // The light reflected from the red and green walls has less blue
+                assert!(rgb.z < rgb.x.min(rgb.y), "{x} {y}: {rgb}");
+                assert_eq!(film.samples(x, y), samples);
+            } else {
+                assert_eq!(rgb, Vec3::ZERO);
+                assert_eq!(film.samples(x, y), 0);
+            }
+        }
+    }
+
+    // Pixels listed more than once still get one sample per pass, even with several threads
+    let duplicated = RenderOptions {
+        num_threads: 4,
+        samples: 8,
+        pixels: Some(vec![(8, 6), (3, 3), (8, 6), (8, 6)]),
+        ..options.clone()
+    };
+    let scene_desc = SceneLoader::load_from_path(small_cornell_box_path()).unwrap();
+    let (film, samples) = render_scene(scene_desc, &duplicated).unwrap();
+    assert_eq!(film.samples(8, 6), samples);
+    assert_eq!(film.samples(3, 3), samples);
+
+    let outside = RenderOptions {
+        pixels: Some(vec![(16, 0)]),
+        ..options
+    };
+    let scene_desc = SceneLoader::load_from_path(small_cornell_box_path()).unwrap();
+    assert!(render_scene(scene_desc, &outside).is_err());
+}
@@ -0,0 +1,50 @@
+use rt_summer::{
+    pbrt_loader::SceneLoader,
+    render_scene,
+    stats::{RayKind, TestKind, STATS},
+    util::test_dir,
+    RenderOptions,
+};
+
+/// The stats are global, so this is the only test in this binary
+#[test]
+fn test_primary_ray_count() {
+    let dir = test_dir("stats");
+
+    let scene_path = dir.join("scene.pbrt");
+    std::fs::write(
+        &scene_path,
+        r#"
+        Camera "perspective" "float fov" 45
+        Film "rgb" "integer xresolution" 16 "integer yresolution" 8
+        WorldBegin
+        AttributeBegin
+        AreaLightSource "diffuse" "rgb L" [1 1 1]
+        Transform [1 0 0 0  0 1 0 0  0 0 1 0  0 0 5 1]
+        Shape "sphere" "float radius" 1
+        AttributeEnd
+        "#,
+    )
+    .unwrap();
+
+    STATS.enable();
+
+    let scene_desc = SceneLoader::load_from_path(scene_path).unwrap();
+    let options = RenderOptions {
+        num_threads: 2,
+        samples: 1,
+        ..RenderOptions::default()
+    };
+    let (film, samples) = render_scene(scene_desc, &options).unwrap();
+    assert_eq!(samples, 1);
+
+    let pixels = (film.width() * film.height()) as u64;
+    assert_eq!(STATS.rays(RayKind::Primary), pixels);
+    assert!(STATS.rays(RayKind::Intersection) >= pixels);
+
+    if cfg!(feature = "intersection-stats") {
+        assert!(STATS.tests(TestKind::Aabb) >= pixels);
+        assert!(STATS.tests(TestKind::Sphere) > 0);
+        assert_eq!(STATS.tests(TestKind::Triangle), 0);
+    }
+}